@@ -3,14 +3,18 @@ use std::process::Command;
 // 重新编译只需要cargo build
 fn main() {
     let mut config = prost_build::Config::new();
-    config.bytes(&["."]);
-    config.type_attribute(".", "#[derive(PartialOrd)]");
+    config.bytes(["."]);
+    config.type_attribute(
+        ".",
+        "#[derive(PartialOrd, serde::Serialize, serde::Deserialize)] \
+         #[serde(rename_all = \"snake_case\")]",
+    );
     config
         .out_dir("src/pb")
         .compile_protos(&["abi.proto"], &["."])
         .unwrap();
     Command::new("cargo")
-        .args(&["fmt", "--", "src/*.rs"])
+        .args(["fmt", "--", "src/*.rs"])
         .status()
         .expect("cargo fmt failed");
 
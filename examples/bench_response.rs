@@ -0,0 +1,48 @@
+//! 对比构造 CommandResponse 再 encode 和用 ResponseFrame 直接编码的性能
+//!
+//! cargo run --release --example bench_response
+
+use std::time::Instant;
+
+use bytes::BytesMut;
+use kv2::{CommandResponse, FrameCoder, Kvpair, ResponseFrame, Value};
+
+const ROUNDS: u32 = 100_000;
+
+fn main() {
+    // 模拟一个小 table 的 HGETALL，value 小于压缩的阈值
+    let pairs: Vec<Kvpair> = (0..16)
+        .map(|i| Kvpair::new(format!("key{}", i), format!("value{}", i).into()))
+        .collect();
+    let mut buf = BytesMut::with_capacity(4096);
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let res: CommandResponse = pairs.clone().into();
+        res.encode_frame(&mut buf).unwrap();
+        buf.clear();
+    }
+    report("prost", start);
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let mut frame = ResponseFrame::new(&mut buf, 200);
+        for pair in &pairs {
+            let value: &Value = pair.value.as_ref().unwrap();
+            frame.pair(&pair.key, value);
+        }
+        frame.finish().unwrap();
+        buf.clear();
+    }
+    report("zero-copy", start);
+}
+
+fn report(name: &str, start: Instant) {
+    let elapsed = start.elapsed();
+    println!(
+        "{:>10}: {:?} total, {:?}/op",
+        name,
+        elapsed,
+        elapsed / ROUNDS
+    );
+}
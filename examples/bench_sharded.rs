@@ -0,0 +1,43 @@
+//! 对比多个线程同时写一个 table 时 MemTable 和 ShardedMemTable 的性能
+//!
+//! cargo run --release --example bench_sharded
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kv2::{MemTable, ShardedMemTable, Storage};
+
+const ROUNDS: usize = 200_000;
+
+fn main() {
+    let threads = thread::available_parallelism().map_or(4, |n| n.get());
+    println!("{} threads, {} ops each", threads, ROUNDS);
+    report("MemTable", run(Arc::new(MemTable::new()), threads));
+    report("Sharded", run(Arc::new(ShardedMemTable::new()), threads));
+}
+
+// 每个线程写自己的 key，再读一遍，所有的线程都用同一个 table
+fn run<S: Storage + Send + Sync + 'static>(store: Arc<S>, threads: usize) -> Duration {
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..ROUNDS {
+                    let key = format!("{}-{}", t, i % 1024);
+                    store.set("bench", key.clone(), (i as i64).into()).unwrap();
+                    store.get("bench", &key).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    println!("{:>10}: {:?} total", name, elapsed);
+}
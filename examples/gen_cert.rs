@@ -8,7 +8,6 @@ struct CertPem {
     key: String,
 }
 
-
 #[tokio::main]
 async fn main() -> Result<()> {
     let pem = create_ca()?;
@@ -23,7 +22,7 @@ async fn main() -> Result<()> {
 
 fn create_ca() -> Result<CertPem> {
     let (cert, key) = generate_ca(
-        &["acme.inc"],
+        ["acme.inc"],
         "CN",
         "Acme Inc.",
         "Acme CA",
@@ -61,4 +60,4 @@ async fn gen_files(pem: &CertPem) -> Result<()> {
     fs::write(format!("fixtures/{}.cert", name), pem.cert.as_bytes()).await?;
     fs::write(format!("fixtures/{}.key", name), pem.key.as_bytes()).await?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -8,7 +8,7 @@ use tracing::info;
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let service: Service<SledDb> = ServiceInner::new(SledDb::new("/tmp/kvserver"))
+    let service: Service<SledDb> = ServiceInner::new(SledDb::new("/tmp/kvserver")?)
         .fn_before_send(|res| match res.message.as_ref() {
             "" => res.message = "altered. Original message is empty".into(),
             s => res.message = format!("altered: {}", s),
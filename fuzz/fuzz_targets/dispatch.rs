@@ -0,0 +1,13 @@
+#![no_main]
+
+use kv2::{CommandRequest, MemTable, Service, ServiceInner};
+use libfuzzer_sys::fuzz_target;
+use prost::Message;
+
+// 任意 protobuf 解码成 CommandRequest 后交给 Service 执行，不能 panic
+fuzz_target!(|data: &[u8]| {
+    if let Ok(cmd) = CommandRequest::decode(data) {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let _ = service.execute(cmd);
+    }
+});
@@ -0,0 +1,24 @@
+#![no_main]
+
+use bytes::BytesMut;
+use kv2::{read_frame, CommandRequest, FrameCoder};
+use libfuzzer_sys::fuzz_target;
+
+// 任意字节当成网络输入：先 read_frame，再 decode_frame，都不能 panic
+fuzz_target!(|data: &[u8]| {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    rt.block_on(async {
+        let mut stream = data;
+        let mut buf = BytesMut::new();
+        if read_frame(&mut stream, &mut buf).await.is_ok() {
+            let _ = CommandRequest::decode_frame(&mut buf);
+        }
+    });
+
+    // 直接把字节当成一个完整的 frame
+    let mut buf = BytesMut::from(data);
+    let _ = CommandRequest::decode_frame(&mut buf);
+});
@@ -1,5 +1,6 @@
 use anyhow::Result;
-use kv2::{CommandRequest, ProstClientStream, TlsClientConnector};
+use kv2::{KvClient, TlsClientConnector, WsStream, YamuxCtrl};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tracing::info;
 
@@ -13,17 +14,33 @@ async fn main() -> Result<()> {
     let addr = "127.0.0.1:9527";
     // 连接服务器
     let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(ca_cert))?;
-    let stream = TcpStream::connect(addr).await?;
-    let stream = connector.connect(stream).await?;
 
-    let mut client = ProstClientStream::new(stream);
+    let stream = connector.connect(TcpStream::connect(addr).await?).await?;
 
-    // 生成一个 HSET 命令
-    let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
+    // 服务器设置了 KV_TRANSPORT=ws 时，先在 TLS 上做 WebSocket 握手
+    if std::env::var("KV_TRANSPORT").is_ok_and(|v| v == "ws") {
+        let stream = WsStream::connect("wss://kvserver.acme.inc:9527/", stream).await?;
+        return open(stream).await;
+    }
+    open(stream).await
+}
 
+// 服务器设置了 KV_YAMUX=1 时，客户端也要用 yamux
+async fn open<S>(stream: S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if std::env::var("KV_YAMUX").is_ok_and(|v| v == "1") {
+        let ctrl = YamuxCtrl::new_client(stream);
+        return run(KvClient::new(ctrl.open_stream().await?)).await;
+    }
+    run(KvClient::new(stream)).await
+}
+
+async fn run<S: AsyncRead + AsyncWrite + Unpin + Send>(mut client: KvClient<S>) -> Result<()> {
     // 发送 HSET 命令
-    let data = client.execute(cmd).await?;
-    info!("Got response: {:?}", data);
+    let old = client.hset("table1", "hello", "world").await?;
+    info!("Got response: {:?}", old);
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,173 @@
+use std::path::PathBuf;
+
+use http::StatusCode;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::{
+    CommandRequest, CommandResponse, KvError, Kvpair, MemTable, ProstClientStream, Service,
+    ServiceInner, SledDb, Storage, TlsClientConnector, Value,
+};
+
+/// 打开 Kv 的方式
+pub enum KvConfig {
+    /// 纯内存，进程退出数据就没了
+    Memory,
+    /// 用 sled 存到指定的目录
+    Sled(PathBuf),
+    /// 连接一个远程的 kv server，tls 为 None 时使用明文 TCP
+    Remote {
+        addr: String,
+        tls: Option<TlsClientConnector>,
+    },
+}
+
+/// 可以装进 Box 的双向 stream
+trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+enum Backend {
+    Memory(Service<MemTable>),
+    Sled(Service<SledDb>),
+    Remote(Mutex<ProstClientStream<Box<dyn AsyncStream>>>),
+}
+
+/// 嵌入式的 kv
+///
+/// 直接在进程内使用 Service，不经过网络。同样的接口也可以连接远程的 server，
+/// 这样应用从嵌入式切换到网络模式时只需要改 KvConfig。
+pub struct Kv {
+    backend: Backend,
+}
+
+impl Kv {
+    pub async fn open(config: KvConfig) -> Result<Self, KvError> {
+        let backend = match config {
+            KvConfig::Memory => Backend::Memory(ServiceInner::new(MemTable::new()).into()),
+            KvConfig::Sled(path) => Backend::Sled(ServiceInner::new(SledDb::new(path)?).into()),
+            KvConfig::Remote { addr, tls } => {
+                let stream = TcpStream::connect(addr).await?;
+                let stream: Box<dyn AsyncStream> = match tls {
+                    Some(connector) => Box::new(connector.connect(stream).await?),
+                    None => Box::new(stream),
+                };
+                Backend::Remote(Mutex::new(ProstClientStream::new(stream)))
+            }
+        };
+
+        Ok(Self { backend })
+    }
+
+    /// 执行任意的命令，返回原始的 CommandResponse
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        match &self.backend {
+            Backend::Memory(svc) => Ok(svc.execute_async(cmd).await),
+            Backend::Sled(svc) => Ok(svc.execute_async(cmd).await),
+            Backend::Remote(client) => client.lock().await.execute(cmd).await,
+        }
+    }
+
+    /// 获取一个 key 的 value，key 不存在返回 None
+    pub async fn hget(
+        &self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<Option<Value>, KvError> {
+        let res = self.execute(CommandRequest::new_hget(table, key)).await?;
+        if res.status == StatusCode::NOT_FOUND.as_u16() as u32 {
+            return Ok(None);
+        }
+        Ok(res.ok()?.first_value())
+    }
+
+    /// 获取 table 中所有的 kv pair
+    pub async fn hgetall(&self, table: impl Into<String>) -> Result<Vec<Kvpair>, KvError> {
+        let res = self.execute(CommandRequest::new_hgetall(table)).await?;
+        Ok(res.ok()?.pairs)
+    }
+
+    /// 设置一个 key 的 value，返回之前的 value
+    pub async fn hset(
+        &self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        let res = self
+            .execute(CommandRequest::new_hset(table, key, value.into()))
+            .await?;
+        Ok(res.ok()?.first_value())
+    }
+
+    /// 遍历 table 中的 kv pair。嵌入模式下直接使用存储的 iterator，
+    /// 不需要先把整个 table 读出来
+    pub async fn iter(
+        &self,
+        table: impl Into<String>,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let table = table.into();
+        match &self.backend {
+            Backend::Memory(svc) => svc.store().get_iter(&table),
+            Backend::Sled(svc) => svc.store().get_iter(&table),
+            Backend::Remote(_) => Ok(Box::new(self.hgetall(table).await?.into_iter())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProstServerStream;
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn embedded_memory_should_work() -> Result<()> {
+        let kv = Kv::open(KvConfig::Memory).await?;
+        test_kv(kv).await
+    }
+
+    #[tokio::test]
+    async fn embedded_sled_should_work() -> Result<()> {
+        let dir = tempdir()?;
+        let kv = Kv::open(KvConfig::Sled(dir.path().into())).await?;
+        test_kv(kv).await
+    }
+
+    #[tokio::test]
+    async fn remote_should_work() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            ProstServerStream::new(stream, service).process().await
+        });
+
+        let kv = Kv::open(KvConfig::Remote {
+            addr: addr.to_string(),
+            tls: None,
+        })
+        .await?;
+        test_kv(kv).await
+    }
+
+    async fn test_kv(kv: Kv) -> Result<()> {
+        assert_eq!(kv.hset("t1", "k1", "v1").await?, None);
+        assert_eq!(kv.hset("t1", "k1", "v2").await?, Some("v1".into()));
+        assert_eq!(kv.hget("t1", "k1").await?, Some("v2".into()));
+        assert_eq!(kv.hget("t1", "k2").await?, None);
+
+        kv.hset("t1", "k2", 10).await?;
+        let mut pairs: Vec<_> = kv.iter("t1").await?.collect();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            pairs,
+            vec![Kvpair::new("k1", "v2".into()), Kvpair::new("k2", 10.into())]
+        );
+        assert_eq!(kv.hgetall("t1").await?.len(), 2);
+        Ok(())
+    }
+}
@@ -10,9 +10,17 @@ pub enum KvError {
 
     #[error("Cannot parse command:`{0}`")]
     InvalidCommand(String),
-    #[error("Cannot convert value {:0} to {1}")]
+    #[error("Unsupported by this server: {0}")]
+    Unsupported(String),
+    #[error("Server is busy: {0}")]
+    Busy(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Cannot convert value {0:?} to {1}")]
     ConvertError(Value, &'static str),
-    #[error("Cannot process command {0} with table: {1}, key: {2}, Error: {}")]
+    #[error("Cannot process command {0} with table: {1}, key: {2}, Error: {3}")]
     StorageError(&'static str, String, String, String),
     #[error("Certificate parse error: Error to load {0} {0}")]
     CertifcateParseError(&'static str, &'static str),
@@ -27,6 +35,25 @@ pub enum KvError {
     IoError(#[from] std::io::Error),
     #[error("TLS error")]
     TlsError(#[from] tokio_rustls::rustls::TLSError),
+    #[error("QUIC error: {0}")]
+    QuicError(String),
+    #[error("Yamux error: {0}")]
+    YamuxError(#[from] yamux::ConnectionError),
+    #[error("WebSocket error: {0}")]
+    WebSocketError(#[from] tokio_tungstenite::tungstenite::Error),
+
+    #[error("Object store error: {0}")]
+    ObjectStoreError(String),
+    #[error("Plugin error: {0}")]
+    PluginError(String),
+    #[error("Failed to encode/decode MessagePack message: {0}")]
+    MsgpackError(String),
+    #[error("Failed to access LMDB: {0}")]
+    LmdbError(String),
+    #[error("Failed to encrypt/decrypt value: {0}")]
+    CryptoError(String),
+    #[error("Data is corrupted: {0}")]
+    DataCorruption(String),
 
     #[error("Internal error: {0}")]
     Internal(String),
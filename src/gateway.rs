@@ -0,0 +1,157 @@
+//! axum 集成
+//!
+//! - `CommandResponse` 实现了 `IntoResponse`，status 直接作为 HTTP status，body 是 JSON
+//! - `rest_router` 提供 REST 路由，把请求转换成 CommandRequest 交给同一个 Service：
+//!   - `GET    /v1/{table}/{key}` -> HGET
+//!   - `PUT    /v1/{table}/{key}` -> HSET，body 是 JSON 的 value
+//!   - `DELETE /v1/{table}/{key}` -> HDEL
+//!   - `GET    /v1/{table}`       -> HGETALL
+//!   - `GET    /metrics`          -> INFO 和每个 table 的统计，Prometheus 的文本格式
+//!
+//! Service 本身可以 Clone，所以在自己的 handler 里用 `State<Service<Store>>` 就能拿到它。
+
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde_json::Value as JsonValue;
+
+use crate::{
+    json_to_value, prometheus_text, CommandRequest, CommandResponse, Service, Storage,
+    StorageMetrics,
+};
+
+impl IntoResponse for CommandResponse {
+    fn into_response(self) -> Response {
+        let status =
+            StatusCode::from_u16(self.status as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (status, Json(self)).into_response()
+    }
+}
+
+/// 创建 REST gateway 的路由，可以 nest 到已有的 axum 应用里
+pub fn rest_router<Store>(service: Service<Store>) -> Router
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/metrics", get(metrics::<Store>))
+        .route("/v1/{table}", get(hgetall::<Store>))
+        .route(
+            "/v1/{table}/{key}",
+            get(hget::<Store>).put(hset::<Store>).delete(hdel::<Store>),
+        )
+        .with_state(service)
+}
+
+async fn hget<Store: Storage + 'static>(
+    State(service): State<Service<Store>>,
+    Path((table, key)): Path<(String, String)>,
+) -> CommandResponse {
+    service.execute(CommandRequest::new_hget(table, key))
+}
+
+async fn hgetall<Store: Storage + 'static>(
+    State(service): State<Service<Store>>,
+    Path(table): Path<String>,
+) -> CommandResponse {
+    service.execute(CommandRequest::new_hgetall(table))
+}
+
+// 和 INFO 一样，只是 Storage::metrics 只调用一次，table 的统计也用它的
+async fn metrics<Store: Storage + 'static>(State(service): State<Service<Store>>) -> Response {
+    let mut info = service.stats().info::<Store>();
+    let tables = match service.store().metrics() {
+        Ok(metrics) => {
+            info.extend(metrics.pairs());
+            let StorageMetrics { tables, .. } = metrics;
+            tables
+        }
+        Err(_) => Vec::new(),
+    };
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        prometheus_text(&info, &tables),
+    )
+        .into_response()
+}
+
+async fn hset<Store: Storage + 'static>(
+    State(service): State<Service<Store>>,
+    Path((table, key)): Path<(String, String)>,
+    Json(body): Json<JsonValue>,
+) -> Response {
+    match json_to_value(body) {
+        Some(v) => service
+            .execute(CommandRequest::new_hset(table, key, v))
+            .into_response(),
+        None => (StatusCode::BAD_REQUEST, "Unsupported value").into_response(),
+    }
+}
+
+async fn hdel<Store: Storage + 'static>(
+    State(service): State<Service<Store>>,
+    Path((table, key)): Path<(String, String)>,
+) -> CommandResponse {
+    service.execute(CommandRequest::new_hdel(table, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, ServiceInner};
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    async fn call(app: &Router, method: &str, uri: &str, body: &str) -> (u16, CommandResponse) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        let status = res.status().as_u16();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn rest_router_should_work() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let app = rest_router(service);
+
+        let (status, _) = call(&app, "PUT", "/v1/t1/k1", r#""v1""#).await;
+        assert_eq!(status, 200);
+        call(&app, "PUT", "/v1/t1/k2", "42").await;
+
+        let (status, res) = call(&app, "GET", "/v1/t1/k1", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(res.values, vec!["v1".into()]);
+
+        let (status, res) = call(&app, "GET", "/v1/t1", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(res.pairs.len(), 2);
+
+        let (status, _) = call(&app, "GET", "/v1/t1/k3", "").await;
+        assert_eq!(status, 404);
+
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains("kv_storage_writes 2\n"));
+        assert!(text.contains("kv_storage_misses 1\n"));
+        assert!(text.contains("kv_table_keys{table=\"t1\"} 2\n"));
+
+        let (status, res) = call(&app, "DELETE", "/v1/t1/k2", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(res.values, vec![42.into()]);
+        let (status, _) = call(&app, "GET", "/v1/t1/k2", "").await;
+        assert_eq!(status, 404);
+    }
+}
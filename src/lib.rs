@@ -1,11 +1,21 @@
+mod embedded;
 mod error;
+#[cfg(any(test, feature = "axum"))]
+mod gateway;
 mod network;
 mod pb;
 mod service;
 mod storage;
 
+#[cfg(any(test, feature = "proptest"))]
+pub mod strategies;
+
+pub use embedded::{Kv, KvConfig};
 pub use error::KvError;
+#[cfg(any(test, feature = "axum"))]
+pub use gateway::rest_router;
 pub use network::*;
 pub use pb::abi::*;
+pub use pb::PROTOCOL_VERSION;
 pub use service::*;
 pub use storage::*;
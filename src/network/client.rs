@@ -0,0 +1,236 @@
+use http::StatusCode;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{CommandRequest, CommandResponse, Connect, KvError, Kvpair, ProstClientStream, Value};
+
+/// 连接 kv server 的异步客户端，每个命令是一个类型化的方法
+///
+/// 底下是一个 ProstClientStream，一个连接上的命令按顺序执行。
+/// 服务器返回的错误按 status 还原成对应的 KvError，key 不存在的时候返回 None
+pub struct KvClient<S> {
+    stream: ProstClientStream<S>,
+}
+
+impl<S> KvClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: ProstClientStream::new(stream),
+        }
+    }
+
+    /// 用 TcpConnector 或者 TlsClientConnector 建立连接
+    pub async fn connect<C>(connector: &C, addr: &str) -> Result<Self, KvError>
+    where
+        C: Connect<Stream = S>,
+    {
+        Ok(Self::new(connector.connect(addr).await?))
+    }
+
+    /// 执行任意的命令，返回原始的 CommandResponse，不检查 status
+    pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        self.stream.execute(cmd).await
+    }
+
+    /// 获取一个 key 的 value，key 不存在返回 None
+    pub async fn hget(
+        &mut self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<Option<Value>, KvError> {
+        self.optional(CommandRequest::new_hget(table, key)).await
+    }
+
+    /// 获取 table 中所有的 kv pair
+    pub async fn hgetall(&mut self, table: impl Into<String>) -> Result<Vec<Kvpair>, KvError> {
+        Ok(self.call(CommandRequest::new_hgetall(table)).await?.pairs)
+    }
+
+    /// 设置一个 key 的 value，返回之前的 value
+    pub async fn hset(
+        &mut self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        let cmd = CommandRequest::new_hset(table, key, value.into());
+        self.optional(cmd).await
+    }
+
+    /// 删除一个 key，返回它之前的 value
+    pub async fn hdel(
+        &mut self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<Option<Value>, KvError> {
+        self.optional(CommandRequest::new_hdel(table, key)).await
+    }
+
+    pub async fn hexist(
+        &mut self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<bool, KvError> {
+        let value = self
+            .optional(CommandRequest::new_hexist(table, key))
+            .await?;
+        value.map_or(Ok(false), bool::try_from)
+    }
+
+    /// 一次读多个 key，不存在的 key 对应 None
+    pub async fn hmget(
+        &mut self,
+        table: impl Into<String>,
+        keys: Vec<String>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let res = self.call(CommandRequest::new_hmget(table, keys)).await?;
+        Ok(optional_values(res))
+    }
+
+    /// 一次写多个 key，返回它们之前的 value
+    pub async fn hmset(
+        &mut self,
+        table: impl Into<String>,
+        pairs: Vec<Kvpair>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let res = self.call(CommandRequest::new_hmset(table, pairs)).await?;
+        Ok(optional_values(res))
+    }
+
+    /// 一次删除多个 key，返回它们之前的 value
+    pub async fn hmdel(
+        &mut self,
+        table: impl Into<String>,
+        keys: Vec<String>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let res = self.call(CommandRequest::new_hmdel(table, keys)).await?;
+        Ok(optional_values(res))
+    }
+
+    /// 给整数 value 加上 delta，返回加完之后的值。key 不存在时从 0 开始
+    pub async fn hincrby(
+        &mut self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+        delta: i64,
+    ) -> Result<i64, KvError> {
+        let cmd = CommandRequest::new_hincrby(table, key, delta);
+        let value = self.optional(cmd).await?.unwrap_or_default();
+        i64::try_from(value)
+    }
+
+    pub async fn hkeys(&mut self, table: impl Into<String>) -> Result<Vec<String>, KvError> {
+        let res = self.call(CommandRequest::new_hkeys(table)).await?;
+        res.values.into_iter().map(String::try_from).collect()
+    }
+
+    pub async fn hlen(&mut self, table: impl Into<String>) -> Result<usize, KvError> {
+        let value = self.optional(CommandRequest::new_hlen(table)).await?;
+        Ok(i64::try_from(value.unwrap_or_default())? as usize)
+    }
+
+    /// 等待下一条订阅推送的消息
+    pub async fn next_message(&mut self) -> Result<CommandResponse, KvError> {
+        self.stream.next_message().await
+    }
+
+    // 执行命令，不成功的时候返回对应的错误
+    async fn call(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let res = self.stream.execute(cmd).await?;
+        match StatusCode::from_u16(res.status as u16) {
+            Ok(status) if status.is_success() => Ok(res),
+            _ => Err(status_error(res)),
+        }
+    }
+
+    // 服务器用 404 或者空的 Value 表示没有值
+    async fn optional(&mut self, cmd: CommandRequest) -> Result<Option<Value>, KvError> {
+        match self.call(cmd).await {
+            Ok(res) => Ok(res.first_value()),
+            Err(KvError::NotFound(..)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// 把服务器返回的错误还原成 KvError，和 From<KvError> for CommandResponse 相反。
+/// 没有对应的 status 的错误都是 Internal
+fn status_error(res: CommandResponse) -> KvError {
+    let message = res.message;
+    match StatusCode::from_u16(res.status as u16) {
+        Ok(StatusCode::NOT_FOUND) => KvError::NotFound(message, String::new()),
+        Ok(StatusCode::BAD_REQUEST) => KvError::InvalidCommand(message),
+        Ok(StatusCode::NOT_IMPLEMENTED) => KvError::Unsupported(message),
+        Ok(StatusCode::SERVICE_UNAVAILABLE) => KvError::Busy(message),
+        Ok(StatusCode::FORBIDDEN) => KvError::Forbidden(message),
+        Ok(StatusCode::CONFLICT) => KvError::Conflict(message),
+        _ => KvError::Internal(format!("{}: {}", res.status, message)),
+    }
+}
+
+fn optional_values(res: CommandResponse) -> Vec<Option<Value>> {
+    res.values
+        .into_iter()
+        .map(|v| Some(v).filter(|v| v.value.is_some()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, ProstServerStream, Service, ServiceInner, TcpConnector};
+    use anyhow::Result;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn start_server() -> Result<KvClient<TcpStream>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            ProstServerStream::new(stream, service).process().await
+        });
+        Ok(KvClient::connect(&TcpConnector, &addr.to_string()).await?)
+    }
+
+    #[tokio::test]
+    async fn kv_client_should_work() -> Result<()> {
+        let mut client = start_server().await?;
+        assert_eq!(client.hset("t1", "k1", "v1").await?, None);
+        assert_eq!(client.hset("t1", "k1", "v2").await?, Some("v1".into()));
+        assert_eq!(client.hget("t1", "k1").await?, Some("v2".into()));
+        assert_eq!(client.hget("t1", "k2").await?, None);
+        assert!(client.hexist("t1", "k1").await?);
+        assert!(!client.hexist("t1", "k2").await?);
+
+        let pairs = vec![Kvpair::new("k2", 2.into()), Kvpair::new("k3", 3.into())];
+        assert_eq!(client.hmset("t1", pairs).await?, [None, None]);
+        let keys = vec!["k2".into(), "k4".into()];
+        assert_eq!(client.hmget("t1", keys).await?, [Some(2.into()), None]);
+        assert_eq!(client.hincrby("t1", "k3", 10).await?, 13);
+        assert_eq!(client.hlen("t1").await?, 3);
+        let mut keys = client.hkeys("t1").await?;
+        keys.sort();
+        assert_eq!(keys, ["k1", "k2", "k3"]);
+        assert_eq!(client.hgetall("t1").await?.len(), 3);
+
+        assert_eq!(client.hdel("t1", "k1").await?, Some("v2".into()));
+        assert_eq!(client.hdel("t1", "k1").await?, None);
+        let keys = vec!["k2".into(), "k3".into()];
+        assert_eq!(client.hmdel("t1", keys).await?.len(), 2);
+        assert_eq!(client.hlen("t1").await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kv_client_should_map_errors() -> Result<()> {
+        let mut client = start_server().await?;
+        client.hset("t1", "k1", "v1").await?;
+        // 不是整数的 value 不能 HINCRBY
+        let err = client.hincrby("t1", "k1", 1).await.unwrap_err();
+        assert!(matches!(err, KvError::InvalidCommand(_)), "{:?}", err);
+        Ok(())
+    }
+}
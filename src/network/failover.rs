@@ -0,0 +1,331 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tracing::warn;
+
+use crate::{CommandRequest, CommandResponse, KvError, ProstClientStream, TlsClientConnector};
+
+type ConnectFuture<'a, S> = Pin<Box<dyn Future<Output = Result<S, KvError>> + Send + 'a>>;
+
+/// 建立到某个 endpoint 的连接
+pub trait Connect {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send;
+
+    fn connect<'a>(&'a self, addr: &'a str) -> ConnectFuture<'a, Self::Stream>;
+}
+
+/// 明文 TCP 连接
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpConnector;
+
+impl Connect for TcpConnector {
+    type Stream = TcpStream;
+
+    fn connect<'a>(&'a self, addr: &'a str) -> ConnectFuture<'a, Self::Stream> {
+        Box::pin(async move { Ok(TcpStream::connect(addr).await?) })
+    }
+}
+
+/// TCP 之上再做 TLS 握手
+impl Connect for TlsClientConnector {
+    type Stream = tokio_rustls::client::TlsStream<TcpStream>;
+
+    fn connect<'a>(&'a self, addr: &'a str) -> ConnectFuture<'a, Self::Stream> {
+        Box::pin(async move {
+            let stream = TcpStream::connect(addr).await?;
+            TlsClientConnector::connect(self, stream).await
+        })
+    }
+}
+
+/// 熔断器的配置
+#[derive(Debug, Clone)]
+pub struct CircuitConfig {
+    /// 统计错误率的滑动窗口大小（最近多少次请求）
+    pub window: usize,
+    /// 窗口内至少有这么多次请求才会计算错误率
+    pub min_requests: usize,
+    /// 错误率达到这个比例就打开熔断器
+    pub error_threshold: f64,
+    /// 熔断器打开后，多久之后放一个探测请求过去
+    pub open_duration: Duration,
+}
+
+impl Default for CircuitConfig {
+    fn default() -> Self {
+        Self {
+            window: 20,
+            min_requests: 5,
+            error_threshold: 0.5,
+            open_duration: Duration::from_secs(5),
+        }
+    }
+}
+
+/// 熔断器的状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// 正常工作
+    Closed,
+    /// 熔断中，直到 Instant 之前都跳过这个 endpoint
+    Open(Instant),
+    /// 允许一个探测请求，成功则关闭，失败则重新打开
+    HalfOpen,
+}
+
+/// 单个 endpoint 的熔断器，记录最近请求的成败
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitConfig,
+    state: CircuitState,
+    outcomes: VecDeque<bool>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitConfig) -> Self {
+        Self {
+            outcomes: VecDeque::with_capacity(config.window),
+            config,
+            state: CircuitState::Closed,
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    /// 现在能否往这个 endpoint 发请求。熔断时间到了会转入 HalfOpen
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.state {
+            CircuitState::Closed | CircuitState::HalfOpen => true,
+            CircuitState::Open(until) if now >= until => {
+                self.state = CircuitState::HalfOpen;
+                true
+            }
+            CircuitState::Open(_) => false,
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        if self.state == CircuitState::HalfOpen {
+            self.state = CircuitState::Closed;
+            self.outcomes.clear();
+        }
+        self.push(true);
+    }
+
+    pub fn record_failure(&mut self, now: Instant) {
+        if self.state == CircuitState::HalfOpen {
+            self.trip(now);
+            return;
+        }
+        self.push(false);
+
+        let total = self.outcomes.len();
+        let failures = self.outcomes.iter().filter(|ok| !**ok).count();
+        if total >= self.config.min_requests
+            && failures as f64 / total as f64 >= self.config.error_threshold
+        {
+            self.trip(now);
+        }
+    }
+
+    fn trip(&mut self, now: Instant) {
+        self.state = CircuitState::Open(now + self.config.open_duration);
+        self.outcomes.clear();
+    }
+
+    fn push(&mut self, ok: bool) {
+        if self.outcomes.len() == self.config.window {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(ok);
+    }
+}
+
+struct Endpoint<S> {
+    addr: String,
+    breaker: CircuitBreaker,
+    conn: Option<ProstClientStream<S>>,
+}
+
+/// 带熔断和故障转移的客户端
+///
+/// 每个 endpoint 有自己的熔断器。请求按顺序发给第一个没有熔断的 endpoint，
+/// 连接失败或者返回 5xx 都算一次错误，并立刻换下一个 endpoint 重试。
+/// 注意重试意味着写操作在故障时可能被执行不止一次。
+pub struct FailoverClient<C: Connect> {
+    connector: C,
+    endpoints: Vec<Endpoint<C::Stream>>,
+}
+
+impl<C: Connect> FailoverClient<C> {
+    pub fn new(connector: C, addrs: &[&str], config: CircuitConfig) -> Self {
+        let endpoints = addrs
+            .iter()
+            .map(|addr| Endpoint {
+                addr: addr.to_string(),
+                breaker: CircuitBreaker::new(config.clone()),
+                conn: None,
+            })
+            .collect();
+        Self {
+            connector,
+            endpoints,
+        }
+    }
+
+    /// 查看某个 endpoint 熔断器的状态
+    pub fn state(&self, addr: &str) -> Option<CircuitState> {
+        self.endpoints
+            .iter()
+            .find(|e| e.addr == addr)
+            .map(|e| e.breaker.state())
+    }
+
+    pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let mut last = None;
+
+        for ep in self.endpoints.iter_mut() {
+            if !ep.breaker.allow(Instant::now()) {
+                continue;
+            }
+
+            match Self::try_endpoint(&self.connector, ep, cmd.clone()).await {
+                // 5xx 说明服务器自己出了问题，换一个 endpoint 试试，
+                // 但如果都失败了，还是把最后的 response 返回给调用者
+                Ok(res) if res.status >= 500 => {
+                    ep.breaker.record_failure(Instant::now());
+                    last = Some(Ok(res));
+                }
+                Ok(res) => {
+                    ep.breaker.record_success();
+                    return Ok(res);
+                }
+                Err(e) => {
+                    warn!("Endpoint {} failed: {}", ep.addr, e);
+                    ep.conn = None;
+                    ep.breaker.record_failure(Instant::now());
+                    last = Some(Err(e));
+                }
+            }
+        }
+
+        last.unwrap_or_else(|| Err(KvError::Internal("No healthy endpoint".into())))
+    }
+
+    async fn try_endpoint(
+        connector: &C,
+        ep: &mut Endpoint<C::Stream>,
+        cmd: CommandRequest,
+    ) -> Result<CommandResponse, KvError> {
+        if ep.conn.is_none() {
+            let stream = connector.connect(&ep.addr).await?;
+            ep.conn = Some(ProstClientStream::new(stream));
+        }
+        ep.conn.as_mut().unwrap().execute(cmd).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_ok, MemTable, ProstServerStream, Service, ServiceInner, Value};
+    use anyhow::Result;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    fn config() -> CircuitConfig {
+        CircuitConfig {
+            window: 4,
+            min_requests: 2,
+            error_threshold: 0.5,
+            open_duration: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn circuit_breaker_should_open_when_error_rate_exceeds_threshold() {
+        let mut breaker = CircuitBreaker::new(config());
+        let now = Instant::now();
+
+        breaker.record_success();
+        breaker.record_success();
+        breaker.record_failure(now);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        breaker.record_failure(now);
+        assert!(matches!(breaker.state(), CircuitState::Open(_)));
+        assert!(!breaker.allow(now));
+    }
+
+    #[test]
+    fn circuit_breaker_should_probe_after_open_duration() {
+        let mut breaker = CircuitBreaker::new(config());
+        let now = Instant::now();
+        breaker.record_failure(now);
+        breaker.record_failure(now);
+
+        // 熔断时间过了，放一个探测请求，失败则重新打开
+        let later = now + Duration::from_millis(150);
+        assert!(breaker.allow(later));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.record_failure(later);
+        assert!(!breaker.allow(later));
+
+        // 探测成功则关闭
+        let later = later + Duration::from_millis(150);
+        assert!(breaker.allow(later));
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn failover_client_should_skip_dead_endpoint() -> Result<()> {
+        let dead = "127.0.0.1:1";
+        let addr = start_server().await?.to_string();
+
+        let mut client = FailoverClient::new(TcpConnector, &[dead, &addr], config());
+
+        for _ in 0..2 {
+            let cmd = CommandRequest::new_hget("t1", "k1");
+            let res = client.execute(cmd).await?;
+            assert_eq!(res.status, 404);
+        }
+        assert!(matches!(client.state(dead), Some(CircuitState::Open(_))));
+        assert_eq!(client.state(&addr), Some(CircuitState::Closed));
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute(cmd).await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failover_client_without_endpoint_should_fail() {
+        let mut client = FailoverClient::new(TcpConnector, &[], config());
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert!(res.is_err());
+    }
+
+    async fn start_server() -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let server = ProstServerStream::new(stream, service.clone());
+                tokio::spawn(server.process());
+            }
+        });
+
+        Ok(addr)
+    }
+}
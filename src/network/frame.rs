@@ -4,13 +4,14 @@ use crate::{CommandRequest, CommandResponse, KvError};
 use bytes::{Buf, BufMut, BytesMut};
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use prost::Message;
+use serde::{de::DeserializeOwned, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::debug;
 
 /// 长度整个占用4个字节
 pub const LEN_LEN: usize = 4;
-/// 长度占31bit, 所以最大的 frame 是2G
-const MAX_FRAME: usize = 2 * 1024 * 1024 * 1024;
+/// 长度占30bit, 所以一个 frame 最大是1G
+const MAX_FRAME: usize = (1 << 30) - 1;
 /// 这是因为以太网的 MTU 是 1500，除去 IP 头 20 字节、TCP 头 20 字节，还剩 1460；
 /// 一般 TCP 包会包含一些 Option（比如 timestamp），IP 包也可能包含，所以我们预留 20 字节；再减去 4 字节的长度，就是 1436，
 /// 不用分片的最大消息长度。如果大于这个，很可能会导致分片，我们就干脆压缩一下。
@@ -18,111 +19,252 @@ const MAX_FRAME: usize = 2 * 1024 * 1024 * 1024;
 const COMPRESSION_LIMIT: usize = 1436;
 /// 代表压缩的 bit(整个长度4字节的最高位)
 const COMPRESSION_BIT: usize = 1 << 31;
+/// 代表后面还有 continuation frame 的 bit(次高位)
+const CONTINUATION_BIT: usize = 1 << 30;
+/// 发送时每个 frame 最多携带的 payload，更大的消息拆成多个 frame
+const MAX_CHUNK: usize = 1024 * 1024;
+/// 一个消息重组后（以及解压后）最大的大小
+pub(crate) const MAX_MESSAGE: usize = 256 * 1024 * 1024;
+/// 一个消息最多的 frame 数，发送的一方不会拆出更多的 frame。
+/// 空的 continuation frame 也占 header 的 4 个字节，不能无限地接收
+const MAX_FRAMES: usize = MAX_MESSAGE / MAX_CHUNK + 1;
+/// 读取 frame 时每次最多扩充的 buffer 大小
+const READ_CHUNK: usize = 64 * 1024;
+/// 客户端协商编码时发送的 header。它的长度超过了 MAX_MESSAGE，
+/// 不可能是一个正常的 frame，服务器据此区分 handshake 和老的客户端
+pub(crate) const HANDSHAKE: u32 = u32::MAX;
+
+/// frame payload 的编码方式，连接建立时通过 handshake 协商，缺省是 protobuf
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Protobuf,
+    /// 用 serde 把同样的类型编码成 MessagePack
+    #[cfg(any(test, feature = "msgpack"))]
+    MessagePack,
+}
+
+impl Encoding {
+    /// handshake 里代表这个编码的字节
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Encoding::Protobuf => 0,
+            #[cfg(any(test, feature = "msgpack"))]
+            Encoding::MessagePack => 1,
+        }
+    }
+
+    /// 不认识的编码返回 None
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Encoding::Protobuf),
+            #[cfg(any(test, feature = "msgpack"))]
+            1 => Some(Encoding::MessagePack),
+            _ => None,
+        }
+    }
+
+    fn encode<M: Message + Serialize>(self, msg: &M) -> Result<Vec<u8>, KvError> {
+        match self {
+            Encoding::Protobuf => Ok(msg.encode_to_vec()),
+            #[cfg(any(test, feature = "msgpack"))]
+            Encoding::MessagePack => {
+                rmp_serde::to_vec_named(msg).map_err(|e| KvError::MsgpackError(e.to_string()))
+            }
+        }
+    }
+
+    fn decode<M: Message + Default + DeserializeOwned>(self, data: &[u8]) -> Result<M, KvError> {
+        match self {
+            Encoding::Protobuf => Ok(M::decode(data)?),
+            #[cfg(any(test, feature = "msgpack"))]
+            Encoding::MessagePack => {
+                rmp_serde::from_slice(data).map_err(|e| KvError::MsgpackError(e.to_string()))
+            }
+        }
+    }
+}
 
 /// 处理 Frame 的 encode/decode
 pub trait FrameCoder
 where
-    Self: Message + Sized + Default,
+    Self: Message + Serialize + DeserializeOwned + Sized + Default,
 {
-    /// 把一个 Message encode 成一个 frame
+    /// 把一个 Message 用 protobuf encode 成 frame
     fn encode_frame(&self, buf: &mut BytesMut) -> Result<(), KvError> {
-        let size = self.encoded_len();
+        self.encode_frame_with(Encoding::Protobuf, buf)
+    }
 
-        if size > MAX_FRAME {
+    /// 把一个 protobuf 编码的完整的消息 decode 成一个 Message
+    fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
+        Self::decode_frame_with(Encoding::Protobuf, buf)
+    }
+
+    /// 把一个 Message 按 encoding 编码成 frame，超过 MAX_CHUNK 的消息会拆成多个 frame，
+    /// 除了最后一个，每个 frame 都设置 CONTINUATION_BIT
+    fn encode_frame_with(&self, encoding: Encoding, buf: &mut BytesMut) -> Result<(), KvError> {
+        let buf1 = encoding.encode(self)?;
+        let size = buf1.len();
+
+        if size > MAX_MESSAGE {
             return Err(KvError::FrameError);
         }
 
-        // 我们先写入长度，如果需要压缩，再重写压缩后的长度
-        buf.put_u32(size as _);
-
         if size > COMPRESSION_LIMIT {
-            let mut buf1 = Vec::with_capacity(size);
-            self.encode(&mut buf1)?;
-
-            // BytesMut 支持逻辑上的 split( 之后还能 unsplit)
-            // 所以我们先把长度这4个字节拿走，清除
-            let payload = buf.split_off(LEN_LEN);
-            buf.clear();
-
             // 处理 gzip 压缩，具体可以参考 flate2文档
-            let mut encoder = GzEncoder::new(payload.writer(), Compression::default());
+            let mut encoder = GzEncoder::new(BytesMut::new().writer(), Compression::default());
             encoder.write_all(&buf1[..])?;
 
             // 压缩完成后，从 gzip encoder 中把 BytesMut再拿回来
             let payload = encoder.finish()?.into_inner();
             debug!("Encode a frame: size {}({})", size, payload.len());
 
-            // 写入压缩后的长度
-            buf.put_u32((payload.len() | COMPRESSION_BIT) as _);
-
-            // 把 BytesMut 再合并回来
-            buf.unsplit(payload);
-
-            Ok(())
+            // 压缩后的数据按 MAX_CHUNK 切开，每一块都带上 compression bit
+            let mut chunks = payload.chunks(MAX_CHUNK).peekable();
+            while let Some(chunk) = chunks.next() {
+                let more = match chunks.peek() {
+                    Some(_) => CONTINUATION_BIT,
+                    None => 0,
+                };
+                buf.put_u32((chunk.len() | COMPRESSION_BIT | more) as _);
+                buf.put_slice(chunk);
+            }
         } else {
-            self.encode(buf)?;
-            Ok(())
+            // 小于 COMPRESSION_LIMIT 的消息一个 frame 就够了
+            buf.put_u32(size as _);
+            buf.put_slice(&buf1);
         }
+        Ok(())
     }
 
-    /// 把一个完整的 frame decode 成一个 Message
-    fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
-        // 先取4字节，从中拿出长度和 compression bit
-        let header = buf.get_u32() as usize;
-        let (len, compressed) = decode_header(header);
-        debug!("Got a frame: msg len {}, compressed {}", len, compressed);
+    /// 把一个完整的消息（一个或多个 frame）按 encoding decode 成一个 Message
+    fn decode_frame_with(encoding: Encoding, buf: &mut BytesMut) -> Result<Self, KvError> {
+        let (payload, compressed) = split_payload(buf)?;
+        debug!(
+            "Got a frame: msg len {}, compressed {}",
+            payload.len(),
+            compressed
+        );
 
         if compressed {
-            // 解压缩
-            let mut decoder = GzDecoder::new(&buf[..len]);
-            let mut buf1 = Vec::with_capacity(len * 2);
-            decoder.read_to_end(&mut buf1)?;
-            buf.advance(len);
+            // 解压缩，解压后的大小也不能超过 MAX_MESSAGE，防止 gzip 炸弹
+            let decoder = GzDecoder::new(&payload[..]);
+            let mut buf1 = Vec::with_capacity(payload.len() * 2);
+            decoder
+                .take(MAX_MESSAGE as u64 + 1)
+                .read_to_end(&mut buf1)?;
+            if buf1.len() > MAX_MESSAGE {
+                return Err(KvError::FrameError);
+            }
 
             // decode 成相应的消息
-            Ok(Self::decode(&buf1[..buf1.len()])?)
+            encoding.decode(&buf1)
         } else {
-            let msg = Self::decode(&buf[..len])?;
-            buf.advance(len);
-            Ok(msg)
+            encoding.decode(&payload)
+        }
+    }
+}
+
+/// 从 buf 中取出一个消息的 payload，多个 frame 的话拼接起来
+fn split_payload(buf: &mut BytesMut) -> Result<(BytesMut, bool), KvError> {
+    let (len, compressed, mut more) = split_header(buf)?;
+    // 只有一个 frame 的时候不需要拷贝
+    let mut payload = buf.split_to(len);
+
+    while more {
+        let (len, _, next) = split_header(buf)?;
+        if payload.len() + len > MAX_MESSAGE {
+            return Err(KvError::FrameError);
         }
+        payload.extend_from_slice(&buf[..len]);
+        buf.advance(len);
+        more = next;
     }
+
+    Ok((payload, compressed))
 }
 
-/// 从 stream 中读取一个完整的 frame
+/// 取出 frame 的 header，并确认 buf 里有完整的 frame
+fn split_header(buf: &mut BytesMut) -> Result<(usize, bool, bool), KvError> {
+    // 数据来自网络，不能信任，长度不够就直接报错而不是 panic
+    if buf.len() < LEN_LEN {
+        return Err(KvError::FrameError);
+    }
+
+    // 先取4字节，从中拿出长度、compression bit 和 continuation bit
+    let header = decode_header(buf.get_u32() as usize);
+    if header.0 > buf.len() {
+        return Err(KvError::FrameError);
+    }
+    Ok(header)
+}
+
+/// 从 stream 中读取一个完整的消息，包括它所有的 continuation frame
 pub async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<(), KvError>
 where
     S: AsyncRead + Unpin + Send,
 {
-    let header = stream.read_u32().await? as usize;
-    let (len, _compressed) = decode_header(header);
-    // 如果没有这么大的内存，就分配少一个 frame 的内存，保证它可用
-    buf.reserve(LEN_LEN + len);
-    buf.put_u32(header as _);
-    // advance_mut 是 unsafe 的原因是， 从当前位置 pos 到 pos+len,
-    // 这段内存目前没有初始化，我们就是为了 reserve 这段内存，然后从 stream
-    // 里面读取，读取完，它就是初始化的。所以我们这么用是安全的
-    unsafe {buf.advance_mut(len)};
-    stream.read_exact(&mut buf[LEN_LEN..]).await?;
-    Ok(())
+    let header = stream.read_u32().await?;
+    read_frame_from(stream, header, buf).await
+}
+
+/// 和 read_frame 一样，只是第一个 frame 的 header 已经读出来了
+pub(crate) async fn read_frame_from<S>(
+    stream: &mut S,
+    header: u32,
+    buf: &mut BytesMut,
+) -> Result<(), KvError>
+where
+    S: AsyncRead + Unpin + Send,
+{
+    let mut header = header as usize;
+    let mut total = 0;
+    for _ in 0..MAX_FRAMES {
+        let (len, _compressed, more) = decode_header(header);
+
+        // 重组后的消息不能超过 MAX_MESSAGE
+        total += len;
+        if total > MAX_MESSAGE {
+            return Err(KvError::FrameError);
+        }
+        buf.put_u32(header as _);
+
+        // 长度来自对端，不能照着它一次分配内存，否则一个伪造的 header
+        // 就能让我们分配很多内存。这里按实际收到的数据一块一块地扩充 buffer
+        let start = buf.len();
+        let mut frame = (&mut *stream).take(len as u64);
+        while buf.len() - start < len {
+            buf.reserve(READ_CHUNK.min(len - (buf.len() - start)));
+            if frame.read_buf(buf).await? == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+
+        if !more {
+            return Ok(());
+        }
+        header = stream.read_u32().await? as usize;
+    }
+    Err(KvError::FrameError)
 }
 
 impl FrameCoder for CommandRequest {}
 impl FrameCoder for CommandResponse {}
 
-fn decode_header(header: usize) -> (usize, bool) {
-    let len = header & !COMPRESSION_BIT;
+fn decode_header(header: usize) -> (usize, bool, bool) {
+    let len = header & MAX_FRAME;
     let compressed = header & COMPRESSION_BIT == COMPRESSION_BIT;
-    (len, compressed)
+    let more = header & CONTINUATION_BIT == CONTINUATION_BIT;
+    (len, compressed, more)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::pin::Pin;
-    use std::task::{Context, Poll};
     use super::*;
     use crate::Value;
     use bytes::Bytes;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
     use tokio::io::ReadBuf;
 
     #[test]
@@ -133,7 +275,7 @@ mod tests {
         cmd.encode_frame(&mut buf).unwrap();
 
         // 最高位没设置
-        assert_eq!(is_compressed(&buf), false);
+        assert!(!is_compressed(&buf));
 
         let cmd1 = CommandRequest::decode_frame(&mut buf).unwrap();
         assert_eq!(cmd, cmd1);
@@ -148,7 +290,7 @@ mod tests {
         res.encode_frame(&mut buf).unwrap();
 
         // 最高位没设置
-        assert_eq!(is_compressed(&buf), false);
+        assert!(!is_compressed(&buf));
 
         let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
         assert_eq!(res, res1);
@@ -163,7 +305,7 @@ mod tests {
         res.encode_frame(&mut buf).unwrap();
 
         // 最高位设置了
-        assert_eq!(is_compressed(&buf), true);
+        assert!(is_compressed(&buf));
 
         let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
         assert_eq!(res, res1);
@@ -185,13 +327,14 @@ mod tests {
         fn poll_read(
             self: Pin<&mut Self>,
             _cx: &mut Context<'_>,
-            buf: &mut ReadBuf<'_>
+            buf: &mut ReadBuf<'_>,
         ) -> Poll<std::io::Result<()>> {
             // 看看 ReadBuf 需要多大的数据
-            let len = buf.capacity();
+            let this = self.get_mut();
+            let len = buf.remaining().min(this.buf.len());
 
             // split 出这么大的数据
-            let data = self.get_mut().buf.split_to(len);
+            let data = this.buf.split_to(len);
 
             // 拷贝给 ReadBuf
             buf.put_slice(&data);
@@ -206,7 +349,7 @@ mod tests {
         let mut buf = BytesMut::new();
         let cmd = CommandRequest::new_hdel("t1", "k1");
         cmd.encode_frame(&mut buf).unwrap();
-        let mut stream = DummyStream{ buf };
+        let mut stream = DummyStream { buf };
 
         let mut data = BytesMut::new();
         read_frame(&mut stream, &mut data).await.unwrap();
@@ -214,4 +357,119 @@ mod tests {
         let cmd1 = CommandRequest::decode_frame(&mut data).unwrap();
         assert_eq!(cmd, cmd1);
     }
+
+    #[tokio::test]
+    async fn read_frame_with_forged_length_should_fail() {
+        // header 声称有 128M 数据，实际只有几个字节
+        let mut buf = BytesMut::new();
+        buf.put_u32(128 * 1024 * 1024);
+        buf.put_slice(b"hello");
+        let mut stream = DummyStream { buf };
+
+        let mut data = BytesMut::new();
+        let result = read_frame(&mut stream, &mut data).await;
+        assert!(result.is_err());
+        assert!(data.capacity() < 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn read_frame_over_message_limit_should_fail() {
+        let mut buf = BytesMut::new();
+        buf.put_u32((MAX_MESSAGE + 1) as _);
+        let mut stream = DummyStream { buf };
+
+        let mut data = BytesMut::new();
+        let result = read_frame(&mut stream, &mut data).await;
+        assert!(matches!(result, Err(KvError::FrameError)));
+    }
+
+    #[tokio::test]
+    async fn read_frame_with_endless_continuation_should_fail() {
+        let mut buf = BytesMut::new();
+        for _ in 0..=MAX_FRAMES {
+            buf.put_u32(CONTINUATION_BIT as _);
+        }
+        let mut stream = DummyStream { buf };
+
+        let mut data = BytesMut::new();
+        let result = read_frame(&mut stream, &mut data).await;
+        assert!(matches!(result, Err(KvError::FrameError)));
+        assert!(data.len() <= MAX_FRAMES * LEN_LEN);
+    }
+
+    #[tokio::test]
+    async fn large_message_should_be_chunked() {
+        // 不容易压缩的数据，压缩后仍然超过 MAX_CHUNK
+        let mut seed = 42u64;
+        let data: Vec<u8> = (0..3 * MAX_CHUNK)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect();
+        let res: CommandResponse = Value::from(Bytes::from(data)).into();
+
+        let mut buf = BytesMut::new();
+        res.encode_frame(&mut buf).unwrap();
+        let header = u32::from_be_bytes(buf[..LEN_LEN].try_into().unwrap()) as usize;
+        assert_eq!(decode_header(header), (MAX_CHUNK, true, true));
+
+        let mut stream = DummyStream { buf };
+        let mut data = BytesMut::new();
+        read_frame(&mut stream, &mut data).await.unwrap();
+        assert!(stream.buf.is_empty());
+
+        let res1 = CommandResponse::decode_frame(&mut data).unwrap();
+        assert_eq!(res, res1);
+    }
+
+    #[test]
+    fn msgpack_encode_decode_should_work() {
+        let mut buf = BytesMut::new();
+        // 大的消息同样会压缩
+        let values: Vec<Value> = vec![1.into(), "hello".repeat(1000).as_str().into()];
+        let res: CommandResponse = values.into();
+        res.encode_frame_with(Encoding::MessagePack, &mut buf)
+            .unwrap();
+        assert!(is_compressed(&buf));
+
+        let res1 = CommandResponse::decode_frame_with(Encoding::MessagePack, &mut buf).unwrap();
+        assert_eq!(res, res1);
+    }
+
+    #[test]
+    fn decode_truncated_continuation_should_fail() {
+        let mut buf = BytesMut::new();
+        buf.put_u32((5 | CONTINUATION_BIT) as _);
+        buf.put_slice(b"hello");
+        assert!(CommandRequest::decode_frame(&mut buf).is_err());
+    }
+
+    #[test]
+    fn decode_truncated_frame_should_fail() {
+        let mut buf = BytesMut::from(&[0u8, 0][..]);
+        assert!(CommandRequest::decode_frame(&mut buf).is_err());
+
+        let mut buf = BytesMut::new();
+        buf.put_u32(100);
+        buf.put_slice(b"hello");
+        assert!(CommandRequest::decode_frame(&mut buf).is_err());
+
+        let mut buf = BytesMut::new();
+        buf.put_u32(5 | COMPRESSION_BIT as u32);
+        buf.put_slice(b"hello");
+        assert!(CommandRequest::decode_frame(&mut buf).is_err());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn command_request_frame_should_roundtrip(cmd in crate::strategies::command_request()) {
+            let mut buf = BytesMut::new();
+            cmd.encode_frame(&mut buf).unwrap();
+            let cmd1 = CommandRequest::decode_frame(&mut buf).unwrap();
+            proptest::prop_assert_eq!(cmd, cmd1);
+        }
+    }
 }
@@ -1,90 +1,259 @@
+mod client;
+mod failover;
 mod frame;
+mod multiplex;
+#[cfg(feature = "quic")]
+mod quic;
+mod stream;
 mod tls;
+mod websocket;
+mod zero_copy;
+
+pub use client::KvClient;
+pub use failover::{
+    CircuitBreaker, CircuitConfig, CircuitState, Connect, FailoverClient, TcpConnector,
+};
+pub use frame::{read_frame, Encoding, FrameCoder};
+pub use multiplex::{accept_streams, YamuxCtrl, YamuxStream};
+#[cfg(feature = "quic")]
+pub use quic::{QuicConnection, QuicConnector, QuicServer, QuicStream};
+pub use stream::ProstStream;
+pub use tls::{client_identity, TlsClientConnector, TlsServerAcceptor};
+pub use websocket::WsStream;
+pub use zero_copy::ResponseFrame;
+
+pub(crate) use frame::MAX_MESSAGE;
+
+use frame::HANDSHAKE;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::info;
 
-pub use tls::{TlsClientConnector, TlsServerAcceptor};
-pub use frame::{read_frame, FrameCoder};
+use crate::{
+    free_lazily, AdmissionControl, BlockingPool, CommandRequest, CommandResponse, KvError,
+    MemTable, Reply, Service, Storage,
+};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 
-use bytes::BytesMut;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tracing::info;
+// 服务器读 request，写 response
+type ServerStream<S> = ProstStream<S, CommandRequest, CommandResponse>;
 
-use crate::{CommandRequest, CommandResponse, KvError, Service};
+/// 一个连接上等待写出去的 response 和订阅消息的个数，
+/// 订阅者读得太慢时超出的消息会被丢弃
+const SEND_BUFFER: usize = 128;
 
 /// 处理服务器端的某个 accept 下来的 socket 的读写
-pub struct ProstServerStream<S> {
+pub struct ProstServerStream<S, Store = MemTable> {
     inner: S,
-    service: Service,
+    service: Service<Store>,
+    encoding: Encoding,
+    pool: Option<BlockingPool>,
+    admission: Option<AdmissionControl>,
+    identity: Option<String>,
 }
 
-
 /// 处理客户端 socket 的读写
 pub struct ProstClientStream<S> {
-    inner: S,
+    inner: ProstStream<S, CommandResponse, CommandRequest>,
+    // execute 等待 response 时收到的订阅消息
+    messages: VecDeque<CommandResponse>,
 }
 
-impl<S> ProstServerStream<S>
+impl<S, Store> ProstServerStream<S, Store>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send,
+    Store: Storage + Send + Sync + 'static,
 {
-    pub fn new(stream: S, service: Service) -> Self {
+    pub fn new(stream: S, service: Service<Store>) -> Self {
         Self {
             inner: stream,
             service,
+            encoding: Encoding::default(),
+            pool: None,
+            admission: None,
+            identity: None,
         }
     }
 
+    /// 执行命令之前先经过准入控制，多个连接应该共享同一个 AdmissionControl
+    pub fn with_admission(mut self, admission: AdmissionControl) -> Self {
+        self.admission = Some(admission);
+        self
+    }
+
+    /// 这个连接的客户端身份，一般是 client_identity 从 TLS 客户端证书里取出来的
+    pub fn with_identity(mut self, identity: Option<String>) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// 在 BlockingPool 里执行命令，而不是在 tokio 的线程上直接访问存储
+    pub fn with_pool(mut self, pool: BlockingPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
     pub async fn process(mut self) -> Result<(), KvError> {
-        while let Ok(cmd) = self.recv().await {
-            info!("Got a new command: {:?}", cmd);
-            let res = self.service.execute(cmd);
-            self.send(res).await?;
+        let _connection = self.service.stats().connect();
+        // 客户端可以先发一个 handshake 协商 payload 的编码，老的客户端直接发 frame
+        let mut header = match self.inner.read_u32().await {
+            Ok(header) => header,
+            Err(_) => return Ok(()),
+        };
+        if header == HANDSHAKE {
+            self.handshake().await?;
+            header = self.inner.read_u32().await?;
         }
+
+        let Self {
+            inner,
+            service,
+            encoding,
+            pool,
+            admission,
+            identity,
+        } = self;
+        let pool = pool.or_else(|| service.pool().cloned());
+        let (reader, writer) = tokio::io::split(inner);
+        let mut reader = ServerStream::with_encoding(reader, encoding);
+        let mut writer = ServerStream::with_encoding(writer, encoding);
+        // response 和订阅推送的消息都放进这个 channel，由写的一边依次发出去
+        let (tx, mut rx) = mpsc::channel(SEND_BUFFER);
+        // HSCAN 的 cursor 和订阅只属于这个连接，连接断开时一起释放
+        // 同一个连接上的命令是依次执行的，这个锁不会有竞争，只是为了能交给 BlockingPool
+        let session = service.session(tx.clone()).with_identity(identity.clone());
+        let session = Arc::new(Mutex::new(session));
+
+        let read = async move {
+            let mut first = Some(header);
+            'conn: loop {
+                let cmd = match first.take() {
+                    Some(header) => reader.recv_from(header).await,
+                    None => reader.recv().await,
+                };
+                let Ok(cmd) = cmd else { break };
+                info!("Got a new command: {:?}", cmd);
+                let _permit = match &admission {
+                    Some(admission) => {
+                        let priority = admission.priority(&cmd, identity.as_deref());
+                        match admission.admit(priority).await {
+                            Ok(permit) => Some(permit),
+                            Err(e) => match tx.send(e.into()).await {
+                                Ok(()) => continue,
+                                Err(_) => break,
+                            },
+                        }
+                    }
+                    None => None,
+                };
+                let service = service.clone();
+                let session = session.clone();
+                let execute = move || service.execute_reply(cmd, &mut session.lock().unwrap());
+                let reply = match &pool {
+                    Some(pool) => pool
+                        .run(execute)
+                        .await
+                        .unwrap_or_else(|e| Reply::Single(e.into())),
+                    None => execute(),
+                };
+                // 分块的 response 依次放进 channel，推送的消息可能夹在中间，
+                // 它们的 subscription 不是 0，客户端可以区分开
+                for res in reply {
+                    // 写的一边出错退出了
+                    if tx.send(res).await.is_err() {
+                        break 'conn;
+                    }
+                }
+            }
+            // 取消所有的订阅之后 channel 就没有别的发送端了，
+            // 写的一边发完剩下的消息就会退出
+            drop(session);
+        };
+        let write = async move {
+            while let Some(res) = rx.recv().await {
+                writer.send(&res).await?;
+                // 比如 HSET 覆盖了一个很大的旧值，不要在这里释放它
+                free_lazily(res);
+            }
+            Ok(())
+        };
         // info!("Client {:?} disconnected", self.addr);
-        Ok(())
+        tokio::join!(read, write).1
     }
 
-    async fn send(&mut self, msg: CommandResponse) -> Result<(), KvError> {
-        let mut buf = BytesMut::new();
-        msg.encode_frame(&mut buf)?;
-        let encoded = buf.freeze();
-        self.inner.write_all(&encoded[..]).await?;
+    /// 读取客户端想要的编码，不支持的话回复 protobuf
+    async fn handshake(&mut self) -> Result<(), KvError> {
+        let id = self.inner.read_u8().await?;
+        self.encoding = Encoding::from_id(id).unwrap_or_default();
+        self.inner.write_u8(self.encoding.id()).await?;
+        self.inner.flush().await?;
+        info!("Negotiated encoding: {:?}", self.encoding);
         Ok(())
     }
-
-    async fn recv(&mut self) -> Result<CommandRequest, KvError> {
-        let mut buf = BytesMut::new();
-        let stream = &mut self.inner;
-        read_frame(stream, &mut buf).await?;
-        CommandRequest::decode_frame(&mut buf)
-    }
 }
 
 impl<S> ProstClientStream<S>
-    where
-        S: AsyncRead + AsyncWrite + Unpin + Send,
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
 {
     pub fn new(stream: S) -> Self {
-        Self { inner: stream }
+        Self {
+            inner: ProstStream::new(stream),
+            messages: VecDeque::new(),
+        }
     }
 
-    pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
-        self.send(cmd).await?;
-        Ok(self.recv().await?)
+    /// 和服务器协商 payload 的编码，服务器不支持时退回 protobuf。
+    /// 不支持 handshake 的老服务器会直接断开连接
+    pub async fn with_encoding(mut stream: S, encoding: Encoding) -> Result<Self, KvError> {
+        stream.write_u32(HANDSHAKE).await?;
+        stream.write_u8(encoding.id()).await?;
+        stream.flush().await?;
+        let id = stream.read_u8().await?;
+        let encoding = Encoding::from_id(id)
+            .ok_or_else(|| KvError::Internal(format!("Unknown encoding {}", id)))?;
+
+        Ok(Self {
+            inner: ProstStream::with_encoding(stream, encoding),
+            messages: VecDeque::new(),
+        })
     }
 
-    async fn send(&mut self, msg: CommandRequest) -> Result<(), KvError> {
-        let mut buf = BytesMut::new();
-        msg.encode_frame(&mut buf)?;
-        let encoded = buf.freeze();
-        self.inner.write_all(&encoded[..]).await?;
-        Ok(())
+    /// 当前连接使用的编码
+    pub fn encoding(&self) -> Encoding {
+        self.inner.encoding()
     }
 
-    async fn recv(&mut self) -> Result<CommandResponse, KvError> {
-        let mut buf = BytesMut::new();
-        let stream = &mut self.inner;
-        read_frame(stream, &mut buf).await?;
-        CommandResponse::decode_frame(&mut buf)
+    /// 发送命令并等待 response，分块返回的 response 会合并成一个
+    pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        self.inner.send(&cmd).await?;
+        let mut merged: Option<CommandResponse> = None;
+        loop {
+            // 订阅推送的消息可能夹在 response 之前，先留给 next_message
+            let mut res = self.inner.recv().await?;
+            if res.subscription != 0 {
+                self.messages.push_back(res);
+                continue;
+            }
+            if let Some(mut prev) = merged.take() {
+                prev.pairs.append(&mut res.pairs);
+                prev.more = res.more;
+                res = prev;
+            }
+            if !res.more {
+                return Ok(res);
+            }
+            merged = Some(res);
+        }
+    }
+
+    /// 等待下一条订阅推送的消息，pair 的 key 是 topic，value 是发布的数据
+    pub async fn next_message(&mut self) -> Result<CommandResponse, KvError> {
+        match self.messages.pop_front() {
+            Some(msg) => Ok(msg),
+            None => self.inner.recv().await,
+        }
     }
 }
 
@@ -95,7 +264,7 @@ mod tests {
     use std::net::SocketAddr;
     use tokio::net::{TcpListener, TcpStream};
 
-    use crate::{assert_res_ok, MemTable, ServiceInner, Value};
+    use crate::{assert_res_ok, MemTable, Priority, ServiceInner, Value};
 
     use super::*;
 
@@ -145,6 +314,172 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn client_server_msgpack_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::with_encoding(stream, Encoding::MessagePack).await?;
+        assert_eq!(client.encoding(), Encoding::MessagePack);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute(cmd).await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = client.execute(cmd).await?;
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_hscan_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        for i in 0..5 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i.into());
+            client.execute(cmd).await?;
+        }
+
+        let res = client
+            .execute(CommandRequest::new_hscan("t1", 0, 3))
+            .await?;
+        assert_eq!(res.pairs.len(), 3);
+        assert_ne!(res.cursor, 0);
+
+        let res = client
+            .execute(CommandRequest::new_hscan("t1", res.cursor, 3))
+            .await?;
+        assert_eq!(res.pairs.len(), 2);
+        assert_eq!(res.cursor, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_with_blocking_pool_should_work() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let pool = BlockingPool::new(2, 16)?;
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            ProstServerStream::new(stream, service)
+                .with_pool(pool)
+                .process()
+                .await
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        client.execute(cmd).await?;
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_shed_when_overloaded() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let admission = AdmissionControl::new(1, 0);
+        let server_admission = admission.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            ProstServerStream::new(stream, service)
+                .with_admission(server_admission)
+                .process()
+                .await
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        // 模拟另一个连接正在执行命令
+        let busy = admission.admit(Priority::Normal).await?;
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.status, 503);
+
+        drop(busy);
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.status, 404);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_receive_published_messages() -> Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let res = client
+            .execute(CommandRequest::new_subscribe("news"))
+            .await?;
+        assert_eq!(res.subscription, 0);
+        let id = res.values[0].clone();
+
+        // 推送给自己的消息可能比 PUBLISH 的 response 先到
+        let res = client
+            .execute(CommandRequest::new_publish("news", "hello".into()))
+            .await?;
+        assert_res_ok(res, &[1.into()], &[]);
+        let msg = client.next_message().await?;
+        assert_eq!(Value::from(msg.subscription as i64), id);
+        assert_eq!(msg.pairs, vec![crate::Kvpair::new("news", "hello".into())]);
+
+        let res = client
+            .execute(CommandRequest::new_unsubscribe("news", 0))
+            .await?;
+        assert_res_ok(res, &[1.into()], &[]);
+        let res = client
+            .execute(CommandRequest::new_publish("news", "hello".into()))
+            .await?;
+        assert_res_ok(res, &[0.into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_merge_chunked_responses() -> Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        for i in 0..5 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i.into());
+            client.execute(cmd).await?;
+        }
+
+        // 服务器发回 3 个 frame，最后一个的 more 是 false
+        client
+            .inner
+            .send(&CommandRequest::new_hgetall_chunked("t1", 2))
+            .await?;
+        let mut frames = Vec::new();
+        loop {
+            let res = client.inner.recv().await?;
+            frames.push((res.pairs.len(), res.more));
+            if !res.more {
+                break;
+            }
+        }
+        assert_eq!(frames, [(2, true), (2, true), (1, false)]);
+
+        let res = client
+            .execute(CommandRequest::new_hgetall_chunked("t1", 2))
+            .await?;
+        assert_eq!(res.pairs.len(), 5);
+        assert!(!res.more);
+
+        Ok(())
+    }
+
     async fn start_server() -> Result<SocketAddr> {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
@@ -160,4 +495,4 @@ mod tests {
 
         Ok(addr)
     }
-}
\ No newline at end of file
+}
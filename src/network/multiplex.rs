@@ -0,0 +1,177 @@
+use std::future::poll_fn;
+use std::task::Poll;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use yamux::{Config, Connection, Mode};
+
+use crate::KvError;
+
+/// yamux 连接里的一个 stream，可以像 TcpStream 一样交给 ProstClientStream 或者 ProstServerStream
+pub type YamuxStream = Compat<yamux::Stream>;
+
+type Opening = oneshot::Sender<Result<YamuxStream, KvError>>;
+
+/// 客户端的 yamux 连接，在一个 TCP/TLS 连接上打开多个 stream
+///
+/// 每个 stream 是一个独立的请求/响应通道，订阅也可以放在单独的 stream 上，
+/// 不会和普通命令的 response 混在一起。连接由后台的 task 驱动，
+/// YamuxCtrl drop 之后关闭连接
+pub struct YamuxCtrl {
+    tx: mpsc::UnboundedSender<Opening>,
+}
+
+impl YamuxCtrl {
+    pub fn new_client<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let conn = Connection::new(stream.compat(), Config::default(), Mode::Client);
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(drive(conn, rx));
+        Self { tx }
+    }
+
+    /// 打开一个新的 stream，连接已经断开时返回错误
+    pub async fn open_stream(&self) -> Result<YamuxStream, KvError> {
+        let closed = || KvError::Internal("Yamux connection is closed".into());
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(tx).map_err(|_| closed())?;
+        rx.await.map_err(|_| closed())?
+    }
+}
+
+// yamux 要求一直 poll_next_inbound 才会读写底层的连接，打开 stream 也在同一个 task 里做
+async fn drive<S>(mut conn: Connection<Compat<S>>, mut rx: mpsc::UnboundedReceiver<Opening>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut opening: Option<Opening> = None;
+    poll_fn(|cx| loop {
+        if opening.is_none() {
+            match rx.poll_recv(cx) {
+                Poll::Ready(Some(tx)) => opening = Some(tx),
+                Poll::Ready(None) => return conn.poll_close(cx).map(|_| ()),
+                Poll::Pending => {}
+            }
+        }
+        if let Some(tx) = opening.take() {
+            match conn.poll_new_outbound(cx) {
+                Poll::Ready(stream) => {
+                    let _ = tx.send(stream.map(|s| s.compat()).map_err(Into::into));
+                    continue;
+                }
+                Poll::Pending => opening = Some(tx),
+            }
+        }
+        match conn.poll_next_inbound(cx) {
+            // 服务器不会主动打开 stream
+            Poll::Ready(Some(Ok(_))) => continue,
+            // 连接断开了，等着的 open_stream 收到错误
+            Poll::Ready(Some(Err(_)) | None) => return Poll::Ready(()),
+            Poll::Pending => return Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// 服务器端的 yamux 连接，客户端打开的每个 stream 交给 handle，
+/// 一般是 spawn 一个 ProstServerStream 处理它。连接断开时返回
+pub async fn accept_streams<S>(
+    stream: S,
+    mut handle: impl FnMut(YamuxStream),
+) -> Result<(), KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut conn = Connection::new(stream.compat(), Config::default(), Mode::Server);
+    while let Some(stream) = poll_fn(|cx| conn.poll_next_inbound(cx)).await {
+        handle(stream?.compat());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, KvClient, MemTable, ProstServerStream, Service, ServiceInner};
+    use anyhow::Result;
+    use std::net::SocketAddr;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn start_server() -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            accept_streams(stream, |stream| {
+                let stream = ProstServerStream::new(stream, service.clone());
+                tokio::spawn(stream.process());
+            })
+            .await
+        });
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn yamux_streams_should_share_one_connection() -> Result<()> {
+        let addr = start_server().await?;
+        let ctrl = YamuxCtrl::new_client(TcpStream::connect(addr).await?);
+
+        let mut handles = Vec::new();
+        for i in 0..8 {
+            let stream = ctrl.open_stream().await?;
+            handles.push(tokio::spawn(async move {
+                let mut client = KvClient::new(stream);
+                for j in 0..10 {
+                    client.hset("t1", format!("k{}-{}", i, j), j).await?;
+                }
+                Ok::<_, KvError>(())
+            }));
+        }
+        for handle in handles {
+            handle.await??;
+        }
+        let mut client = KvClient::new(ctrl.open_stream().await?);
+        assert_eq!(client.hlen("t1").await?, 80);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn yamux_should_carry_subscriptions_on_their_own_stream() -> Result<()> {
+        let addr = start_server().await?;
+        let ctrl = YamuxCtrl::new_client(TcpStream::connect(addr).await?);
+
+        let mut subscriber = crate::ProstClientStream::new(ctrl.open_stream().await?);
+        let res = subscriber
+            .execute(CommandRequest::new_subscribe("news"))
+            .await?;
+        assert_eq!(res.status, 200);
+        let mut publisher = KvClient::new(ctrl.open_stream().await?);
+        let res = publisher
+            .execute(CommandRequest::new_publish("news", "hello".into()))
+            .await?;
+        assert_eq!(res.values, [1.into()]);
+
+        let msg = subscriber.next_message().await?;
+        assert_eq!(msg.pairs[0].value, Some("hello".into()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_stream_should_fail_after_disconnect() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            // 接受连接之后马上断开
+            drop(listener.accept().await.unwrap());
+        });
+        let ctrl = YamuxCtrl::new_client(TcpStream::connect(addr).await?);
+        let mut client = KvClient::new(ctrl.open_stream().await?);
+        assert!(client.hget("t1", "k1").await.is_err());
+        assert!(ctrl.open_stream().await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use quinn::rustls::{self, crypto::CryptoProvider, version::TLS13, RootCertStore};
+use quinn::{Endpoint, RecvStream, SendStream};
+use tokio::io::Join;
+use tracing::{info, warn};
+
+use crate::KvError;
+
+/// 和 TLS 一样的 ALPN
+const ALPN_KV: &[u8] = b"kv";
+
+/// QUIC 连接里的一个双向 stream，可以交给 ProstClientStream 或者 ProstServerStream
+pub type QuicStream = Join<RecvStream, SendStream>;
+
+/// QUIC 的服务器端，QUIC 自带 TLS 1.3，不需要再包一层 TlsServerAcceptor
+pub struct QuicServer {
+    endpoint: Endpoint,
+}
+
+impl QuicServer {
+    /// 在 addr 上监听 UDP，cert 和 key 是 PEM 格式的服务器证书和私钥
+    pub fn bind(addr: SocketAddr, cert: &str, key: &str) -> Result<Self, KvError> {
+        let certs = CertificateDer::pem_slice_iter(cert.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| KvError::CertifcateParseError("server", "cert"))?;
+        let key = PrivateKeyDer::from_pem_slice(key.as_bytes())
+            .map_err(|_| KvError::CertifcateParseError("private", "key"))?;
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&TLS13])
+            .map_err(quic_error)?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|_| KvError::CertifcateParseError("server", "cert"))?;
+        tls.alpn_protocols = vec![ALPN_KV.to_vec()];
+
+        let crypto = QuicServerConfig::try_from(tls).map_err(quic_error)?;
+        let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        Ok(Self {
+            endpoint: Endpoint::server(config, addr)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, KvError> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// 接受连接，客户端打开的每个 stream 交给 handle，一般是 spawn 一个 ProstServerStream
+    /// 处理它。一个连接握手失败或者断开不影响别的连接，endpoint 关闭时返回
+    pub async fn serve(&self, handle: impl Fn(QuicStream) + Send + Sync + 'static) {
+        let handle = Arc::new(handle);
+        while let Some(incoming) = self.endpoint.accept().await {
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                let conn = match incoming.await {
+                    Ok(conn) => conn,
+                    Err(e) => return warn!("QUIC handshake failed: {:?}", e),
+                };
+                info!("QUIC client {:?} connected", conn.remote_address());
+                while let Ok((send, recv)) = conn.accept_bi().await {
+                    handle(tokio::io::join(recv, send));
+                }
+            });
+        }
+    }
+
+    /// 关闭 endpoint，所有的连接都会断开
+    pub fn close(&self) {
+        self.endpoint.close(0u32.into(), b"shutdown");
+    }
+}
+
+/// QUIC 的客户端，用 server_ca 验证服务器证书
+pub struct QuicConnector {
+    endpoint: Endpoint,
+    domain: String,
+}
+
+impl QuicConnector {
+    pub fn new(domain: impl Into<String>, server_ca: &str) -> Result<Self, KvError> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(server_ca.as_bytes()) {
+            let cert = cert.map_err(|_| KvError::CertifcateParseError("CA", "cert"))?;
+            roots.add(cert).map_err(quic_error)?;
+        }
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&TLS13])
+            .map_err(quic_error)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN_KV.to_vec()];
+
+        let crypto = QuicClientConfig::try_from(tls).map_err(quic_error)?;
+        let mut endpoint = Endpoint::client(SocketAddr::from(([0, 0, 0, 0], 0)))?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        Ok(Self {
+            endpoint,
+            domain: domain.into(),
+        })
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> Result<QuicConnection, KvError> {
+        let connecting = self
+            .endpoint
+            .connect(addr, &self.domain)
+            .map_err(quic_error)?;
+        Ok(QuicConnection(connecting.await.map_err(quic_error)?))
+    }
+}
+
+/// 客户端的 QUIC 连接，drop 的时候关闭
+pub struct QuicConnection(quinn::Connection);
+
+impl QuicConnection {
+    /// 打开一个新的 stream，比如每个订阅用一个单独的 stream
+    pub async fn open_stream(&self) -> Result<QuicStream, KvError> {
+        let (send, recv) = self.0.open_bi().await.map_err(quic_error)?;
+        Ok(tokio::io::join(recv, send))
+    }
+}
+
+impl Drop for QuicConnection {
+    fn drop(&mut self) {
+        self.0.close(0u32.into(), b"done");
+    }
+}
+
+// 不依赖进程里安装的缺省 CryptoProvider
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn quic_error(e: impl Display) -> KvError {
+    KvError::QuicError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KvClient, MemTable, ProstServerStream, Service, ServiceInner};
+    use anyhow::Result;
+
+    const CA_CERT: &str = include_str!("../../fixtures/ca.cert");
+    const SERVER_CERT: &str = include_str!("../../fixtures/server.cert");
+    const SERVER_KEY: &str = include_str!("../../fixtures/server.key");
+
+    fn start_server() -> Result<SocketAddr> {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let server = QuicServer::bind(addr, SERVER_CERT, SERVER_KEY)?;
+        let addr = server.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(async move {
+            server
+                .serve(move |stream| {
+                    tokio::spawn(ProstServerStream::new(stream, service.clone()).process());
+                })
+                .await
+        });
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn quic_streams_should_work() -> Result<()> {
+        let addr = start_server()?;
+        let connector = QuicConnector::new("kvserver.acme.inc", CA_CERT)?;
+        let conn = connector.connect(addr).await?;
+
+        let mut c1 = KvClient::new(conn.open_stream().await?);
+        let mut c2 = KvClient::new(conn.open_stream().await?);
+        assert_eq!(c1.hset("t1", "k1", "v1").await?, None);
+        assert_eq!(c2.hget("t1", "k1").await?, Some("v1".into()));
+        assert_eq!(c1.hset("t1", "k1", "v2").await?, Some("v1".into()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quic_with_bad_domain_should_fail() -> Result<()> {
+        let addr = start_server()?;
+        let connector = QuicConnector::new("kvserver1.acme.inc", CA_CERT)?;
+        assert!(matches!(
+            connector.connect(addr).await,
+            Err(KvError::QuicError(_))
+        ));
+        Ok(())
+    }
+}
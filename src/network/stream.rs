@@ -0,0 +1,152 @@
+use std::marker::PhantomData;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use super::frame::read_frame_from;
+use crate::{read_frame, Encoding, FrameCoder, KvError};
+
+/// 在任意的 AsyncRead / AsyncWrite 上收发 frame，In 是读到的消息，Out 是发出去的消息
+///
+/// 客户端是 ProstStream<S, CommandResponse, CommandRequest>，服务器反过来。
+/// 只读或者只写的 stream（比如 tokio::io::split 出来的一半）也可以用，
+/// 只能调用对应的 recv 或者 send。超过 MAX_MESSAGE 的消息返回 FrameError
+pub struct ProstStream<S, In, Out> {
+    inner: S,
+    encoding: Encoding,
+    _msg: PhantomData<fn(Out) -> In>,
+}
+
+impl<S, In, Out> ProstStream<S, In, Out> {
+    pub fn new(stream: S) -> Self {
+        Self::with_encoding(stream, Encoding::default())
+    }
+
+    /// 用已经协商好的编码收发 payload
+    pub fn with_encoding(stream: S, encoding: Encoding) -> Self {
+        Self {
+            inner: stream,
+            encoding,
+            _msg: PhantomData,
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, In, Out> ProstStream<S, In, Out>
+where
+    S: AsyncRead + Unpin + Send,
+    In: FrameCoder,
+{
+    /// 读取一个完整的消息，数据不够的时候一直等，对端关闭时返回 IoError
+    pub async fn recv(&mut self) -> Result<In, KvError> {
+        let mut buf = BytesMut::new();
+        read_frame(&mut self.inner, &mut buf).await?;
+        In::decode_frame_with(self.encoding, &mut buf)
+    }
+
+    /// 和 recv 一样，只是第一个 frame 的 header 已经读出来了
+    pub(crate) async fn recv_from(&mut self, header: u32) -> Result<In, KvError> {
+        let mut buf = BytesMut::new();
+        read_frame_from(&mut self.inner, header, &mut buf).await?;
+        In::decode_frame_with(self.encoding, &mut buf)
+    }
+}
+
+impl<S, In, Out> ProstStream<S, In, Out>
+where
+    S: AsyncWrite + Unpin,
+    Out: FrameCoder,
+{
+    /// 编码成一个或多个 frame 之后一次写出去。WebSocket 这样有缓冲的 stream 要 flush 才会发送
+    pub async fn send(&mut self, msg: &Out) -> Result<(), KvError> {
+        let mut buf = BytesMut::new();
+        msg.encode_frame_with(self.encoding, &mut buf)?;
+        self.inner.write_all(&buf).await?;
+        self.inner.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, CommandResponse};
+    use bytes::{BufMut, Bytes};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    // 每次最多读出 step 个字节，模拟网络上一点一点到达的数据
+    struct MockStream {
+        buf: BytesMut,
+        step: usize,
+    }
+
+    impl AsyncRead for MockStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+            let len = buf.remaining().min(this.buf.len()).min(this.step);
+            buf.put_slice(&this.buf.split_to(len));
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for MockStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.get_mut().buf.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_handle_partial_reads() {
+        let stream = MockStream {
+            buf: BytesMut::new(),
+            step: 3,
+        };
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        // 压缩过的大消息
+        let big = CommandRequest::new_hset("t1", "k2", Bytes::from(vec![7; 8192]).into());
+        stream.send(&cmd).await.unwrap();
+        stream.send(&big).await.unwrap();
+        assert_eq!(stream.recv().await.unwrap(), cmd);
+        assert_eq!(stream.recv().await.unwrap(), big);
+        // 没有数据了
+        assert!(matches!(stream.recv().await, Err(KvError::IoError(_))));
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_reject_oversized_frames() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(((1 << 30) - 1) as _);
+        buf.put_slice(b"hello");
+        let stream = MockStream { buf, step: 1024 };
+        let mut stream = ProstStream::<_, CommandResponse, CommandRequest>::new(stream);
+        assert!(matches!(stream.recv().await, Err(KvError::FrameError)));
+    }
+}
@@ -1,18 +1,22 @@
 use std::io::Cursor;
 use std::sync::Arc;
 
+use crate::KvError;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_rustls::rustls::{internal::pemfile, Certificate, ClientConfig, ServerConfig};
+use tokio_rustls::rustls::{internal::pemfile, Certificate, ClientConfig, ServerConfig, Session};
 use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore};
 use tokio_rustls::webpki::DNSNameRef;
 use tokio_rustls::TlsConnector;
 use tokio_rustls::{
     client::TlsStream as ClientTlsStream, server::TlsStream as ServerTlsStream, TlsAcceptor,
 };
-use crate::KvError;
 
 /// KV Server 自己的 ALPN(Application-Layer Protocol Negotiation)
 const ALPN_KV: &str = "kv";
+/// 浏览器用 wss 连接时只提供 http/1.1
+const ALPN_HTTP: &str = "http/1.1";
+/// 证书 subject 里 CN 的 OID 2.5.4.3
+const CN_OID: &[u8] = &[0x55, 0x04, 0x03];
 
 /// 存放 TLS ServerConfig 并提供方法 accept 把底层的协议转换成 TLS
 #[derive(Clone)]
@@ -34,7 +38,7 @@ impl TlsClientConnector {
         identity: Option<(&str, &str)>,
         server_ca: Option<&str>,
     ) -> Result<Self, KvError> {
-       let mut config = ClientConfig::new();
+        let mut config = ClientConfig::new();
 
         // 如果有客户端证书，加载之
         if let Some((cert, key)) = identity {
@@ -68,7 +72,7 @@ impl TlsClientConnector {
         S: AsyncRead + AsyncWrite + Unpin + Send,
     {
         let dns = DNSNameRef::try_from_ascii_str(self.domain.as_str())
-            .map_err(|_|KvError::Internal("Invalid DNS name".into()))?;
+            .map_err(|_| KvError::Internal("Invalid DNS name".into()))?;
 
         let stream = TlsConnector::from(self.config.clone())
             .connect(dns, stream)
@@ -79,7 +83,8 @@ impl TlsClientConnector {
 }
 
 impl TlsServerAcceptor {
-    /// 加载 server cert / CA cert, 生成 ServerConfig
+    /// 加载 server cert / CA cert, 生成 ServerConfig。
+    /// 有 client_ca 时要求客户端提供这个 CA 签发的证书，没有证书或者验证不过的连接握手失败
     pub fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Self, KvError> {
         let certs = load_certs(cert)?;
         let key = load_key(key)?;
@@ -102,8 +107,8 @@ impl TlsServerAcceptor {
         // 加载服务器证书
         config
             .set_single_cert(certs, key)
-            .map_err(|_|KvError::CertifcateParseError("server", "cert"))?;
-        config.set_protocols(&[Vec::from(&ALPN_KV[..])]);
+            .map_err(|_| KvError::CertifcateParseError("server", "cert"))?;
+        config.set_protocols(&[Vec::from(ALPN_KV), Vec::from(ALPN_HTTP)]);
 
         Ok(Self {
             inner: Arc::new(config),
@@ -120,9 +125,68 @@ impl TlsServerAcceptor {
     }
 }
 
+/// 客户端证书 subject 的 CN，服务器用它作为这个连接的身份。
+/// 没有要求客户端证书，或者证书里没有 CN 时返回 None
+pub fn client_identity<S>(stream: &ServerTlsStream<S>) -> Option<String> {
+    let certs = stream.get_ref().1.get_peer_certificates()?;
+    common_name(&certs.first()?.0)
+}
+
+// 只解析到 subject 为止：Certificate 是 SEQUENCE { tbsCertificate, .. }，
+// tbsCertificate 依次是可选的 [0] version、serial、signature、issuer、validity、subject
+fn common_name(der: &[u8]) -> Option<String> {
+    let (cert, _) = der_item(der, 0x30)?;
+    let (mut tbs, _) = der_item(cert, 0x30)?;
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_tlv(tbs)?.2;
+    }
+    for _ in 0..4 {
+        tbs = der_tlv(tbs)?.2;
+    }
+    // subject 是 SEQUENCE OF SET OF SEQUENCE { OID, value }
+    let (mut rdns, _) = der_item(tbs, 0x30)?;
+    while !rdns.is_empty() {
+        let (mut attrs, rest) = der_item(rdns, 0x31)?;
+        rdns = rest;
+        while !attrs.is_empty() {
+            let (attr, rest) = der_item(attrs, 0x30)?;
+            attrs = rest;
+            let (oid, value) = der_item(attr, 0x06)?;
+            if oid == CN_OID {
+                return String::from_utf8(der_tlv(value)?.1.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+// 取出开头的一个 DER 元素，返回 tag、内容和剩下的数据
+fn der_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&first, mut data) = data.split_first()?;
+    let len = match first {
+        n if n < 0x80 => n as usize,
+        n => {
+            let (bytes, rest) = data.split_at_checked((n & 0x7f) as usize)?;
+            if bytes.is_empty() || bytes.len() > 4 {
+                return None;
+            }
+            data = rest;
+            bytes.iter().fold(0, |len, &b| len << 8 | b as usize)
+        }
+    };
+    let (content, rest) = data.split_at_checked(len)?;
+    Some((tag, content, rest))
+}
+
+fn der_item(data: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (t, content, rest) = der_tlv(data)?;
+    (t == tag).then_some((content, rest))
+}
+
 fn load_certs(cert: &str) -> Result<Vec<Certificate>, KvError> {
     let mut cert = Cursor::new(cert);
-    pemfile::certs(&mut cert).map_err(|_|KvError::CertifcateParseError("server", "cert"))
+    pemfile::certs(&mut cert).map_err(|_| KvError::CertifcateParseError("server", "cert"))
 }
 
 fn load_key(key: &str) -> Result<PrivateKey, KvError> {
@@ -182,25 +246,79 @@ mod tests {
 
     #[tokio::test]
     async fn tls_with_client_cert_should_work() -> Result<()> {
-       let client_identity = Some((CLIENT_CERT, CLIENT_KEY));
+        let client_identity = Some((CLIENT_CERT, CLIENT_KEY));
         let ca = Some(CA_CERT);
 
-        let addr = start_server(ca.clone()).await?;
+        let addr = start_server(ca).await?;
 
         let connector = TlsClientConnector::new("kvserver.acme.inc", client_identity, ca)?;
         let stream = TcpStream::connect(addr).await?;
         let mut stream = connector.connect(stream).await?;
         stream.write_all(b"hello world!").await?;
-        let mut buf = [0;12];
+        let mut buf = [0; 12];
         stream.read_exact(&mut buf).await?;
         assert_eq!(&buf, b"hello world!");
 
         Ok(())
     }
 
+    #[tokio::test]
+    async fn tls_should_expose_client_identity() -> Result<()> {
+        let ca = Some(CA_CERT);
+        let acceptor = TlsServerAcceptor::new(SERVER_CERT, SERVER_KEY, ca)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let mut identities = Vec::new();
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let identity = match acceptor.accept(stream).await {
+                    Ok(stream) => client_identity(&stream),
+                    Err(_) => Some("rejected".into()),
+                };
+                identities.push(identity);
+            }
+            identities
+        });
+
+        let connector =
+            TlsClientConnector::new("kvserver.acme.inc", Some((CLIENT_CERT, CLIENT_KEY)), ca)?;
+        let mut stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        stream.write_all(b"hello").await?;
+
+        // 没有客户端证书的连接被拒绝
+        let connector = TlsClientConnector::new("kvserver.acme.inc", None, ca)?;
+        let stream = TcpStream::connect(addr).await?;
+        if let Ok(mut stream) = connector.connect(stream).await {
+            let _ = stream.write_all(b"hello").await;
+        }
+
+        let identities = server.await?;
+        assert_eq!(
+            identities,
+            [
+                Some("awesome-device-id".to_string()),
+                Some("rejected".into())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn common_name_should_skip_other_attributes() {
+        let certs = load_certs(CLIENT_CERT).unwrap();
+        assert_eq!(
+            common_name(&certs[0].0).as_deref(),
+            Some("awesome-device-id")
+        );
+        let certs = load_certs(SERVER_CERT).unwrap();
+        assert_eq!(common_name(&certs[0].0).as_deref(), Some("Acme KV server"));
+        assert_eq!(common_name(&certs[0].0[..100]), None);
+    }
+
     #[tokio::test]
     async fn tls_with_bad_domain_should_work() -> Result<()> {
-       let addr = start_server(None).await?;
+        let addr = start_server(None).await?;
 
         let connector = TlsClientConnector::new("kvserver1.acme.inc", None, Some(CA_CERT))?;
         let stream = TcpStream::connect(addr).await?;
@@ -227,5 +345,4 @@ mod tests {
 
         Ok(addr)
     }
-
-}
\ No newline at end of file
+}
@@ -0,0 +1,175 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::Bytes;
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::KvError;
+
+/// 把 WebSocket 连接当成一个字节流，可以交给 ProstClientStream 或者 ProstServerStream
+///
+/// 每次写出去的数据是一个 binary message，ProstStream 一次写出一个完整的 frame，
+/// 所以浏览器里的 JS 客户端收到的每个 message 就是一个 frame，发过来的也一样。
+/// 读的时候把 binary message 的内容拼起来，text message 和 ping / pong 都忽略，
+/// 收到 close 相当于读到了 EOF
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    // 当前 message 还没有读完的部分
+    pending: Bytes,
+}
+
+impl<S> WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// 服务器端：在 TCP 或者 TLS 的连接上完成 WebSocket 的握手
+    pub async fn accept(stream: S) -> Result<Self, KvError> {
+        let inner = tokio_tungstenite::accept_async(stream).await?;
+        Ok(Self::new(inner))
+    }
+
+    /// 客户端：在已经建立的连接上向 url（比如 wss://kvserver.acme.inc:9527）发起握手
+    pub async fn connect(url: &str, stream: S) -> Result<Self, KvError> {
+        let (inner, _) = tokio_tungstenite::client_async(url, stream).await?;
+        Ok(Self::new(inner))
+    }
+
+    fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            pending: Bytes::new(),
+        }
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.pending.is_empty() {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => this.pending = data,
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Poll::Ready(Err(ws_io_error(e))),
+            }
+        }
+        let len = buf.remaining().min(this.pending.len());
+        buf.put_slice(&this.pending.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut inner = Pin::new(&mut self.get_mut().inner);
+        ready!(inner.as_mut().poll_ready(cx)).map_err(ws_io_error)?;
+        inner
+            .start_send(Message::binary(buf.to_vec()))
+            .map_err(ws_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(ws_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_close(cx)
+            .map_err(ws_io_error)
+    }
+}
+
+fn ws_io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CommandRequest, CommandResponse, FrameCoder, KvClient, MemTable, ProstServerStream,
+        Service, ServiceInner, TlsClientConnector, TlsServerAcceptor,
+    };
+    use anyhow::Result;
+    use bytes::BytesMut;
+    use futures_util::{SinkExt, StreamExt};
+    use std::net::SocketAddr;
+    use tokio::net::{TcpListener, TcpStream};
+
+    const CA_CERT: &str = include_str!("../../fixtures/ca.cert");
+    const SERVER_CERT: &str = include_str!("../../fixtures/server.cert");
+    const SERVER_KEY: &str = include_str!("../../fixtures/server.key");
+
+    async fn start_server() -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let acceptor = TlsServerAcceptor::new(SERVER_CERT, SERVER_KEY, None)?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let stream = acceptor.accept(stream).await.unwrap();
+                let stream = WsStream::accept(stream).await.unwrap();
+                tokio::spawn(ProstServerStream::new(stream, service.clone()).process());
+            }
+        });
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn websocket_over_tls_should_work() -> Result<()> {
+        let addr = start_server().await?;
+        let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(CA_CERT))?;
+        let stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        let stream = WsStream::connect("wss://kvserver.acme.inc/", stream).await?;
+
+        let mut client = KvClient::new(stream);
+        assert_eq!(client.hset("t1", "k1", "v1").await?, None);
+        assert_eq!(client.hget("t1", "k1").await?, Some("v1".into()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn websocket_message_should_carry_one_frame() -> Result<()> {
+        // 模拟 JS 客户端：直接用 WebSocket message 收发 frame
+        let addr = start_server().await?;
+        let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(CA_CERT))?;
+        let stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        let (mut ws, _) =
+            tokio_tungstenite::client_async("wss://kvserver.acme.inc/", stream).await?;
+
+        let mut buf = BytesMut::new();
+        CommandRequest::new_hset("t1", "k1", "v1".into()).encode_frame(&mut buf)?;
+        ws.send(Message::binary(buf.freeze())).await?;
+
+        let msg = ws.next().await.unwrap()?;
+        let mut buf = BytesMut::from(&msg.into_data()[..]);
+        let res = CommandResponse::decode_frame(&mut buf)?;
+        assert_eq!(res.status, 200);
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! 实验性的零拷贝 response 编码
+//!
+//! 正常的路径是先构造 CommandResponse，再 encode 到 buffer 里。读多的场景下，
+//! 构造 CommandResponse 要把 value 收集到 Vec 里，这些分配只是为了马上被编码掉。
+//! ResponseFrame 按 protobuf 的 wire format 直接把字段写进输出的 buffer，
+//! 结果和 `CommandResponse::encode_frame` 兼容，客户端不需要任何改动。
+//!
+//! 代价是：不会压缩，也不会经过 Service 的 hook。`examples/bench_response.rs`
+//! 对比了两种方式的性能。
+
+use bytes::{BufMut, BytesMut};
+use prost::encoding::{encode_key, encode_varint, encoded_len_varint, message, WireType};
+
+use super::frame::{LEN_LEN, MAX_MESSAGE};
+use crate::{KvError, Value, PROTOCOL_VERSION};
+
+/// 直接写在输出 buffer 里的一个 response frame
+pub struct ResponseFrame<'a> {
+    buf: &'a mut BytesMut,
+    start: usize,
+}
+
+impl<'a> ResponseFrame<'a> {
+    /// 在 buf 的末尾开始一个新的 frame
+    pub fn new(buf: &'a mut BytesMut, status: u32) -> Self {
+        let start = buf.len();
+        // 先占住 header 的位置，finish 的时候再写入长度
+        buf.put_u32(0);
+        if status != 0 {
+            encode_key(1, WireType::Varint, buf);
+            encode_varint(status as u64, buf);
+        }
+        encode_key(100, WireType::Varint, buf);
+        encode_varint(PROTOCOL_VERSION as u64, buf);
+        Self { buf, start }
+    }
+
+    pub fn message(&mut self, msg: &str) -> &mut Self {
+        if !msg.is_empty() {
+            put_bytes(2, msg.as_bytes(), self.buf);
+        }
+        self
+    }
+
+    pub fn value(&mut self, value: &Value) -> &mut Self {
+        message::encode(3, value, self.buf);
+        self
+    }
+
+    pub fn pair(&mut self, key: &str, value: &Value) -> &mut Self {
+        // Kvpair 是嵌套的消息，需要先算出它的长度
+        let mut len = message::encoded_len(2, value);
+        if !key.is_empty() {
+            len += 1 + encoded_len_varint(key.len() as u64) + key.len();
+        }
+
+        encode_key(4, WireType::LengthDelimited, self.buf);
+        encode_varint(len as u64, self.buf);
+        if !key.is_empty() {
+            put_bytes(1, key.as_bytes(), self.buf);
+        }
+        message::encode(2, value, self.buf);
+        self
+    }
+
+    /// 写入 frame 的长度。超出消息大小限制的话丢掉这个 frame 并报错
+    pub fn finish(self) -> Result<(), KvError> {
+        let len = self.buf.len() - self.start - LEN_LEN;
+        if len > MAX_MESSAGE {
+            self.buf.truncate(self.start);
+            return Err(KvError::FrameError);
+        }
+        self.buf[self.start..self.start + LEN_LEN].copy_from_slice(&(len as u32).to_be_bytes());
+        Ok(())
+    }
+}
+
+fn put_bytes(tag: u32, data: &[u8], buf: &mut BytesMut) {
+    encode_key(tag, WireType::LengthDelimited, buf);
+    encode_varint(data.len() as u64, buf);
+    buf.put_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandResponse, FrameCoder, Kvpair};
+
+    #[test]
+    fn response_frame_should_match_command_response() {
+        let values: Vec<Value> = vec!["hello".into(), Value::default(), 42.into()];
+        let pairs = vec![Kvpair::new("k1", "v1".into()), Kvpair::new("", 1.5.into())];
+
+        let mut buf = BytesMut::new();
+        let mut frame = ResponseFrame::new(&mut buf, 200);
+        frame.message("ok");
+        for v in &values {
+            frame.value(v);
+        }
+        for p in &pairs {
+            frame.pair(&p.key, p.value.as_ref().unwrap());
+        }
+        frame.finish().unwrap();
+
+        let res = CommandResponse::decode_frame(&mut buf).unwrap();
+        assert_eq!(
+            res,
+            CommandResponse {
+                status: 200,
+                message: "ok".into(),
+                values,
+                pairs,
+                version: PROTOCOL_VERSION,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn response_frame_can_be_appended() {
+        let mut buf = BytesMut::new();
+        ResponseFrame::new(&mut buf, 404).finish().unwrap();
+        let mut frame = ResponseFrame::new(&mut buf, 200);
+        frame.value(&"v".into());
+        frame.finish().unwrap();
+
+        assert_eq!(CommandResponse::decode_frame(&mut buf).unwrap().status, 404);
+        let res = CommandResponse::decode_frame(&mut buf).unwrap();
+        assert_eq!(res.values, vec!["v".into()]);
+        assert!(buf.is_empty());
+    }
+}
@@ -1,15 +1,26 @@
 /// 来自客户端的命令请求
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
+    /// 100 之前的编号留给命令，下面是协议层面的字段
+    /// 客户端使用的协议版本；老的客户端不设置，是 0
+    #[prost(uint32, tag = "100")]
+    pub version: u32,
+    /// 扩展字段，服务器不认识的扩展会被忽略，除非它标记为 critical
+    #[prost(message, repeated, tag = "101")]
+    pub extensions: ::prost::alloc::vec::Vec<Extension>,
     #[prost(
         oneof = "command_request::RequestData",
-        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9"
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50"
     )]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
 pub mod command_request {
-    #[derive(PartialOrd, Clone, PartialEq, ::prost::Oneof)]
+    #[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum RequestData {
         #[prost(message, tag = "1")]
         Hget(super::Hget),
@@ -29,10 +40,94 @@ pub mod command_request {
         Hexist(super::Hexist),
         #[prost(message, tag = "9")]
         Hmexist(super::Hmexist),
+        #[prost(message, tag = "10")]
+        Custom(super::Custom),
+        #[prost(message, tag = "11")]
+        Hscan(super::Hscan),
+        #[prost(message, tag = "12")]
+        Admin(super::Admin),
+        #[prost(message, tag = "13")]
+        Undelete(super::Undelete),
+        #[prost(message, tag = "14")]
+        PurgeTrash(super::PurgeTrash),
+        #[prost(message, tag = "15")]
+        CloneTable(super::CloneTable),
+        #[prost(message, tag = "16")]
+        Move(super::Move),
+        #[prost(message, tag = "17")]
+        Hincrby(super::Hincrby),
+        #[prost(message, tag = "18")]
+        Hsetnx(super::Hsetnx),
+        #[prost(message, tag = "19")]
+        Hgetdel(super::Hgetdel),
+        #[prost(message, tag = "20")]
+        Hgetset(super::Hgetset),
+        #[prost(message, tag = "21")]
+        Hexpire(super::Hexpire),
+        #[prost(message, tag = "22")]
+        Hpersist(super::Hpersist),
+        #[prost(message, tag = "23")]
+        Httl(super::Httl),
+        #[prost(message, tag = "24")]
+        Hexpireat(super::Hexpireat),
+        #[prost(message, tag = "25")]
+        Hkeys(super::Hkeys),
+        #[prost(message, tag = "26")]
+        Hvals(super::Hvals),
+        #[prost(message, tag = "27")]
+        Hlen(super::Hlen),
+        #[prost(message, tag = "28")]
+        Hdroptable(super::Hdroptable),
+        #[prost(message, tag = "29")]
+        Htables(super::Htables),
+        #[prost(message, tag = "30")]
+        Hflushall(super::Hflushall),
+        #[prost(message, tag = "31")]
+        Hstats(super::Hstats),
+        #[prost(message, tag = "32")]
+        Ping(super::Ping),
+        #[prost(message, tag = "33")]
+        Info(super::Info),
+        #[prost(message, tag = "34")]
+        Subscribe(super::Subscribe),
+        #[prost(message, tag = "35")]
+        Unsubscribe(super::Unsubscribe),
+        #[prost(message, tag = "36")]
+        Publish(super::Publish),
+        #[prost(message, tag = "37")]
+        Multi(super::Multi),
+        #[prost(message, tag = "38")]
+        Exec(super::Exec),
+        #[prost(message, tag = "39")]
+        Discard(super::Discard),
+        #[prost(message, tag = "40")]
+        Watch(super::Watch),
+        #[prost(message, tag = "41")]
+        Unwatch(super::Unwatch),
+        #[prost(message, tag = "42")]
+        Hgetver(super::Hgetver),
+        #[prost(message, tag = "43")]
+        Hsetver(super::Hsetver),
+        #[prost(message, tag = "44")]
+        Batch(super::Batch),
+        #[prost(message, tag = "45")]
+        Hrange(super::Hrange),
+        #[prost(message, tag = "46")]
+        Hmeta(super::Hmeta),
+        #[prost(message, tag = "47")]
+        Hgetat(super::Hgetat),
+        #[prost(message, tag = "48")]
+        Backup(super::Backup),
+        #[prost(message, tag = "49")]
+        Restore(super::Restore),
+        #[prost(message, tag = "50")]
+        Hquery(super::Hquery),
     }
 }
 /// 服务器的响应
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandResponse {
     /// 状态码；复用 HTTP 2xx/4xx/5xx 状态码
     #[prost(uint32, tag = "1")]
@@ -46,23 +141,209 @@ pub struct CommandResponse {
     /// 成功返回的 kv pairs
     #[prost(message, repeated, tag = "4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// HSCAN 返回的下一个 cursor，0 表示遍历结束
+    #[prost(uint64, tag = "5")]
+    pub cursor: u64,
+    /// 服务器推送的 PUBLISH 消息所属的订阅 id，普通的 response 是 0
+    #[prost(uint64, tag = "6")]
+    pub subscription: u64,
+    /// EXEC 返回的每个命令的 response
+    #[prost(message, repeated, tag = "7")]
+    pub responses: ::prost::alloc::vec::Vec<CommandResponse>,
+    /// 分成多个 response 发送时，除了最后一个都是 true
+    #[prost(bool, tag = "8")]
+    pub more: bool,
+    /// 服务器的协议版本
+    #[prost(uint32, tag = "100")]
+    pub version: u32,
+    /// 扩展字段
+    #[prost(message, repeated, tag = "101")]
+    pub extensions: ::prost::alloc::vec::Vec<Extension>,
+}
+/// 协议扩展，新的功能可以先以扩展的形式出现，不需要客户端和服务器同时升级
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Extension {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub data: ::prost::bytes::Bytes,
+    /// 为 true 时，不认识这个扩展的一方必须拒绝整个消息
+    #[prost(bool, tag = "3")]
+    pub critical: bool,
 }
 /// 从 table 中获取一个 key，返回 value
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hget {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
     #[prost(string, tag = "2")]
     pub key: ::prost::alloc::string::String,
 }
-/// 从 table 中获取所有的 Kvpair
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+/// 从 table 中获取所有的 Kvpair，pattern 不为空时只返回 key 和它匹配的，
+/// 比如 user:*:profile
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hgetall {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub pattern: ::prost::alloc::string::String,
+    /// 只返回 value 满足条件的 kv pair
+    #[prost(message, optional, tag = "3")]
+    pub filter: ::core::option::Option<ValueFilter>,
+    /// 大于 0 时 pairs 分成多个 response 发送，每个最多 chunk_size 个
+    #[prost(uint32, tag = "4")]
+    pub chunk_size: u32,
+    /// 0 不保证顺序，大于 0 按 key 升序，小于 0 按 key 降序
+    #[prost(sint32, tag = "5")]
+    pub order: i32,
+}
+/// value 的过滤条件，设置了的条件都要满足
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueFilter {
+    /// string 或者 binary 类型的 value 以它开头
+    #[prost(string, tag = "1")]
+    pub prefix: ::prost::alloc::string::String,
+    /// string 或者 binary 类型的 value 包含它
+    #[prost(string, tag = "2")]
+    pub contains: ::prost::alloc::string::String,
+    /// value 的类型：string、binary、integer、float 或者 bool，空字符串表示不限
+    #[prost(string, tag = "3")]
+    pub kind: ::prost::alloc::string::String,
+}
+/// 从 table 中获取所有的 key，放在 values 里返回
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hkeys {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
 }
+/// 从 table 中获取所有的 value
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hvals {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 返回 table 里 key 的数量
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hlen {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 删除整个 table，返回删除的 key 的数量
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hdroptable {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 返回所有 table 的名字
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Htables {}
+/// 删除所有 table，返回删除的 key 的数量。服务器缺省不允许，返回 403
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hflushall {}
+/// 每个 table 的 key 的数量和大小，以及它们的总和。
+/// 返回 <table>.keys、<table>.bytes，然后是 keys、bytes，最后是 backend 自己的统计，
+/// 比如 sled.size_on_disk
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hstats {}
+/// 探测连接是否可用，测量 RTT，不访问存储。原样返回 payload，payload 为空时返回 PONG
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Ping {
+    #[prost(string, tag = "1")]
+    pub payload: ::prost::alloc::string::String,
+}
+/// 服务器的版本、运行时间、存储 backend、连接数和命令计数，
+/// 以及 storage. 开头的存储的读写计数、命中率和 backend 自己的统计
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Info {}
+/// 订阅 topic，返回订阅 id；之后发布到这个 topic 的消息由服务器推送过来
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Subscribe {
+    #[prost(string, tag = "1")]
+    pub topic: ::prost::alloc::string::String,
+}
+/// 取消这个连接上的订阅，id 为 0 时取消 topic 上所有的订阅，返回取消的个数
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Unsubscribe {
+    #[prost(string, tag = "1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "2")]
+    pub id: u64,
+}
+/// 向 topic 发布一条消息，返回收到的订阅者个数
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Publish {
+    #[prost(string, tag = "1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub data: ::core::option::Option<Value>,
+}
+/// 开始一个事务，之后的命令先排队，直到 EXEC 或者 DISCARD
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Multi {}
+/// 依次执行排队的命令，期间不会有别的命令插进来，responses 里是每个命令的结果。
+/// 和 Redis 一样，某个命令失败不会回滚前面已经执行的命令
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Exec {}
+/// 丢弃排队的命令
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Discard {}
+/// 在 MULTI 之前 watch 一组 key，之后只要有一个被改动过，EXEC 就返回 409 不执行
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Watch {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag = "2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 取消这个连接上所有的 watch，EXEC 和 DISCARD 之后也会自动取消
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Unwatch {}
 /// 从 table 中获取一组 key，返回它们的 value
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmget {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -70,14 +351,18 @@ pub struct Hmget {
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
 /// 返回的值
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
     #[prost(oneof = "value::Value", tags = "1, 2, 3, 4, 5")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
 pub mod value {
-    #[derive(PartialOrd, Clone, PartialEq, ::prost::Oneof)]
+    #[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum Value {
         #[prost(string, tag = "1")]
         String(::prost::alloc::string::String),
@@ -92,7 +377,9 @@ pub mod value {
     }
 }
 /// 返回的 kvpair
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Kvpair {
     #[prost(string, tag = "1")]
     pub key: ::prost::alloc::string::String,
@@ -101,7 +388,9 @@ pub struct Kvpair {
 }
 /// 往 table 里存一个 kvpair，
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hset {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -110,7 +399,9 @@ pub struct Hset {
 }
 /// 往 table 中存一组 kvpair，
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmset {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -118,7 +409,9 @@ pub struct Hmset {
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
 }
 /// 从 table 中删除一个 key，返回它之前的值
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hdel {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -126,7 +419,9 @@ pub struct Hdel {
     pub key: ::prost::alloc::string::String,
 }
 /// 从 table 中删除一组 key，返回它们之前的值
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmdel {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -134,7 +429,9 @@ pub struct Hmdel {
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
 /// 查看 key 是否存在
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hexist {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -142,10 +439,286 @@ pub struct Hexist {
     pub key: ::prost::alloc::string::String,
 }
 /// 查看一组 key 是否存在
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmexist {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
     #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 调用插件注册的自定义命令
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Custom {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub args: ::prost::alloc::vec::Vec<Value>,
+}
+/// 分批遍历 table，cursor 为 0 时开始新的遍历，之后用返回的 cursor 继续
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hscan {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "2")]
+    pub cursor: u64,
+    /// 每批最多返回多少个 kv pair，0 表示使用缺省值
+    #[prost(uint32, tag = "3")]
+    pub count: u32,
+    /// 和 HGETALL 一样的 glob 模式，只在开始遍历时使用
+    #[prost(string, tag = "4")]
+    pub pattern: ::prost::alloc::string::String,
+    /// 小于 0 时按 key 降序遍历，否则升序。和 pattern 一样只在开始遍历时使用
+    #[prost(sint32, tag = "5")]
+    pub order: i32,
+}
+/// 交给存储 backend 处理的管理命令，比如查看 backend 内部的状态
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Admin {
+    #[prost(string, tag = "1")]
+    pub command: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub args: ::prost::alloc::vec::Vec<Value>,
+}
+/// 从回收站里恢复被删除的 key
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Undelete {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 清理 table 的回收站，all 为 false 时只清理超过保留期的
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PurgeTrash {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bool, tag = "2")]
+    pub all: bool,
+}
+/// 把 src 的当前内容复制到一个新的 table dst，dst 必须是空的
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CloneTable {
+    #[prost(string, tag = "1")]
+    pub src: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub dst: ::prost::alloc::string::String,
+}
+/// 把 key 原子地移到另一个 table，目标已经存在时失败，除非 force 为 true
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Move {
+    #[prost(string, tag = "1")]
+    pub src_table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub dst_table: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(bool, tag = "4")]
+    pub force: bool,
+}
+/// 把 key 的整数 value 原子地加上 delta，key 不存在时从 0 开始，返回新的值
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrby {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag = "3")]
+    pub delta: i64,
+}
+/// key 不存在时才写入，返回是否写入了
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetnx {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub pair: ::core::option::Option<Kvpair>,
+}
+/// 依次执行一组命令，responses 里是每个命令的结果。和 EXEC 不同，
+/// 命令之间可以插进别的连接的命令，只是省掉了逐个发送的 frame 和系统调用
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Batch {
+    #[prost(message, repeated, tag = "1")]
+    pub requests: ::prost::alloc::vec::Vec<CommandRequest>,
+}
+/// 按 key 的顺序返回 [start, end) 里的 kv pair。start 或 end 为空表示那一端没有边界，
+/// limit 为 0 表示不限制个数
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hrange {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub start: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub end: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "4")]
+    pub limit: u32,
+}
+/// 返回 key 的元数据：created_at、updated_at 和 updates，key 不存在时返回 404。
+/// backend 没有记录元数据时返回 501
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hmeta {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 返回 key 的第 version 个版本的 value，这个版本没有保留下来时返回 404。
+/// version 为 0 时返回所有保留下来的版本，新的在前，pair 的 key 是版本号
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetat {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "3")]
+    pub version: u64,
+}
+/// 把所有 table 备份到服务器备份目录下的 name 文件里，返回 key 的数量。
+/// name 不能包含路径；服务器没有设置备份目录时返回 403。备份期间照常处理别的命令
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Backup {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+}
+/// 从服务器备份目录下的 name 文件恢复，文件损坏时返回 500 且不写入任何 key。
+/// 已经存在的 key 被覆盖，返回 key 的数量
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Restore {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+}
+/// 用 table 上名为 index 的二级索引找出索引的值等于 value 的 kv pair，最多 limit 个，
+/// 0 表示不限制。值都按文本比较；没有这个索引时返回 404，backend 不支持索引时返回 501
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hquery {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub index: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub value: ::core::option::Option<Value>,
+    #[prost(uint32, tag = "4")]
+    pub limit: u32,
+}
+/// 返回 key 的 value 和 version，key 不存在时返回 404
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetver {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// key 当前的 version 等于 version 时才写入，返回新的 version，不相等时返回 409。
+/// version 为 0 表示 key 必须不存在
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetver {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub pair: ::core::option::Option<Kvpair>,
+    #[prost(uint64, tag = "3")]
+    pub version: u64,
+}
+/// 原子地取出并删除一个 key，key 不存在时返回 404
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetdel {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 原子地写入新的 value 并返回旧的 value。和 HSET 不同，key 原来不存在时
+/// values 是空的，而不是一个空的 value，客户端可以区分这两种情况
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetset {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub pair: ::core::option::Option<Kvpair>,
+}
+/// 设置 key 在 ttl_ms 毫秒之后过期，返回 key 是否存在。再次 HSET 会清除过期时间
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexpire {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "3")]
+    pub ttl_ms: u64,
+}
+/// 和 HEXPIRE 一样，但是过期时间是 UNIX 时间的毫秒数，已经过去的时间会立即删除 key
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexpireat {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag = "3")]
+    pub at_ms: i64,
+}
+/// 清除 key 的过期时间，返回 key 原来是否有过期时间
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hpersist {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 返回 key 还有多少毫秒过期，没有过期时间的话返回 -1，key 不存在返回 404
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Httl {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+}
@@ -6,93 +6,565 @@ use bytes::Bytes;
 use http::StatusCode;
 use prost::Message;
 
-use crate::KvError;
+use crate::{KvError, Order, FLUSH_EXTENSION};
 use abi::{command_request::RequestData, *};
 
+/// 当前的协议版本，增加命令或者协议层面的字段时加一
+pub const PROTOCOL_VERSION: u32 = 34;
+
+impl From<RequestData> for CommandRequest {
+    fn from(data: RequestData) -> Self {
+        Self {
+            request_data: Some(data),
+            version: PROTOCOL_VERSION,
+            extensions: vec![],
+        }
+    }
+}
+
 impl CommandRequest {
+    /// 附加一个扩展
+    pub fn with_extension(mut self, ext: Extension) -> Self {
+        self.extensions.push(ext);
+        self
+    }
+
+    /// 写命令成功之后先把数据写到磁盘再返回。扩展是 critical 的，
+    /// 不支持它的老服务器会拒绝命令，而不是在没有写盘的情况下返回成功
+    pub fn with_flush(self) -> Self {
+        self.with_extension(Extension::new(FLUSH_EXTENSION, Bytes::new(), true))
+    }
+
     /// 创建 HSET 命令
     pub fn new_hset(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
-        Self {
-            request_data: Some(RequestData::Hset(Hset {
-                table: table.into(),
-                pair: Some(Kvpair::new(key, value)),
-            })),
-        }
+        RequestData::Hset(Hset {
+            table: table.into(),
+            pair: Some(Kvpair::new(key, value)),
+        })
+        .into()
     }
 
     /// 创建HGETALL命令
     pub fn new_hgetall(table: impl Into<String>) -> Self {
-        Self {
-            request_data: Some(RequestData::Hgetall(Hgetall {
-                table: table.into(),
-            })),
-        }
+        Self::new_hgetall_matching(table, "")
+    }
+
+    /// 创建只返回 key 和 pattern 匹配的 HGETALL 命令
+    pub fn new_hgetall_matching(table: impl Into<String>, pattern: impl Into<String>) -> Self {
+        RequestData::Hgetall(Hgetall {
+            table: table.into(),
+            pattern: pattern.into(),
+            filter: None,
+            chunk_size: 0,
+            order: 0,
+        })
+        .into()
+    }
+
+    /// 创建按 order 的顺序返回 kv pair 的 HGETALL 命令
+    pub fn new_hgetall_ordered(table: impl Into<String>, order: Order) -> Self {
+        RequestData::Hgetall(Hgetall {
+            table: table.into(),
+            order: order.sign(),
+            ..Default::default()
+        })
+        .into()
+    }
+
+    /// 创建分成多个 response 返回的 HGETALL 命令，每个最多 chunk_size 个 kv pair
+    pub fn new_hgetall_chunked(table: impl Into<String>, chunk_size: u32) -> Self {
+        RequestData::Hgetall(Hgetall {
+            table: table.into(),
+            chunk_size,
+            ..Default::default()
+        })
+        .into()
+    }
+
+    /// 创建只返回 value 满足 filter 的 HGETALL 命令
+    pub fn new_hgetall_filtered(table: impl Into<String>, filter: ValueFilter) -> Self {
+        RequestData::Hgetall(Hgetall {
+            table: table.into(),
+            pattern: String::new(),
+            filter: Some(filter),
+            chunk_size: 0,
+            order: 0,
+        })
+        .into()
     }
 
     /// 创建HGET命令
     pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
-        Self {
-            request_data: Some(RequestData::Hget(Hget {
-                table: table.into(),
-                key: key.into(),
-            })),
-        }
+        RequestData::Hget(Hget {
+            table: table.into(),
+            key: key.into(),
+        })
+        .into()
     }
 
     pub fn new_mgetall(table: impl Into<String>) -> Self {
-        Self {
-            request_data: Some(RequestData::Hgetall(Hgetall {
-                table: table.into(),
-            }))
-        }
+        Self::new_hgetall(table)
+    }
+
+    pub fn new_hmget(table: impl Into<String>, keys: Vec<String>) -> Self {
+        RequestData::Hmget(Hmget {
+            table: table.into(),
+            keys,
+        })
+        .into()
     }
 
     pub fn new_hmset(table: impl Into<String>, pairs: Vec<Kvpair>) -> Self {
-        Self {
-            request_data: Some(RequestData::Hmset(Hmset {
-                table: table.into(),
-                pairs,
-            }))
-        }
+        RequestData::Hmset(Hmset {
+            table: table.into(),
+            pairs,
+        })
+        .into()
     }
 
     pub fn new_hdel(table: impl Into<String>, key: impl Into<String>) -> Self {
-        Self {
-            request_data: Some(RequestData::Hdel(Hdel {
-               table: table.into(),
-                key: key.into(),
-            }))
-        }
+        RequestData::Hdel(Hdel {
+            table: table.into(),
+            key: key.into(),
+        })
+        .into()
     }
 
     pub fn new_hmdel(table: impl Into<String>, keys: Vec<String>) -> Self {
-        Self {
-            request_data: Some(RequestData::Hmdel(Hmdel {
-                table: table.into(),
-                keys,
-            })),
-        }
+        RequestData::Hmdel(Hmdel {
+            table: table.into(),
+            keys,
+        })
+        .into()
     }
 
     pub fn new_hexist(table: impl Into<String>, key: impl Into<String>) -> Self {
-        Self {
-            request_data: Some(RequestData::Hexist(Hexist {
-                table: table.into(),
-                key: key.into(),
-            })),
-        }
+        RequestData::Hexist(Hexist {
+            table: table.into(),
+            key: key.into(),
+        })
+        .into()
     }
 
     pub fn new_hmexist(table: impl Into<String>, keys: Vec<String>) -> Self {
+        RequestData::Hmexist(Hmexist {
+            table: table.into(),
+            keys,
+        })
+        .into()
+    }
+
+    /// 创建HSCAN命令，cursor 为 0 时开始新的遍历
+    pub fn new_hscan(table: impl Into<String>, cursor: u64, count: u32) -> Self {
+        Self::new_hscan_matching(table, cursor, count, "")
+    }
+
+    /// 创建只返回 key 和 pattern 匹配的 HSCAN 命令
+    pub fn new_hscan_matching(
+        table: impl Into<String>,
+        cursor: u64,
+        count: u32,
+        pattern: impl Into<String>,
+    ) -> Self {
+        RequestData::Hscan(Hscan {
+            table: table.into(),
+            cursor,
+            count,
+            pattern: pattern.into(),
+            order: 0,
+        })
+        .into()
+    }
+
+    /// 创建按 order 的顺序遍历的 HSCAN 命令
+    pub fn new_hscan_ordered(
+        table: impl Into<String>,
+        cursor: u64,
+        count: u32,
+        order: Order,
+    ) -> Self {
+        RequestData::Hscan(Hscan {
+            table: table.into(),
+            cursor,
+            count,
+            order: order.sign(),
+            ..Default::default()
+        })
+        .into()
+    }
+
+    /// 创建由存储 backend 处理的 ADMIN 命令
+    pub fn new_admin(command: impl Into<String>, args: Vec<Value>) -> Self {
+        RequestData::Admin(Admin {
+            command: command.into(),
+            args,
+        })
+        .into()
+    }
+
+    /// 创建 UNDELETE 命令
+    pub fn new_undelete(table: impl Into<String>, key: impl Into<String>) -> Self {
+        RequestData::Undelete(Undelete {
+            table: table.into(),
+            key: key.into(),
+        })
+        .into()
+    }
+
+    /// 创建 PURGETRASH 命令
+    pub fn new_purge_trash(table: impl Into<String>, all: bool) -> Self {
+        RequestData::PurgeTrash(PurgeTrash {
+            table: table.into(),
+            all,
+        })
+        .into()
+    }
+
+    /// 创建 CLONETABLE 命令
+    pub fn new_clone_table(src: impl Into<String>, dst: impl Into<String>) -> Self {
+        RequestData::CloneTable(CloneTable {
+            src: src.into(),
+            dst: dst.into(),
+        })
+        .into()
+    }
+
+    /// 创建 MOVE 命令
+    pub fn new_move(
+        src_table: impl Into<String>,
+        dst_table: impl Into<String>,
+        key: impl Into<String>,
+        force: bool,
+    ) -> Self {
+        RequestData::Move(Move {
+            src_table: src_table.into(),
+            dst_table: dst_table.into(),
+            key: key.into(),
+            force,
+        })
+        .into()
+    }
+
+    /// 创建 HINCRBY 命令
+    pub fn new_hincrby(table: impl Into<String>, key: impl Into<String>, delta: i64) -> Self {
+        RequestData::Hincrby(Hincrby {
+            table: table.into(),
+            key: key.into(),
+            delta,
+        })
+        .into()
+    }
+
+    /// 创建 HSETNX 命令
+    pub fn new_hsetnx(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+        RequestData::Hsetnx(Hsetnx {
+            table: table.into(),
+            pair: Some(Kvpair::new(key, value)),
+        })
+        .into()
+    }
+
+    /// 创建 HGETDEL 命令
+    pub fn new_hgetdel(table: impl Into<String>, key: impl Into<String>) -> Self {
+        RequestData::Hgetdel(Hgetdel {
+            table: table.into(),
+            key: key.into(),
+        })
+        .into()
+    }
+
+    /// 创建 HGETSET 命令
+    pub fn new_hgetset(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+        RequestData::Hgetset(Hgetset {
+            table: table.into(),
+            pair: Some(Kvpair::new(key, value)),
+        })
+        .into()
+    }
+
+    /// 创建 HEXPIRE 命令
+    pub fn new_hexpire(table: impl Into<String>, key: impl Into<String>, ttl_ms: u64) -> Self {
+        RequestData::Hexpire(Hexpire {
+            table: table.into(),
+            key: key.into(),
+            ttl_ms,
+        })
+        .into()
+    }
+
+    /// 创建 HEXPIREAT 命令
+    pub fn new_hexpireat(table: impl Into<String>, key: impl Into<String>, at_ms: i64) -> Self {
+        RequestData::Hexpireat(Hexpireat {
+            table: table.into(),
+            key: key.into(),
+            at_ms,
+        })
+        .into()
+    }
+
+    /// 创建 HPERSIST 命令
+    pub fn new_hpersist(table: impl Into<String>, key: impl Into<String>) -> Self {
+        RequestData::Hpersist(Hpersist {
+            table: table.into(),
+            key: key.into(),
+        })
+        .into()
+    }
+
+    /// 创建 HTTL 命令
+    pub fn new_httl(table: impl Into<String>, key: impl Into<String>) -> Self {
+        RequestData::Httl(Httl {
+            table: table.into(),
+            key: key.into(),
+        })
+        .into()
+    }
+
+    /// 创建 HKEYS 命令
+    pub fn new_hkeys(table: impl Into<String>) -> Self {
+        RequestData::Hkeys(Hkeys {
+            table: table.into(),
+        })
+        .into()
+    }
+
+    /// 创建 HVALS 命令
+    pub fn new_hvals(table: impl Into<String>) -> Self {
+        RequestData::Hvals(Hvals {
+            table: table.into(),
+        })
+        .into()
+    }
+
+    /// 创建 HLEN 命令
+    pub fn new_hlen(table: impl Into<String>) -> Self {
+        RequestData::Hlen(Hlen {
+            table: table.into(),
+        })
+        .into()
+    }
+
+    /// 创建 HDROPTABLE 命令
+    pub fn new_hdroptable(table: impl Into<String>) -> Self {
+        RequestData::Hdroptable(Hdroptable {
+            table: table.into(),
+        })
+        .into()
+    }
+
+    /// 创建 HTABLES 命令
+    pub fn new_htables() -> Self {
+        RequestData::Htables(Htables {}).into()
+    }
+
+    /// 创建 HFLUSHALL 命令，服务器需要用 allow_flushall 打开它
+    pub fn new_hflushall() -> Self {
+        RequestData::Hflushall(Hflushall {}).into()
+    }
+
+    /// 创建 HSTATS 命令
+    pub fn new_hstats() -> Self {
+        RequestData::Hstats(Hstats {}).into()
+    }
+
+    /// 创建 PING 命令
+    pub fn new_ping(payload: impl Into<String>) -> Self {
+        RequestData::Ping(Ping {
+            payload: payload.into(),
+        })
+        .into()
+    }
+
+    /// 创建 INFO 命令
+    pub fn new_info() -> Self {
+        RequestData::Info(Info {}).into()
+    }
+
+    /// 创建 SUBSCRIBE 命令
+    pub fn new_subscribe(topic: impl Into<String>) -> Self {
+        RequestData::Subscribe(Subscribe {
+            topic: topic.into(),
+        })
+        .into()
+    }
+
+    /// 创建 UNSUBSCRIBE 命令，id 为 0 时取消 topic 上所有的订阅
+    pub fn new_unsubscribe(topic: impl Into<String>, id: u64) -> Self {
+        RequestData::Unsubscribe(Unsubscribe {
+            topic: topic.into(),
+            id,
+        })
+        .into()
+    }
+
+    /// 创建 PUBLISH 命令
+    pub fn new_publish(topic: impl Into<String>, data: Value) -> Self {
+        RequestData::Publish(Publish {
+            topic: topic.into(),
+            data: Some(data),
+        })
+        .into()
+    }
+
+    /// 创建 MULTI 命令
+    pub fn new_multi() -> Self {
+        RequestData::Multi(Multi {}).into()
+    }
+
+    /// 创建 EXEC 命令
+    pub fn new_exec() -> Self {
+        RequestData::Exec(Exec {}).into()
+    }
+
+    /// 创建 DISCARD 命令
+    pub fn new_discard() -> Self {
+        RequestData::Discard(Discard {}).into()
+    }
+
+    /// 创建 WATCH 命令
+    pub fn new_watch(table: impl Into<String>, keys: Vec<String>) -> Self {
+        RequestData::Watch(Watch {
+            table: table.into(),
+            keys,
+        })
+        .into()
+    }
+
+    /// 创建 UNWATCH 命令
+    pub fn new_unwatch() -> Self {
+        RequestData::Unwatch(Unwatch {}).into()
+    }
+
+    /// 创建 HGETVER 命令
+    pub fn new_hmeta(table: impl Into<String>, key: impl Into<String>) -> Self {
+        RequestData::Hmeta(Hmeta {
+            table: table.into(),
+            key: key.into(),
+        })
+        .into()
+    }
+
+    /// 创建 HGETAT 命令，version 为 0 表示返回所有保留下来的版本
+    pub fn new_hgetat(table: impl Into<String>, key: impl Into<String>, version: u64) -> Self {
+        RequestData::Hgetat(Hgetat {
+            table: table.into(),
+            key: key.into(),
+            version,
+        })
+        .into()
+    }
+
+    /// 创建 HQUERY 命令，limit 为 0 表示不限制
+    pub fn new_hquery(
+        table: impl Into<String>,
+        index: impl Into<String>,
+        value: Value,
+        limit: u32,
+    ) -> Self {
+        RequestData::Hquery(Hquery {
+            table: table.into(),
+            index: index.into(),
+            value: Some(value),
+            limit,
+        })
+        .into()
+    }
+
+    /// 创建 BACKUP 命令，服务器需要用 backup_dir 设置备份目录
+    pub fn new_backup(name: impl Into<String>) -> Self {
+        RequestData::Backup(Backup { name: name.into() }).into()
+    }
+
+    /// 创建 RESTORE 命令
+    pub fn new_restore(name: impl Into<String>) -> Self {
+        RequestData::Restore(Restore { name: name.into() }).into()
+    }
+
+    pub fn new_hgetver(table: impl Into<String>, key: impl Into<String>) -> Self {
+        RequestData::Hgetver(Hgetver {
+            table: table.into(),
+            key: key.into(),
+        })
+        .into()
+    }
+
+    /// 创建 HSETVER 命令，version 为 0 表示 key 必须不存在
+    pub fn new_hsetver(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: Value,
+        version: u64,
+    ) -> Self {
+        RequestData::Hsetver(Hsetver {
+            table: table.into(),
+            pair: Some(Kvpair::new(key, value)),
+            version,
+        })
+        .into()
+    }
+
+    /// 创建 BATCH 命令
+    /// 创建 HRANGE 命令，start 或者 end 为空表示那一端没有边界
+    pub fn new_hrange(
+        table: impl Into<String>,
+        start: impl Into<String>,
+        end: impl Into<String>,
+        limit: u32,
+    ) -> Self {
+        RequestData::Hrange(Hrange {
+            table: table.into(),
+            start: start.into(),
+            end: end.into(),
+            limit,
+        })
+        .into()
+    }
+
+    pub fn new_batch(requests: Vec<CommandRequest>) -> Self {
+        RequestData::Batch(Batch { requests }).into()
+    }
+
+    /// 创建调用插件自定义命令的 CUSTOM 命令
+    pub fn new_custom(name: impl Into<String>, args: Vec<Value>) -> Self {
+        RequestData::Custom(Custom {
+            name: name.into(),
+            args,
+        })
+        .into()
+    }
+}
+
+impl Extension {
+    pub fn new(name: impl Into<String>, data: impl Into<Bytes>, critical: bool) -> Self {
         Self {
-            request_data: Some(RequestData::Hmexist(Hmexist {
-                table: table.into(),
-                keys,
-            })),
+            name: name.into(),
+            data: data.into(),
+            critical,
         }
     }
 }
 
+impl CommandResponse {
+    /// 把非 2xx 的 response 转换成错误
+    pub(crate) fn ok(self) -> Result<Self, KvError> {
+        match StatusCode::from_u16(self.status as u16) {
+            Ok(status) if status.is_success() => Ok(self),
+            _ => Err(KvError::Internal(format!(
+                "{}: {}",
+                self.status, self.message
+            ))),
+        }
+    }
+
+    /// 取出第一个 value，服务器用一个空的 Value 表示没有值
+    pub(crate) fn first_value(mut self) -> Option<Value> {
+        if self.values.is_empty() {
+            return None;
+        }
+        Some(self.values.swap_remove(0)).filter(|v| v.value.is_some())
+    }
+}
+
 impl Kvpair {
     /// 创建一个新的 kv pair
     pub fn new(key: impl Into<String>, value: Value) -> Self {
@@ -103,6 +575,32 @@ impl Kvpair {
     }
 }
 
+impl ValueFilter {
+    /// 以 prefix 开头的 string 或者 binary
+    pub fn prefix(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            ..Default::default()
+        }
+    }
+
+    /// 包含 s 的 string 或者 binary
+    pub fn contains(s: impl Into<String>) -> Self {
+        Self {
+            contains: s.into(),
+            ..Default::default()
+        }
+    }
+
+    /// kind 类型的 value：string、binary、integer、float 或者 bool
+    pub fn kind(kind: impl Into<String>) -> Self {
+        Self {
+            kind: kind.into(),
+            ..Default::default()
+        }
+    }
+}
+
 /// 从 String 转换成 Value
 impl From<String> for Value {
     fn from(s: String) -> Self {
@@ -172,13 +670,16 @@ impl From<KvError> for CommandResponse {
         let mut result = Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: e.to_string(),
-            values: vec![],
-            pairs: vec![],
+            ..Default::default()
         };
 
         match e {
             KvError::NotFound(_, _) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
             KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::Unsupported(_) => result.status = StatusCode::NOT_IMPLEMENTED.as_u16() as _,
+            KvError::Busy(_) => result.status = StatusCode::SERVICE_UNAVAILABLE.as_u16() as _,
+            KvError::Forbidden(_) => result.status = StatusCode::FORBIDDEN.as_u16() as _,
+            KvError::Conflict(_) => result.status = StatusCode::CONFLICT.as_u16() as _,
             _ => {}
         }
 
@@ -186,6 +687,17 @@ impl From<KvError> for CommandResponse {
     }
 }
 
+/// 从一组命令的 response 转换成 CommandResponse
+impl From<Vec<CommandResponse>> for CommandResponse {
+    fn from(v: Vec<CommandResponse>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            responses: v,
+            ..Default::default()
+        }
+    }
+}
+
 impl From<Vec<Value>> for CommandResponse {
     fn from(v: Vec<Value>) -> Self {
         Self {
@@ -223,6 +735,17 @@ impl TryFrom<Value> for i64 {
     }
 }
 
+impl TryFrom<Value> for String {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::String(s)) => Ok(s),
+            _ => Err(KvError::ConvertError(v, "String")),
+        }
+    }
+}
+
 impl TryFrom<Value> for f64 {
     type Error = KvError;
 
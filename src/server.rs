@@ -1,27 +1,438 @@
-use anyhow::Result;
-use kv2::{MemTable, ProstServerStream, Service, ServiceInner, TlsServerAcceptor};
+use anyhow::{anyhow, bail, Result};
+use kv2::{
+    accept_streams, client_identity, dump, load, migrate, verify, AdmissionControl, Cipher,
+    DumpFormat, DynStorage, EncryptedStore, IndexField, IndexedStore, Kvpair, MemTable,
+    MigrateCheckpoint, ProstServerStream, Service, ServiceInner, SledDb, SoftDeleteStore,
+    TlsServerAcceptor, WsStream,
+};
+use prost::Message;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use tracing::info;
+use tracing::{info, warn};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("verify") {
+        return run_verify(&args[1..]);
+    }
+    if args.first().map(String::as_str) == Some("migrate") {
+        return run_migrate(&args[1..]);
+    }
+    if let Some(cmd @ ("dump" | "load")) = args.first().map(String::as_str) {
+        return run_dump(cmd == "load", &args[1..]);
+    }
     let addr = "0.0.0.0:9527";
 
-    // 以后从配置文件取
-    let server_cert = include_str!("../fixtures/server.cert");
-    let server_key = include_str!("../fixtures/server.key");
-
-    let acceptor = TlsServerAcceptor::new(server_cert, server_key, None)?;
-    let service: Service = ServiceInner::new(MemTable::new()).into();
+    // KV_BACKEND 选择存储，缺省是 memory，也可以是 sled:<path>
+    let backend = std::env::var("KV_BACKEND").unwrap_or_else(|_| "memory".into());
+    let store = indexed(soft_delete(open_backend(&backend)?)?)?;
+    let mut inner = ServiceInner::new(store);
+    // 测试环境可以打开 HFLUSHALL，不用重启就能清空数据
+    if std::env::var_os("KV_ALLOW_FLUSHALL").is_some() {
+        inner = inner.allow_flushall();
+    }
+    // KV_BACKUP_DIR 打开 BACKUP 和 RESTORE，备份文件放在这个目录里
+    if let Some(dir) = std::env::var_os("KV_BACKUP_DIR") {
+        inner = inner.backup_dir(dir);
+    }
+    let service: Service<DynStorage> = inner.into();
+    // 读的时候会顺便删掉过期的 key，没人读的由后台定期清理
+    let _sweeper = service.spawn_expiry_sweeper(Duration::from_secs(1))?;
+    // KV_HTTP_ADDR 打开 HTTP/JSON gateway，给只会 HTTP 的内部工具用
+    if let Ok(http_addr) = std::env::var("KV_HTTP_ADDR") {
+        serve_http(&http_addr, service.clone()).await?;
+    }
+    // 最多同时执行 256 个命令，再排队 1024 个，更多的直接返回 503。管理命令和健康检查另外排队 64 个，
+    // KV_PRIORITY_IDENTITIES（逗号分隔的客户端身份）里的客户端可以用扩展把命令提到这个队列
+    let mut admission = AdmissionControl::new(256, 1024).high_queue(64);
+    let trusted = std::env::var("KV_PRIORITY_IDENTITIES").unwrap_or_default();
+    for identity in trusted.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        admission = admission.trust(identity);
+    }
+    // KV_TRANSPORT=quic 时在同一个端口上用 QUIC（UDP）代替 TCP + TLS，
+    // KV_TRANSPORT=ws 时在 TLS 上再做 WebSocket 握手，浏览器可以用 wss 直接连接
+    let websocket = match std::env::var("KV_TRANSPORT").as_deref() {
+        Ok("quic") => return serve_quic(addr, service, admission).await,
+        Ok("ws") => true,
+        Ok("tcp") | Err(_) => false,
+        Ok(other) => bail!("Unsupported transport {}, expect tcp, ws or quic", other),
+    };
+    let acceptor = tls_acceptor()?;
+    // KV_YAMUX=1 时客户端在一个连接上用 yamux 打开多个 stream，每个 stream 单独处理
+    let yamux = std::env::var("KV_YAMUX").is_ok_and(|v| v == "1");
     let listener = TcpListener::bind(addr).await?;
     info!("Start listening on {}", addr);
     loop {
         let tls = acceptor.clone();
         let (stream, addr) = listener.accept().await?;
         info!("Client {:?} connected", addr);
-        let stream = tls.accept(stream).await?;
-        let stream = ProstServerStream::new(stream, service.clone());
-        tokio::spawn(async move {stream.process().await});
+        let (service, admission) = (service.clone(), admission.clone());
+        // 握手失败（比如没有客户端证书）只断开这个连接
+        tokio::spawn(async move {
+            let stream = match tls.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("TLS handshake with {:?} failed: {:?}", addr, e);
+                    return Ok(());
+                }
+            };
+            let identity = client_identity(&stream);
+            let conn = Connection {
+                service,
+                admission,
+                identity,
+                yamux,
+            };
+            if !websocket {
+                return conn.serve(stream).await;
+            }
+            match WsStream::accept(stream).await {
+                Ok(stream) => conn.serve(stream).await,
+                Err(e) => {
+                    warn!("WebSocket handshake with {:?} failed: {:?}", addr, e);
+                    Ok(())
+                }
+            }
+        });
+    }
+}
+
+/// 握手完成之后的一个连接，不管底下是 TLS 还是 WebSocket 都一样处理
+struct Connection {
+    service: Service<DynStorage>,
+    admission: AdmissionControl,
+    identity: Option<String>,
+    yamux: bool,
+}
+
+impl Connection {
+    async fn serve<S>(self, stream: S) -> Result<(), kv2::KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let Self {
+            service,
+            admission,
+            identity,
+            yamux,
+        } = self;
+        if !yamux {
+            return ProstServerStream::new(stream, service)
+                .with_admission(admission)
+                .with_identity(identity)
+                .process()
+                .await;
+        }
+        accept_streams(stream, |stream| {
+            let stream = ProstServerStream::new(stream, service.clone())
+                .with_admission(admission.clone())
+                .with_identity(identity.clone());
+            tokio::spawn(stream.process());
+        })
+        .await
+    }
+}
+
+/// KV_TLS_CERT 和 KV_TLS_KEY 是服务器证书和私钥的 PEM 文件，
+/// 都没有设置时用 fixtures 里的测试证书。设置了 KV_TLS_CLIENT_CA 时
+/// 只接受这个 CA 签发的客户端证书，证书的 CN 作为连接的身份
+fn tls_acceptor() -> Result<TlsServerAcceptor> {
+    let (cert, key) = server_cert()?;
+    let client_ca = match std::env::var("KV_TLS_CLIENT_CA") {
+        Ok(path) => Some(read_pem(&path)?),
+        Err(_) => None,
+    };
+    Ok(TlsServerAcceptor::new(&cert, &key, client_ca.as_deref())?)
+}
+
+fn server_cert() -> Result<(String, String)> {
+    match (std::env::var("KV_TLS_CERT"), std::env::var("KV_TLS_KEY")) {
+        (Ok(cert), Ok(key)) => Ok((read_pem(&cert)?, read_pem(&key)?)),
+        (Err(_), Err(_)) => Ok((
+            include_str!("../fixtures/server.cert").to_string(),
+            include_str!("../fixtures/server.key").to_string(),
+        )),
+        _ => bail!("KV_TLS_CERT and KV_TLS_KEY must be set together"),
+    }
+}
+
+/// 客户端打开的每个 QUIC stream 用一个 ProstServerStream 处理。QUIC 还不支持客户端证书
+#[cfg(feature = "quic")]
+async fn serve_quic(
+    addr: &str,
+    service: Service<DynStorage>,
+    admission: AdmissionControl,
+) -> Result<()> {
+    if std::env::var_os("KV_TLS_CLIENT_CA").is_some() {
+        bail!("KV_TLS_CLIENT_CA is not supported with QUIC");
+    }
+    let (cert, key) = server_cert()?;
+    let server = kv2::QuicServer::bind(addr.parse()?, &cert, &key)?;
+    info!("Start listening on {} (QUIC)", addr);
+    server
+        .serve(move |stream| {
+            let stream =
+                ProstServerStream::new(stream, service.clone()).with_admission(admission.clone());
+            tokio::spawn(stream.process());
+        })
+        .await;
+    Ok(())
+}
+
+#[cfg(not(feature = "quic"))]
+async fn serve_quic(_: &str, _: Service<DynStorage>, _: AdmissionControl) -> Result<()> {
+    bail!("kvs is built without the quic feature")
+}
+
+/// gateway 是明文的 HTTP，应该只监听内网的地址。绑定成功之后在后台运行
+#[cfg(feature = "axum")]
+async fn serve_http(addr: &str, service: Service<DynStorage>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Start HTTP gateway on {}", addr);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, kv2::rest_router(service)).await {
+            warn!("HTTP gateway stopped: {:?}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(feature = "axum"))]
+async fn serve_http(_: &str, _: Service<DynStorage>) -> Result<()> {
+    bail!("kvs is built without the axum feature")
+}
+
+fn read_pem(path: &str) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|e| anyhow!("Cannot read {}: {}", path, e))
+}
+
+/// kvs verify --a sled:/x --b sled:/y --table t1 [--table t2 ...] [--repair]
+fn run_verify(args: &[String]) -> Result<()> {
+    let (mut a, mut b, mut tables, mut repair) = (None, None, Vec::new(), false);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+        match arg.as_str() {
+            "--a" => a = Some(open_backend(value()?)?),
+            "--b" => b = Some(open_backend(value()?)?),
+            "--table" => tables.push(value()?.as_str()),
+            "--repair" => repair = true,
+            _ => bail!("Unknown argument {}", arg),
+        }
+    }
+    let (a, b) = match (a, b) {
+        (Some(a), Some(b)) => (a, b),
+        _ => bail!("Usage: kvs verify --a sled:<path> --b sled:<path> --table <name> [--repair]"),
+    };
+
+    let report = verify(&a, &b, &tables, repair)?;
+    for d in &report.differences {
+        println!("{}\t{}\t{:?}", d.table, d.key, d.kind);
+    }
+    println!(
+        "checked {} keys, {} differences, {} repaired",
+        report.checked,
+        report.differences.len(),
+        report.repaired
+    );
+    if !report.is_consistent() && !repair {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// kvs migrate --from sled:/x --to sled:/y [--checkpoint <file>]
+///
+/// 指定了 checkpoint 时每复制完一批就把位置写到这个文件里，中断之后用同样的参数再运行
+/// 从那里继续，全部复制完之后删掉这个文件
+fn run_migrate(args: &[String]) -> Result<()> {
+    let (mut from, mut to, mut checkpoint) = (None, None, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+        match arg.as_str() {
+            "--from" => from = Some(open_backend(value()?)?),
+            "--to" => to = Some(open_backend(value()?)?),
+            "--checkpoint" => checkpoint = Some(Path::new(value()?)),
+            _ => bail!("Unknown argument {}", arg),
+        }
+    }
+    let (from, to) = match (from, to) {
+        (Some(from), Some(to)) => (from, to),
+        _ => bail!("Usage: kvs migrate --from sled:<path> --to sled:<path> [--checkpoint <file>]"),
+    };
+
+    let resume = match checkpoint {
+        Some(path) if path.exists() => Some(load_checkpoint(path)?),
+        _ => None,
+    };
+    if let Some(resume) = &resume {
+        info!("Resume from table {} after {:?}", resume.table, resume.key);
+    }
+    let mut saved = Ok(());
+    let keys = migrate(&from, &to, resume, |p| {
+        info!(
+            "Copied {} keys, {}/{} tables done, at table {}",
+            p.keys, p.tables_done, p.tables, p.checkpoint.table
+        );
+        if let (Some(path), Ok(())) = (checkpoint, &saved) {
+            saved = save_checkpoint(path, &p.checkpoint);
+        }
+    })?;
+    saved?;
+    to.flush()?;
+    // 源是空的时候没有写过 checkpoint
+    if let Some(path) = checkpoint.filter(|p| p.exists()) {
+        std::fs::remove_file(path)?;
+    }
+    println!("migrated {} keys", keys);
+    Ok(())
+}
+
+// checkpoint 文件是一个 Kvpair：key 是 table，value 是 table 里最后复制完的 key
+fn load_checkpoint(path: &Path) -> Result<MigrateCheckpoint> {
+    let pair = Kvpair::decode(std::fs::read(path)?.as_slice())?;
+    let key = pair.value.map(String::try_from).transpose()?;
+    Ok(MigrateCheckpoint {
+        table: pair.key,
+        key,
+    })
+}
+
+fn save_checkpoint(path: &Path, checkpoint: &MigrateCheckpoint) -> Result<()> {
+    let value = checkpoint.key.clone().map(Into::into);
+    let pair = Kvpair {
+        key: checkpoint.table.clone(),
+        value,
+    };
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, pair.encode_to_vec())?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// kvs dump --backend sled:/x [--table t1 ...] [--format json|csv] [--file <path>]
+/// kvs load --backend sled:/x [--format json|csv] [--file <path>]
+///
+/// dump 缺省导出所有 table 到 stdout，load 缺省从 stdin 读
+fn run_dump(loading: bool, args: &[String]) -> Result<()> {
+    let (mut backend, mut tables, mut format, mut file) = (None, Vec::new(), None, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+        match arg.as_str() {
+            "--backend" => backend = Some(open_backend(value()?)?),
+            "--table" if !loading => tables.push(value()?.as_str()),
+            "--format" => format = Some(value()?.parse::<DumpFormat>()?),
+            "--file" => file = Some(value()?),
+            _ => bail!("Unknown argument {}", arg),
+        }
+    }
+    let backend = match backend {
+        Some(backend) => backend,
+        None => {
+            bail!("Usage: kvs dump|load --backend sled:<path> [--format json|csv] [--file <path>]")
+        }
+    };
+    // 没有指定格式时看文件的扩展名
+    let format = format.unwrap_or_else(|| match file {
+        Some(f) if f.ends_with(".csv") => DumpFormat::Csv,
+        _ => DumpFormat::JsonLines,
+    });
+
+    if loading {
+        let n = match file {
+            Some(f) => load(&backend, format, &mut BufReader::new(File::open(f)?))?,
+            None => load(&backend, format, &mut io::stdin().lock())?,
+        };
+        backend.flush()?;
+        eprintln!("loaded {} keys", n);
+    } else {
+        let n = match file {
+            Some(f) => dump(
+                &backend,
+                &tables,
+                format,
+                &mut BufWriter::new(File::create(f)?),
+            )?,
+            None => dump(&backend, &tables, format, &mut io::stdout().lock())?,
+        };
+        eprintln!("dumped {} keys", n);
+    }
+    Ok(())
+}
+
+/// KV_SOFT_DELETE 是逗号分隔的 table，这些 table 里删除的 key 先放进回收站，
+/// 可以用 UNDELETE 恢复。KV_TRASH_RETENTION 是保留的秒数，缺省一天，之后由 expiry sweeper 回收
+fn soft_delete(backend: DynStorage) -> Result<DynStorage> {
+    let tables = match std::env::var("KV_SOFT_DELETE") {
+        Ok(tables) => tables,
+        Err(_) => return Ok(backend),
+    };
+    let retention = match std::env::var("KV_TRASH_RETENTION") {
+        Ok(secs) => secs.parse()?,
+        Err(_) => 86400,
+    };
+    let tables = tables.split(',').filter(|t| !t.is_empty());
+    let store = SoftDeleteStore::new(backend, tables, Duration::from_secs(retention));
+    Ok(Box::new(store))
+}
+
+/// KV_INDEXES 是逗号分隔的 `table:name=field`，field 是 value 或者 `$.a.b` 这样的 JSON 字段，
+/// 比如 `users:by_city=$.address.city`。启动时用现有的数据重建索引
+fn indexed(backend: DynStorage) -> Result<DynStorage> {
+    let specs = match std::env::var("KV_INDEXES") {
+        Ok(specs) => specs,
+        Err(_) => return Ok(backend),
+    };
+    let mut store = IndexedStore::new(backend);
+    for spec in specs.split(',').filter(|s| !s.is_empty()) {
+        let (table, name, field) = spec
+            .split_once(':')
+            .and_then(|(table, rest)| Some((table, rest.split_once('=')?)))
+            .map(|(table, (name, field))| (table, name, field))
+            .ok_or_else(|| anyhow!("Bad index {}, expect table:name=field", spec))?;
+        store = store.with_index(table, name, field.parse::<IndexField>()?);
+    }
+    let n = store.rebuild()?;
+    info!("Indexed {} keys", n);
+    Ok(Box::new(store))
+}
+
+/// 设置了 KV_ENCRYPTION_KEY（64 个十六进制字符）时，value 加密之后才写入 backend。
+/// KV_SLED_BLOOM=1 时 sled 给每个 table 维护 bloom filter
+fn open_backend(spec: &str) -> Result<DynStorage> {
+    let bloom = std::env::var("KV_SLED_BLOOM").is_ok_and(|v| v == "1");
+    let backend: DynStorage = match spec.split_once(':') {
+        _ if spec == "memory" => Box::new(MemTable::new()),
+        Some(("sled", path)) => Box::new(SledDb::builder(path).bloom(bloom).open()?),
+        _ => bail!("Unsupported backend {}, expect memory or sled:<path>", spec),
+    };
+    match std::env::var("KV_ENCRYPTION_KEY") {
+        Ok(key) => {
+            let key = decode_hex(&key).ok_or_else(|| anyhow!("KV_ENCRYPTION_KEY is not hex"))?;
+            Ok(Box::new(EncryptedStore::new(
+                backend,
+                Cipher::Aes256Gcm,
+                &key,
+            )?))
+        }
+        Err(_) => Ok(backend),
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
     }
-}
\ No newline at end of file
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+use crate::command_request::RequestData;
+use crate::{CommandRequest, KvError};
+
+/// 客户端可以用这个扩展指定命令的优先级，data 是一个字节，见 Priority::from_byte。
+/// 只有 AdmissionControl::trust 过的身份可以要求 High，别的客户端要求 High 时按 Normal 处理
+pub const PRIORITY_EXTENSION: &str = "priority";
+
+/// 命令的优先级
+///
+/// 排队的时候高优先级的命令先执行，High 的命令有自己的队列，不和别的命令抢位置，
+/// 这样服务器被批量的遍历压满的时候，管理命令和健康检查依旧可以完成。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High = 0,
+    Normal = 1,
+    Low = 2,
+}
+
+impl Priority {
+    pub fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(Priority::High),
+            1 => Some(Priority::Normal),
+            2 => Some(Priority::Low),
+            _ => None,
+        }
+    }
+}
+
+impl Priority {
+    /// 命令的优先级。trusted 为 false 时扩展里的 High 当作 Normal，
+    /// 不然任何客户端都可以让自己的命令绕过普通的队列
+    pub fn of(cmd: &CommandRequest, trusted: bool) -> Self {
+        let tagged = cmd
+            .extensions
+            .iter()
+            .find(|ext| ext.name == PRIORITY_EXTENSION)
+            .and_then(|ext| ext.data.first().copied().and_then(Priority::from_byte));
+        match tagged {
+            Some(Priority::High) if !trusted => return Priority::Normal,
+            Some(priority) => return priority,
+            None => {}
+        }
+
+        match &cmd.request_data {
+            Some(RequestData::Admin(_) | RequestData::Ping(_) | RequestData::Info(_)) => {
+                Priority::High
+            }
+            Some(RequestData::Hgetall(_) | RequestData::Hscan(_)) => Priority::Low,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+impl From<&CommandRequest> for Priority {
+    /// 没有信任的身份时的优先级
+    fn from(cmd: &CommandRequest) -> Self {
+        Priority::of(cmd, false)
+    }
+}
+
+/// 服务器级别的准入控制
+///
+/// 同时最多执行 concurrency 个命令，超出的 Normal 和 Low 最多 queue 个排队等待，
+/// High 另外最多 high_queue 个，再多的直接返回 KvError::Busy。过载的时候让一部分请求快速失败，
+/// 而不是让所有请求的延迟一起上涨直到超时。所有连接共享同一个 AdmissionControl。
+/// 每个 Priority 有自己的队列，有空位时先唤醒优先级高的。
+#[derive(Clone)]
+pub struct AdmissionControl {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    concurrency: usize,
+    queue: usize,
+    high_queue: usize,
+    // 可以用扩展要求 High 的客户端身份
+    trusted: Vec<String>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    // 排队的请求，轮到它时通过 channel 把 Permit 交给它。如果它在收到之前放弃了，
+    // channel 里的 Permit 会随之 drop，位置也就让给了下一个
+    waiters: [VecDeque<oneshot::Sender<Permit>>; 3],
+}
+
+impl State {
+    fn queued(&mut self) -> usize {
+        self.queued_in(&[Priority::High, Priority::Normal, Priority::Low])
+    }
+
+    // 已经放弃等待的请求不占队列的位置
+    fn queued_in(&mut self, priorities: &[Priority]) -> usize {
+        priorities
+            .iter()
+            .map(|&p| {
+                let q = &mut self.waiters[p as usize];
+                q.retain(|w| !w.is_closed());
+                q.len()
+            })
+            .sum()
+    }
+}
+
+/// 执行一个命令的许可，drop 的时候把位置让给下一个排队的请求
+pub struct Permit {
+    // 交接失败的 Permit 是 None，drop 时什么也不做
+    inner: Option<Arc<Inner>>,
+}
+
+impl AdmissionControl {
+    /// High 的队列缺省和 queue 一样长，用 high_queue 调整
+    pub fn new(concurrency: usize, queue: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                concurrency: concurrency.max(1),
+                queue,
+                high_queue: queue,
+                trusted: Vec::new(),
+                state: Mutex::new(State::default()),
+            }),
+        }
+    }
+
+    /// High 的命令最多排队的个数。要在 clone 给各个连接之前调用
+    pub fn high_queue(mut self, n: usize) -> Self {
+        self.inner_mut().high_queue = n;
+        self
+    }
+
+    /// 允许这个客户端身份（见 client_identity）用扩展要求 High。要在 clone 给各个连接之前调用
+    pub fn trust(mut self, identity: impl Into<String>) -> Self {
+        self.inner_mut().trusted.push(identity.into());
+        self
+    }
+
+    /// 连接上的命令的优先级，identity 是连接的客户端身份
+    pub fn priority(&self, cmd: &CommandRequest, identity: Option<&str>) -> Priority {
+        let trusted = identity.is_some_and(|id| self.inner.trusted.iter().any(|t| t == id));
+        Priority::of(cmd, trusted)
+    }
+
+    fn inner_mut(&mut self) -> &mut Inner {
+        Arc::get_mut(&mut self.inner).expect("admission control is already shared")
+    }
+
+    /// 等待执行的许可，队列满了立刻返回 Busy
+    pub async fn admit(&self, priority: Priority) -> Result<Permit, KvError> {
+        let rx = {
+            let mut state = self.inner.state.lock().unwrap();
+            if state.running < self.inner.concurrency {
+                state.running += 1;
+                return Ok(self.permit());
+            }
+            let (queued, limit) = match priority {
+                Priority::High => (state.queued_in(&[Priority::High]), self.inner.high_queue),
+                _ => (
+                    state.queued_in(&[Priority::Normal, Priority::Low]),
+                    self.inner.queue,
+                ),
+            };
+            if queued >= limit {
+                return Err(KvError::Busy("too many requests".into()));
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiters[priority as usize].push_back(tx);
+            rx
+        };
+
+        // 前一个 Permit drop 时把它的位置直接交给我们，running 不变
+        rx.await
+            .map_err(|_| KvError::Internal("admission control is closed".into()))
+    }
+
+    /// 正在执行的命令数
+    pub fn running(&self) -> usize {
+        self.inner.state.lock().unwrap().running
+    }
+
+    /// 排队等待的命令数
+    pub fn queued(&self) -> usize {
+        self.inner.state.lock().unwrap().queued()
+    }
+
+    fn permit(&self) -> Permit {
+        Permit {
+            inner: Some(self.inner.clone()),
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let inner = match self.inner.take() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let mut state = inner.state.lock().unwrap();
+        while let Some(waiter) = state.waiters.iter_mut().find_map(|q| q.pop_front()) {
+            let permit = Permit {
+                inner: Some(inner.clone()),
+            };
+            match waiter.send(permit) {
+                Ok(()) => return,
+                // 对方已经放弃了，不能在持有锁的时候 drop 这个 Permit
+                Err(mut permit) => permit.inner = None,
+            }
+        }
+        state.running -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Extension;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn admission_should_queue_then_shed() {
+        let ac = AdmissionControl::new(1, 1);
+        let first = ac.admit(Priority::Normal).await.unwrap();
+
+        let queued = tokio::spawn({
+            let ac = ac.clone();
+            async move { ac.admit(Priority::Normal).await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(ac.queued(), 1);
+
+        assert!(matches!(
+            ac.admit(Priority::Normal).await,
+            Err(KvError::Busy(_))
+        ));
+
+        drop(first);
+        queued.await.unwrap().unwrap();
+        assert_eq!(ac.running(), 0);
+        assert_eq!(ac.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiter_should_not_hold_slot() {
+        let ac = AdmissionControl::new(1, 1);
+        let first = ac.admit(Priority::Normal).await.unwrap();
+
+        let res = tokio::time::timeout(Duration::from_millis(10), ac.admit(Priority::Normal)).await;
+        assert!(res.is_err());
+        assert_eq!(ac.queued(), 0);
+
+        drop(first);
+        assert_eq!(ac.running(), 0);
+        let _second = ac.admit(Priority::Normal).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn high_priority_should_go_first() {
+        let ac = AdmissionControl::new(1, 1);
+        let first = ac.admit(Priority::Normal).await.unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for priority in [Priority::Low, Priority::High] {
+            let (ac, tx) = (ac.clone(), tx.clone());
+            tokio::spawn(async move {
+                let _permit = ac.admit(priority).await.unwrap();
+                tx.send(priority).unwrap();
+            });
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        // 普通的队列已经满了，但 High 有自己的队列；它也满了之后 High 一样返回 Busy
+        assert_eq!(ac.queued(), 2);
+        assert!(ac.admit(Priority::Normal).await.is_err());
+        assert!(ac.admit(Priority::High).await.is_err());
+
+        drop(first);
+        assert_eq!(rx.recv().await, Some(Priority::High));
+        assert_eq!(rx.recv().await, Some(Priority::Low));
+    }
+
+    #[test]
+    fn priority_should_come_from_command_or_extension() {
+        let cmd = CommandRequest::new_admin("divergence", vec![]);
+        assert_eq!(Priority::from(&cmd), Priority::High);
+        let cmd = CommandRequest::new_hscan("t1", 0, 10);
+        assert_eq!(Priority::from(&cmd), Priority::Low);
+
+        let cmd = CommandRequest::new_hget("t1", "k1").with_extension(Extension::new(
+            PRIORITY_EXTENSION,
+            vec![2u8],
+            false,
+        ));
+        assert_eq!(Priority::from(&cmd), Priority::Low);
+        assert_eq!(
+            Priority::from(&CommandRequest::new_ping("")),
+            Priority::High
+        );
+    }
+
+    #[test]
+    fn only_trusted_identity_should_request_high() {
+        let ac = AdmissionControl::new(1, 1).trust("ops");
+        let cmd = CommandRequest::new_hget("t1", "k1").with_extension(Extension::new(
+            PRIORITY_EXTENSION,
+            vec![0u8],
+            false,
+        ));
+        assert_eq!(Priority::from(&cmd), Priority::Normal);
+        assert_eq!(ac.priority(&cmd, None), Priority::Normal);
+        assert_eq!(ac.priority(&cmd, Some("app")), Priority::Normal);
+        assert_eq!(ac.priority(&cmd, Some("ops")), Priority::High);
+    }
+}
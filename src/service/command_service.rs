@@ -1,7 +1,9 @@
+use std::ops::Bound;
+
 use crate::*;
 
 impl CommandService for Hget {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
+    fn execute(self, store: &impl Storage) -> Reply {
         match store.get(&self.table, &self.key) {
             Ok(Some(v)) => v.into(),
             Ok(None) => KvError::NotFound(self.table, self.key).into(),
@@ -10,17 +12,270 @@ impl CommandService for Hget {
     }
 }
 
+// 要求分块返回时边读 get_iter 边发送，不会把整个 table 先收集成 Vec<Kvpair>
 impl CommandService for Hgetall {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get_all(&self.table) {
-            Ok(v) => v.into(),
+    fn execute(self, store: &impl Storage) -> Reply {
+        let chunk_size = self.chunk_size as usize;
+        match hgetall(self, store) {
+            Ok(pairs) if chunk_size > 0 => Reply::chunked(pairs, chunk_size),
+            Ok(pairs) => pairs.collect::<Vec<_>>().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// ValueFilter.kind 可以使用的值
+const VALUE_KINDS: [&str; 5] = ["string", "binary", "integer", "float", "bool"];
+
+// 一边遍历一边过滤 value，不满足的 kv pair 不会被收集起来。
+// 没有 pattern 也没有 filter、也不分块的时候还是交给 get_all
+fn hgetall(
+    cmd: Hgetall,
+    store: &impl Storage,
+) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+    let pattern = Glob::optional(&cmd.pattern)?;
+    if let Some(f) = &cmd.filter {
+        if !f.kind.is_empty() && !VALUE_KINDS.contains(&f.kind.as_str()) {
+            return Err(KvError::InvalidCommand(format!(
+                "Unknown value kind {}",
+                f.kind
+            )));
+        }
+    }
+
+    let iter = match (&pattern, &cmd.filter, Order::from_sign(cmd.order)) {
+        (None, None, Order::Unordered) if cmd.chunk_size == 0 => {
+            return Ok(Box::new(store.get_all(&cmd.table)?.into_iter()))
+        }
+        (None, _, Order::Unordered) => store.get_iter(&cmd.table)?,
+        (Some(pattern), _, Order::Unordered) => store.get_iter_matching(&cmd.table, pattern)?,
+        (None, _, order) => store.get_iter_ordered(&cmd.table, order)?,
+        (Some(pattern), _, order) => {
+            let pattern = pattern.clone();
+            let iter = store.get_iter_ordered(&cmd.table, order)?;
+            Box::new(iter.filter(move |p| pattern.matches(&p.key)))
+        }
+    };
+    Ok(match cmd.filter {
+        Some(f) => {
+            Box::new(iter.filter(move |p| p.value.as_ref().is_some_and(|v| value_matches(&f, v))))
+        }
+        None => iter,
+    })
+}
+
+fn value_matches(filter: &ValueFilter, value: &Value) -> bool {
+    let (kind, bytes) = match &value.value {
+        Some(value::Value::String(s)) => ("string", Some(s.as_bytes())),
+        Some(value::Value::Binary(b)) => ("binary", Some(b.as_ref())),
+        Some(value::Value::Integer(_)) => ("integer", None),
+        Some(value::Value::Float(_)) => ("float", None),
+        Some(value::Value::Bool(_)) => ("bool", None),
+        None => ("", None),
+    };
+    let (prefix, needle) = (filter.prefix.as_bytes(), filter.contains.as_bytes());
+    (filter.kind.is_empty() || filter.kind == kind)
+        && (prefix.is_empty() || bytes.is_some_and(|b| b.starts_with(prefix)))
+        && (needle.is_empty()
+            || bytes.is_some_and(|b| b.windows(needle.len()).any(|w| w == needle)))
+}
+
+// 用 get_iter 遍历，backend 不需要先把整个 table 收集成 Vec<Kvpair>
+impl CommandService for Hkeys {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.get_iter(&self.table) {
+            Ok(iter) => iter.map(|p| Value::from(p.key)).collect::<Vec<_>>().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hvals {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.get_iter(&self.table) {
+            Ok(iter) => iter
+                .map(|p| p.value.unwrap_or_default())
+                .collect::<Vec<_>>()
+                .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hlen {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.len(&self.table) {
+            Ok(len) => Value::from(len as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hdroptable {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.drop_table(&self.table) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Htables {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.list_tables() {
+            Ok(names) => names
+                .into_iter()
+                .map(Value::from)
+                .collect::<Vec<_>>()
+                .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+// 是否允许执行由 Service 检查，这里只负责删除
+impl CommandService for Hflushall {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.flush_all() {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hstats {
+    fn execute(self, store: &impl Storage) -> Reply {
+        let StorageMetrics {
+            tables: stats,
+            backend,
+            ..
+        } = match store.metrics() {
+            Ok(metrics) => metrics,
+            Err(e) => return e.into(),
+        };
+        let (keys, bytes) = stats
+            .iter()
+            .fold((0, 0), |(keys, bytes), s| (keys + s.keys, bytes + s.bytes));
+        let mut pairs: Vec<Kvpair> = stats
+            .into_iter()
+            .flat_map(|s| {
+                [
+                    Kvpair::new(format!("{}.keys", s.table), (s.keys as i64).into()),
+                    Kvpair::new(format!("{}.bytes", s.table), (s.bytes as i64).into()),
+                ]
+            })
+            .collect();
+        pairs.push(Kvpair::new("keys", (keys as i64).into()));
+        pairs.push(Kvpair::new("bytes", (bytes as i64).into()));
+        pairs.extend(backend);
+        pairs.into()
+    }
+}
+
+// 先读 version 再读 value，中间有写入的话拿到的是旧的 version，
+// 接下来的 HSETVER 会失败，而不会覆盖掉没有看到的 value
+impl CommandService for Hgetver {
+    fn execute(self, store: &impl Storage) -> Reply {
+        let version = match store.version(&self.table, &self.key) {
+            Ok(Some(version)) => version,
+            Ok(None) => return KvError::NotFound(self.table, self.key).into(),
+            Err(e) => return e.into(),
+        };
+        match store.get(&self.table, &self.key) {
+            Ok(Some(v)) => vec![v, (version as i64).into()].into(),
+            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hsetver {
+    fn execute(self, store: &impl Storage) -> Reply {
+        let pair = match self.pair {
+            Some(pair) => pair,
+            None => return KvError::InvalidCommand("HSETVER has no kv pair".into()).into(),
+        };
+        let value = pair.value.unwrap_or_default();
+        match store.set_if_version(&self.table, pair.key, value, self.version) {
+            Ok(version) => Value::from(version as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hrange {
+    fn execute(self, store: &impl Storage) -> Reply {
+        let start = match self.start.as_str() {
+            "" => Bound::Unbounded,
+            start => Bound::Included(start),
+        };
+        let end = match self.end.as_str() {
+            "" => Bound::Unbounded,
+            end => Bound::Excluded(end),
+        };
+        let limit = match self.limit {
+            0 => usize::MAX,
+            n => n as usize,
+        };
+        match store.get_range(&self.table, (start, end)) {
+            Ok(iter) => iter.take(limit).collect::<Vec<_>>().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmeta {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.meta(&self.table, &self.key) {
+            Ok(Some(meta)) => Vec::<Kvpair>::from(meta).into(),
+            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hgetat {
+    fn execute(self, store: &impl Storage) -> Reply {
+        let versions = match store.history(&self.table, &self.key) {
+            Ok(versions) => versions,
+            Err(e) => return e.into(),
+        };
+        if self.version == 0 {
+            let pairs: Vec<_> = versions
+                .into_iter()
+                .map(|(version, v)| Kvpair::new(version.to_string(), v))
+                .collect();
+            return pairs.into();
+        }
+        match versions
+            .into_iter()
+            .find(|(version, _)| *version == self.version)
+        {
+            Some((_, v)) => v.into(),
+            None => {
+                let key = format!("{}@{}", self.key, self.version);
+                KvError::NotFound(self.table, key).into()
+            }
+        }
+    }
+}
+
+impl CommandService for Hquery {
+    fn execute(self, store: &impl Storage) -> Reply {
+        let limit = match self.limit {
+            0 => usize::MAX,
+            n => n as usize,
+        };
+        let value = self.value.unwrap_or_default();
+        match store.query(&self.table, &self.index, &value, limit) {
+            Ok(pairs) => pairs.into(),
             Err(e) => e.into(),
         }
     }
 }
 
 impl CommandService for Hset {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
+    fn execute(self, store: &impl Storage) -> Reply {
         match self.pair {
             Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
                 Ok(Some(v)) => v.into(),
@@ -32,10 +287,205 @@ impl CommandService for Hset {
     }
 }
 
+impl CommandService for Hdel {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.del(&self.table, &self.key) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => Value::default().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+// 批量的命令交给 backend 一次处理，不存在的 key 返回空的 value
+impl CommandService for Hmget {
+    fn execute(self, store: &impl Storage) -> Reply {
+        batch_values(store.mget(&self.table, &self.keys)).into()
+    }
+}
+
+impl CommandService for Hmset {
+    fn execute(self, store: &impl Storage) -> Reply {
+        batch_values(store.mset(&self.table, self.pairs)).into()
+    }
+}
+
+impl CommandService for Hmdel {
+    fn execute(self, store: &impl Storage) -> Reply {
+        batch_values(store.mdel(&self.table, &self.keys)).into()
+    }
+}
+
+fn batch_values(result: Result<Vec<Option<Value>>, KvError>) -> CommandResponse {
+    match result {
+        Ok(values) => values
+            .into_iter()
+            .map(Option::unwrap_or_default)
+            .collect::<Vec<_>>()
+            .into(),
+        Err(e) => e.into(),
+    }
+}
+
+impl CommandService for Hexist {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.contains(&self.table, &self.key) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmexist {
+    fn execute(self, store: &impl Storage) -> Reply {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in &self.keys {
+            match store.contains(&self.table, key) {
+                Ok(v) => values.push(Value::from(v)),
+                Err(e) => return e.into(),
+            }
+        }
+        values.into()
+    }
+}
+
+impl CommandService for Admin {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.admin(&self.command, &self.args) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Undelete {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.undelete(&self.table, &self.key) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for PurgeTrash {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.purge_trash(&self.table, self.all) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for CloneTable {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.clone_table(&self.src, &self.dst) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Move {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.move_key(&self.src_table, &self.dst_table, &self.key, self.force) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(self.src_table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrby {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.incr(&self.table, &self.key, self.delta) {
+            Ok(n) => Value::from(n).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hsetnx {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match self.pair {
+            Some(v) => match store.set_nx(&self.table, v.key, v.value.unwrap_or_default()) {
+                Ok(written) => Value::from(written).into(),
+                Err(e) => e.into(),
+            },
+            None => KvError::InvalidCommand("HSETNX has no kv pair".into()).into(),
+        }
+    }
+}
+
+// 和 HDEL 一样只调用一次 del，读和删之间没有别人可以插进来；
+// 不同的是 key 不存在时返回 404，消费者可以由此知道队列空了
+impl CommandService for Hgetdel {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.del(&self.table, &self.key) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+// Storage::set 本身就是原子的交换，backend 在写入的同时返回旧的 value
+impl CommandService for Hgetset {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match self.pair {
+            Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
+                Ok(old) => old.into_iter().collect::<Vec<_>>().into(),
+                Err(e) => e.into(),
+            },
+            None => KvError::InvalidCommand("HGETSET has no kv pair".into()).into(),
+        }
+    }
+}
+
+impl CommandService for Hexpire {
+    fn execute(self, store: &impl Storage) -> Reply {
+        let ttl = i64::try_from(self.ttl_ms).unwrap_or(i64::MAX);
+        let deadline = now_ms().saturating_add(ttl);
+        match store.expire_at(&self.table, &self.key, deadline) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hexpireat {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.expire_at(&self.table, &self.key, self.at_ms) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hpersist {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.persist(&self.table, &self.key) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Httl {
+    fn execute(self, store: &impl Storage) -> Reply {
+        match store.deadline(&self.table, &self.key) {
+            Ok(Some(d)) => Value::from((d - now_ms()).max(0)).into(),
+            Ok(None) => Value::from(-1).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::command_request::RequestData;
+    use prost::Message;
 
     #[test]
     fn hset_should_work() {
@@ -89,14 +539,431 @@ mod tests {
         assert_res_ok(res, &[], pairs);
     }
 
-    // 从 Request中得到Response, 目前处理HGET/HGETALL/HSET
+    #[test]
+    fn hdel_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hset("score", "u1", 10.into());
+        dispatch(cmd, &store);
+
+        let cmd = CommandRequest::new_hdel("score", "u1");
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(res, &[10.into()], &[]);
+
+        // 已经删掉了，返回空的 value
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[Value::default()], &[]);
+        let res = dispatch(CommandRequest::new_hget("score", "u1"), &store);
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn hmdel_and_hmexist_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("score", "u1", 10.into()), &store);
+        dispatch(CommandRequest::new_hset("score", "u3", 11.into()), &store);
+
+        let keys = vec!["u1".into(), "u2".into(), "u3".into()];
+        let cmd = CommandRequest::new_hmexist("score", keys.clone());
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[true.into(), false.into(), true.into()], &[]);
+
+        let cmd = CommandRequest::new_hmdel("score", keys.clone());
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[10.into(), Value::default(), 11.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hexist("score", "u1"), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+    }
+
+    #[test]
+    fn hmget_and_hmset_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("score", "u1", 10.into()), &store);
+
+        let pairs = vec![Kvpair::new("u1", 11.into()), Kvpair::new("u2", 12.into())];
+        let res = dispatch(CommandRequest::new_hmset("score", pairs), &store);
+        assert_res_ok(res, &[10.into(), Value::default()], &[]);
+
+        let keys = vec!["u1".into(), "u2".into(), "u3".into()];
+        let res = dispatch(CommandRequest::new_hmget("score", keys), &store);
+        assert_res_ok(res, &[11.into(), 12.into(), Value::default()], &[]);
+    }
+
+    #[test]
+    fn hrange_should_work() {
+        let store = MemTable::new();
+        for key in ["u3", "u1", "u4", "u2"] {
+            dispatch(CommandRequest::new_hset("score", key, key.into()), &store);
+        }
+        let pair = |k: &str| Kvpair::new(k, k.into());
+
+        let res = dispatch(CommandRequest::new_hrange("score", "u2", "u4", 0), &store);
+        assert_eq!(res.pairs, [pair("u2"), pair("u3")]);
+        let res = dispatch(CommandRequest::new_hrange("score", "", "u3", 0), &store);
+        assert_eq!(res.pairs, [pair("u1"), pair("u2")]);
+        let res = dispatch(CommandRequest::new_hrange("score", "u2", "", 2), &store);
+        assert_eq!(res.pairs, [pair("u2"), pair("u3")]);
+        let res = dispatch(CommandRequest::new_hrange("score", "u4", "u2", 0), &store);
+        assert!(res.pairs.is_empty());
+    }
+
+    #[test]
+    fn hmeta_should_work() {
+        let store = MemTable::with_meta();
+        dispatch(CommandRequest::new_hset("t1", "k1", 1.into()), &store);
+        dispatch(CommandRequest::new_hset("t1", "k1", 2.into()), &store);
+        let res = dispatch(CommandRequest::new_hmeta("t1", "k1"), &store);
+        assert_eq!(res.status, 200);
+        let names: Vec<_> = res.pairs.iter().map(|p| p.key.as_str()).collect();
+        assert_eq!(names, ["created_at", "updated_at", "updates"]);
+        assert_eq!(res.pairs[2].value, Some(1.into()));
+
+        let res = dispatch(CommandRequest::new_hmeta("t1", "k2"), &store);
+        assert_res_error(res, 404, "Not found");
+        let res = dispatch(CommandRequest::new_hmeta("t1", "k1"), &MemTable::new());
+        assert_res_error(res, 501, "metadata");
+    }
+
+    #[test]
+    fn hincrby_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_hincrby("score", "u1", 5), &store);
+        assert_res_ok(res, &[5.into()], &[]);
+        let res = dispatch(CommandRequest::new_hincrby("score", "u1", -7), &store);
+        assert_res_ok(res, &[(-2).into()], &[]);
+
+        dispatch(
+            CommandRequest::new_hset("score", "u2", "ten".into()),
+            &store,
+        );
+        let res = dispatch(CommandRequest::new_hincrby("score", "u2", 1), &store);
+        assert_res_error(res, 400, "not an integer");
+    }
+
+    #[test]
+    fn hsetnx_should_only_write_absent_key() {
+        let store = MemTable::new();
+        let res = dispatch(
+            CommandRequest::new_hsetnx("lock", "job1", "w1".into()),
+            &store,
+        );
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(
+            CommandRequest::new_hsetnx("lock", "job1", "w2".into()),
+            &store,
+        );
+        assert_res_ok(res, &[false.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hget("lock", "job1"), &store);
+        assert_res_ok(res, &["w1".into()], &[]);
+    }
+
+    #[test]
+    fn hgetdel_should_work() {
+        let store = MemTable::new();
+        dispatch(
+            CommandRequest::new_hset("jobs", "j1", "payload".into()),
+            &store,
+        );
+
+        let cmd = CommandRequest::new_hgetdel("jobs", "j1");
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(res, &["payload".into()], &[]);
+        let res = dispatch(cmd, &store);
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn hgetset_should_swap() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_hgetset("t1", "k1", "v1".into()), &store);
+        assert_res_ok(res, &[], &[]);
+        let res = dispatch(CommandRequest::new_hgetset("t1", "k1", "v2".into()), &store);
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_res_ok(res, &["v2".into()], &[]);
+    }
+
+    #[test]
+    fn hexpire_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_hexpire("t1", "k1", 1000), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+        let res = dispatch(CommandRequest::new_httl("t1", "k1"), &store);
+        assert_res_error(res, 404, "Not found");
+
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        let res = dispatch(CommandRequest::new_httl("t1", "k1"), &store);
+        assert_res_ok(res, &[(-1).into()], &[]);
+        let res = dispatch(CommandRequest::new_hexpire("t1", "k1", 60_000), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_httl("t1", "k1"), &store);
+        let ttl: i64 = res.values[0].clone().try_into().unwrap();
+        assert!(ttl > 0 && ttl <= 60_000);
+
+        let res = dispatch(CommandRequest::new_hpersist("t1", "k1"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_httl("t1", "k1"), &store);
+        assert_res_ok(res, &[(-1).into()], &[]);
+
+        dispatch(CommandRequest::new_hexpire("t1", "k1", 0), &store);
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn hexpireat_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        let at = now_ms() + 60_000;
+        let res = dispatch(CommandRequest::new_hexpireat("t1", "k1", at), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        assert_eq!(store.deadline("t1", "k1").unwrap(), Some(at));
+
+        // 已经过去的时间会立即删除 key
+        let res = dispatch(
+            CommandRequest::new_hexpireat("t1", "k1", at - 120_000),
+            &store,
+        );
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn hkeys_and_hvals_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        dispatch(CommandRequest::new_hset("t1", "k2", "v2".into()), &store);
+
+        let res = dispatch(CommandRequest::new_hkeys("t1"), &store);
+        let mut keys = res.values;
+        keys.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(keys, vec!["k1".into(), "k2".into()]);
+
+        let res = dispatch(CommandRequest::new_hvals("t1"), &store);
+        let mut values = res.values;
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(values, vec!["v1".into(), "v2".into()]);
+        assert!(res.pairs.is_empty());
+    }
+
+    #[test]
+    fn hlen_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        dispatch(CommandRequest::new_hset("t1", "k2", "v2".into()), &store);
+        let res = dispatch(CommandRequest::new_hlen("t1"), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = dispatch(CommandRequest::new_hlen("t2"), &store);
+        assert_res_ok(res, &[0.into()], &[]);
+    }
+
+    #[test]
+    fn hgetall_with_pattern_should_filter_keys() {
+        let store = MemTable::new();
+        let cmds = vec![
+            CommandRequest::new_hset("t1", "user:1:profile", "p1".into()),
+            CommandRequest::new_hset("t1", "user:1:settings", "s1".into()),
+            CommandRequest::new_hset("t1", "user:2:profile", "p2".into()),
+        ];
+        for cmd in cmds {
+            dispatch(cmd, &store);
+        }
+
+        let res = dispatch(
+            CommandRequest::new_hgetall_matching("t1", "user:*:profile"),
+            &store,
+        );
+        let pairs = &[
+            Kvpair::new("user:1:profile", "p1".into()),
+            Kvpair::new("user:2:profile", "p2".into()),
+        ];
+        assert_res_ok(res, &[], pairs);
+
+        let res = dispatch(CommandRequest::new_hgetall_matching("t1", "[user"), &store);
+        assert_res_error(res, 400, "Unclosed");
+    }
+
+    #[test]
+    fn hgetall_with_order_should_sort_keys() {
+        let store = MemTable::new();
+        for key in ["k2", "k3", "k1"] {
+            dispatch(CommandRequest::new_hset("t1", key, key.into()), &store);
+        }
+        let keys = |res: CommandResponse| -> Vec<String> {
+            res.pairs.into_iter().map(|p| p.key).collect()
+        };
+
+        let cmd = CommandRequest::new_hgetall_ordered("t1", Order::Ascending);
+        assert_eq!(keys(dispatch(cmd, &store)), ["k1", "k2", "k3"]);
+        let cmd = CommandRequest::new_hgetall_ordered("t1", Order::Descending);
+        assert_eq!(keys(dispatch(cmd, &store)), ["k3", "k2", "k1"]);
+
+        let mut cmd = CommandRequest::new_hgetall_matching("t1", "k[23]");
+        if let Some(RequestData::Hgetall(v)) = &mut cmd.request_data {
+            v.order = -1;
+        }
+        assert_eq!(keys(dispatch(cmd, &store)), ["k3", "k2"]);
+    }
+
+    #[test]
+    fn hgetall_with_value_filter_should_work() {
+        let store = MemTable::new();
+        let cmds = vec![
+            CommandRequest::new_hset("t1", "k1", "http://a".into()),
+            CommandRequest::new_hset("t1", "k2", "https://b".into()),
+            CommandRequest::new_hset("t1", "k3", 42.into()),
+            CommandRequest::new_hset("t1", "k4", b"http-bin".into()),
+        ];
+        for cmd in cmds {
+            dispatch(cmd, &store);
+        }
+
+        let cmd = CommandRequest::new_hgetall_filtered("t1", ValueFilter::prefix("https"));
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[], &[Kvpair::new("k2", "https://b".into())]);
+
+        let cmd = CommandRequest::new_hgetall_filtered("t1", ValueFilter::contains("://"));
+        let res = dispatch(cmd, &store);
+        let pairs = &[
+            Kvpair::new("k1", "http://a".into()),
+            Kvpair::new("k2", "https://b".into()),
+        ];
+        assert_res_ok(res, &[], pairs);
+
+        let cmd = CommandRequest::new_hgetall_filtered("t1", ValueFilter::kind("integer"));
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[], &[Kvpair::new("k3", 42.into())]);
+
+        let filter = ValueFilter {
+            prefix: "http".into(),
+            kind: "binary".into(),
+            ..Default::default()
+        };
+        let res = dispatch(CommandRequest::new_hgetall_filtered("t1", filter), &store);
+        assert_res_ok(res, &[], &[Kvpair::new("k4", b"http-bin".into())]);
+
+        let cmd = CommandRequest::new_hgetall_filtered("t1", ValueFilter::kind("list"));
+        let res = dispatch(cmd, &store);
+        assert_res_error(res, 400, "Unknown value kind");
+    }
+
+    #[test]
+    fn hdroptable_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        dispatch(CommandRequest::new_hset("t1", "k2", "v2".into()), &store);
+
+        let res = dispatch(CommandRequest::new_hdroptable("t1"), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = dispatch(CommandRequest::new_hlen("t1"), &store);
+        assert_res_ok(res, &[0.into()], &[]);
+    }
+
+    #[test]
+    fn htables_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t2", "k1", "v1".into()), &store);
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        let res = dispatch(CommandRequest::new_htables(), &store);
+        assert_res_ok(res, &["t1".into(), "t2".into()], &[]);
+    }
+
+    #[test]
+    fn hflushall_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        dispatch(CommandRequest::new_hset("t2", "k1", "v1".into()), &store);
+        let res = dispatch(CommandRequest::new_hflushall(), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = dispatch(CommandRequest::new_htables(), &store);
+        assert_res_ok(res, &[], &[]);
+    }
+
+    #[test]
+    fn hstats_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        dispatch(CommandRequest::new_hset("t1", "k2", "v2".into()), &store);
+        dispatch(CommandRequest::new_hset("t2", "k1", "v1".into()), &store);
+
+        let size = "k1".len() + Value::from("v1").encoded_len();
+        let res = dispatch(CommandRequest::new_hstats(), &store);
+        let pairs = &[
+            Kvpair::new("t1.keys", 2.into()),
+            Kvpair::new("t1.bytes", (2 * size as i64).into()),
+            Kvpair::new("t2.keys", 1.into()),
+            Kvpair::new("t2.bytes", (size as i64).into()),
+            Kvpair::new("keys", 3.into()),
+            Kvpair::new("bytes", (3 * size as i64).into()),
+        ];
+        assert_eq!(res.status, 200);
+        assert_eq!(&res.pairs[..6], pairs);
+        // 最后是 MemTable 自己的统计
+        assert_eq!(res.pairs[6].key, "memory.bytes");
+    }
+
+    #[test]
+    fn hsetver_should_check_version() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_hgetver("t1", "k1"), &store);
+        assert_res_error(res, 404, "Not found");
+
+        let res = dispatch(
+            CommandRequest::new_hsetver("t1", "k1", "v1".into(), 0),
+            &store,
+        );
+        let v1 = res.values[0].clone();
+        let res = dispatch(CommandRequest::new_hgetver("t1", "k1"), &store);
+        assert_res_ok(res, &["v1".into(), v1.clone()], &[]);
+
+        dispatch(CommandRequest::new_hset("t1", "k1", "v2".into()), &store);
+        let version = i64::try_from(v1).unwrap() as u64;
+        let res = dispatch(
+            CommandRequest::new_hsetver("t1", "k1", "v3".into(), version),
+            &store,
+        );
+        assert_res_error(res, 409, "version");
+    }
+
+    // 从 Request中得到Response, 只处理这里测试的命令。分块的结果合成一个 response
     fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
-        match cmd.request_data.unwrap() {
+        let reply = match cmd.request_data.unwrap() {
             RequestData::Hget(v) => v.execute(store),
             RequestData::Hgetall(v) => v.execute(store),
             RequestData::Hset(v) => v.execute(store),
+            RequestData::Hdel(v) => v.execute(store),
+            RequestData::Hmget(v) => v.execute(store),
+            RequestData::Hmset(v) => v.execute(store),
+            RequestData::Hmdel(v) => v.execute(store),
+            RequestData::Hexist(v) => v.execute(store),
+            RequestData::Hmexist(v) => v.execute(store),
+            RequestData::Hincrby(v) => v.execute(store),
+            RequestData::Hsetnx(v) => v.execute(store),
+            RequestData::Hgetdel(v) => v.execute(store),
+            RequestData::Hgetset(v) => v.execute(store),
+            RequestData::Hexpire(v) => v.execute(store),
+            RequestData::Hpersist(v) => v.execute(store),
+            RequestData::Httl(v) => v.execute(store),
+            RequestData::Hexpireat(v) => v.execute(store),
+            RequestData::Hkeys(v) => v.execute(store),
+            RequestData::Hvals(v) => v.execute(store),
+            RequestData::Hlen(v) => v.execute(store),
+            RequestData::Hdroptable(v) => v.execute(store),
+            RequestData::Htables(v) => v.execute(store),
+            RequestData::Hflushall(v) => v.execute(store),
+            RequestData::Hstats(v) => v.execute(store),
+            RequestData::Hgetver(v) => v.execute(store),
+            RequestData::Hsetver(v) => v.execute(store),
+            RequestData::Hrange(v) => v.execute(store),
+            RequestData::Hmeta(v) => v.execute(store),
+            RequestData::Hgetat(v) => v.execute(store),
+            RequestData::Hquery(v) => v.execute(store),
             _ => todo!(),
-        }
+        };
+        reply.into()
     }
 
     // 测试成功的返回的结果
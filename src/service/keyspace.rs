@@ -0,0 +1,271 @@
+use crate::command_request::RequestData;
+use crate::*;
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+/// 键空间通知的 topic 前缀
+///
+/// table 上的 key 被写入或者删除之后，服务器向 `__keyspace__:<table>` 发布
+/// `set:<key>` 或者 `del:<key>`，下游的缓存可以订阅它来失效对应的 key。
+/// 设置了 changefeed（见 ServiceInner::with_changefeed）的话，存储自己过期或者淘汰的 key
+/// 发布 `expired:<key>` 或者 `evicted:<key>`。整个 table 的操作（HDROPTABLE、HFLUSHALL、CLONE）
+/// 不发通知。
+pub const KEYSPACE_PREFIX: &str = "__keyspace__:";
+
+/// table 的键空间通知 topic
+pub fn keyspace_topic(table: &str) -> String {
+    format!("{}{}", KEYSPACE_PREFIX, table)
+}
+
+/// 一个写命令可能改动的 key，命令执行成功之后按照结果发布键空间通知，
+/// 同时让 watch 了这些 key 的连接的 EXEC 失败
+pub(crate) struct KeyChanges(Vec<Change>);
+
+struct Change {
+    target: Target,
+    op: &'static str,
+    when: When,
+    // 有人订阅了这个 table 的键空间通知
+    notify: bool,
+}
+
+enum Target {
+    Key(String, String),
+    // 整个 table，None 表示所有的 table，只影响 WATCH
+    Table(Option<String>),
+}
+
+// 有的命令不一定真的改动了 key，要看 response 里对应的 value
+enum When {
+    Always,
+    // value 是 true，比如 HSETNX 写入了
+    Written(usize),
+    // value 不是空的，比如 HDEL 删除的 key 原来存在
+    Existed(usize),
+}
+
+impl KeyChanges {
+    /// 没有人订阅或者 watch 涉及的 key 时返回 None，不用为它们复制 key
+    pub(crate) fn new(data: &RequestData, broker: &Broker, watches: &Watches) -> Option<Self> {
+        let mut changes = Vec::new();
+        // op 是空的只影响 WATCH，不发通知
+        let mut key = |table: &str, op: &'static str, key: &str, when| {
+            let notify = !op.is_empty() && broker.subscribers(&keyspace_topic(table)) > 0;
+            if notify || watches.is_watched(table, key) {
+                changes.push(Change {
+                    target: Target::Key(table.into(), key.into()),
+                    op,
+                    when,
+                    notify,
+                });
+            }
+        };
+        match data {
+            RequestData::Hset(Hset {
+                table,
+                pair: Some(pair),
+            })
+            | RequestData::Hgetset(Hgetset {
+                table,
+                pair: Some(pair),
+            }) => key(table, "set", &pair.key, When::Always),
+            RequestData::Hsetnx(Hsetnx {
+                table,
+                pair: Some(pair),
+            }) => key(table, "set", &pair.key, When::Written(0)),
+            RequestData::Hsetver(Hsetver {
+                table,
+                pair: Some(pair),
+                ..
+            }) => key(table, "set", &pair.key, When::Always),
+            RequestData::Hincrby(v) => key(&v.table, "set", &v.key, When::Always),
+            RequestData::Undelete(v) => key(&v.table, "set", &v.key, When::Always),
+            RequestData::Hdel(v) => key(&v.table, "del", &v.key, When::Existed(0)),
+            RequestData::Hgetdel(v) => key(&v.table, "del", &v.key, When::Always),
+            RequestData::Hmset(v) => {
+                for pair in &v.pairs {
+                    key(&v.table, "set", &pair.key, When::Always);
+                }
+            }
+            RequestData::Hmdel(v) => {
+                for (i, k) in v.keys.iter().enumerate() {
+                    key(&v.table, "del", k, When::Existed(i));
+                }
+            }
+            RequestData::Move(v) => {
+                key(&v.src_table, "del", &v.key, When::Always);
+                key(&v.dst_table, "set", &v.key, When::Always);
+            }
+            // 改了过期时间，deadline 已经过去的话 key 被删掉了
+            RequestData::Hexpire(v) => key(&v.table, "", &v.key, When::Written(0)),
+            RequestData::Hexpireat(v) => key(&v.table, "", &v.key, When::Written(0)),
+            RequestData::Hpersist(v) => key(&v.table, "", &v.key, When::Written(0)),
+            _ => {}
+        }
+
+        let table = match data {
+            RequestData::Hdroptable(v) => Some(Some(v.table.clone())),
+            RequestData::CloneTable(v) => Some(Some(v.dst.clone())),
+            // 不知道 RESTORE 和插件的命令改了哪些 table
+            RequestData::Hflushall(_) | RequestData::Restore(_) | RequestData::Custom(_) => {
+                Some(None)
+            }
+            _ => None,
+        };
+        if let Some(table) = table.filter(|_| !watches.is_empty()) {
+            changes.push(Change {
+                target: Target::Table(table),
+                op: "",
+                when: When::Always,
+                notify: false,
+            });
+        }
+
+        match changes.is_empty() {
+            true => None,
+            false => Some(Self(changes)),
+        }
+    }
+
+    /// 命令执行成功的话，根据实际发生的改动发布通知、标记 watch
+    pub(crate) fn apply(self, res: &CommandResponse, broker: &Broker, watches: &Watches) {
+        if !(200..300).contains(&res.status) {
+            return;
+        }
+        for change in self.0 {
+            let happened = match change.when {
+                When::Always => true,
+                When::Written(i) => res.values.get(i) == Some(&true.into()),
+                When::Existed(i) => res.values.get(i).is_some_and(|v| v.value.is_some()),
+            };
+            if !happened {
+                continue;
+            }
+            match change.target {
+                Target::Key(table, key) => {
+                    watches.touch(&table, &key);
+                    if change.notify {
+                        let data = format!("{}:{}", change.op, key);
+                        broker.publish(&keyspace_topic(&table), data.into());
+                    }
+                }
+                Target::Table(table) => watches.touch_table(table.as_deref()),
+            }
+        }
+    }
+}
+
+/// 在后台线程里把 changefeed 的事件转成键空间通知，同时让 watch 了这些 key 的 EXEC 失败。
+/// 存储（changefeed 的所有发送者）drop 之后线程退出
+pub(crate) fn forward_changefeed(
+    changefeed: &Changefeed,
+    broker: Arc<Broker>,
+    watches: Arc<Watches>,
+) {
+    let mut rx = changefeed.subscribe();
+    let forward = move || loop {
+        let event = match rx.blocking_recv() {
+            Ok(event) => event,
+            // 丢掉的事件不知道是哪些 key，保守地让所有的 watch 失效
+            Err(RecvError::Lagged(_)) => {
+                watches.touch_table(None);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        watches.touch(&event.table, &event.key);
+        let op = match event.kind {
+            KeyEventKind::Expired => "expired",
+            KeyEventKind::Evicted => "evicted",
+        };
+        let topic = keyspace_topic(&event.table);
+        if broker.subscribers(&topic) > 0 {
+            broker.publish(&topic, format!("{}:{}", op, event.key).into());
+        }
+    };
+    if let Err(e) = thread::Builder::new()
+        .name("kv-changefeed".into())
+        .spawn(forward)
+    {
+        warn!("failed to start changefeed thread: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    fn received(rx: &mut mpsc::Receiver<CommandResponse>) -> Vec<Kvpair> {
+        let mut pairs = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            pairs.extend(msg.pairs);
+        }
+        pairs
+    }
+
+    fn event(table: &str, data: &str) -> Kvpair {
+        Kvpair::new(keyspace_topic(table), data.into())
+    }
+
+    #[test]
+    fn writes_should_publish_keyspace_events() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let (tx, mut rx) = mpsc::channel(16);
+        let mut session = service.session(tx);
+        for table in ["t1", "t2"] {
+            let topic = keyspace_topic(table);
+            service.execute_in(CommandRequest::new_subscribe(topic), &mut session);
+        }
+
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        service.execute(CommandRequest::new_hsetnx("t1", "k1", "v2".into()));
+        service.execute(CommandRequest::new_hdel("t1", "missing"));
+        service.execute(CommandRequest::new_hset("t3", "k1", "v1".into()));
+        service.execute(CommandRequest::new_move("t1", "t2", "k1", false));
+        service.execute(CommandRequest::new_hmdel(
+            "t2",
+            vec!["k1".into(), "k2".into()],
+        ));
+        assert_eq!(
+            received(&mut rx),
+            vec![
+                event("t1", "set:k1"),
+                event("t1", "del:k1"),
+                event("t2", "set:k1"),
+                event("t2", "del:k1"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn expiry_should_publish_keyspace_events() {
+        let feed = Changefeed::new(16);
+        let store = MemTable::new().with_changefeed(feed.clone());
+        let service: Service = ServiceInner::new(store).with_changefeed(&feed).into();
+        let (tx, mut rx) = mpsc::channel(16);
+        let mut session = service.session(tx);
+        service.execute_in(
+            CommandRequest::new_subscribe(keyspace_topic("t1")),
+            &mut session,
+        );
+
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        service.execute(CommandRequest::new_hexpire("t1", "k1", 1));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(service.store().purge_expired().unwrap(), 1);
+
+        let mut pairs = Vec::new();
+        while pairs.len() < 2 {
+            let msg = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await;
+            pairs.extend(msg.unwrap().unwrap().pairs);
+        }
+        assert_eq!(
+            pairs,
+            vec![event("t1", "set:k1"), event("t1", "expired:k1")]
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! tower 集成
+//!
+//! Service 实现了 `tower::Service<CommandRequest>`，这样超时、限流、重试、
+//! load shedding 之类的中间件都可以直接用 tower 生态里现成的。
+//! tower 的中间件会把错误变成 `BoxError`，用 `CatchErrorLayer` 包在最外层，
+//! 就能把这些错误再转换回 CommandResponse。
+
+use std::convert::Infallible;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use http::StatusCode;
+use tower::{load_shed::error::Overloaded, timeout::error::Elapsed, BoxError, Layer};
+
+use crate::*;
+
+impl<Store: Storage + 'static> tower::Service<CommandRequest> for Service<Store> {
+    type Response = CommandResponse;
+    type Error = KvError;
+    type Future = Ready<Result<CommandResponse, KvError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: CommandRequest) -> Self::Future {
+        ready(Ok(self.execute(req)))
+    }
+}
+
+/// 把内层 service 的错误转换成 CommandResponse 的 Layer
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CatchErrorLayer;
+
+impl<S> Layer<S> for CatchErrorLayer {
+    type Service = CatchError<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CatchError {
+            inner,
+            not_ready: None,
+        }
+    }
+}
+
+/// 见 CatchErrorLayer
+#[derive(Debug)]
+pub struct CatchError<S> {
+    inner: S,
+    // 内层 poll_ready 返回的错误，下一次 call 直接用它生成 response
+    not_ready: Option<BoxError>,
+}
+
+impl<S: Clone> Clone for CatchError<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            not_ready: None,
+        }
+    }
+}
+
+impl<S> tower::Service<CommandRequest> for CatchError<S>
+where
+    S: tower::Service<CommandRequest, Response = CommandResponse>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = CommandResponse;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<CommandResponse, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // 内层 service 的错误先存起来，在 call 里转换成 response
+        match self.inner.poll_ready(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
+            Poll::Ready(Err(e)) => {
+                self.not_ready = Some(e.into());
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+
+    fn call(&mut self, req: CommandRequest) -> Self::Future {
+        if let Some(e) = self.not_ready.take() {
+            return Box::pin(ready(Ok(error_to_response(e))));
+        }
+
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            match fut.await {
+                Ok(res) => Ok(res),
+                Err(e) => Ok(error_to_response(e.into())),
+            }
+        })
+    }
+}
+
+fn error_to_response(e: BoxError) -> CommandResponse {
+    let e = match e.downcast::<KvError>() {
+        Ok(e) => return (*e).into(),
+        Err(e) => e,
+    };
+
+    let status = if e.is::<Elapsed>() {
+        StatusCode::GATEWAY_TIMEOUT
+    } else if e.is::<Overloaded>() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    CommandResponse {
+        status: status.as_u16() as _,
+        message: e.to_string(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tower::{Service as _, ServiceBuilder, ServiceExt};
+
+    #[tokio::test]
+    async fn tower_service_should_work() {
+        let mut service: Service = ServiceInner::new(MemTable::new()).into();
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = service.ready().await.unwrap().call(cmd).await.unwrap();
+        assert_res_ok(res, &[Value::default()], &[]);
+    }
+
+    #[tokio::test]
+    async fn tower_middleware_should_compose() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let mut svc = ServiceBuilder::new()
+            .layer(CatchErrorLayer)
+            .timeout(Duration::from_secs(1))
+            .concurrency_limit(16)
+            .service(service);
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = svc.ready().await.unwrap().call(cmd).await.unwrap();
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[tokio::test]
+    async fn catch_error_should_convert_timeout() {
+        let slow = tower::service_fn(|_: CommandRequest| async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok::<_, KvError>(CommandResponse::default())
+        });
+        let mut svc = ServiceBuilder::new()
+            .layer(CatchErrorLayer)
+            .timeout(Duration::from_millis(10))
+            .service(slow);
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = svc.ready().await.unwrap().call(cmd).await.unwrap();
+        assert_eq!(res.status, 504);
+    }
+}
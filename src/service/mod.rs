@@ -1,13 +1,53 @@
+use crate::command_request::RequestData;
 use crate::*;
-use std::sync::Arc;
+use keyspace::KeyChanges;
+use slo::SloWatch;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
 use tracing::debug;
 
+mod admission;
 mod command_service;
+mod keyspace;
+#[cfg(any(test, feature = "tower"))]
+mod middleware;
+#[cfg(feature = "plugin")]
+mod plugin;
+mod pool;
+mod pubsub;
+mod reply;
+mod scan;
+mod session;
+mod slo;
+mod stats;
+mod watch;
+
+pub use admission::{AdmissionControl, Permit, Priority, PRIORITY_EXTENSION};
+pub use keyspace::{keyspace_topic, KEYSPACE_PREFIX};
+#[cfg(any(test, feature = "tower"))]
+pub use middleware::{CatchError, CatchErrorLayer};
+#[cfg(feature = "plugin")]
+pub use plugin::Plugin;
+pub use pool::BlockingPool;
+pub use pubsub::{Broker, PushSender, Subscriptions};
+pub use reply::{Reply, ResponseStream};
+pub use scan::{ScanCursors, CURSOR_TTL, MAX_CURSORS};
+pub use session::Session;
+pub use slo::{BreachKind, CommandClass, SloBreach, SloConfig};
+pub use stats::{ConnectionGuard, ServerStats};
+pub use watch::Watches;
+use watch::Watching;
+
+/// ServiceInner::fn_authorize 注册的权限检查
+pub type Authorize = fn(Option<&str>, &CommandRequest) -> Result<(), KvError>;
 
 /// 对Command的处理的抽象
 pub trait CommandService {
-    /// 处理 Command, 返回 response
-    fn execute(self, store: &impl Storage) -> CommandResponse;
+    /// 处理 Command, 返回 response，结果很大的命令可以返回分块发送的一串 response
+    fn execute(self, store: &impl Storage) -> Reply;
 }
 
 /// Service 数据结构
@@ -32,6 +72,23 @@ pub struct ServiceInner<Store> {
     // 这样事件的处理者可以根据需要，在发送前，修改 CommandResponse。
     on_before_send: Vec<fn(&mut CommandResponse)>,
     on_after_send: Vec<fn()>,
+    // 执行命令之前检查连接的身份，返回错误时不执行
+    on_authorize: Vec<Authorize>,
+    // 延迟或者错误率超出阈值时触发
+    on_slo_breach: Vec<SloWatch>,
+    // 是否允许 HFLUSHALL 清空所有数据
+    flushall: bool,
+    // BACKUP 和 RESTORE 读写的目录，没有的话这两个命令返回 403
+    backup_dir: Option<PathBuf>,
+    stats: Arc<ServerStats>,
+    broker: Arc<Broker>,
+    watches: Arc<Watches>,
+    // 普通的命令拿读锁，EXEC 提交的时候拿写锁，这样提交期间不会有别的命令插进来
+    exec_lock: RwLock<()>,
+    // execute_async 和网络层在这里执行命令，没有的话直接在调用者的线程上执行
+    pool: Option<BlockingPool>,
+    #[cfg(feature = "plugin")]
+    plugins: Vec<Plugin>,
 }
 
 impl<Store: Storage> ServiceInner<Store> {
@@ -42,6 +99,17 @@ impl<Store: Storage> ServiceInner<Store> {
             on_executed: Vec::new(),
             on_before_send: Vec::new(),
             on_after_send: Vec::new(),
+            on_authorize: Vec::new(),
+            on_slo_breach: Vec::new(),
+            flushall: false,
+            backup_dir: None,
+            stats: Arc::default(),
+            broker: Arc::default(),
+            watches: Arc::default(),
+            exec_lock: RwLock::new(()),
+            pool: None,
+            #[cfg(feature = "plugin")]
+            plugins: Vec::new(),
         }
     }
 
@@ -64,24 +132,563 @@ impl<Store: Storage> ServiceInner<Store> {
         self.on_after_send.push(f);
         self
     }
+
+    /// 每个命令执行之前调用 f，参数是连接的客户端身份（见 client_identity）和命令。
+    /// f 返回的错误（通常是 Forbidden）直接作为 response，命令不会执行。
+    /// 不经过连接的 execute 没有身份，MULTI 排队的命令在 EXEC 的时候检查
+    pub fn fn_authorize(mut self, f: Authorize) -> Self {
+        self.on_authorize.push(f);
+        self
+    }
+
+    /// class 这类命令在滚动窗口里的延迟或者错误率超出 config 的阈值时调用 f
+    pub fn fn_slo_breach(
+        mut self,
+        class: CommandClass,
+        config: SloConfig,
+        f: fn(&SloBreach),
+    ) -> Self {
+        self.on_slo_breach.push(SloWatch::new(class, config, f));
+        self
+    }
+
+    /// 允许客户端用 HFLUSHALL 清空所有数据，缺省返回 403
+    pub fn allow_flushall(mut self) -> Self {
+        self.flushall = true;
+        self
+    }
+
+    /// 允许客户端用 BACKUP 和 RESTORE 读写 dir 下面的备份文件，缺省返回 403
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+
+    /// execute_async 在 pool 里访问存储，不会阻塞 tokio 的线程。
+    /// 连接自己没有设置 BlockingPool 时网络层也用它
+    pub fn with_pool(mut self, pool: BlockingPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// 把存储的 changefeed 里过期和淘汰的事件发布成键空间通知，并让 watch 这些 key 的 EXEC 失败。
+    /// changefeed 要和传给存储的是同一个
+    pub fn with_changefeed(self, changefeed: &Changefeed) -> Self {
+        keyspace::forward_changefeed(changefeed, self.broker.clone(), self.watches.clone());
+        self
+    }
+
+    /// 注册 WASM 插件，它提供的自定义命令和 hook 在 execute 时生效
+    #[cfg(feature = "plugin")]
+    pub fn plugin(mut self, plugin: Plugin) -> Self {
+        self.plugins.push(plugin);
+        self
+    }
 }
 
-impl<Store: Storage> Service<Store> {
+impl<Store: Storage + 'static> Service<Store> {
+    /// 直接访问底层的存储，嵌入模式下用来遍历 table
+    pub(crate) fn store(&self) -> &Store {
+        &self.inner.store
+    }
+
+    pub(crate) fn pool(&self) -> Option<&BlockingPool> {
+        self.inner.pool.as_ref()
+    }
+
+    /// INFO 返回的统计，网络层用它记录连接数
+    pub fn stats(&self) -> &Arc<ServerStats> {
+        &self.inner.stats
+    }
+
+    /// INFO 返回的 kv pair：服务器的统计，后面是 Storage::metrics 的 storage.*。
+    /// backend 报告不了 metrics 时只有前者
+    pub fn info(&self) -> Vec<Kvpair> {
+        let mut pairs = self.inner.stats.info::<Store>();
+        if let Ok(metrics) = self.inner.store.metrics() {
+            pairs.extend(metrics.pairs());
+        }
+        pairs
+    }
+
+    // 备份文件在 backup_dir 下面的路径，name 不能跳到别的目录
+    fn backup_path(&self, name: &str) -> Result<PathBuf, KvError> {
+        let dir = match &self.inner.backup_dir {
+            Some(dir) => dir,
+            None => {
+                return Err(KvError::Forbidden(
+                    "backups are disabled on this server".into(),
+                ))
+            }
+        };
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(KvError::InvalidCommand(format!(
+                "bad backup name: {:?}",
+                name
+            )));
+        }
+        Ok(dir.join(name))
+    }
+
+    // 先写到临时文件，写完再改名，备份失败不会留下不完整的文件
+    fn backup(&self, name: &str) -> Result<usize, KvError> {
+        let path = self.backup_path(name)?;
+        let tmp = path.with_extension("tmp");
+        let export = || {
+            let mut w = BufWriter::new(File::create(&tmp)?);
+            let n = self.inner.store.export(&mut w)?;
+            w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&tmp, &path)?;
+            Ok(n)
+        };
+        export().inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+    }
+
+    // 先完整地检查一遍，损坏的备份不会导入一半
+    fn restore(&self, name: &str) -> Result<usize, KvError> {
+        let path = self.backup_path(name)?;
+        let open = |path: &Path| -> Result<_, KvError> {
+            match File::open(path) {
+                Ok(f) => Ok(BufReader::new(f)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    Err(KvError::NotFound("backup".into(), name.into()))
+                }
+                Err(e) => Err(e.into()),
+            }
+        };
+        check_backup(&mut open(&path)?)?;
+        self.inner.store.import(&mut open(&path)?)
+    }
+
+    /// 创建一个连接的 Session，订阅的消息推送到 push
+    pub fn session(&self, push: PushSender) -> Session {
+        Session {
+            subscriptions: Some(Subscriptions::new(self.inner.broker.clone(), push)),
+            ..Session::new()
+        }
+    }
+
+    /// 向 topic 发布一条消息，返回收到消息的订阅者个数
+    pub fn publish(&self, topic: &str, data: Value) -> usize {
+        self.inner.broker.publish(topic, data)
+    }
+
+    /// 分块返回的结果合成一个 response
     pub fn execute(&self, cmd: CommandRequest) -> CommandResponse {
+        self.execute_inner(cmd, None).into()
+    }
+
+    /// 和 execute 一样，同时可以使用连接上的 HSCAN cursor 和订阅
+    pub fn execute_in(&self, cmd: CommandRequest, session: &mut Session) -> CommandResponse {
+        self.execute_inner(cmd, Some(session)).into()
+    }
+
+    /// 网络层使用的 execute_in，命令要求分块返回时结果是一串 response，
+    /// 发送到哪一块才从存储里读到哪里。on_executed 的 hook 和统计只看到第一块，
+    /// on_before_send 的 hook 每一块都会看到
+    pub fn execute_reply(&self, cmd: CommandRequest, session: &mut Session) -> Reply {
+        self.execute_inner(cmd, Some(session))
+    }
+
+    fn execute_inner(&self, cmd: CommandRequest, session: Option<&mut Session>) -> Reply {
+        let session = match session {
+            // MULTI 之后的命令先排队，EXEC 的时候再执行
+            Some(session) if session.in_transaction() && !is_transaction(&cmd) => {
+                let queued = session.queued.get_or_insert_with(Vec::new);
+                let mut res = match check_queued(&cmd, queued) {
+                    Ok(()) => {
+                        debug!("Queued request: {:?}", cmd);
+                        queued.push(cmd);
+                        CommandResponse::from(Value::from("QUEUED"))
+                    }
+                    // 和 Redis 一样，有命令没能排队的话之后的 EXEC 直接失败
+                    Err(e) => {
+                        session.rejected = true;
+                        e.into()
+                    }
+                };
+                res.version = PROTOCOL_VERSION;
+                return res.into();
+            }
+            session => session,
+        };
+
+        // BATCH 里的命令各自加锁，执行整个 BATCH 期间不会一直占着锁
+        let cmd = match cmd.request_data {
+            Some(RequestData::Batch(batch)) => {
+                let mut session = session;
+                let mut responses: Vec<CommandResponse> = Vec::with_capacity(batch.requests.len());
+                for cmd in batch.requests {
+                    responses.push(self.execute_inner(cmd, session.as_deref_mut()).into());
+                }
+                let mut res = CommandResponse::from(responses);
+                res.version = PROTOCOL_VERSION;
+                return res.into();
+            }
+            request_data => CommandRequest {
+                request_data,
+                ..cmd
+            },
+        };
+
+        // EXEC 自己决定什么时候拿哪个锁，见 exec
+        let _read = match cmd.request_data {
+            Some(RequestData::Exec(_)) => None,
+            _ => Some(self.read_lock()),
+        };
+        self.execute_locked(cmd, session)
+    }
+
+    fn read_lock(&self) -> RwLockReadGuard<'_, ()> {
+        let lock = &self.inner.exec_lock;
+        lock.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_lock(&self) -> RwLockWriteGuard<'_, ()> {
+        let lock = &self.inner.exec_lock;
+        lock.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // 执行命令之前发送 on_received 事件，记下执行之后要做的事
+    fn begin(&self, cmd: &CommandRequest) -> Pending {
         debug!("Got request: {:?}", cmd);
+        let start = Instant::now();
+        let class = CommandClass::from(cmd);
         // 发送on_received事件
-        self.inner.on_received.notify(&cmd);
-        let mut res = dispatch(cmd, &self.inner.store);
+        self.inner.on_received.notify(cmd);
+        let (broker, watches) = (&self.inner.broker, &self.inner.watches);
+        let changes = cmd
+            .request_data
+            .as_ref()
+            .and_then(|d| KeyChanges::new(d, broker, watches));
+        let flush = class != CommandClass::Read
+            && cmd.extensions.iter().any(|ext| ext.name == FLUSH_EXTENSION);
+        Pending {
+            start,
+            class,
+            changes,
+            flush,
+        }
+    }
+
+    fn check(&self, cmd: &CommandRequest, identity: Option<&str>) -> Result<(), KvError> {
+        check_protocol(cmd).and_then(|()| {
+            let authorize = &self.inner.on_authorize;
+            authorize.iter().try_for_each(|f| f(identity, cmd))
+        })
+    }
+
+    fn execute_locked(&self, cmd: CommandRequest, session: Option<&mut Session>) -> Reply {
+        let pending = self.begin(&cmd);
+        let watches = &self.inner.watches;
+        let identity = session.as_deref().and_then(Session::identity);
+        let checked = self.check(&cmd, identity);
+        let res: Reply = match (checked, cmd.request_data) {
+            (Err(e), _) => e.into(),
+            (Ok(()), Some(RequestData::Hflushall(_))) if !self.inner.flushall => {
+                KvError::Forbidden("HFLUSHALL is disabled on this server".into()).into()
+            }
+            (Ok(()), Some(RequestData::Info(_))) => self.info().into(),
+            (Ok(()), Some(RequestData::Backup(v))) => match self.backup(&v.name) {
+                Ok(n) => Value::from(n as i64).into(),
+                Err(e) => e.into(),
+            },
+            (Ok(()), Some(RequestData::Restore(v))) => match self.restore(&v.name) {
+                Ok(n) => Value::from(n as i64).into(),
+                Err(e) => e.into(),
+            },
+            (Ok(()), Some(RequestData::Hscan(v))) => match session {
+                Some(session) => session.cursors.scan(v, &self.inner.store).into(),
+                None => KvError::Unsupported("HSCAN without a connection".into()).into(),
+            },
+            (Ok(()), Some(RequestData::Subscribe(v))) => {
+                match session.and_then(|s| s.subscriptions.as_mut()) {
+                    Some(subs) => subs.subscribe(v).into(),
+                    None => KvError::Unsupported("SUBSCRIBE without a connection".into()).into(),
+                }
+            }
+            (Ok(()), Some(RequestData::Unsubscribe(v))) => {
+                match session.and_then(|s| s.subscriptions.as_mut()) {
+                    Some(subs) => subs.unsubscribe(v).into(),
+                    None => KvError::Unsupported("UNSUBSCRIBE without a connection".into()).into(),
+                }
+            }
+            (Ok(()), Some(RequestData::Multi(_))) => match session {
+                Some(session) if session.in_transaction() => {
+                    KvError::InvalidCommand("MULTI calls can not be nested".into()).into()
+                }
+                Some(session) => {
+                    session.queued = Some(Vec::new());
+                    session.rejected = false;
+                    Value::from("OK").into()
+                }
+                None => KvError::Unsupported("MULTI without a connection".into()).into(),
+            },
+            (Ok(()), Some(RequestData::Discard(_))) => match session {
+                Some(session) if session.in_transaction() => {
+                    session.queued = None;
+                    session.watching = None;
+                    session.rejected = false;
+                    Value::from("OK").into()
+                }
+                _ => KvError::InvalidCommand("DISCARD without MULTI".into()).into(),
+            },
+            (Ok(()), Some(RequestData::Watch(v))) => match session {
+                Some(session) if session.in_transaction() => {
+                    KvError::InvalidCommand("WATCH inside MULTI is not allowed".into()).into()
+                }
+                Some(session) => {
+                    let watching = session
+                        .watching
+                        .get_or_insert_with(|| Watching::new(watches.clone()));
+                    for key in &v.keys {
+                        let version = self.inner.store.version(&v.table, key).ok();
+                        watching.watch(&v.table, key, version);
+                    }
+                    Value::from("OK").into()
+                }
+                None => KvError::Unsupported("WATCH without a connection".into()).into(),
+            },
+            (Ok(()), Some(RequestData::Unwatch(_))) => {
+                if let Some(session) = session {
+                    session.watching = None;
+                }
+                Value::from("OK").into()
+            }
+            (Ok(()), Some(RequestData::Exec(_))) => match session {
+                Some(session) if session.in_transaction() => self.exec(session).into(),
+                _ => KvError::InvalidCommand("EXEC without MULTI".into()).into(),
+            },
+            (Ok(()), Some(RequestData::Publish(v))) => {
+                let n = self.publish(&v.topic, v.data.unwrap_or_default());
+                Value::from(n as i64).into()
+            }
+            (Ok(()), request_data) => self.dispatch(
+                CommandRequest {
+                    request_data,
+                    ..cmd
+                },
+                &self.inner.store,
+            ),
+        };
+        self.finish(pending, res)
+    }
+
+    // 分块的结果第一块像一个 response 一样处理，之后的每一块只补上 version、
+    // 发送 on_before_send 事件
+    fn finish(&self, pending: Pending, reply: Reply) -> Reply {
+        let mut stream = match reply {
+            Reply::Single(res) => return Reply::Single(self.finish_response(pending, res)),
+            Reply::Stream(stream) => stream,
+        };
+        let first = self.finish_response(pending, stream.next().unwrap_or_default());
+        let on_before_send = self.inner.on_before_send.clone();
+        let rest = stream.map(move |mut res| {
+            res.version = PROTOCOL_VERSION;
+            on_before_send.notify(&mut res);
+            res
+        });
+        Reply::Stream(Box::new(std::iter::once(first).chain(rest)))
+    }
+
+    // 命令执行完之后：写盘、发布键空间通知、发送 on_executed 和 on_before_send 事件、统计
+    fn finish_response(&self, pending: Pending, mut res: CommandResponse) -> CommandResponse {
+        let Pending {
+            start,
+            class,
+            changes,
+            flush,
+        } = pending;
+        let (broker, watches) = (&self.inner.broker, &self.inner.watches);
+        // 写盘失败时写入可能已经生效了，只是不能保证崩溃之后还在
+        if flush && (200..300).contains(&res.status) {
+            if let Err(e) = self.inner.store.flush() {
+                res = KvError::Internal(format!("written but not flushed: {}", e)).into();
+            }
+        }
+        res.version = PROTOCOL_VERSION;
         debug!("Executed response: {:?}", res);
+        if let Some(changes) = changes {
+            changes.apply(&res, broker, watches);
+        }
         // 发送on_executed事件
         self.inner.on_executed.notify(&res);
+        #[cfg(feature = "plugin")]
+        for plugin in &self.inner.plugins {
+            plugin.after(self, &res);
+        }
         self.inner.on_before_send.notify(&mut res);
         if !self.inner.on_before_send.is_empty() {
             debug!("Modified response: {:?}", res);
         }
 
+        let latency = start.elapsed();
+        self.inner.stats.record(class, &res);
+        for watch in &self.inner.on_slo_breach {
+            watch.record(class, latency, &res);
+        }
         res
     }
+
+    // EXEC：排队的命令先在 Staged 上执行，写入都暂存在里面，全部成功之后用 Storage::commit
+    // 一起写进去，有一个失败的话什么都不写，返回它的错误。执行的时候和普通命令一样拿读锁；
+    // 提交的时候拿写锁，这时没有别的命令在执行，确认读过的 key 没有被改过，
+    // 改过的话拿着写锁重新执行一遍。排队的命令在提交之后才发送事件、发布键空间通知
+    fn exec(&self, session: &mut Session) -> CommandResponse {
+        let cmds = session.queued.take().unwrap_or_default();
+        // 排队的命令执行之前就取消 watch，它们自己的改动不算
+        let watching = session.watching.take();
+        if std::mem::take(&mut session.rejected) {
+            let msg = "transaction discarded because of previous errors";
+            return KvError::InvalidCommand(msg.into()).into();
+        }
+        let identity = session.identity();
+        let store = &self.inner.store;
+        let stage = || {
+            let staged = Staged::new(store);
+            let responses: Vec<_> = cmds
+                .iter()
+                .map(|cmd| self.stage(cmd.clone(), &staged, identity))
+                .collect();
+            (staged, responses)
+        };
+
+        let (staged, responses) = {
+            let _read = self.read_lock();
+            stage()
+        };
+        let _write = self.write_lock();
+        match watching.map(|w| w.is_changed(store)) {
+            Some(Ok(true)) => {
+                let msg = "watched keys changed, transaction discarded";
+                return KvError::Conflict(msg.into()).into();
+            }
+            Some(Err(e)) => return e.into(),
+            _ => {}
+        }
+        let (staged, responses) = match staged.is_stale() {
+            Ok(false) => (staged, responses),
+            Ok(true) => stage(),
+            Err(e) => return e.into(),
+        };
+        if let Some((i, res)) = responses.iter().enumerate().find(|(_, r)| failed(r)) {
+            let mut res = res.clone();
+            res.message = format!("EXEC aborted, command {} failed: {}", i + 1, res.message);
+            return res;
+        }
+        if let Err(e) = store.commit(staged.into_writes()) {
+            return e.into();
+        }
+        cmds.into_iter()
+            .zip(responses)
+            .map(|(cmd, res)| self.complete(cmd, res))
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    // 在 Staged 上执行一个排队的命令，BATCH 里的命令依次执行
+    fn stage(
+        &self,
+        cmd: CommandRequest,
+        staged: &Staged<'_, Store>,
+        identity: Option<&str>,
+    ) -> CommandResponse {
+        if let Err(e) = self.check(&cmd, identity) {
+            return e.into();
+        }
+        match cmd.request_data {
+            Some(RequestData::Batch(v)) => v
+                .requests
+                .into_iter()
+                .map(|cmd| self.stage(cmd, staged, identity))
+                .collect::<Vec<_>>()
+                .into(),
+            request_data => self
+                .dispatch(
+                    CommandRequest {
+                        request_data,
+                        ..cmd
+                    },
+                    staged,
+                )
+                .into(),
+        }
+    }
+
+    // 提交之后为排队的命令补上 execute_locked 在执行前后做的事
+    fn complete(&self, cmd: CommandRequest, res: CommandResponse) -> CommandResponse {
+        match cmd.request_data {
+            Some(RequestData::Batch(v)) => {
+                let responses: Vec<_> = v
+                    .requests
+                    .into_iter()
+                    .zip(res.responses)
+                    .map(|(cmd, res)| self.complete(cmd, res))
+                    .collect();
+                let mut res = CommandResponse::from(responses);
+                res.version = PROTOCOL_VERSION;
+                res
+            }
+            _ => {
+                let pending = self.begin(&cmd);
+                self.finish_response(pending, res)
+            }
+        }
+    }
+
+    #[cfg(not(feature = "plugin"))]
+    fn dispatch(&self, cmd: CommandRequest, store: &impl Storage) -> Reply {
+        dispatch(cmd, store)
+    }
+
+    // 插件的 before hook 可以拒绝请求，自定义命令交给注册了它的插件处理
+    #[cfg(feature = "plugin")]
+    fn dispatch(&self, cmd: CommandRequest, store: &impl Storage) -> Reply {
+        let plugins = &self.inner.plugins;
+        if let Some(res) = plugins.iter().find_map(|p| p.before(self, &cmd)) {
+            return res.into();
+        }
+
+        match cmd.request_data {
+            Some(RequestData::Custom(v)) => {
+                match plugins
+                    .iter()
+                    .find(|p| p.name() == v.name && p.has_command())
+                {
+                    Some(plugin) => plugin.command(self, v).into(),
+                    None => KvError::InvalidCommand(format!("Unknown command {}", v.name)).into(),
+                }
+            }
+            _ => dispatch(cmd, store),
+        }
+    }
+}
+
+impl<Store: Storage + Send + Sync + 'static> Service<Store> {
+    /// 在后台每隔 interval 删除一次过期的 key，返回的 Sweeper drop 的时候停止
+    pub fn spawn_expiry_sweeper(&self, interval: Duration) -> Result<Sweeper, KvError> {
+        let service = self.clone();
+        // 和普通的命令一样拿读锁，不会删掉 EXEC 正在提交的 key
+        Sweeper::spawn("kv-expiry", interval, move || {
+            let _read = service.read_lock();
+            service.store().purge_expired()
+        })
+    }
+
+    /// 异步的 execute。设置了 BlockingPool 时在它的线程里执行，
+    /// 队列满了返回 KvError::Busy 的 response
+    pub async fn execute_async(&self, cmd: CommandRequest) -> CommandResponse {
+        let pool = match self.pool() {
+            Some(pool) => pool,
+            None => return self.execute(cmd),
+        };
+        let service = self.clone();
+        pool.run(move || service.execute(cmd))
+            .await
+            .unwrap_or_else(|e| e.into())
+    }
 }
 
 impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
@@ -92,12 +699,145 @@ impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
     }
 }
 
-// 从 Request中得到Response, 目前处理HGET/HGETALL/HSET
-fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
+/// MULTI 之后最多可以排队的命令个数，BATCH 里的命令分别计算。
+/// EXEC 提交时拿着写锁，不能让一个事务占用太久
+pub const MAX_QUEUED: usize = 1024;
+
+// 排队之后 EXEC 的时候是不是能在 Staged 上执行。连接上的状态、整个 table 的操作和
+// 插件的命令都不能暂存，备份和恢复也不行
+fn check_queued(cmd: &CommandRequest, queued: &[CommandRequest]) -> Result<(), KvError> {
+    if queued.iter().chain([cmd]).map(queued_len).sum::<usize>() > MAX_QUEUED {
+        return Err(KvError::Busy(format!(
+            "at most {} commands can be queued in MULTI",
+            MAX_QUEUED
+        )));
+    }
+    match &cmd.request_data {
+        Some(RequestData::Batch(v)) => v.requests.iter().try_for_each(|c| check_queued(c, &[])),
+        Some(
+            RequestData::Info(_)
+            | RequestData::Backup(_)
+            | RequestData::Restore(_)
+            | RequestData::Hscan(_)
+            | RequestData::Subscribe(_)
+            | RequestData::Unsubscribe(_)
+            | RequestData::Publish(_)
+            | RequestData::Unwatch(_)
+            | RequestData::Hflushall(_)
+            | RequestData::Custom(_),
+        ) => Err(KvError::InvalidCommand(
+            "only key commands can be queued in MULTI".into(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn queued_len(cmd: &CommandRequest) -> usize {
+    match &cmd.request_data {
+        Some(RequestData::Batch(v)) => v.requests.iter().map(queued_len).sum(),
+        _ => 1,
+    }
+}
+
+// 排队的命令失败了，EXEC 不提交。NotFound 是读不存在的 key 的正常结果
+fn failed(res: &CommandResponse) -> bool {
+    let ok = (200..300).contains(&res.status) || res.status == 404;
+    !ok || res.responses.iter().any(failed)
+}
+
+// 执行命令之前记下的，执行之后要用到
+struct Pending {
+    start: Instant,
+    class: CommandClass,
+    changes: Option<KeyChanges>,
+    flush: bool,
+}
+
+// 事务本身的命令不排队，MULTI 里的 WATCH 直接报错
+fn is_transaction(cmd: &CommandRequest) -> bool {
+    matches!(
+        cmd.request_data,
+        Some(
+            RequestData::Multi(_)
+                | RequestData::Exec(_)
+                | RequestData::Discard(_)
+                | RequestData::Watch(_)
+        )
+    )
+}
+
+/// 带着这个扩展的写命令执行成功之后，先调用 Storage::flush 再返回，
+/// 见 CommandRequest::with_flush
+pub const FLUSH_EXTENSION: &str = "flush";
+
+/// 服务器认识的扩展
+const EXTENSIONS: &[&str] = &[PRIORITY_EXTENSION, FLUSH_EXTENSION];
+
+// 不认识的扩展直接忽略，除非它是 critical 的。更新版本的客户端发来的新命令
+// 我们解析不出 request_data，明确告诉它服务器不支持，而不是当成一个空的请求
+fn check_protocol(cmd: &CommandRequest) -> Result<(), KvError> {
+    for ext in &cmd.extensions {
+        if EXTENSIONS.contains(&ext.name.as_str()) {
+            continue;
+        }
+        if ext.critical {
+            return Err(KvError::Unsupported(format!("extension {}", ext.name)));
+        }
+        debug!("Ignore unknown extension {}", ext.name);
+    }
+
+    if cmd.request_data.is_none() && cmd.version > PROTOCOL_VERSION {
+        return Err(KvError::Unsupported(format!(
+            "command from protocol version {}, server version is {}",
+            cmd.version, PROTOCOL_VERSION
+        )));
+    }
+    Ok(())
+}
+
+// 从 Request中得到Response, 处理所有内置的命令
+fn dispatch(cmd: CommandRequest, store: &impl Storage) -> Reply {
     match cmd.request_data {
         Some(RequestData::Hget(v)) => v.execute(store),
         Some(RequestData::Hgetall(v)) => v.execute(store),
         Some(RequestData::Hset(v)) => v.execute(store),
+        Some(RequestData::Hdel(v)) => v.execute(store),
+        Some(RequestData::Hmget(v)) => v.execute(store),
+        Some(RequestData::Hmset(v)) => v.execute(store),
+        Some(RequestData::Hmdel(v)) => v.execute(store),
+        Some(RequestData::Hexist(v)) => v.execute(store),
+        Some(RequestData::Hmexist(v)) => v.execute(store),
+        Some(RequestData::Hincrby(v)) => v.execute(store),
+        Some(RequestData::Hsetnx(v)) => v.execute(store),
+        Some(RequestData::Hgetdel(v)) => v.execute(store),
+        Some(RequestData::Hgetset(v)) => v.execute(store),
+        Some(RequestData::Hexpire(v)) => v.execute(store),
+        Some(RequestData::Hpersist(v)) => v.execute(store),
+        Some(RequestData::Hexpireat(v)) => v.execute(store),
+        Some(RequestData::Hkeys(v)) => v.execute(store),
+        Some(RequestData::Hvals(v)) => v.execute(store),
+        Some(RequestData::Hlen(v)) => v.execute(store),
+        Some(RequestData::Hdroptable(v)) => v.execute(store),
+        Some(RequestData::Htables(v)) => v.execute(store),
+        Some(RequestData::Hflushall(v)) => v.execute(store),
+        Some(RequestData::Hstats(v)) => v.execute(store),
+        Some(RequestData::Httl(v)) => v.execute(store),
+        Some(RequestData::Hgetver(v)) => v.execute(store),
+        Some(RequestData::Hsetver(v)) => v.execute(store),
+        Some(RequestData::Hrange(v)) => v.execute(store),
+        Some(RequestData::Hmeta(v)) => v.execute(store),
+        Some(RequestData::Hgetat(v)) => v.execute(store),
+        Some(RequestData::Hquery(v)) => v.execute(store),
+        Some(RequestData::Admin(v)) => v.execute(store),
+        Some(RequestData::Undelete(v)) => v.execute(store),
+        Some(RequestData::PurgeTrash(v)) => v.execute(store),
+        Some(RequestData::CloneTable(v)) => v.execute(store),
+        Some(RequestData::Move(v)) => v.execute(store),
+        // PING 不经过存储，存储变慢的时候也能测出网络本身的 RTT
+        Some(RequestData::Ping(v)) => match v.payload.is_empty() {
+            true => Value::from("PONG").into(),
+            false => Value::from(v.payload).into(),
+        },
         None => KvError::InvalidCommand("Request has no data".into()).into(),
         _ => KvError::Internal("Not implemented".into()).into(),
     }
@@ -133,6 +873,25 @@ impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg)> {
     }
 }
 
+// 测试成功的返回的结果
+#[cfg(test)]
+pub fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
+    res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(res.status, 200);
+    assert_eq!(res.message, "");
+    assert_eq!(res.values, values);
+    assert_eq!(res.pairs, pairs);
+}
+
+// 测试失败返回的结果
+#[cfg(test)]
+pub fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
+    assert_eq!(res.status, code);
+    assert!(res.message.contains(msg));
+    assert_eq!(res.values, &[]);
+    assert_eq!(res.pairs, &[]);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -161,6 +920,25 @@ mod tests {
         assert_res_ok(res, &["v1".into()], &[]);
     }
 
+    #[tokio::test]
+    async fn execute_async_should_run_in_pool() {
+        let pool = BlockingPool::new(1, 4).unwrap();
+        // 命令在 kv-blocking 线程里执行，否则 hook 里 panic，返回 500
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_received(|_| {
+                let name = thread::current().name().map(String::from);
+                assert!(name.unwrap_or_default().starts_with("kv-blocking"));
+            })
+            .with_pool(pool)
+            .into();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        service.execute_async(cmd).await;
+        let res = service
+            .execute_async(CommandRequest::new_hget("t1", "k1"))
+            .await;
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
     #[test]
     fn event_registration_should_work() {
         fn b(cmd: &CommandRequest) {
@@ -185,31 +963,519 @@ mod tests {
             .into();
 
         let res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
-        assert_eq!(res.status, StatusCode::CREATED.as_u16() as _);
+        assert_eq!(res.status, StatusCode::CREATED.as_u16() as u32);
         assert_eq!(res.message, "");
         assert_eq!(res.values, vec![Value::default()]);
     }
-}
 
-use crate::command_request::RequestData;
-#[cfg(test)]
-use crate::{Kvpair, Value};
+    #[test]
+    fn authorize_should_use_session_identity() {
+        // 只有 admin 可以写，其他人只能读
+        fn acl(identity: Option<&str>, cmd: &CommandRequest) -> Result<(), KvError> {
+            match (identity, CommandClass::from(cmd)) {
+                (Some("admin"), _) | (_, CommandClass::Read) => Ok(()),
+                _ => Err(KvError::Forbidden(format!("{:?} cannot write", identity))),
+            }
+        }
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_authorize(acl)
+            .into();
 
-// 测试成功的返回的结果
-#[cfg(test)]
-pub fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
-    res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    assert_eq!(res.status, 200);
-    assert_eq!(res.message, "");
-    assert_eq!(res.values, values);
-    assert_eq!(res.pairs, pairs);
-}
+        let mut admin = Session::new().with_identity(Some("admin".into()));
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert_res_ok(
+            service.execute_in(cmd, &mut admin),
+            &[Value::default()],
+            &[],
+        );
 
-// 测试失败返回的结果
-#[cfg(test)]
-pub fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
-    assert_eq!(res.status, code);
-    assert!(res.message.contains(msg));
-    assert_eq!(res.values, &[]);
-    assert_eq!(res.pairs, &[]);
+        let mut guest = Session::new().with_identity(Some("guest".into()));
+        let cmd = CommandRequest::new_hset("t1", "k1", "v2".into());
+        assert_res_error(service.execute_in(cmd, &mut guest), 403, "guest");
+        let res = service.execute(CommandRequest::new_hdel("t1", "k1"));
+        assert_res_error(res, 403, "None");
+        let res = service.execute_in(CommandRequest::new_hget("t1", "k1"), &mut guest);
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn service_should_handle_protocol_extensions() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+
+        // 不认识的扩展被忽略
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into())
+            .with_extension(Extension::new("x-trace", "abc", false));
+        let res = service.execute(cmd);
+        assert_eq!(res.version, PROTOCOL_VERSION);
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        // 不认识的 critical 扩展会拒绝整个请求
+        let cmd =
+            CommandRequest::new_hget("t1", "k1").with_extension(Extension::new("x-txn", "", true));
+        let res = service.execute(cmd);
+        assert_res_error(res, 501, "x-txn");
+
+        // 新版本的命令我们解析不出来
+        let cmd = CommandRequest {
+            version: PROTOCOL_VERSION + 1,
+            ..Default::default()
+        };
+        assert_res_error(service.execute(cmd), 501, "version");
+        let res = service.execute(CommandRequest::default());
+        assert_res_error(res, 400, "no data");
+    }
+
+    #[test]
+    fn ping_should_not_touch_storage() {
+        // 任何存储操作都会 panic
+        struct NoStorage;
+        impl Storage for NoStorage {
+            fn get(&self, _: &str, _: &str) -> Result<Option<Value>, KvError> {
+                unreachable!()
+            }
+            fn set(&self, _: &str, _: String, _: Value) -> Result<Option<Value>, KvError> {
+                unreachable!()
+            }
+            fn contains(&self, _: &str, _: &str) -> Result<bool, KvError> {
+                unreachable!()
+            }
+            fn del(&self, _: &str, _: &str) -> Result<Option<Value>, KvError> {
+                unreachable!()
+            }
+            fn get_all(&self, _: &str) -> Result<Vec<Kvpair>, KvError> {
+                unreachable!()
+            }
+            fn get_iter(
+                &self,
+                _: &str,
+            ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+                unreachable!()
+            }
+        }
+
+        let service: Service<_> = ServiceInner::new(NoStorage).into();
+        let res = service.execute(CommandRequest::new_ping(""));
+        assert_res_ok(res, &["PONG".into()], &[]);
+        let res = service.execute(CommandRequest::new_ping("hello"));
+        assert_res_ok(res, &["hello".into()], &[]);
+    }
+
+    #[test]
+    fn flush_extension_should_flush_after_writes() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // 记录 flush 的次数，超过第三个字段之后 flush 失败
+        struct Flushes(MemTable, AtomicUsize, usize);
+        impl Storage for Flushes {
+            fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+                self.0.get(table, key)
+            }
+            fn set(
+                &self,
+                table: &str,
+                key: String,
+                value: Value,
+            ) -> Result<Option<Value>, KvError> {
+                self.0.set(table, key, value)
+            }
+            fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+                self.0.contains(table, key)
+            }
+            fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+                self.0.del(table, key)
+            }
+            fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+                self.0.get_all(table)
+            }
+            fn get_iter(
+                &self,
+                table: &str,
+            ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+                self.0.get_iter(table)
+            }
+            fn flush(&self) -> Result<(), KvError> {
+                match self.1.fetch_add(1, Ordering::Relaxed) < self.2 {
+                    true => Ok(()),
+                    false => Err(KvError::Internal("disk is gone".into())),
+                }
+            }
+        }
+
+        let store = Flushes(MemTable::new(), AtomicUsize::new(0), 1);
+        let service: Service<_> = ServiceInner::new(store).into();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let res = service.execute(CommandRequest::new_hget("t1", "k1").with_flush());
+        assert_res_ok(res, &["v1".into()], &[]);
+        assert_eq!(service.store().1.load(Ordering::Relaxed), 0);
+
+        let res = service.execute(CommandRequest::new_hset("t1", "k1", "v2".into()).with_flush());
+        assert_res_ok(res, &["v1".into()], &[]);
+        assert_eq!(service.store().1.load(Ordering::Relaxed), 1);
+
+        // 写盘失败时告诉客户端，虽然写入已经生效了
+        let res = service.execute(CommandRequest::new_hset("t1", "k1", "v3".into()).with_flush());
+        assert_res_error(res, 500, "not flushed");
+    }
+
+    #[test]
+    fn hgetall_chunks_should_be_read_lazily() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // 记录从 get_iter 里读出来多少个 kv pair
+        struct Pulled(MemTable, Arc<AtomicUsize>);
+        impl Storage for Pulled {
+            fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+                self.0.get(table, key)
+            }
+            fn set(
+                &self,
+                table: &str,
+                key: String,
+                value: Value,
+            ) -> Result<Option<Value>, KvError> {
+                self.0.set(table, key, value)
+            }
+            fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+                self.0.contains(table, key)
+            }
+            fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+                self.0.del(table, key)
+            }
+            fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+                self.0.get_all(table)
+            }
+            fn get_iter(
+                &self,
+                table: &str,
+            ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+                let pulled = self.1.clone();
+                let iter = self.0.get_iter(table)?;
+                Ok(Box::new(iter.inspect(move |_| {
+                    pulled.fetch_add(1, Ordering::Relaxed);
+                })))
+            }
+        }
+
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let service: Service<_> = ServiceInner::new(Pulled(MemTable::new(), pulled.clone())).into();
+        for i in 0..100 {
+            service.execute(CommandRequest::new_hset("t1", format!("k{}", i), i.into()));
+        }
+
+        let mut session = Session::new();
+        let reply =
+            service.execute_reply(CommandRequest::new_hgetall_chunked("t1", 10), &mut session);
+        let mut chunks = reply.into_iter();
+        let first = chunks.next().unwrap();
+        assert_eq!(first.pairs.len(), 10);
+        assert!(first.more);
+        // 只多读了一个用来判断后面还有没有
+        assert_eq!(pulled.load(Ordering::Relaxed), 11);
+
+        assert_eq!(chunks.map(|c| c.pairs.len()).sum::<usize>(), 90);
+        assert_eq!(pulled.load(Ordering::Relaxed), 100);
+    }
+
+    #[test]
+    fn backup_and_restore_should_work() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let res = service.execute(CommandRequest::new_backup("b1"));
+        assert_res_error(res, 403, "disabled");
+
+        let dir = tempfile::tempdir().unwrap();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .backup_dir(dir.path())
+            .into();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        service.execute(CommandRequest::new_hset("t2", "k2", 2.into()));
+        let res = service.execute(CommandRequest::new_backup("b1"));
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = service.execute(CommandRequest::new_backup("../b1"));
+        assert_res_error(res, 400, "bad backup name");
+
+        let restored: Service = ServiceInner::new(MemTable::new())
+            .backup_dir(dir.path())
+            .into();
+        let res = restored.execute(CommandRequest::new_restore("b1"));
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = restored.execute(CommandRequest::new_hget("t2", "k2"));
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = restored.execute(CommandRequest::new_restore("b2"));
+        assert_res_error(res, 404, "b2");
+
+        // RESTORE 可能改了任何 key，watch 的 EXEC 失败
+        let mut session = Session::new();
+        let watch = CommandRequest::new_watch("t1", vec!["k1".into()]);
+        restored.execute_in(watch, &mut session);
+        restored.execute(CommandRequest::new_restore("b1"));
+        restored.execute_in(CommandRequest::new_multi(), &mut session);
+        let res = restored.execute_in(CommandRequest::new_exec(), &mut session);
+        assert_res_error(res, 409, "watched keys changed");
+    }
+
+    #[test]
+    fn info_should_report_server_stats() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let _conn = service.stats().connect();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        service.execute(CommandRequest::new_hget("t1", "k2"));
+
+        let res = service.execute(CommandRequest::new_info());
+        assert_eq!(res.status, 200);
+        let get = |name: &str| {
+            res.pairs
+                .iter()
+                .find(|p| p.key == name)
+                .unwrap()
+                .value
+                .clone()
+        };
+        assert_eq!(get("backend"), Some("MemTable".into()));
+        assert_eq!(get("connections"), Some(1.into()));
+        assert_eq!(get("commands.read"), Some(1.into()));
+        assert_eq!(get("commands.write"), Some(1.into()));
+        assert_eq!(get("errors"), Some(1.into()));
+        assert_eq!(get("storage.reads"), Some(1.into()));
+        assert_eq!(get("storage.misses"), Some(1.into()));
+        assert_eq!(get("storage.writes"), Some(1.into()));
+        assert_eq!(get("storage.keys"), Some(1.into()));
+    }
+
+    #[test]
+    fn flushall_should_be_disabled_by_default() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let res = service.execute(CommandRequest::new_hflushall());
+        assert_res_error(res, 403, "disabled");
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        let service: Service = ServiceInner::new(MemTable::default())
+            .allow_flushall()
+            .into();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let res = service.execute(CommandRequest::new_hflushall());
+        assert_res_ok(res, &[1.into()], &[]);
+    }
+
+    #[test]
+    fn publish_should_reach_subscribed_sessions() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let res = service.execute(CommandRequest::new_subscribe("news"));
+        assert_res_error(res, 501, "SUBSCRIBE");
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+        let mut session = service.session(tx);
+        let res = service.execute_in(CommandRequest::new_subscribe("news"), &mut session);
+        let id = res.values[0].clone();
+
+        let res = service.execute(CommandRequest::new_publish("news", "hello".into()));
+        assert_res_ok(res, &[1.into()], &[]);
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(Value::from(msg.subscription as i64), id);
+        assert_eq!(msg.pairs, vec![Kvpair::new("news", "hello".into())]);
+
+        // 连接断开，订阅随 session 一起取消
+        drop(session);
+        let res = service.execute(CommandRequest::new_publish("news", "hello".into()));
+        assert_res_ok(res, &[0.into()], &[]);
+    }
+
+    #[test]
+    fn exec_should_run_queued_commands() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let mut session = Session::new();
+        let res = service.execute_in(CommandRequest::new_exec(), &mut session);
+        assert_res_error(res, 400, "without MULTI");
+
+        let res = service.execute_in(CommandRequest::new_multi(), &mut session);
+        assert_res_ok(res, &["OK".into()], &[]);
+        let res = service.execute_in(CommandRequest::new_multi(), &mut session);
+        assert_res_error(res, 400, "nested");
+        let res = service.execute_in(
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            &mut session,
+        );
+        assert_res_ok(res, &["QUEUED".into()], &[]);
+        service.execute_in(CommandRequest::new_hgetdel("t1", "missing"), &mut session);
+        service.execute_in(CommandRequest::new_hget("t1", "k1"), &mut session);
+        // 还没有执行
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_error(res, 404, "Not found");
+
+        let res = service.execute_in(CommandRequest::new_exec(), &mut session);
+        assert_res_ok(res.clone(), &[], &[]);
+        let status: Vec<_> = res.responses.iter().map(|r| r.status).collect();
+        assert_eq!(status, [200, 404, 200]);
+        assert_eq!(res.responses[2].values, ["v1".into()]);
+        assert!(!session.in_transaction());
+
+        service.execute_in(CommandRequest::new_multi(), &mut session);
+        service.execute_in(CommandRequest::new_hdel("t1", "k1"), &mut session);
+        let res = service.execute_in(CommandRequest::new_discard(), &mut session);
+        assert_res_ok(res, &["OK".into()], &[]);
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn exec_should_apply_all_or_nothing() {
+        fn check<Store: Storage + 'static>(service: Service<Store>) {
+            let mut session = Session::new();
+            service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+            service.execute_in(CommandRequest::new_multi(), &mut session);
+            service.execute_in(
+                CommandRequest::new_hset("t1", "k2", "v2".into()),
+                &mut session,
+            );
+            service.execute_in(CommandRequest::new_hdel("t2", "k1"), &mut session);
+            // k1 不是整数，整个事务都不提交
+            service.execute_in(CommandRequest::new_hincrby("t1", "k1", 1), &mut session);
+            let res = service.execute_in(CommandRequest::new_exec(), &mut session);
+            assert_res_error(res, 400, "EXEC aborted, command 3 failed");
+            let res = service.execute(CommandRequest::new_hget("t1", "k2"));
+            assert_res_error(res, 404, "Not found");
+
+            service.execute_in(CommandRequest::new_multi(), &mut session);
+            service.execute_in(
+                CommandRequest::new_hset("t1", "k2", "v2".into()),
+                &mut session,
+            );
+            service.execute_in(CommandRequest::new_hincrby("t2", "n", 2), &mut session);
+            service.execute_in(CommandRequest::new_hdel("t1", "k1"), &mut session);
+            let res = service.execute_in(CommandRequest::new_exec(), &mut session);
+            assert_eq!(res.responses[1].values, [2.into()]);
+            assert_eq!(res.responses[2].values, ["v1".into()]);
+            let res = service.execute(CommandRequest::new_hgetall("t1"));
+            assert_res_ok(res, &[], &[Kvpair::new("k2", "v2".into())]);
+        }
+
+        check(ServiceInner::new(MemTable::new()).into());
+        let dir = tempfile::tempdir().unwrap();
+        check(ServiceInner::new(SledDb::new(dir.path()).unwrap()).into());
+    }
+
+    #[test]
+    fn exec_should_fail_after_rejected_commands() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let mut session = Session::new();
+        service.execute_in(CommandRequest::new_multi(), &mut session);
+        service.execute_in(
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            &mut session,
+        );
+        let res = service.execute_in(CommandRequest::new_info(), &mut session);
+        assert_res_error(res, 400, "can be queued in MULTI");
+        let res = service.execute_in(CommandRequest::new_exec(), &mut session);
+        assert_res_error(res, 400, "previous errors");
+        assert!(!service.store().contains("t1", "k1").unwrap());
+
+        // 排队的命令有上限，BATCH 里的分别计算
+        service.execute_in(CommandRequest::new_multi(), &mut session);
+        let batch = (0..MAX_QUEUED)
+            .map(|i| CommandRequest::new_hset("t1", format!("k{}", i), (i as i64).into()))
+            .collect();
+        let res = service.execute_in(CommandRequest::new_batch(batch), &mut session);
+        assert_res_ok(res, &["QUEUED".into()], &[]);
+        let res = service.execute_in(CommandRequest::new_hget("t1", "k1"), &mut session);
+        assert_res_error(res, 503, "at most");
+        service.execute_in(CommandRequest::new_discard(), &mut session);
+        assert!(!session.in_transaction());
+    }
+
+    #[test]
+    fn batch_should_execute_in_order() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let res = service.execute(CommandRequest::new_batch(vec![
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hget("t1", "k1"),
+            CommandRequest::new_hget("t1", "k2"),
+        ]));
+        assert_res_ok(res.clone(), &[], &[]);
+        let status: Vec<_> = res.responses.iter().map(|r| r.status).collect();
+        assert_eq!(status, [200, 200, 404]);
+        assert_eq!(res.responses[1].values, ["v1".into()]);
+
+        // BATCH 里可以有事务
+        let mut session = Session::new();
+        let res = service.execute_in(
+            CommandRequest::new_batch(vec![
+                CommandRequest::new_multi(),
+                CommandRequest::new_hdel("t1", "k1"),
+                CommandRequest::new_exec(),
+            ]),
+            &mut session,
+        );
+        assert_eq!(res.responses[1].values, ["QUEUED".into()]);
+        assert_eq!(res.responses[2].responses[0].values, ["v1".into()]);
+    }
+
+    #[test]
+    fn exec_should_fail_when_watched_keys_changed() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let mut session = Session::new();
+        let watch = || CommandRequest::new_watch("t1", vec!["k1".into()]);
+        let transaction = |session: &mut Session| {
+            service.execute_in(CommandRequest::new_multi(), session);
+            let res = service.execute_in(watch(), session);
+            assert_res_error(res, 400, "inside MULTI");
+            service.execute_in(CommandRequest::new_hincrby("t1", "k1", 1), session);
+            service.execute_in(CommandRequest::new_exec(), session)
+        };
+
+        // 别的连接改了 watch 的 key
+        service.execute_in(watch(), &mut session);
+        service.execute(CommandRequest::new_hset("t1", "k1", 10.into()));
+        let res = transaction(&mut session);
+        assert_res_error(res, 409, "watched keys changed");
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_ok(res, &[10.into()], &[]);
+
+        // 改的是别的 key，EXEC 之后 watch 自动取消
+        service.execute_in(watch(), &mut session);
+        service.execute(CommandRequest::new_hset("t1", "k2", 10.into()));
+        let res = transaction(&mut session);
+        assert_eq!(res.responses[0].values, [11.into()]);
+        assert!(service.inner.watches.is_empty());
+
+        // 改过期时间也算改动，包括马上删掉 key 的 HEXPIREAT
+        for cmd in [
+            CommandRequest::new_hexpire("t1", "k1", 60_000),
+            CommandRequest::new_hpersist("t1", "k1"),
+            CommandRequest::new_hexpireat("t1", "k1", 1),
+        ] {
+            service.execute_in(watch(), &mut session);
+            service.execute(cmd);
+            let res = transaction(&mut session);
+            assert_res_error(res, 409, "watched keys changed");
+        }
+    }
+
+    #[test]
+    fn exec_should_fail_when_watched_keys_changed_outside_commands() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let mut session = Session::new();
+        let watch = || CommandRequest::new_watch("t1", vec!["k1".into()]);
+        service.execute(CommandRequest::new_hset("t1", "k1", 1.into()));
+
+        // MULTI 之后 key 自己过期了，没有任何命令碰过它
+        service.execute(CommandRequest::new_hexpire("t1", "k1", 50));
+        service.execute_in(watch(), &mut session);
+        service.execute_in(CommandRequest::new_multi(), &mut session);
+        service.execute_in(CommandRequest::new_hset("t1", "k2", 1.into()), &mut session);
+        std::thread::sleep(Duration::from_millis(100));
+        let res = service.execute_in(CommandRequest::new_exec(), &mut session);
+        assert_res_error(res, 409, "watched keys changed");
+        let res = service.execute(CommandRequest::new_hget("t1", "k2"));
+        assert_res_error(res, 404, "Not found");
+
+        // 直接 bulk load 到存储里
+        service.execute_in(watch(), &mut session);
+        let mut pairs = std::iter::once(Kvpair::new("k1", 2.into()));
+        service.store().bulk_load("t1", &mut pairs).unwrap();
+        service.execute_in(CommandRequest::new_multi(), &mut session);
+        let res = service.execute_in(CommandRequest::new_exec(), &mut session);
+        assert_res_error(res, 409, "watched keys changed");
+    }
 }
@@ -0,0 +1,410 @@
+//! WASM 插件
+//!
+//! 插件是一个 WASM module，可以注册自定义命令（`Custom`），也可以作为 hook
+//! 在命令执行前后被调用。插件只能通过下面受限的 host API 访问存储。
+//!
+//! 插件需要导出：
+//! - `memory`
+//! - `alloc(len: i32) -> i32`：在插件内存中分配 len 字节，host 用它回传数据
+//! - `command(ptr: i32, len: i32) -> i64`（可选）：输入是 protobuf 编码的 `Custom`，
+//!   返回 protobuf 编码的 `CommandResponse`，位置打包成 `(ptr << 32) | len`
+//! - `before(ptr: i32, len: i32) -> i32`（可选）：输入是编码后的 `CommandRequest`，
+//!   返回 0 继续执行，返回 400..600 之间的 status 拒绝这个请求，别的值算插件出错
+//! - `after(ptr: i32, len: i32)`（可选）：输入是编码后的 `CommandResponse`
+//!
+//! host 在 `kv` 名字空间下提供：
+//! - `get(table_ptr, table_len, key_ptr, key_len) -> i64`：返回打包的编码后的 `Value`，
+//!   key 不存在返回 -1
+//! - `set(table_ptr, table_len, key_ptr, key_len, value_ptr, value_len) -> i32`：
+//!   value 是编码后的 `Value`，覆盖了旧值返回 1，否则返回 0
+//! - `del(table_ptr, table_len, key_ptr, key_len) -> i32`：删除了返回 1，否则返回 0
+//! - `log(level, ptr, len)`：level 0-4 分别对应 error/warn/info/debug/trace
+
+use std::convert::TryInto;
+use std::path::Path;
+
+use prost::Message;
+use tracing::{debug, error, info, trace, warn};
+use wasmtime::{Caller, Config, Engine, Extern, Instance, InstancePre, Linker, Module, Store};
+
+use crate::*;
+
+/// 每次调用插件最多可以消耗的 fuel，防止插件死循环卡住 server
+const PLUGIN_FUEL: u64 = 10_000_000;
+
+/// 一个编译好的 WASM 插件
+pub struct Plugin {
+    name: String,
+    engine: Engine,
+    module: Module,
+    // 链接好 host API 的 module，每次调用只需要在新的 Store 里实例化
+    pre: InstancePre<HostData>,
+}
+
+/// host API 通过它访问 Service 的存储，这样 Linker 不用跟着存储的类型变
+trait Host {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError>;
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+}
+
+impl<S: Storage> Host for Service<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.store.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.inner.store.set(table, key, value)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.store.del(table, key)
+    }
+}
+
+type HostData = Box<dyn Host>;
+
+impl Plugin {
+    /// 从 WASM 二进制（或者 wat 文本）创建插件
+    pub fn new(name: impl Into<String>, wasm: impl AsRef<[u8]>) -> Result<Self, KvError> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(plugin_error)?;
+        let module = Module::new(&engine, wasm).map_err(plugin_error)?;
+        let pre = host_api(&engine)
+            .and_then(|linker| linker.instantiate_pre(&module))
+            .map_err(plugin_error)?;
+
+        Ok(Self {
+            name: name.into(),
+            engine,
+            module,
+            pre,
+        })
+    }
+
+    /// 从文件加载插件
+    pub fn from_file(name: impl Into<String>, path: impl AsRef<Path>) -> Result<Self, KvError> {
+        Self::new(name, std::fs::read(path)?)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn exports(&self, name: &str) -> bool {
+        self.module.get_export(name).is_some()
+    }
+
+    pub(crate) fn has_command(&self) -> bool {
+        self.exports("command")
+    }
+
+    /// 执行插件注册的自定义命令
+    pub(crate) fn command<S: Storage + 'static>(
+        &self,
+        service: &Service<S>,
+        cmd: Custom,
+    ) -> CommandResponse {
+        let result = self
+            .call::<_, i64>(service, "command", &cmd.encode_to_vec())
+            .and_then(|(mut store, instance, packed)| {
+                let data = read_packed(&mut store, &instance, packed)?;
+                Ok(CommandResponse::decode(&data[..])?)
+            });
+
+        match result {
+            Ok(res) => res,
+            Err(e) => plugin_error(e).into(),
+        }
+    }
+
+    /// 执行 before hook，如果插件拒绝了这个请求，返回要发给客户端的 response
+    pub(crate) fn before<S: Storage + 'static>(
+        &self,
+        service: &Service<S>,
+        cmd: &CommandRequest,
+    ) -> Option<CommandResponse> {
+        if !self.exports("before") {
+            return None;
+        }
+
+        match self.call::<_, i32>(service, "before", &cmd.encode_to_vec()) {
+            Ok((_, _, 0)) => None,
+            Ok((_, _, status)) if (400..600).contains(&status) => Some(CommandResponse {
+                status: status as _,
+                message: format!("Rejected by plugin {}", self.name),
+                ..Default::default()
+            }),
+            Ok((_, _, status)) => Some(
+                KvError::Internal(format!(
+                    "Plugin {} before hook returned invalid status {}",
+                    self.name, status
+                ))
+                .into(),
+            ),
+            Err(e) => Some(plugin_error(e).into()),
+        }
+    }
+
+    /// 执行 after hook
+    pub(crate) fn after<S: Storage + 'static>(&self, service: &Service<S>, res: &CommandResponse) {
+        if !self.exports("after") {
+            return;
+        }
+
+        if let Err(e) = self.call::<_, ()>(service, "after", &res.encode_to_vec()) {
+            warn!("Plugin {} after hook failed: {}", self.name, e);
+        }
+    }
+
+    /// 实例化插件，把 input 写进插件内存，然后调用导出的函数。
+    /// 每次调用用一个新的 Store，插件的内存和 fuel 不会带到下一次
+    fn call<S, R>(
+        &self,
+        service: &Service<S>,
+        func: &str,
+        input: &[u8],
+    ) -> wasmtime::Result<(Store<HostData>, Instance, R)>
+    where
+        S: Storage + 'static,
+        R: wasmtime::WasmResults,
+    {
+        let mut store: Store<HostData> = Store::new(&self.engine, Box::new(service.clone()));
+        store.set_fuel(PLUGIN_FUEL)?;
+        let instance = self.pre.instantiate(&mut store)?;
+
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let ptr = alloc.call(&mut store, input.len() as i32)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("plugin has no memory export"))?;
+        memory.write(&mut store, ptr as usize, input)?;
+
+        let f = instance.get_typed_func::<(i32, i32), R>(&mut store, func)?;
+        let result = f.call(&mut store, (ptr, input.len() as i32))?;
+        debug!("Plugin {} {} returned", self.name, func);
+        Ok((store, instance, result))
+    }
+}
+
+/// 插件可以调用的 host API
+fn host_api(engine: &Engine) -> wasmtime::Result<Linker<HostData>> {
+    let mut linker = Linker::new(engine);
+
+    linker.func_wrap(
+        "kv",
+        "get",
+        |mut caller: Caller<'_, HostData>, tp: i32, tl: i32, kp: i32, kl: i32| {
+            let table = read_str(&mut caller, tp, tl)?;
+            let key = read_str(&mut caller, kp, kl)?;
+            match caller.data().get(&table, &key)? {
+                Some(v) => write_packed(&mut caller, &v.encode_to_vec()),
+                None => Ok(-1),
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        "kv",
+        "set",
+        |mut caller: Caller<'_, HostData>, tp: i32, tl: i32, kp: i32, kl: i32, vp: i32, vl: i32| {
+            let table = read_str(&mut caller, tp, tl)?;
+            let key = read_str(&mut caller, kp, kl)?;
+            let value: Value = read(&mut caller, vp, vl)?.as_slice().try_into()?;
+            let old = caller.data().set(&table, key, value)?;
+            Ok(old.is_some() as i32)
+        },
+    )?;
+
+    linker.func_wrap(
+        "kv",
+        "del",
+        |mut caller: Caller<'_, HostData>, tp: i32, tl: i32, kp: i32, kl: i32| {
+            let table = read_str(&mut caller, tp, tl)?;
+            let key = read_str(&mut caller, kp, kl)?;
+            let old = caller.data().del(&table, &key)?;
+            Ok(old.is_some() as i32)
+        },
+    )?;
+
+    linker.func_wrap(
+        "kv",
+        "log",
+        |mut caller: Caller<'_, HostData>, level: i32, ptr: i32, len: i32| {
+            let msg = read_str(&mut caller, ptr, len)?;
+            match level {
+                0 => error!("[plugin] {}", msg),
+                1 => warn!("[plugin] {}", msg),
+                2 => info!("[plugin] {}", msg),
+                3 => debug!("[plugin] {}", msg),
+                _ => trace!("[plugin] {}", msg),
+            }
+            Ok(())
+        },
+    )?;
+
+    Ok(linker)
+}
+
+fn memory<T>(caller: &mut Caller<'_, T>) -> wasmtime::Result<wasmtime::Memory> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(m)) => Ok(m),
+        _ => Err(wasmtime::Error::msg("plugin has no memory export")),
+    }
+}
+
+fn read<T>(caller: &mut Caller<'_, T>, ptr: i32, len: i32) -> wasmtime::Result<Vec<u8>> {
+    let mut buf = vec![0; len as u32 as usize];
+    memory(caller)?.read(&*caller, ptr as u32 as usize, &mut buf)?;
+    Ok(buf)
+}
+
+fn read_str<T>(caller: &mut Caller<'_, T>, ptr: i32, len: i32) -> wasmtime::Result<String> {
+    Ok(String::from_utf8(read(caller, ptr, len)?)?)
+}
+
+/// 调用插件的 alloc 分配内存，把 data 写进去，返回打包后的位置
+fn write_packed<T>(caller: &mut Caller<'_, T>, data: &[u8]) -> wasmtime::Result<i64> {
+    let alloc = match caller.get_export("alloc") {
+        Some(Extern::Func(f)) => f.typed::<i32, i32>(&*caller)?,
+        _ => return Err(wasmtime::Error::msg("plugin has no alloc export")),
+    };
+    let ptr = alloc.call(&mut *caller, data.len() as i32)?;
+    memory(caller)?.write(&mut *caller, ptr as u32 as usize, data)?;
+    Ok(((ptr as u32 as i64) << 32) | data.len() as i64)
+}
+
+fn read_packed(
+    store: &mut Store<HostData>,
+    instance: &Instance,
+    packed: i64,
+) -> wasmtime::Result<Vec<u8>> {
+    let ptr = (packed >> 32) as u32 as usize;
+    let len = packed as u32 as usize;
+    let memory = instance
+        .get_memory(&mut *store, "memory")
+        .ok_or_else(|| wasmtime::Error::msg("plugin has no memory export"))?;
+    let mut buf = vec![0; len];
+    memory.read(&*store, ptr, &mut buf)?;
+    Ok(buf)
+}
+
+fn plugin_error(e: impl std::fmt::Display) -> KvError {
+    KvError::PluginError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_res_ok;
+
+    // 一个简单的插件：command 往 plugin 表里写入 hit = "wasm"，
+    // 并返回 status 200；before hook 拒绝所有 HDEL 请求（tag 6）
+    const DEMO: &str = r#"
+        (module
+          (import "kv" "set" (func $set (param i32 i32 i32 i32 i32 i32) (result i32)))
+          (import "kv" "log" (func $log (param i32 i32 i32)))
+          (memory (export "memory") 1)
+          (global $bump (mut i32) (i32.const 1024))
+          (data (i32.const 0) "plugin")
+          (data (i32.const 8) "hit")
+          ;; Value { string: "wasm" }
+          (data (i32.const 16) "\0a\04wasm")
+          ;; CommandResponse { status: 200 }
+          (data (i32.const 32) "\08\c8\01")
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            global.get $bump
+            local.set $ptr
+            global.get $bump
+            local.get $len
+            i32.add
+            global.set $bump
+            local.get $ptr)
+          (func (export "command") (param i32 i32) (result i64)
+            (call $log (i32.const 2) (i32.const 8) (i32.const 3))
+            (drop (call $set (i32.const 0) (i32.const 6) (i32.const 8) (i32.const 3)
+                             (i32.const 16) (i32.const 6)))
+            (i64.or (i64.shl (i64.const 32) (i64.const 32)) (i64.const 3)))
+          (func (export "before") (param $ptr i32) (param $len i32) (result i32)
+            ;; 第一个字节是 oneof 的 tag：(6 << 3) | 2 = 0x32
+            (if (result i32) (i32.eq (i32.load8_u (local.get $ptr)) (i32.const 0x32))
+              (then (i32.const 403))
+              (else (i32.const 0)))))
+    "#;
+
+    const LOOP: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) i32.const 0)
+          (func (export "command") (param i32 i32) (result i64)
+            (loop $l (br $l))
+            i64.const 0))
+    "#;
+
+    #[test]
+    fn plugin_command_should_work() {
+        let plugin = Plugin::new("demo", DEMO).unwrap();
+        let service: Service = ServiceInner::new(MemTable::new()).plugin(plugin).into();
+
+        let res = service.execute(CommandRequest::new_custom("demo", vec![]));
+        assert_res_ok(res, &[], &[]);
+
+        let res = service.execute(CommandRequest::new_hget("plugin", "hit"));
+        assert_res_ok(res, &["wasm".into()], &[]);
+    }
+
+    #[test]
+    fn plugin_before_hook_should_reject() {
+        let plugin = Plugin::new("demo", DEMO).unwrap();
+        let service: Service = ServiceInner::new(MemTable::new()).plugin(plugin).into();
+
+        let res = service.execute(CommandRequest::new_hdel("t1", "k1"));
+        assert_eq!(res.status, 403);
+
+        let res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        assert_res_ok(res, &[Value::default()], &[]);
+    }
+
+    #[test]
+    fn invalid_before_status_should_be_internal_error() {
+        // before hook 总是返回 -1
+        const BAD: &str = r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "alloc") (param i32) (result i32) i32.const 0)
+              (func (export "before") (param i32 i32) (result i32) i32.const -1))
+        "#;
+        let plugin = Plugin::new("bad", BAD).unwrap();
+        let service: Service = ServiceInner::new(MemTable::new()).plugin(plugin).into();
+
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_eq!(res.status, 500);
+        assert!(res.message.contains("invalid status -1"), "{}", res.message);
+    }
+
+    #[test]
+    fn plugin_with_unknown_import_should_fail_to_load() {
+        const UNKNOWN: &str = r#"
+            (module (import "kv" "nope" (func)) (memory (export "memory") 1))
+        "#;
+        assert!(Plugin::new("unknown", UNKNOWN).is_err());
+    }
+
+    #[test]
+    fn unknown_custom_command_should_fail() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let res = service.execute(CommandRequest::new_custom("demo", vec![]));
+        assert_eq!(res.status, 400);
+    }
+
+    #[test]
+    fn runaway_plugin_should_run_out_of_fuel() {
+        let plugin = Plugin::new("loop", LOOP).unwrap();
+        let service: Service = ServiceInner::new(MemTable::new()).plugin(plugin).into();
+
+        let res = service.execute(CommandRequest::new_custom("loop", vec![]));
+        assert_eq!(res.status, 500);
+    }
+}
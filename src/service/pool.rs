@@ -0,0 +1,115 @@
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use tokio::sync::oneshot;
+
+use crate::KvError;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// 给同步的存储 backend 用的线程池
+///
+/// Storage 的接口是同步的，sled 之类的 backend 在磁盘卡住的时候会阻塞调用者。
+/// 直接在 tokio 的线程上调用会卡住整个 runtime，用 spawn_blocking 的话
+/// 卡住的任务会一直堆积，耗尽 tokio 的 blocking 线程。BlockingPool 的线程数和
+/// 队列长度都是固定的，队列满了立刻返回 KvError::Busy，让客户端稍后重试。
+#[derive(Clone)]
+pub struct BlockingPool {
+    tx: SyncSender<Job>,
+}
+
+impl BlockingPool {
+    /// threads 个线程执行任务，最多 queue 个任务排队
+    pub fn new(threads: usize, queue: usize) -> Result<Self, KvError> {
+        let (tx, rx) = mpsc::sync_channel::<Job>(queue);
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..threads.max(1) {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("kv-blocking-{}", i))
+                .spawn(move || worker(rx))?;
+        }
+        Ok(Self { tx })
+    }
+
+    /// 在线程池里执行 f，等待它的结果
+    pub async fn run<F, R>(&self, f: F) -> Result<R, KvError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            let _ = tx.send(f());
+        });
+        match self.tx.try_send(job) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                return Err(KvError::Busy("blocking pool queue is full".into()))
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(KvError::Internal("blocking pool is closed".into()))
+            }
+        }
+        rx.await
+            .map_err(|_| KvError::Internal("blocking task panicked".into()))
+    }
+}
+
+// 所有的 BlockingPool 都 drop 之后 channel 断开，线程退出
+fn worker(rx: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = match rx.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        job();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn blocking_pool_should_run_jobs() {
+        let pool = BlockingPool::new(2, 4).unwrap();
+        let name = pool
+            .run(|| thread::current().name().unwrap().to_string())
+            .await
+            .unwrap();
+        assert!(name.starts_with("kv-blocking-"));
+    }
+
+    #[tokio::test]
+    async fn blocking_pool_should_shed_when_full() {
+        let pool = BlockingPool::new(1, 1).unwrap();
+        let open = Arc::new(AtomicBool::new(false));
+
+        // 一个任务卡在线程里，一个在队列里
+        let mut running = Vec::new();
+        for _ in 0..2 {
+            let (pool, open) = (pool.clone(), open.clone());
+            running.push(tokio::spawn(async move {
+                pool.run(move || {
+                    while !open.load(Ordering::SeqCst) {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                })
+                .await
+            }));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let res = pool.run(|| ()).await;
+        assert!(matches!(res, Err(KvError::Busy(_))));
+
+        open.store(true, Ordering::SeqCst);
+        for handle in running {
+            handle.await.unwrap().unwrap();
+        }
+    }
+}
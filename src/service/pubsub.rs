@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::warn;
+
+use crate::*;
+
+/// 推送 PUBLISH 消息的 channel，每个连接一个
+pub type PushSender = mpsc::Sender<CommandResponse>;
+
+/// 发布订阅的 topic 表
+///
+/// 一个 Service 一份，所有的 clone 共享。订阅记住连接的推送 channel，
+/// PUBLISH 不会等待慢的订阅者：channel 满了的话这条消息对它丢弃，
+/// 连接已经断开的订阅直接删掉。
+#[derive(Debug, Default)]
+pub struct Broker {
+    next_id: AtomicU64,
+    topics: DashMap<String, HashMap<u64, PushSender>>,
+}
+
+/// 一个连接上的订阅，drop 的时候全部取消
+pub struct Subscriptions {
+    broker: Arc<Broker>,
+    tx: PushSender,
+    // 订阅 id -> topic
+    topics: HashMap<u64, String>,
+}
+
+impl Broker {
+    /// 订阅 topic，发布的消息发到 tx，返回订阅 id。id 从 1 开始，0 留给普通的 response
+    pub fn subscribe(&self, topic: impl Into<String>, tx: PushSender) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.topics.entry(topic.into()).or_default().insert(id, tx);
+        id
+    }
+
+    /// 取消订阅，返回这个订阅是否存在
+    pub fn unsubscribe(&self, topic: &str, id: u64) -> bool {
+        let removed = match self.topics.get_mut(topic) {
+            Some(mut subs) => subs.remove(&id).is_some(),
+            None => false,
+        };
+        // 没有订阅者的 topic 不留在表里
+        self.topics.remove_if(topic, |_, subs| subs.is_empty());
+        removed
+    }
+
+    /// topic 上的订阅者数量
+    pub fn subscribers(&self, topic: &str) -> usize {
+        self.topics.get(topic).map_or(0, |subs| subs.len())
+    }
+
+    /// 向 topic 发布一条消息，返回收到消息的订阅者个数
+    pub fn publish(&self, topic: &str, data: Value) -> usize {
+        let mut subs = match self.topics.get_mut(topic) {
+            Some(subs) => subs,
+            None => return 0,
+        };
+        let mut received = 0;
+        subs.retain(
+            |id, tx| match tx.try_send(message(*id, topic, data.clone())) {
+                Ok(()) => {
+                    received += 1;
+                    true
+                }
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "Subscriber {} of {} is too slow, message dropped",
+                        id, topic
+                    );
+                    true
+                }
+                Err(TrySendError::Closed(_)) => false,
+            },
+        );
+        let empty = subs.is_empty();
+        drop(subs);
+        if empty {
+            self.topics.remove_if(topic, |_, subs| subs.is_empty());
+        }
+        received
+    }
+}
+
+// 推送的消息：pair 的 key 是 topic，value 是发布的数据
+fn message(id: u64, topic: &str, data: Value) -> CommandResponse {
+    CommandResponse {
+        subscription: id,
+        version: PROTOCOL_VERSION,
+        ..vec![Kvpair::new(topic, data)].into()
+    }
+}
+
+impl Subscriptions {
+    pub(crate) fn new(broker: Arc<Broker>, tx: PushSender) -> Self {
+        Self {
+            broker,
+            tx,
+            topics: HashMap::new(),
+        }
+    }
+
+    /// 这个连接上的订阅数量
+    pub fn len(&self) -> usize {
+        self.topics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.topics.is_empty()
+    }
+
+    pub(crate) fn subscribe(&mut self, cmd: Subscribe) -> CommandResponse {
+        if cmd.topic.is_empty() {
+            return KvError::InvalidCommand("Topic can not be empty".into()).into();
+        }
+        let id = self.broker.subscribe(cmd.topic.clone(), self.tx.clone());
+        self.topics.insert(id, cmd.topic);
+        Value::from(id as i64).into()
+    }
+
+    // 只能取消这个连接自己的订阅
+    pub(crate) fn unsubscribe(&mut self, cmd: Unsubscribe) -> CommandResponse {
+        let ids: Vec<u64> = self
+            .topics
+            .iter()
+            .filter(|(id, topic)| **topic == cmd.topic && (cmd.id == 0 || **id == cmd.id))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &ids {
+            self.topics.remove(id);
+            self.broker.unsubscribe(&cmd.topic, *id);
+        }
+        Value::from(ids.len() as i64).into()
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for (id, topic) in self.topics.drain() {
+            self.broker.unsubscribe(&topic, id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broker_should_publish_to_subscribers() {
+        let broker = Broker::default();
+        let (tx, mut rx) = mpsc::channel(4);
+        let id = broker.subscribe("news", tx.clone());
+        broker.subscribe("other", tx);
+
+        assert_eq!(broker.publish("news", "hello".into()), 1);
+        assert_eq!(broker.publish("nobody", "hello".into()), 0);
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.subscription, id);
+        assert_eq!(msg.pairs, vec![Kvpair::new("news", "hello".into())]);
+        assert!(rx.try_recv().is_err());
+
+        assert!(broker.unsubscribe("news", id));
+        assert!(!broker.unsubscribe("news", id));
+        assert_eq!(broker.publish("news", "hello".into()), 0);
+    }
+
+    #[test]
+    fn broker_should_skip_slow_and_drop_closed_subscribers() {
+        let broker = Broker::default();
+        let (slow, _slow_rx) = mpsc::channel(1);
+        let (closed, closed_rx) = mpsc::channel(1);
+        broker.subscribe("t", slow);
+        broker.subscribe("t", closed);
+        drop(closed_rx);
+
+        assert_eq!(broker.publish("t", 1.into()), 1);
+        assert_eq!(broker.subscribers("t"), 1);
+        // channel 满了，消息丢弃但是订阅还在
+        assert_eq!(broker.publish("t", 2.into()), 0);
+        assert_eq!(broker.subscribers("t"), 1);
+    }
+
+    #[test]
+    fn subscriptions_should_be_cancelled_on_drop() {
+        let broker = Arc::new(Broker::default());
+        let (tx, _rx) = mpsc::channel(4);
+        let mut subs = Subscriptions::new(broker.clone(), tx);
+        for _ in 0..2 {
+            subs.subscribe(Subscribe { topic: "t".into() });
+        }
+        subs.subscribe(Subscribe { topic: "u".into() });
+        assert_eq!(broker.subscribers("t"), 2);
+
+        let res = subs.unsubscribe(Unsubscribe {
+            topic: "t".into(),
+            id: 0,
+        });
+        assert_res_ok(res, &[2.into()], &[]);
+        assert_eq!(broker.subscribers("t"), 0);
+
+        drop(subs);
+        assert_eq!(broker.subscribers("u"), 0);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::*;
+
+/// 依次发送的一串 response，最后一个的 more 是 false
+pub type ResponseStream = Box<dyn Iterator<Item = CommandResponse> + Send>;
+
+/// 一个命令的结果：一个 response，或者分成多个 frame 发送的一串 response
+pub enum Reply {
+    Single(CommandResponse),
+    Stream(ResponseStream),
+}
+
+impl Reply {
+    /// 每 n 个 kv pair 一块，发送到哪一块才从 pairs 里读到哪里，
+    /// 不会把整个 table 先收集起来。pairs 是空的时候也有一块
+    pub fn chunked(pairs: Box<dyn Iterator<Item = Kvpair> + Send>, n: usize) -> Self {
+        let mut pairs = pairs.peekable();
+        let mut done = false;
+        Reply::Stream(Box::new(std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let chunk: Vec<Kvpair> = pairs.by_ref().take(n.max(1)).collect();
+            done = pairs.peek().is_none();
+            let mut res = CommandResponse::from(chunk);
+            res.more = !done;
+            Some(res)
+        })))
+    }
+}
+
+// 能转换成 CommandResponse 的都可以直接作为一个 response 的 Reply
+macro_rules! single_reply {
+    ($($ty:ty),*) => {
+        $(impl From<$ty> for Reply {
+            fn from(res: $ty) -> Self {
+                Reply::Single(res.into())
+            }
+        })*
+    };
+}
+
+single_reply!(
+    CommandResponse,
+    Value,
+    KvError,
+    Vec<Kvpair>,
+    Vec<Value>,
+    Vec<CommandResponse>
+);
+
+// 不能分块发送的地方（比如 Service::execute）把所有的块合成一个 response
+impl From<Reply> for CommandResponse {
+    fn from(reply: Reply) -> Self {
+        let mut chunks = reply.into_iter();
+        let mut res = chunks.next().unwrap_or_default();
+        for chunk in chunks {
+            res.pairs.extend(chunk.pairs);
+            res.values.extend(chunk.values);
+        }
+        res.more = false;
+        res
+    }
+}
+
+impl IntoIterator for Reply {
+    type Item = CommandResponse;
+    type IntoIter = ResponseStream;
+
+    fn into_iter(self) -> Self::IntoIter {
+        match self {
+            Reply::Single(res) => Box::new(std::iter::once(res)),
+            Reply::Stream(stream) => stream,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_should_split_pairs_into_chunks() {
+        let pairs: Vec<Kvpair> = (0..5)
+            .map(|i| Kvpair::new(format!("k{}", i), i.into()))
+            .collect();
+        let reply = |n| Reply::chunked(Box::new(pairs.clone().into_iter()), n);
+        let chunks: Vec<_> = reply(2).into_iter().collect();
+        let sizes: Vec<_> = chunks.iter().map(|c| (c.pairs.len(), c.more)).collect();
+        assert_eq!(sizes, [(2, true), (2, true), (1, false)]);
+        let merged: Vec<_> = chunks.into_iter().flat_map(|c| c.pairs).collect();
+        assert_eq!(merged, pairs);
+
+        let single: Vec<_> = reply(5).into_iter().collect();
+        assert_eq!(single.len(), 1);
+        assert!(!single[0].more);
+        assert_eq!(CommandResponse::from(reply(2)).pairs, pairs);
+
+        let empty: Vec<_> = Reply::chunked(Box::new(std::iter::empty()), 2)
+            .into_iter()
+            .collect();
+        assert_eq!(empty.len(), 1);
+        assert!(empty[0].pairs.is_empty() && !empty[0].more);
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+/// 一个连接最多同时打开的 cursor 数量，超出时关闭最久没用的那个
+pub const MAX_CURSORS: usize = 16;
+/// cursor 多久没有被使用就会被清理掉
+pub const CURSOR_TTL: Duration = Duration::from_secs(60);
+/// HSCAN 没有指定 count 时每批返回的个数
+const DEFAULT_COUNT: usize = 10;
+/// 每批最多返回的个数，防止一次 HSCAN 变成 HGETALL
+const MAX_COUNT: usize = 1000;
+
+/// 服务器端维护的 HSCAN cursor
+///
+/// 每个连接一份。cursor 只记住上一批最后一个 key，下一批用 Storage::scan
+/// 从它后面接着读，所以不管 table 多大，一个 cursor 都只占一个 key 的内存。
+/// 遍历期间一直存在的 key 正好返回一次，遍历期间写入或者删除的 key 可能返回也可能不返回。
+/// 降序遍历用 Storage::get_iter_ordered 跳过上一批最后一个 key 和它前面的 key。
+/// cursor 的数量有上限，而且过了 CURSOR_TTL 没用就会被清理；连接断开时
+/// ScanCursors 随之 drop，所有的 cursor 也就释放了。
+#[derive(Default)]
+pub struct ScanCursors {
+    next_id: u64,
+    cursors: HashMap<u64, Cursor>,
+}
+
+struct Cursor {
+    table: String,
+    pattern: Option<Glob>,
+    desc: bool,
+    // 上一批最后一个 key
+    last_key: Option<String>,
+    last_used: Instant,
+}
+
+impl ScanCursors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 当前打开的 cursor 数量
+    pub fn len(&self) -> usize {
+        self.cursors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cursors.is_empty()
+    }
+
+    pub fn scan(&mut self, cmd: Hscan, store: &impl Storage) -> CommandResponse {
+        let now = Instant::now();
+        self.cursors
+            .retain(|_, c| now.duration_since(c.last_used) < CURSOR_TTL);
+
+        let id = match cmd.cursor {
+            0 => match self.open(cmd.table, &cmd.pattern, cmd.order < 0, now) {
+                Ok(id) => id,
+                Err(e) => return e.into(),
+            },
+            id => id,
+        };
+
+        let cursor = match self.cursors.get_mut(&id) {
+            Some(cursor) => cursor,
+            None => {
+                return KvError::InvalidCommand(format!("Unknown or expired cursor {}", id)).into()
+            }
+        };
+
+        let count = match cmd.count as usize {
+            0 => DEFAULT_COUNT,
+            n => n.min(MAX_COUNT),
+        };
+        // 多取一个，用来判断后面还有没有
+        let after = cursor.last_key.as_deref();
+        let pairs = match cursor.desc {
+            true => scan_desc(
+                store,
+                &cursor.table,
+                after,
+                cursor.pattern.as_ref(),
+                count + 1,
+            ),
+            false => store.scan(&cursor.table, after, cursor.pattern.as_ref(), count + 1),
+        };
+        let mut pairs = match pairs {
+            Ok(pairs) => pairs,
+            Err(e) => return e.into(),
+        };
+        let more = pairs.len() > count;
+        pairs.truncate(count);
+        cursor.last_key = pairs.last().map(|p| p.key.clone());
+        cursor.last_used = now;
+
+        // 取完了就关闭 cursor，用 0 告诉客户端遍历结束
+        let next = match more {
+            true => id,
+            false => {
+                self.cursors.remove(&id);
+                0
+            }
+        };
+
+        let mut res: CommandResponse = pairs.into();
+        res.cursor = next;
+        res
+    }
+
+    fn open(
+        &mut self,
+        table: String,
+        pattern: &str,
+        desc: bool,
+        now: Instant,
+    ) -> Result<u64, KvError> {
+        let pattern = Glob::optional(pattern)?;
+        if self.cursors.len() >= MAX_CURSORS {
+            let oldest = self
+                .cursors
+                .iter()
+                .min_by_key(|(id, c)| (c.last_used, **id))
+                .map(|(id, _)| *id);
+            if let Some(id) = oldest {
+                self.cursors.remove(&id);
+            }
+        }
+
+        self.next_id += 1;
+        self.cursors.insert(
+            self.next_id,
+            Cursor {
+                table,
+                pattern,
+                desc,
+                last_key: None,
+                last_used: now,
+            },
+        );
+        Ok(self.next_id)
+    }
+}
+
+// 按 key 降序返回 before 之前的最多 count 个 kv pair
+fn scan_desc(
+    store: &impl Storage,
+    table: &str,
+    before: Option<&str>,
+    pattern: Option<&Glob>,
+    count: usize,
+) -> Result<Vec<Kvpair>, KvError> {
+    Ok(store
+        .get_iter_ordered(table, Order::Descending)?
+        .skip_while(|p| before.is_some_and(|before| p.key.as_str() >= before))
+        .filter(|p| pattern.is_none_or(|pattern| pattern.matches(&p.key)))
+        .take(count)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_with(n: usize) -> MemTable {
+        let store = MemTable::new();
+        for i in 0..n {
+            store.hset("t1", format!("k{}", i), i as i64).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn scan_should_iterate_whole_table() {
+        let store = store_with(25);
+        let mut cursors = ScanCursors::new();
+
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let res = cursors.scan(
+                Hscan {
+                    table: "t1".into(),
+                    cursor,
+                    count: 10,
+                    ..Default::default()
+                },
+                &store,
+            );
+            assert_eq!(res.status, 200);
+            assert!(res.pairs.len() <= 10);
+            keys.extend(res.pairs.into_iter().map(|p| p.key));
+            cursor = res.cursor;
+
+            // 遍历期间的写入不会让已有的 key 重复或者遗漏
+            store.hset("t1", "k0", 1).unwrap();
+            store.hset("t1", "new", 1).unwrap();
+            if cursor == 0 {
+                break;
+            }
+        }
+
+        keys.retain(|k| k != "new");
+        keys.dedup();
+        assert_eq!(keys.len(), 25);
+        assert!(cursors.is_empty());
+    }
+
+    #[test]
+    fn scan_should_reject_unknown_cursor() {
+        let store = store_with(1);
+        let mut cursors = ScanCursors::new();
+        let res = cursors.scan(
+            Hscan {
+                table: "t1".into(),
+                cursor: 42,
+                count: 0,
+                ..Default::default()
+            },
+            &store,
+        );
+        assert_res_error(res, 400, "cursor 42");
+    }
+
+    #[test]
+    fn scan_should_bound_open_cursors() {
+        let store = store_with(5);
+        let mut cursors = ScanCursors::new();
+        for _ in 0..MAX_CURSORS + 3 {
+            let cmd = Hscan {
+                table: "t1".into(),
+                cursor: 0,
+                count: 1,
+                ..Default::default()
+            };
+            assert_ne!(cursors.scan(cmd, &store).cursor, 0);
+        }
+        assert_eq!(cursors.len(), MAX_CURSORS);
+
+        // 最早的 cursor 已经被关闭了
+        let cmd = Hscan {
+            table: "t1".into(),
+            cursor: 1,
+            count: 1,
+            ..Default::default()
+        };
+        assert_eq!(cursors.scan(cmd, &store).status, 400);
+    }
+
+    #[test]
+    fn scan_should_filter_by_pattern() {
+        let store = store_with(25);
+        let mut cursors = ScanCursors::new();
+
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let cmd = Hscan {
+                table: "t1".into(),
+                cursor,
+                count: 3,
+                pattern: "k1?".into(),
+                ..Default::default()
+            };
+            let res = cursors.scan(cmd, &store);
+            keys.extend(res.pairs.into_iter().map(|p| p.key));
+            cursor = res.cursor;
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert_eq!(
+            keys,
+            (10..20).map(|i| format!("k{}", i)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn scan_desc_should_iterate_in_reverse() {
+        let store = store_with(10);
+        let mut cursors = ScanCursors::new();
+
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let cmd = Hscan {
+                table: "t1".into(),
+                cursor,
+                count: 3,
+                order: -1,
+                ..Default::default()
+            };
+            let res = cursors.scan(cmd, &store);
+            keys.extend(res.pairs.into_iter().map(|p| p.key));
+            cursor = res.cursor;
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert_eq!(
+            keys,
+            (0..10).rev().map(|i| format!("k{}", i)).collect::<Vec<_>>()
+        );
+    }
+}
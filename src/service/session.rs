@@ -0,0 +1,49 @@
+use super::watch::Watching;
+use crate::*;
+
+/// 一个连接上的状态：HSCAN 的 cursor、SUBSCRIBE 的订阅、WATCH 的 key 和 MULTI 之后排队的命令
+///
+/// 网络层每个连接创建一个，连接断开时 drop，cursor 随之释放，订阅也全部取消。
+#[derive(Default)]
+pub struct Session {
+    pub(crate) cursors: ScanCursors,
+    // 没有推送 channel 的连接不能订阅
+    pub(crate) subscriptions: Option<Subscriptions>,
+    // MULTI 之后排队的命令，不在事务里时是 None
+    pub(crate) queued: Option<Vec<CommandRequest>>,
+    // MULTI 之后有命令没能排队，EXEC 会放弃整个事务
+    pub(crate) rejected: bool,
+    pub(crate) watching: Option<Watching>,
+    // TLS 客户端证书里的身份，没有验证过客户端时是 None
+    pub(crate) identity: Option<String>,
+}
+
+impl Session {
+    /// 不能订阅的连接，只能使用 HSCAN
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cursors(&self) -> &ScanCursors {
+        &self.cursors
+    }
+
+    pub fn subscriptions(&self) -> Option<&Subscriptions> {
+        self.subscriptions.as_ref()
+    }
+
+    /// 设置这个连接的客户端身份，ServiceInner::fn_authorize 的 hook 用它做权限检查
+    pub fn with_identity(mut self, identity: Option<String>) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    /// 是否在 MULTI 和 EXEC 之间
+    pub fn in_transaction(&self) -> bool {
+        self.queued.is_some()
+    }
+}
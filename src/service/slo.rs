@@ -0,0 +1,318 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::command_request::RequestData;
+use crate::*;
+
+/// 窗口被切成多少个桶，过期时整桶丢掉
+const BUCKETS: usize = 10;
+
+/// 命令的分类，SLO 按分类统计
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandClass {
+    Read,
+    Write,
+    /// 插件提供的自定义命令
+    Custom,
+    /// 存储 backend 的管理命令
+    Admin,
+    /// 没有 request_data 的请求
+    Unknown,
+}
+
+impl From<&CommandRequest> for CommandClass {
+    fn from(cmd: &CommandRequest) -> Self {
+        match &cmd.request_data {
+            Some(
+                RequestData::Hget(_)
+                | RequestData::Hgetall(_)
+                | RequestData::Hmget(_)
+                | RequestData::Hexist(_)
+                | RequestData::Hmexist(_)
+                | RequestData::Hscan(_)
+                | RequestData::Httl(_)
+                | RequestData::Hkeys(_)
+                | RequestData::Hvals(_)
+                | RequestData::Hlen(_)
+                | RequestData::Htables(_)
+                | RequestData::Hstats(_)
+                | RequestData::Ping(_)
+                | RequestData::Subscribe(_)
+                | RequestData::Unsubscribe(_)
+                | RequestData::Multi(_)
+                | RequestData::Discard(_)
+                | RequestData::Watch(_)
+                | RequestData::Unwatch(_)
+                | RequestData::Hgetver(_)
+                | RequestData::Hrange(_)
+                | RequestData::Hmeta(_)
+                | RequestData::Hgetat(_)
+                | RequestData::Hquery(_),
+            ) => CommandClass::Read,
+            Some(
+                RequestData::Hset(_)
+                | RequestData::Hmset(_)
+                | RequestData::Hdel(_)
+                | RequestData::Hmdel(_)
+                | RequestData::Undelete(_)
+                | RequestData::PurgeTrash(_)
+                | RequestData::CloneTable(_)
+                | RequestData::Move(_)
+                | RequestData::Hincrby(_)
+                | RequestData::Hsetnx(_)
+                | RequestData::Hgetdel(_)
+                | RequestData::Hgetset(_)
+                | RequestData::Hexpire(_)
+                | RequestData::Hpersist(_)
+                | RequestData::Hexpireat(_)
+                | RequestData::Hdroptable(_)
+                | RequestData::Publish(_)
+                | RequestData::Exec(_)
+                | RequestData::Hsetver(_)
+                | RequestData::Batch(_),
+            ) => CommandClass::Write,
+            Some(RequestData::Custom(_)) => CommandClass::Custom,
+            Some(
+                RequestData::Admin(_)
+                | RequestData::Hflushall(_)
+                | RequestData::Info(_)
+                | RequestData::Backup(_)
+                | RequestData::Restore(_),
+            ) => CommandClass::Admin,
+            None => CommandClass::Unknown,
+        }
+    }
+}
+
+/// SLO 的阈值
+#[derive(Debug, Clone)]
+pub struct SloConfig {
+    /// 滚动窗口的长度
+    pub window: Duration,
+    /// 窗口内请求数少于这个值时不做判断
+    pub min_requests: usize,
+    /// 延迟的目标，比如 p99 不超过 10ms，就是 latency = 10ms、quantile = 0.99
+    pub latency: Duration,
+    pub quantile: f64,
+    /// 5xx 占比的上限
+    pub error_rate: f64,
+}
+
+impl Default for SloConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60),
+            min_requests: 100,
+            latency: Duration::from_millis(10),
+            quantile: 0.99,
+            error_rate: 0.01,
+        }
+    }
+}
+
+/// 违反了哪一项 SLO
+#[derive(Debug, Clone, PartialEq)]
+pub enum BreachKind {
+    /// 超过 latency 的请求占比 slow_rate，超出了 1 - quantile
+    Latency { slow_rate: f64 },
+    /// 5xx 的占比
+    ErrorRate { error_rate: f64 },
+}
+
+/// SLO 被违反时传给 hook 的事件
+#[derive(Debug, Clone)]
+pub struct SloBreach {
+    pub class: CommandClass,
+    pub kind: BreachKind,
+    /// 窗口内的请求数
+    pub requests: usize,
+    pub config: SloConfig,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Bucket {
+    // 这个桶对应的时间片编号
+    slice: u64,
+    total: usize,
+    slow: usize,
+    errors: usize,
+}
+
+struct WindowState {
+    start: Instant,
+    buckets: [Bucket; BUCKETS],
+    latency_breached: bool,
+    error_breached: bool,
+}
+
+/// 监视一类命令的 SLO
+///
+/// 窗口按时间分成 BUCKETS 个桶，每个桶只记录计数，内存占用和请求量无关。
+/// hook 只在 SLO 从满足变成不满足的时候触发一次，恢复之后才会再次触发。
+pub(crate) struct SloWatch {
+    class: CommandClass,
+    config: SloConfig,
+    hook: fn(&SloBreach),
+    state: Mutex<WindowState>,
+}
+
+impl SloWatch {
+    pub(crate) fn new(class: CommandClass, config: SloConfig, hook: fn(&SloBreach)) -> Self {
+        Self {
+            class,
+            config,
+            hook,
+            state: Mutex::new(WindowState {
+                start: Instant::now(),
+                buckets: [Bucket::default(); BUCKETS],
+                latency_breached: false,
+                error_breached: false,
+            }),
+        }
+    }
+
+    pub(crate) fn record(&self, class: CommandClass, latency: Duration, res: &CommandResponse) {
+        if class != self.class {
+            return;
+        }
+
+        let now = Instant::now();
+        let breaches = {
+            let mut state = self.state.lock().unwrap();
+            let slice_len = (self.config.window / BUCKETS as u32).max(Duration::from_micros(1));
+            let slice = (now.duration_since(state.start).as_nanos() / slice_len.as_nanos()) as u64;
+
+            let bucket = &mut state.buckets[slice as usize % BUCKETS];
+            if bucket.slice != slice {
+                *bucket = Bucket {
+                    slice,
+                    ..Default::default()
+                };
+            }
+            bucket.total += 1;
+            bucket.slow += (latency > self.config.latency) as usize;
+            bucket.errors += (res.status >= 500) as usize;
+
+            self.check(&mut state, slice)
+        };
+
+        // 在锁外面调用 hook
+        for breach in breaches {
+            (self.hook)(&breach);
+        }
+    }
+
+    fn check(&self, state: &mut WindowState, slice: u64) -> Vec<SloBreach> {
+        let (mut total, mut slow, mut errors) = (0, 0, 0);
+        for b in state
+            .buckets
+            .iter()
+            .filter(|b| b.slice + BUCKETS as u64 > slice)
+        {
+            total += b.total;
+            slow += b.slow;
+            errors += b.errors;
+        }
+        if total < self.config.min_requests {
+            return vec![];
+        }
+
+        let mut breaches = Vec::new();
+        let slow_rate = slow as f64 / total as f64;
+        let breached = slow_rate > 1.0 - self.config.quantile;
+        if breached && !state.latency_breached {
+            breaches.push(self.breach(BreachKind::Latency { slow_rate }, total));
+        }
+        state.latency_breached = breached;
+
+        let error_rate = errors as f64 / total as f64;
+        let breached = error_rate > self.config.error_rate;
+        if breached && !state.error_breached {
+            breaches.push(self.breach(BreachKind::ErrorRate { error_rate }, total));
+        }
+        state.error_breached = breached;
+
+        breaches
+    }
+
+    fn breach(&self, kind: BreachKind, requests: usize) -> SloBreach {
+        SloBreach {
+            class: self.class,
+            kind,
+            requests,
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static LATENCY_BREACHES: AtomicUsize = AtomicUsize::new(0);
+    static ERROR_BREACHES: AtomicUsize = AtomicUsize::new(0);
+
+    fn on_latency(breach: &SloBreach) {
+        assert_eq!(breach.class, CommandClass::Write);
+        assert!(matches!(breach.kind, BreachKind::Latency { .. }));
+        LATENCY_BREACHES.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_error(breach: &SloBreach) {
+        assert_eq!(breach.class, CommandClass::Read);
+        if let BreachKind::ErrorRate { error_rate } = breach.kind {
+            assert!(error_rate > breach.config.error_rate);
+            ERROR_BREACHES.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn slo_hook_should_fire_once_per_breach() {
+        let config = SloConfig {
+            min_requests: 5,
+            latency: Duration::ZERO,
+            error_rate: 1.0,
+            ..Default::default()
+        };
+        let service: Service = ServiceInner::new(MemTable::new())
+            .fn_slo_breach(CommandClass::Write, config, on_latency)
+            .into();
+
+        for i in 0..20 {
+            service.execute(CommandRequest::new_hset("t1", format!("k{}", i), i.into()));
+            // 读命令不算在 Write 里
+            service.execute(CommandRequest::new_hget("t1", "k1"));
+        }
+        assert_eq!(LATENCY_BREACHES.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn slo_hook_should_watch_error_rate() {
+        let config = SloConfig {
+            min_requests: 5,
+            latency: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let service: Service = ServiceInner::new(MemTable::new())
+            .fn_before_send(|res| {
+                // 模拟服务器错误：t2 上的请求都变成 500
+                if res.message.contains("table: t2") {
+                    res.status = 500;
+                }
+            })
+            .fn_slo_breach(CommandClass::Read, config, on_error)
+            .into();
+
+        // 4xx 不算错误
+        for _ in 0..10 {
+            service.execute(CommandRequest::new_hget("t1", "k1"));
+        }
+        assert_eq!(ERROR_BREACHES.load(Ordering::SeqCst), 0);
+
+        for _ in 0..20 {
+            service.execute(CommandRequest::new_hget("t2", "k1"));
+        }
+        assert_eq!(ERROR_BREACHES.load(Ordering::SeqCst), 1);
+    }
+}
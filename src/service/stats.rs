@@ -0,0 +1,148 @@
+use std::any::type_name;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+/// INFO 里命令计数的名字，顺序就是计数器的下标
+const CLASSES: [(CommandClass, &str); 5] = [
+    (CommandClass::Read, "read"),
+    (CommandClass::Write, "write"),
+    (CommandClass::Custom, "custom"),
+    (CommandClass::Admin, "admin"),
+    (CommandClass::Unknown, "unknown"),
+];
+
+/// 服务器运行时的统计，INFO 命令返回它
+///
+/// 一个 Service 一份，所有的 clone 共享。命令的计数由 Service 在执行时更新，
+/// 连接数由网络层在连接建立和断开的时候更新。
+#[derive(Debug)]
+pub struct ServerStats {
+    start: Instant,
+    connections: AtomicUsize,
+    total_connections: AtomicU64,
+    commands: [AtomicU64; CLASSES.len()],
+    errors: AtomicU64,
+}
+
+/// 一个打开的连接，drop 的时候减少连接数
+pub struct ConnectionGuard(Arc<ServerStats>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            connections: AtomicUsize::new(0),
+            total_connections: AtomicU64::new(0),
+            commands: Default::default(),
+            errors: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ServerStats {
+    pub fn uptime(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// 当前打开的连接数
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// 网络层在连接建立时调用，连接处理完之后 drop 返回的 guard
+    pub fn connect(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+
+    /// 执行过的这类命令的数量
+    pub fn commands(&self, class: CommandClass) -> u64 {
+        self.commands[slot(class)].load(Ordering::Relaxed)
+    }
+
+    /// 返回 4xx 或者 5xx 的命令的数量
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record(&self, class: CommandClass, res: &CommandResponse) {
+        self.commands[slot(class)].fetch_add(1, Ordering::Relaxed);
+        if res.status >= 400 {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// INFO 返回的 kv pair，backend 是 Store 去掉模块路径之后的类型名
+    pub(crate) fn info<Store>(&self) -> Vec<Kvpair> {
+        let mut pairs = vec![
+            Kvpair::new("version", env!("CARGO_PKG_VERSION").into()),
+            Kvpair::new("protocol_version", (PROTOCOL_VERSION as i64).into()),
+            Kvpair::new("uptime_secs", (self.uptime().as_secs() as i64).into()),
+            Kvpair::new("backend", short_type_name(type_name::<Store>()).into()),
+            Kvpair::new("connections", (self.connections() as i64).into()),
+            Kvpair::new(
+                "total_connections",
+                (self.total_connections.load(Ordering::Relaxed) as i64).into(),
+            ),
+        ];
+        for (class, name) in CLASSES {
+            let n = self.commands(class) as i64;
+            pairs.push(Kvpair::new(format!("commands.{}", name), n.into()));
+        }
+        pairs.push(Kvpair::new("errors", (self.errors() as i64).into()));
+        pairs
+    }
+}
+
+fn slot(class: CommandClass) -> usize {
+    CLASSES.iter().position(|(c, _)| *c == class).unwrap()
+}
+
+// kv2::storage::throttle::WriteThrottle<kv2::storage::memory::MemTable>
+// 变成 WriteThrottle<MemTable>
+fn short_type_name(name: &str) -> String {
+    let is_sep = |c: char| "<>,;()[]& ".contains(c);
+    let mut short = String::new();
+    for part in name.split_inclusive(is_sep) {
+        let (path, sep) = part.split_at(part.trim_end_matches(is_sep).len());
+        short.push_str(path.rsplit("::").next().unwrap_or(path));
+        short.push_str(sep);
+    }
+    short
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_type_name_should_strip_paths() {
+        assert_eq!(
+            short_type_name(type_name::<WriteThrottle<Arc<MemTable>>>()),
+            "WriteThrottle<Arc<MemTable>>"
+        );
+        assert_eq!(short_type_name("(a::B, &c::D)"), "(B, &D)");
+    }
+
+    #[test]
+    fn connections_should_be_counted() {
+        let stats = Arc::new(ServerStats::default());
+        let a = stats.connect();
+        let b = stats.connect();
+        assert_eq!(stats.connections(), 2);
+        drop(a);
+        drop(b);
+        assert_eq!(stats.connections(), 0);
+        assert_eq!(stats.total_connections.load(Ordering::Relaxed), 2);
+    }
+}
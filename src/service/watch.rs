@@ -0,0 +1,151 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+use crate::{KvError, Storage};
+
+/// 正在被 WATCH 的 key，以及 watch 它们的连接
+///
+/// 一个 Service 一份。写命令改动了 key 之后，watch 这个 key 的连接都被标记为 dirty，
+/// 它们接下来的 EXEC 会失败。没有人 watch 的时候写命令只多一次 is_empty 的检查。
+/// 过期、bulk load 这些不经过命令的改动由 Watching 记下的 version 发现
+#[derive(Debug, Default)]
+pub struct Watches {
+    keys: DashMap<(String, String), Vec<Arc<AtomicBool>>>,
+}
+
+// watch 的 key 和 WATCH 时它的 version，存储不支持 version 的时候只能靠 dirty
+type Watched = ((String, String), Option<Option<u64>>);
+
+/// 一个连接 watch 的 key，drop 的时候从 Watches 里删掉
+pub(crate) struct Watching {
+    watches: Arc<Watches>,
+    dirty: Arc<AtomicBool>,
+    keys: Vec<Watched>,
+}
+
+impl Watches {
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn is_watched(&self, table: &str, key: &str) -> bool {
+        !self.is_empty() && self.keys.contains_key(&(table.into(), key.into()))
+    }
+
+    /// key 被改动了
+    pub fn touch(&self, table: &str, key: &str) {
+        if self.is_empty() {
+            return;
+        }
+        if let Some(flags) = self.keys.get(&(table.into(), key.into())) {
+            flags.iter().for_each(|f| f.store(true, Ordering::Release));
+        }
+    }
+
+    /// 整个 table 被改动了，table 为 None 时表示所有的 table
+    pub fn touch_table(&self, table: Option<&str>) {
+        for entry in self.keys.iter() {
+            if table.is_none_or(|t| entry.key().0 == t) {
+                entry
+                    .value()
+                    .iter()
+                    .for_each(|f| f.store(true, Ordering::Release));
+            }
+        }
+    }
+}
+
+impl Watching {
+    pub(crate) fn new(watches: Arc<Watches>) -> Self {
+        Self {
+            watches,
+            dirty: Arc::default(),
+            keys: Vec::new(),
+        }
+    }
+
+    pub(crate) fn watch(&mut self, table: &str, key: &str, version: Option<Option<u64>>) {
+        let k = (table.to_string(), key.to_string());
+        if self.keys.iter().any(|(watched, _)| *watched == k) {
+            return;
+        }
+        let mut flags = self.watches.keys.entry(k.clone()).or_default();
+        flags.push(self.dirty.clone());
+        drop(flags);
+        self.keys.push((k, version));
+    }
+
+    /// WATCH 之后有没有 key 被改动过：写命令标记了 dirty，或者 key 现在的 version
+    /// 和 WATCH 时不一样了（包括过期之后不存在了）
+    pub(crate) fn is_changed(&self, store: &impl Storage) -> Result<bool, KvError> {
+        if self.dirty.load(Ordering::Acquire) {
+            return Ok(true);
+        }
+        for ((table, key), version) in &self.keys {
+            if let Some(version) = version {
+                if store.version(table, key)? != *version {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Drop for Watching {
+    fn drop(&mut self) {
+        for (k, _) in self.keys.drain(..) {
+            if let Some(mut flags) = self.watches.keys.get_mut(&k) {
+                flags.retain(|f| !Arc::ptr_eq(f, &self.dirty));
+            }
+            self.watches.keys.remove_if(&k, |_, flags| flags.is_empty());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+
+    #[test]
+    fn touched_keys_should_make_watching_dirty() {
+        // 不记录 version，只看 dirty
+        let store = MemTable::new();
+        let watches = Arc::new(Watches::default());
+        let mut a = Watching::new(watches.clone());
+        let mut b = Watching::new(watches.clone());
+        a.watch("t1", "k1", None);
+        a.watch("t1", "k1", None);
+        b.watch("t2", "k1", None);
+
+        watches.touch("t1", "k2");
+        assert!(!a.is_changed(&store).unwrap());
+        watches.touch("t1", "k1");
+        assert!(a.is_changed(&store).unwrap() && !b.is_changed(&store).unwrap());
+        watches.touch_table(Some("t2"));
+        assert!(b.is_changed(&store).unwrap());
+
+        drop(a);
+        assert!(!watches.is_watched("t1", "k1"));
+        drop(b);
+        assert!(watches.is_empty());
+    }
+
+    #[test]
+    fn changed_versions_should_make_watching_changed() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let watches = Arc::new(Watches::default());
+        let mut a = Watching::new(watches.clone());
+        a.watch("t1", "k1", Some(store.version("t1", "k1").unwrap()));
+        a.watch("t1", "k2", Some(store.version("t1", "k2").unwrap()));
+        assert!(!a.is_changed(&store).unwrap());
+
+        // 没有经过 Watches::touch 的写入
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        assert!(a.is_changed(&store).unwrap());
+    }
+}
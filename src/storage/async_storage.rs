@@ -0,0 +1,161 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use crate::{BlockingPool, KvError, Kvpair, Storage, Value};
+
+/// 异步的存储接口
+///
+/// Storage 的接口是同步的，访问网络或者等待磁盘的 backend 会阻塞调用它的线程。
+/// 自己做 I/O 的 backend 可以直接实现 AsyncStorage；已有的同步 backend 用
+/// PooledStorage 包起来，在 BlockingPool 里执行，不会占住 tokio 的线程
+pub trait AsyncStorage: Send + Sync {
+    fn get(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send;
+    fn set(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send;
+    fn contains(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<bool, KvError>> + Send;
+    fn del(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send;
+    fn get_all(&self, table: &str) -> impl Future<Output = Result<Vec<Kvpair>, KvError>> + Send;
+    fn mget(
+        &self,
+        table: &str,
+        keys: Vec<String>,
+    ) -> impl Future<Output = Result<Vec<Option<Value>>, KvError>> + Send;
+    fn mset(
+        &self,
+        table: &str,
+        pairs: Vec<Kvpair>,
+    ) -> impl Future<Output = Result<Vec<Option<Value>>, KvError>> + Send;
+    fn mdel(
+        &self,
+        table: &str,
+        keys: Vec<String>,
+    ) -> impl Future<Output = Result<Vec<Option<Value>>, KvError>> + Send;
+}
+
+/// 在 BlockingPool 里执行同步的 Storage，实现 AsyncStorage
+pub struct PooledStorage<S> {
+    store: Arc<S>,
+    pool: BlockingPool,
+}
+
+impl<S> Clone for PooledStorage<S> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<S: Storage + Send + Sync + 'static> PooledStorage<S> {
+    pub fn new(store: S, pool: BlockingPool) -> Self {
+        Self::from_arc(Arc::new(store), pool)
+    }
+
+    /// 和同步的调用者共享同一个存储
+    pub fn from_arc(store: Arc<S>, pool: BlockingPool) -> Self {
+        Self { store, pool }
+    }
+
+    pub fn store(&self) -> &Arc<S> {
+        &self.store
+    }
+
+    // 参数都是 owned 的，拿到线程池里执行。队列满了返回 KvError::Busy
+    async fn run<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&S) -> Result<R, KvError> + Send + 'static,
+    ) -> Result<R, KvError> {
+        let store = self.store.clone();
+        self.pool.run(move || f(&store)).await?
+    }
+}
+
+impl<S: Storage + Send + Sync + 'static> AsyncStorage for PooledStorage<S> {
+    async fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.to_string(), key.to_string());
+        self.run(move |s| s.get(&table, &key)).await
+    }
+
+    async fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let table = table.to_string();
+        self.run(move |s| s.set(&table, key, value)).await
+    }
+
+    async fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let (table, key) = (table.to_string(), key.to_string());
+        self.run(move |s| s.contains(&table, &key)).await
+    }
+
+    async fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.to_string(), key.to_string());
+        self.run(move |s| s.del(&table, &key)).await
+    }
+
+    async fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let table = table.to_string();
+        self.run(move |s| s.get_all(&table)).await
+    }
+
+    async fn mget(&self, table: &str, keys: Vec<String>) -> Result<Vec<Option<Value>>, KvError> {
+        let table = table.to_string();
+        self.run(move |s| s.mget(&table, &keys)).await
+    }
+
+    async fn mset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+        let table = table.to_string();
+        self.run(move |s| s.mset(&table, pairs)).await
+    }
+
+    async fn mdel(&self, table: &str, keys: Vec<String>) -> Result<Vec<Option<Value>>, KvError> {
+        let table = table.to_string();
+        self.run(move |s| s.mdel(&table, &keys)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+
+    #[tokio::test]
+    async fn pooled_storage_should_work() {
+        let pool = BlockingPool::new(2, 4).unwrap();
+        let store = PooledStorage::new(MemTable::new(), pool);
+        assert_eq!(
+            store.set("t1", "k1".into(), "v1".into()).await.unwrap(),
+            None
+        );
+        assert_eq!(store.get("t1", "k1").await.unwrap(), Some("v1".into()));
+        assert!(store.contains("t1", "k1").await.unwrap());
+
+        let pairs = vec![Kvpair::new("k2", 2.into())];
+        assert_eq!(store.mset("t1", pairs).await.unwrap(), [None]);
+        assert_eq!(store.get_all("t1").await.unwrap().len(), 2);
+        // 同步的调用者看到的是同一份数据
+        assert_eq!(store.store().get("t1", "k2").unwrap(), Some(2.into()));
+
+        let keys = vec!["k1".into(), "k2".into()];
+        assert_eq!(
+            store.mdel("t1", keys.clone()).await.unwrap(),
+            [Some("v1".into()), Some(2.into())]
+        );
+        assert_eq!(store.mget("t1", keys).await.unwrap(), [None, None]);
+    }
+}
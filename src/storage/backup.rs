@@ -0,0 +1,248 @@
+use flate2::Crc;
+use std::io::{ErrorKind, Read, Write};
+
+use crate::{KvError, Kvpair, Storage, Value};
+
+/// 备份文件以它开头
+const MAGIC: &[u8; 4] = b"KVBK";
+/// 备份格式的版本，格式改变时加一。读的时候拒绝不认识的版本
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+// 导入的时候每攒够这么多 key 写一次
+const IMPORT_BATCH: usize = 1024;
+
+const END: u8 = 0;
+const TABLE: u8 = 1;
+const PAIR: u8 = 2;
+
+// 写入的同时计算 crc，最后一个 record 带着之前所有字节的 crc
+struct Checked<T> {
+    inner: T,
+    crc: Crc,
+}
+
+impl<W: Write> Checked<W> {
+    fn write(&mut self, data: &[u8]) -> Result<(), KvError> {
+        self.crc.update(data);
+        self.inner.write_all(data)?;
+        Ok(())
+    }
+
+    fn write_field(&mut self, data: &[u8]) -> Result<(), KvError> {
+        self.write(&(data.len() as u32).to_be_bytes())?;
+        self.write(data)
+    }
+}
+
+impl<R: Read> Checked<R> {
+    fn read<const N: usize>(&mut self) -> Result<[u8; N], KvError> {
+        let mut buf = [0; N];
+        self.inner.read_exact(&mut buf).map_err(truncated)?;
+        self.crc.update(&buf);
+        Ok(buf)
+    }
+
+    fn read_field(&mut self) -> Result<Vec<u8>, KvError> {
+        let len = u32::from_be_bytes(self.read()?) as usize;
+        let mut buf = vec![0; len];
+        self.inner.read_exact(&mut buf).map_err(truncated)?;
+        self.crc.update(&buf);
+        Ok(buf)
+    }
+
+    fn string(&mut self) -> Result<String, KvError> {
+        String::from_utf8(self.read_field()?).map_err(|_| corrupted("name is not UTF-8"))
+    }
+}
+
+fn truncated(e: std::io::Error) -> KvError {
+    match e.kind() {
+        ErrorKind::UnexpectedEof => corrupted("backup is truncated"),
+        _ => e.into(),
+    }
+}
+
+fn corrupted(msg: &str) -> KvError {
+    KvError::DataCorruption(msg.into())
+}
+
+/// Storage::export 的缺省实现
+///
+/// 格式是 magic 和版本号，然后是一串 record：table 的名字，接着是它的每个 key、
+/// 编码之后的 value 和过期时间（0 表示没有）；最后一个 record 是 key 的数量和 crc32。
+/// 每个 table 用 Storage::snapshot 读，导出的是那个时刻的内容
+pub(crate) fn export<S: Storage + ?Sized>(store: &S, w: &mut dyn Write) -> Result<usize, KvError> {
+    let mut w = Checked {
+        inner: w,
+        crc: Crc::new(),
+    };
+    w.write(MAGIC)?;
+    w.write(&BACKUP_FORMAT_VERSION.to_be_bytes())?;
+    let mut n = 0u64;
+    for table in store.list_tables()? {
+        let snapshot = store.snapshot(&table)?;
+        if snapshot.is_empty() {
+            continue;
+        }
+        w.write(&[TABLE])?;
+        w.write_field(table.as_bytes())?;
+        for pair in snapshot.into_pairs() {
+            let deadline = deadline_of(store, &table, &pair.key)?;
+            let value: Vec<u8> = pair.value.unwrap_or_default().try_into()?;
+            w.write(&[PAIR])?;
+            w.write_field(pair.key.as_bytes())?;
+            w.write_field(&value)?;
+            w.write(&deadline.to_be_bytes())?;
+            n += 1;
+        }
+    }
+    w.write(&[END])?;
+    w.write(&n.to_be_bytes())?;
+    let crc = w.crc.sum();
+    w.inner.write_all(&crc.to_be_bytes())?;
+    w.inner.flush()?;
+    Ok(n as usize)
+}
+
+/// key 的过期时间，0 表示没有。不支持过期时间的 backend 当作都没有过期时间，
+/// 读的时候刚好过期被删掉的 key 也是
+pub(crate) fn deadline_of<S: Storage + ?Sized>(
+    store: &S,
+    table: &str,
+    key: &str,
+) -> Result<i64, KvError> {
+    match store.deadline(table, key) {
+        Ok(d) => Ok(d.unwrap_or(0)),
+        Err(KvError::NotFound(..) | KvError::Unsupported(_)) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// 用 mset 写入一批 key，再设置它们的过期时间。不支持过期时间的 backend 丢掉过期时间
+pub(crate) fn put_batch<S: Storage + ?Sized>(
+    store: &S,
+    table: &str,
+    batch: Vec<(Kvpair, i64)>,
+) -> Result<(), KvError> {
+    let deadlines: Vec<_> = batch
+        .iter()
+        .filter(|(_, d)| *d != 0)
+        .map(|(p, d)| (p.key.clone(), *d))
+        .collect();
+    store.mset(table, batch.into_iter().map(|(p, _)| p).collect())?;
+    for (key, deadline) in deadlines {
+        match store.expire_at(table, &key, deadline) {
+            Ok(_) | Err(KvError::Unsupported(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Storage::import 的缺省实现，按 IMPORT_BATCH 个 key 一批用 put_batch 写入
+pub(crate) fn import<S: Storage + ?Sized>(store: &S, r: &mut dyn Read) -> Result<usize, KvError> {
+    read(r, |table, batch| put_batch(store, table, batch))
+}
+
+/// 只检查 Storage::export 写出的备份是否完整，返回里面 key 的数量
+pub fn check_backup(r: &mut dyn Read) -> Result<usize, KvError> {
+    read(r, |_, _| Ok(()))
+}
+
+type Batch = Vec<(Kvpair, i64)>;
+
+fn read(
+    r: &mut dyn Read,
+    mut apply: impl FnMut(&str, Batch) -> Result<(), KvError>,
+) -> Result<usize, KvError> {
+    let mut r = Checked {
+        inner: r,
+        crc: Crc::new(),
+    };
+    if &r.read::<4>()? != MAGIC {
+        return Err(corrupted("not a backup"));
+    }
+    let version = u32::from_be_bytes(r.read()?);
+    if version != BACKUP_FORMAT_VERSION {
+        return Err(KvError::Unsupported(format!("backup format {}", version)));
+    }
+
+    let (mut table, mut batch, mut n) = (None::<String>, Vec::new(), 0u64);
+    loop {
+        let [tag] = r.read()?;
+        if matches!(tag, TABLE | END) || batch.len() >= IMPORT_BATCH {
+            if let Some(table) = &table {
+                apply(table, std::mem::take(&mut batch))?;
+            }
+        }
+        match tag {
+            TABLE => table = Some(r.string()?),
+            PAIR if table.is_some() => {
+                let key = r.string()?;
+                let value = Value::try_from(r.read_field()?.as_slice())?;
+                let deadline = i64::from_be_bytes(r.read()?);
+                batch.push((Kvpair::new(key, value), deadline));
+                n += 1;
+            }
+            END => break,
+            _ => return Err(corrupted("unknown record")),
+        }
+    }
+    let count = u64::from_be_bytes(r.read()?);
+    let expected = r.crc.sum();
+    let mut crc = [0; 4];
+    r.inner.read_exact(&mut crc).map_err(truncated)?;
+    if count != n || u32::from_be_bytes(crc) != expected {
+        return Err(corrupted("backup checksum mismatch"));
+    }
+    Ok(n as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, SledDb, StorageExt};
+    use tempfile::tempdir;
+
+    #[test]
+    fn export_import_should_roundtrip() {
+        let src = MemTable::new();
+        src.hset("t1", "k1", "v1").unwrap();
+        src.hset("t1", "k2", 42).unwrap();
+        src.expire_at("t1", "k2", i64::MAX).unwrap();
+        for i in 0..IMPORT_BATCH + 1 {
+            src.hset("t2", format!("k{}", i), i as i64).unwrap();
+        }
+        let mut buf = Vec::new();
+        assert_eq!(src.export(&mut buf).unwrap(), IMPORT_BATCH + 3);
+        assert_eq!(check_backup(&mut buf.as_slice()).unwrap(), IMPORT_BATCH + 3);
+
+        let dst = SledDb::new(tempdir().unwrap()).unwrap();
+        dst.hset("t1", "k1", "old").unwrap();
+        assert_eq!(dst.import(&mut buf.as_slice()).unwrap(), IMPORT_BATCH + 3);
+        assert_eq!(dst.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(dst.deadline("t1", "k2").unwrap(), Some(i64::MAX));
+        assert_eq!(dst.len("t2").unwrap(), IMPORT_BATCH + 1);
+    }
+
+    #[test]
+    fn import_should_reject_bad_backups() {
+        let src = MemTable::new();
+        src.hset("t1", "k1", "v1").unwrap();
+        let mut buf = Vec::new();
+        src.export(&mut buf).unwrap();
+
+        let dst = MemTable::new();
+        let err = dst.import(&mut &buf[..buf.len() - 1]).unwrap_err();
+        assert!(matches!(err, KvError::DataCorruption(_)), "{:?}", err);
+        let mut flipped = buf.clone();
+        flipped[12] ^= 1;
+        assert!(check_backup(&mut flipped.as_slice()).is_err());
+        let mut newer = buf.clone();
+        newer[7] = 2;
+        assert!(matches!(
+            check_backup(&mut newer.as_slice()),
+            Err(KvError::Unsupported(_))
+        ));
+    }
+}
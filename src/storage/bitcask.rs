@@ -0,0 +1,649 @@
+use flate2::Crc;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
+use tracing::warn;
+
+use super::expiry::Sweeper;
+use super::{
+    incr_value, split_table_key, table_key, table_prefix, COMPACT_COMMAND, FLUSH_COMMAND,
+    STORAGE_COMMAND,
+};
+use crate::{Glob, KvError, Kvpair, Storage, Value};
+
+/// 缺省的 segment 大小，写满之后换一个新的文件
+pub const BITCASK_SEGMENT_SIZE: u64 = 64 << 20;
+
+// 每个 record 的头：crc32、key 的长度、value 的长度，crc 覆盖头的后两项和 key、value
+const HEADER_LEN: usize = 12;
+// value 的长度是这个值的 record 表示删除
+const TOMBSTONE: u32 = u32::MAX;
+
+/// Bitcask 式的日志存储：所有的写都追加到当前的 segment 文件末尾，
+/// 内存里的 keydir 记录每个 key 最新的 value 在哪个文件的什么位置，读只需要一次 pread。
+///
+/// key 用 table_key(table, key) 编码，keydir 是有序的，同一个 table 的 key 排在一起。
+/// 启动时按顺序重放所有的 segment 重建 keydir，遇到写了一半的 record 就截断，
+/// 所以崩溃最多丢掉最后一个没写完的 record。compact 把旧 segment 里还有用的 value
+/// 复制到一个新的文件，然后删掉旧的文件，期间不阻塞读写。
+/// 不支持过期时间、version 和 MOVE
+#[derive(Clone)]
+pub struct BitcaskStore(Arc<Bitcask>);
+
+struct Bitcask {
+    dir: PathBuf,
+    segment_size: u64,
+    keydir: RwLock<BTreeMap<Vec<u8>, Location>>,
+    // 所有 segment 的只读文件，读的时候用
+    segments: RwLock<BTreeMap<u64, Arc<File>>>,
+    // 写的时候拿着这个锁，keydir 的更新顺序和日志里的顺序一样
+    writer: Mutex<Writer>,
+    // 同时只有一个 compact
+    compacting: Mutex<()>,
+    // 所有 segment 的大小，和其中已经被覆盖或者删除的 record 的大小
+    bytes: AtomicU64,
+    dead: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    segment: u64,
+    // value 在文件里的位置和长度
+    offset: u64,
+    len: u32,
+    // 整个 record 的长度，被覆盖之后算作 dead
+    size: u32,
+}
+
+struct Writer {
+    id: u64,
+    file: File,
+    size: u64,
+}
+
+impl BitcaskStore {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self::with_segment_size(path, BITCASK_SEGMENT_SIZE)
+    }
+
+    pub fn with_segment_size(path: impl AsRef<Path>, segment_size: u64) -> Self {
+        Self::open(path.as_ref(), segment_size).unwrap()
+    }
+
+    fn open(dir: &Path, segment_size: u64) -> Result<Self, KvError> {
+        fs::create_dir_all(dir)?;
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let id = name.to_str().and_then(|n| n.strip_suffix(".data"));
+            if let Some(id) = id.and_then(|id| id.parse::<u64>().ok()) {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+
+        let mut keydir = BTreeMap::new();
+        let mut segments = BTreeMap::new();
+        let (mut bytes, mut dead) = (0, 0);
+        for &id in &ids {
+            let path = segment_path(dir, id);
+            let (size, garbage) = replay(&path, id, &mut keydir)?;
+            segments.insert(id, Arc::new(File::open(&path)?));
+            bytes += size;
+            dead += garbage;
+        }
+        // 旧的 segment 不再写，从一个新的文件开始
+        let id = ids.last().map_or(0, |id| id + 1);
+        let writer = Writer::create(dir, id)?;
+        segments.insert(id, Arc::new(File::open(segment_path(dir, id))?));
+        Ok(Self(Arc::new(Bitcask {
+            dir: dir.into(),
+            segment_size,
+            keydir: RwLock::new(keydir),
+            segments: RwLock::new(segments),
+            writer: Mutex::new(writer),
+            compacting: Mutex::new(()),
+            bytes: AtomicU64::new(bytes),
+            dead: AtomicU64::new(dead),
+        })))
+    }
+
+    /// 在后台每隔 interval 检查一次，一半以上的数据已经没用的时候 compact
+    pub fn spawn_compaction(&self, interval: Duration) -> Result<Sweeper, KvError> {
+        let store = self.clone();
+        Sweeper::spawn("kv-bitcask-compact", interval, move || {
+            let (bytes, dead) = (store.0.bytes(), store.0.dead());
+            match dead * 2 > bytes {
+                true => store.compact(),
+                false => Ok(0),
+            }
+        })
+    }
+
+    /// 把旧 segment 里还有用的 value 复制到一个新的 segment，删掉旧的，返回回收的字节数。
+    ///
+    /// 先换一个新的写文件，之后的写都在它里面，旧的 segment 不会再变。新的 segment 的编号
+    /// 在旧的和写文件之间，重放的时候顺序不会乱；复制的途中崩溃只会留下一个不完整的副本
+    pub fn compact(&self) -> Result<usize, KvError> {
+        let db = &self.0;
+        let _compacting = lock(&db.compacting);
+        let merged = {
+            let mut writer = lock(&db.writer);
+            let merged = writer.id + 1;
+            db.rotate(&mut writer, merged + 1)?;
+            merged
+        };
+        let old: Vec<u64> = read(&db.segments)
+            .range(..merged)
+            .map(|(id, _)| *id)
+            .collect();
+        let live: Vec<(Vec<u8>, Location)> = read(&db.keydir)
+            .iter()
+            .filter(|(_, loc)| loc.segment < merged)
+            .map(|(name, loc)| (name.clone(), *loc))
+            .collect();
+
+        let mut out = Writer::create(&db.dir, merged)?;
+        let mut moved = Vec::with_capacity(live.len());
+        for (name, loc) in live {
+            // 已经被覆盖或者删除
+            let value = match db.read_at(&loc) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            let new = out.append(&name, Some(&value))?;
+            moved.push((name, loc, new));
+        }
+        out.file.sync_all()?;
+        write(&db.segments).insert(merged, Arc::new(File::open(segment_path(&db.dir, merged))?));
+
+        // 复制的途中被改掉的 key 保留新的位置
+        let mut keydir = write(&db.keydir);
+        for (name, old, new) in moved {
+            if let Some(loc) = keydir.get_mut(&name).filter(|loc| **loc == old) {
+                *loc = new;
+            }
+        }
+        drop(keydir);
+
+        let mut freed = 0;
+        let mut segments = write(&db.segments);
+        for id in old {
+            segments.remove(&id);
+            let path = segment_path(&db.dir, id);
+            freed += fs::metadata(&path)?.len();
+            fs::remove_file(path)?;
+        }
+        drop(segments);
+        // compact 的时候新写的 record 被覆盖算作 dead，这里只重新计算大小
+        db.bytes.fetch_add(out.size, Ordering::Relaxed);
+        db.bytes.fetch_sub(freed, Ordering::Relaxed);
+        let garbage = freed.saturating_sub(out.size);
+        let _ = db
+            .dead
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |d| {
+                Some(d.saturating_sub(garbage))
+            });
+        Ok(garbage as usize)
+    }
+}
+
+impl Bitcask {
+    fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn dead(&self) -> u64 {
+        self.dead.load(Ordering::Relaxed)
+    }
+
+    fn get(&self, name: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+        loop {
+            let loc = match read(&self.keydir).get(name) {
+                Some(loc) => *loc,
+                None => return Ok(None),
+            };
+            match self.read_at(&loc) {
+                Ok(value) => return Ok(Some(value)),
+                // compact 刚刚删掉了这个 segment，keydir 已经指向新的位置
+                Err(KvError::NotFound(..)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn read_at(&self, loc: &Location) -> Result<Vec<u8>, KvError> {
+        let file = match read(&self.segments).get(&loc.segment) {
+            Some(file) => file.clone(),
+            None => {
+                let segment = loc.segment.to_string();
+                return Err(KvError::NotFound("segment".into(), segment));
+            }
+        };
+        let mut value = vec![0; loc.len as usize];
+        file.read_exact_at(&mut value, loc.offset)?;
+        Ok(value)
+    }
+
+    // 追加一个 record 并且更新 keydir，返回旧的 value 的位置
+    fn append(
+        &self,
+        writer: &mut Writer,
+        name: &[u8],
+        value: Option<&[u8]>,
+    ) -> Result<Option<Location>, KvError> {
+        let loc = writer.append(name, value)?;
+        self.bytes.fetch_add(loc.size as u64, Ordering::Relaxed);
+        let mut keydir = write(&self.keydir);
+        let old = match value {
+            Some(_) => keydir.insert(name.to_vec(), loc),
+            None => {
+                // tombstone 本身也是没用的数据
+                self.dead.fetch_add(loc.size as u64, Ordering::Relaxed);
+                keydir.remove(name)
+            }
+        };
+        drop(keydir);
+        if let Some(old) = old {
+            self.dead.fetch_add(old.size as u64, Ordering::Relaxed);
+        }
+        if writer.size >= self.segment_size {
+            let id = writer.id + 1;
+            self.rotate(writer, id)?;
+        }
+        Ok(old)
+    }
+
+    fn rotate(&self, writer: &mut Writer, id: u64) -> Result<(), KvError> {
+        writer.file.sync_all()?;
+        let next = Writer::create(&self.dir, id)?;
+        let file = Arc::new(File::open(segment_path(&self.dir, id))?);
+        write(&self.segments).insert(id, file);
+        *writer = next;
+        Ok(())
+    }
+
+    // 一个 table 的所有 key，after 之后的，最多 count 个
+    fn names(&self, table: &str, after: Option<&str>, count: usize) -> Vec<Vec<u8>> {
+        let prefix = table_prefix(table);
+        let start = match after {
+            Some(key) => Bound::Excluded(table_key(table, key)),
+            None => Bound::Included(prefix.clone()),
+        };
+        read(&self.keydir)
+            .range((start, Bound::Unbounded))
+            .map(|(name, _)| name)
+            .take_while(|name| name.starts_with(&prefix))
+            .take(count)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Writer {
+    fn create(dir: &Path, id: u64) -> Result<Self, KvError> {
+        let path = segment_path(dir, id);
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { id, file, size })
+    }
+
+    fn append(&mut self, name: &[u8], value: Option<&[u8]>) -> Result<Location, KvError> {
+        let record = encode_record(name, value);
+        self.file.write_all(&record)?;
+        let loc = Location {
+            segment: self.id,
+            offset: self.size + (HEADER_LEN + name.len()) as u64,
+            len: value.map_or(0, |v| v.len() as u32),
+            size: record.len() as u32,
+        };
+        self.size += record.len() as u64;
+        Ok(loc)
+    }
+}
+
+impl Storage for BitcaskStore {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        decode(self.0.get(&table_key(table, key))?)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let name = table_key(table, &key);
+        let value: Vec<u8> = value.try_into()?;
+        let mut writer = lock(&self.0.writer);
+        let old = self.0.get(&name)?;
+        self.0.append(&mut writer, &name, Some(&value))?;
+        decode(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(read(&self.0.keydir).contains_key(&table_key(table, key)))
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let name = table_key(table, key);
+        let mut writer = lock(&self.0.writer);
+        let old = match self.0.get(&name)? {
+            Some(old) => old,
+            None => return Ok(None),
+        };
+        self.0.append(&mut writer, &name, None)?;
+        decode(Some(old))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        Ok(self.get_iter(table)?.collect())
+    }
+
+    // 先取出所有的 key，value 在遍历的时候才读。遍历途中被删掉的 key 会被跳过
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let names = self.0.names(table, None, usize::MAX);
+        Ok(Box::new(pairs(self.0.clone(), names)))
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        pattern: Option<&Glob>,
+        count: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let mut names = self.0.names(table, after, usize::MAX);
+        if let Some(pattern) = pattern {
+            names.retain(|name| split_table_key(name).is_some_and(|(_, k)| pattern.matches(k)));
+        }
+        names.truncate(count);
+        Ok(pairs(self.0.clone(), names).collect())
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self.0.names(table, None, usize::MAX).len())
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let keydir = read(&self.0.keydir);
+        let mut names: Vec<String> = Vec::new();
+        for name in keydir.keys() {
+            if let Some((table, _)) = split_table_key(name) {
+                if names.last().map(String::as_str) != Some(table) {
+                    names.push(table.into());
+                }
+            }
+        }
+        // key 先按 table 名字的长度排序，所以还要再按名字排一次
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        let mut writer = lock(&self.0.writer);
+        let names = self.0.names(table, None, usize::MAX);
+        for name in &names {
+            self.0.append(&mut writer, name, None)?;
+        }
+        Ok(names.len())
+    }
+
+    // 所有的数据都不要了，直接删掉所有的 segment
+    fn flush_all(&self) -> Result<usize, KvError> {
+        let db = &self.0;
+        let _compacting = lock(&db.compacting);
+        let mut writer = lock(&db.writer);
+        let n = std::mem::take(&mut *write(&db.keydir)).len();
+        let id = writer.id + 1;
+        db.rotate(&mut writer, id)?;
+        let mut segments = write(&db.segments);
+        let old: Vec<u64> = segments.range(..writer.id).map(|(id, _)| *id).collect();
+        for id in old {
+            segments.remove(&id);
+            fs::remove_file(segment_path(&db.dir, id))?;
+        }
+        db.bytes.store(0, Ordering::Relaxed);
+        db.dead.store(0, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn admin(&self, command: &str, _args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        match command {
+            STORAGE_COMMAND => Ok(vec![
+                Kvpair::new("size_on_disk", (self.0.bytes() as i64).into()),
+                Kvpair::new("keys", (read(&self.0.keydir).len() as i64).into()),
+                Kvpair::new("segments", (read(&self.0.segments).len() as i64).into()),
+                Kvpair::new("dead_bytes", (self.0.dead() as i64).into()),
+            ]),
+            FLUSH_COMMAND => {
+                lock(&self.0.writer).file.sync_all()?;
+                Ok(vec![Kvpair::new("flushed", true.into())])
+            }
+            COMPACT_COMMAND => {
+                let freed = self.compact()?;
+                Ok(vec![Kvpair::new("freed", (freed as i64).into())])
+            }
+            _ => Err(KvError::Unsupported(format!("admin command {}", command))),
+        }
+    }
+
+    // 所有的写都拿着写锁，检查和写入之间不会有别的修改
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        let name = table_key(table, &key);
+        let value: Vec<u8> = value.try_into()?;
+        let mut writer = lock(&self.0.writer);
+        if read(&self.0.keydir).contains_key(&name) {
+            return Ok(false);
+        }
+        self.0.append(&mut writer, &name, Some(&value))?;
+        Ok(true)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let name = table_key(table, key);
+        let mut writer = lock(&self.0.writer);
+        let old = decode(self.0.get(&name)?)?;
+        let n = incr_value(key, old.as_ref(), delta)?;
+        let value: Vec<u8> = Value::from(n).try_into()?;
+        self.0.append(&mut writer, &name, Some(&value))?;
+        Ok(n)
+    }
+}
+
+// 按顺序读出 names 的 value
+fn pairs(db: Arc<Bitcask>, names: Vec<Vec<u8>>) -> impl Iterator<Item = Kvpair> {
+    names.into_iter().filter_map(move |name| {
+        let (_, key) = split_table_key(&name)?;
+        let value = db.get(&name).ok()??;
+        Some(Kvpair::new(key, Value::try_from(value.as_slice()).ok()?))
+    })
+}
+
+fn encode_record(name: &[u8], value: Option<&[u8]>) -> Vec<u8> {
+    let len = value.map_or(TOMBSTONE, |v| v.len() as u32);
+    let mut record = Vec::with_capacity(HEADER_LEN + name.len() + len as usize);
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    record.extend_from_slice(&len.to_be_bytes());
+    record.extend_from_slice(name);
+    record.extend_from_slice(value.unwrap_or_default());
+    let mut crc = Crc::new();
+    crc.update(&record[4..]);
+    record[..4].copy_from_slice(&crc.sum().to_be_bytes());
+    record
+}
+
+// 重放一个 segment，返回它的大小和里面没用的数据的大小。
+// 遇到不完整或者校验失败的 record 时把文件截断到它之前
+fn replay(
+    path: &Path,
+    id: u64,
+    keydir: &mut BTreeMap<Vec<u8>, Location>,
+) -> Result<(u64, u64), KvError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let (mut offset, mut dead) = (0u64, 0u64);
+    loop {
+        match read_record(&mut reader) {
+            Ok(Some((name, value))) => {
+                let size = (HEADER_LEN + name.len() + value.as_ref().map_or(0, Vec::len)) as u32;
+                let old = match value {
+                    Some(value) => {
+                        let loc = Location {
+                            segment: id,
+                            offset: offset + (HEADER_LEN + name.len()) as u64,
+                            len: value.len() as u32,
+                            size,
+                        };
+                        keydir.insert(name, loc)
+                    }
+                    None => {
+                        dead += size as u64;
+                        keydir.remove(&name)
+                    }
+                };
+                dead += old.map_or(0, |loc| loc.size as u64);
+                offset += size as u64;
+            }
+            Ok(None) => return Ok((offset, dead)),
+            Err(e) => {
+                warn!("Truncating {:?} at {}: {}", path, offset, e);
+                OpenOptions::new().write(true).open(path)?.set_len(offset)?;
+                return Ok((offset, dead));
+            }
+        }
+    }
+}
+
+type Record = (Vec<u8>, Option<Vec<u8>>);
+
+// 读下一个 record，文件正好在 record 之间结束时返回 None
+fn read_record(reader: &mut impl Read) -> Result<Option<Record>, KvError> {
+    let mut header = [0; HEADER_LEN];
+    let mut n = 0;
+    while n < HEADER_LEN {
+        match reader.read(&mut header[n..]) {
+            Ok(0) if n == 0 => return Ok(None),
+            Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(len) => n += len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let checksum = u32::from_be_bytes(header[..4].try_into().unwrap());
+    let name_len = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+    let value_len = u32::from_be_bytes(header[8..].try_into().unwrap());
+    let body_len = name_len
+        + if value_len == TOMBSTONE {
+            0
+        } else {
+            value_len as usize
+        };
+    let mut body = vec![0; body_len];
+    reader.read_exact(&mut body)?;
+
+    let mut crc = Crc::new();
+    crc.update(&header[4..]);
+    crc.update(&body);
+    if crc.sum() != checksum {
+        return Err(KvError::Internal("bitcask record checksum mismatch".into()));
+    }
+    let value = body.split_off(name_len);
+    Ok(Some((body, (value_len != TOMBSTONE).then_some(value))))
+}
+
+fn decode(value: Option<Vec<u8>>) -> Result<Option<Value>, KvError> {
+    value.map(|v| Value::try_from(v.as_slice())).transpose()
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:010}.data", id))
+}
+
+// 持有锁的线程 panic 了也继续用，keydir 和文件总是先写文件再更新 keydir
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn read<T>(l: &RwLock<T>) -> std::sync::RwLockReadGuard<'_, T> {
+    l.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write<T>(l: &RwLock<T>) -> std::sync::RwLockWriteGuard<'_, T> {
+    l.write().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StorageExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn bitcask_should_recover_after_reopen() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStore::with_segment_size(dir.path(), 64);
+        for i in 0..10 {
+            store.hset("t1", format!("k{}", i), i).unwrap();
+        }
+        store.del("t1", "k3").unwrap();
+        store.hset("t1", "k4", "v4").unwrap();
+        drop(store);
+
+        // 崩溃时最后一个 record 只写了一半
+        let last = fs::read_dir(dir.path()).unwrap().count() as u64;
+        let last = segment_path(dir.path(), last);
+        let record = encode_record(&table_key("t1", "k0"), Some(b"value"));
+        fs::write(&last, &record[..record.len() - 1]).unwrap();
+
+        let store = BitcaskStore::new(dir.path());
+        assert_eq!(store.len("t1").unwrap(), 9);
+        assert_eq!(store.get("t1", "k3").unwrap(), None);
+        assert_eq!(store.get("t1", "k4").unwrap(), Some("v4".into()));
+        assert_eq!(store.get("t1", "k9").unwrap(), Some(9.into()));
+        assert_eq!(fs::metadata(last).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn bitcask_drop_table_should_survive_reopen() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStore::new(dir.path());
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+        store.hset("t2", "k1", "v1").unwrap();
+        assert_eq!(store.drop_table("t1").unwrap(), 2);
+        drop(store);
+
+        let store = BitcaskStore::new(dir.path());
+        assert_eq!(store.list_tables().unwrap(), ["t2"]);
+        assert_eq!(store.flush_all().unwrap(), 1);
+        drop(store);
+        assert!(BitcaskStore::new(dir.path())
+            .list_tables()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn bitcask_compact_should_keep_live_values() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStore::with_segment_size(dir.path(), 64);
+        for i in 0..20 {
+            store.hset("t1", "k1", i).unwrap();
+            store.hset("t1", format!("k{}", i), i).unwrap();
+        }
+        store.del("t1", "k5").unwrap();
+        let before = store.0.bytes();
+        assert!(store.compact().unwrap() > 0);
+        assert!(store.0.bytes() < before);
+
+        store.hset("t1", "k2", "new").unwrap();
+        for store in [store, BitcaskStore::new(dir.path())] {
+            assert_eq!(store.len("t1").unwrap(), 19);
+            assert_eq!(store.get("t1", "k1").unwrap(), Some(19.into()));
+            assert_eq!(store.get("t1", "k2").unwrap(), Some("new".into()));
+            assert_eq!(store.get("t1", "k5").unwrap(), None);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// 每个 key 用的位数和 hash 的次数，误判率大约是 1%
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+/// 新的 table 的第一个 filter 能放下的 key 的数量
+const MIN_CAPACITY: usize = 1024;
+
+// 容量固定的 bloom filter，位用原子操作设置，插入不需要写锁
+#[derive(Debug)]
+struct Filter {
+    bits: Vec<AtomicU64>,
+    capacity: usize,
+    len: AtomicUsize,
+}
+
+impl Filter {
+    fn new(capacity: usize) -> Self {
+        let words = (capacity * BITS_PER_KEY).div_ceil(64);
+        Self {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            capacity,
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    // 双重 hash：第 i 个位置是 h1 + i * h2
+    fn positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let n = (self.bits.len() * 64) as u64;
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % n) as usize)
+    }
+
+    fn insert(&self, hash: u64) {
+        for p in self.positions(hash) {
+            self.bits[p / 64].fetch_or(1 << (p % 64), Ordering::Relaxed);
+        }
+        self.len.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn contains(&self, hash: u64) -> bool {
+        self.positions(hash)
+            .all(|p| self.bits[p / 64].load(Ordering::Relaxed) & (1 << (p % 64)) != 0)
+    }
+
+    fn is_full(&self) -> bool {
+        self.len.load(Ordering::Relaxed) >= self.capacity
+    }
+}
+
+// 一个 table 的 filter。最后一个满了就加一个容量翻倍的新的，
+// 查的时候看所有的 filter，不用重新扫描 table，也不会漏掉并发的插入
+#[derive(Debug, Default)]
+struct TableFilter(RwLock<Vec<Filter>>);
+
+impl TableFilter {
+    fn insert(&self, hash: u64) {
+        {
+            let filters = self.0.read().unwrap_or_else(PoisonError::into_inner);
+            if let Some(last) = filters.last().filter(|f| !f.is_full()) {
+                last.insert(hash);
+                return;
+            }
+        }
+        let mut filters = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        if filters.last().is_none_or(Filter::is_full) {
+            let capacity = filters.last().map_or(MIN_CAPACITY, |f| f.capacity * 2);
+            filters.push(Filter::new(capacity));
+        }
+        filters.last().unwrap().insert(hash);
+    }
+
+    fn contains(&self, hash: u64) -> bool {
+        let filters = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        filters.iter().any(|f| f.contains(hash))
+    }
+
+    fn bytes(&self) -> usize {
+        let filters = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        filters.iter().map(|f| f.bits.len() * 8).sum()
+    }
+}
+
+/// 每个 table 一个 bloom filter，记录 table 里可能有哪些 key
+///
+/// 写入 key 之前先加到 filter 里，filter 说没有的 key 一定不存在，读的时候不用访问磁盘。
+/// 删除不会从 filter 里去掉 key，只是多了误判，重新打开的时候按现有的 key 重建
+#[derive(Debug, Default)]
+pub(crate) struct Blooms {
+    tables: RwLock<HashMap<String, Arc<TableFilter>>>,
+    skips: AtomicU64,
+}
+
+impl Blooms {
+    pub fn insert(&self, table: &str, key: &[u8]) {
+        let hash = hash(key);
+        {
+            let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
+            if let Some(filter) = tables.get(table) {
+                filter.insert(hash);
+                return;
+            }
+        }
+        let filter = {
+            let mut tables = self.tables.write().unwrap_or_else(PoisonError::into_inner);
+            tables.entry(table.into()).or_default().clone()
+        };
+        filter.insert(hash);
+    }
+
+    /// key 是否可能在 table 里，返回 false 时记一次跳过
+    pub fn may_contain(&self, table: &str, key: &[u8]) -> bool {
+        let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
+        let found = tables.get(table).is_some_and(|f| f.contains(hash(key)));
+        if !found {
+            self.skips.fetch_add(1, Ordering::Relaxed);
+        }
+        found
+    }
+
+    /// filter 说没有、不用读磁盘的次数
+    pub fn skips(&self) -> u64 {
+        self.skips.load(Ordering::Relaxed)
+    }
+
+    /// 所有 filter 占用的内存
+    pub fn bytes(&self) -> usize {
+        let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
+        tables.values().map(|f| f.bytes()).sum()
+    }
+}
+
+fn hash(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blooms_should_not_miss_inserted_keys() {
+        let blooms = Blooms::default();
+        // 超过第一个 filter 的容量，会加新的 filter
+        let n = MIN_CAPACITY * 4;
+        for i in 0..n {
+            blooms.insert("t1", format!("k{}", i).as_bytes());
+        }
+        assert!((0..n).all(|i| blooms.may_contain("t1", format!("k{}", i).as_bytes())));
+        assert!(!blooms.may_contain("t2", b"k1"));
+
+        let misses = (n..n * 2)
+            .filter(|i| blooms.may_contain("t1", format!("k{}", i).as_bytes()))
+            .count();
+        assert!(misses < n / 20, "{} false positives", misses);
+        assert!(blooms.skips() > (n - n / 20) as u64);
+        assert!(blooms.bytes() >= n * BITS_PER_KEY / 8);
+    }
+}
@@ -0,0 +1,416 @@
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use super::{Changefeed, KeyEvent, KeyEventKind};
+use crate::{free_lazily, BulkLoadStats, KvError, Kvpair, Storage, Value};
+
+/// 热点 key 保存在 upstream 的这个 table 里，key 是 `<table>:<key>`，value 是 table
+pub const HOT_KEYS_TABLE: &str = "__hot__";
+
+/// 读穿透的缓存
+///
+/// 读的时候先查本地，没有或者已经过期就去 upstream（通常是一个 RemoteStore）取，
+/// 取到后在本地缓存 ttl 这么久。写操作直接写到 upstream，同时更新本地的缓存，
+/// 所以 upstream 始终是数据的权威来源。get_all 和 get_iter 本地无法知道缓存是否完整，
+/// 总是访问 upstream。
+///
+/// 设置了 changefeed 的话，缓存的 key 过期时会发出 Expired 事件。
+///
+/// 重启之后缓存是空的，所有的读都要访问 upstream。可以在停止前用 save_hot_keys
+/// 把最近访问的 key 记到 upstream，启动时在接受请求之前调用 warm_up 预先加载。
+pub struct ReadThroughCache<L, U> {
+    local: L,
+    upstream: U,
+    ttl: Duration,
+    expires: DashMap<(String, String), Instant>,
+    // 缓存中的 key 最近一次被访问的序号，越大越新
+    accessed: DashMap<(String, String), u64>,
+    seq: AtomicU64,
+    changefeed: Option<Changefeed>,
+}
+
+impl<L: Storage, U: Storage> ReadThroughCache<L, U> {
+    pub fn new(local: L, upstream: U, ttl: Duration) -> Self {
+        Self {
+            local,
+            upstream,
+            ttl,
+            expires: DashMap::new(),
+            accessed: DashMap::new(),
+            seq: AtomicU64::new(0),
+            changefeed: None,
+        }
+    }
+
+    pub fn with_changefeed(mut self, changefeed: Changefeed) -> Self {
+        self.changefeed = Some(changefeed);
+        self
+    }
+
+    /// 预先加载 tables 里所有的 key，hot_keys 为 true 时再加载 save_hot_keys 记下的 key，
+    /// 返回加载的 key 的数量
+    pub fn warm_up(&self, tables: &[&str], hot_keys: bool) -> Result<usize, KvError> {
+        let mut loaded = 0;
+        for table in tables {
+            for pair in self.upstream.get_iter(table)? {
+                self.cache(table, &pair.key, pair.value.unwrap_or_default())?;
+                loaded += 1;
+            }
+        }
+        if !hot_keys {
+            return Ok(loaded);
+        }
+
+        for pair in self.upstream.get_iter(HOT_KEYS_TABLE)? {
+            let table = match pair.value.map(String::try_from) {
+                Some(Ok(table)) => table,
+                _ => continue,
+            };
+            let key = match pair.key.strip_prefix(&format!("{}:", table)) {
+                Some(key) => key,
+                None => continue,
+            };
+            if let Some(v) = self.upstream.get(&table, key)? {
+                self.cache(&table, key, v)?;
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// 把最近访问的 n 个 key 记到 upstream，替换掉之前记下的
+    pub fn save_hot_keys(&self, n: usize) -> Result<usize, KvError> {
+        let mut hot: Vec<_> = self
+            .accessed
+            .iter()
+            .map(|e| (*e.value(), e.key().clone()))
+            .collect();
+        hot.sort_unstable_by_key(|(seq, _)| Reverse(*seq));
+        hot.truncate(n);
+
+        for pair in self.upstream.get_iter(HOT_KEYS_TABLE)? {
+            self.upstream.del(HOT_KEYS_TABLE, &pair.key)?;
+        }
+        for (_, (table, key)) in &hot {
+            let id = format!("{}:{}", table, key);
+            self.upstream
+                .set(HOT_KEYS_TABLE, id, table.as_str().into())?;
+        }
+        Ok(hot.len())
+    }
+
+    fn cache(&self, table: &str, key: &str, value: Value) -> Result<(), KvError> {
+        self.local.set(table, key.into(), value)?;
+        self.expires
+            .insert((table.into(), key.into()), Instant::now() + self.ttl);
+        self.touch(table, key);
+        Ok(())
+    }
+
+    fn touch(&self, table: &str, key: &str) {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        self.accessed.insert((table.into(), key.into()), seq);
+    }
+
+    fn evict(&self, table: &str, key: &str) -> Result<(), KvError> {
+        self.expires.remove(&(table.into(), key.into()));
+        self.accessed.remove(&(table.into(), key.into()));
+        if let Some(v) = self.local.del(table, key)? {
+            free_lazily(v);
+        }
+        Ok(())
+    }
+
+    // table 为 None 时清空整个缓存
+    fn evict_table(&self, table: Option<&str>) -> Result<(), KvError> {
+        let keys: Vec<_> = self
+            .expires
+            .iter()
+            .filter(|e| table.is_none_or(|t| e.key().0 == t))
+            .map(|e| e.key().clone())
+            .collect();
+        for (table, key) in keys {
+            self.evict(&table, &key)?;
+        }
+        Ok(())
+    }
+
+    /// 本地缓存中没有过期的 value
+    fn cached(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let id = (table.to_string(), key.to_string());
+        let now = Instant::now();
+        let fresh = match self.expires.get(&id) {
+            Some(t) => *t > now,
+            None => return Ok(None),
+        };
+        if fresh {
+            self.touch(table, key);
+            return self.local.get(table, key);
+        }
+
+        // 只有把过期时间删掉的那个调用者发出事件，并发访问时不会重复
+        if self.expires.remove_if(&id, |_, t| *t <= now).is_some() {
+            self.accessed.remove(&id);
+            if let Some(v) = self.local.del(table, key)? {
+                if let Some(feed) = &self.changefeed {
+                    feed.publish(KeyEvent::new(table, key, KeyEventKind::Expired, &v));
+                }
+                free_lazily(v);
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<L: Storage, U: Storage> Storage for ReadThroughCache<L, U> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        if let Some(v) = self.cached(table, key)? {
+            return Ok(Some(v));
+        }
+
+        match self.upstream.get(table, key)? {
+            Some(v) => {
+                self.cache(table, key, v.clone())?;
+                Ok(Some(v))
+            }
+            None => {
+                self.evict(table, key)?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let old = self.upstream.set(table, key.clone(), value.clone())?;
+        self.cache(table, &key, value)?;
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.get(table, key)?.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let old = self.upstream.del(table, key)?;
+        self.evict(table, key)?;
+        Ok(old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.upstream.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.upstream.get_iter(table)
+    }
+
+    fn mset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+        let olds = self.upstream.mset(table, pairs.clone())?;
+        for pair in pairs {
+            self.cache(table, &pair.key, pair.value.unwrap_or_default())?;
+        }
+        Ok(olds)
+    }
+
+    fn mdel(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        let olds = self.upstream.mdel(table, keys)?;
+        for key in keys {
+            self.evict(table, key)?;
+        }
+        Ok(olds)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let n = self.upstream.incr(table, key, delta)?;
+        self.cache(table, key, n.into())?;
+        Ok(n)
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        let written = self.upstream.set_nx(table, key.clone(), value.clone())?;
+        if written {
+            self.cache(table, &key, value)?;
+        }
+        Ok(written)
+    }
+
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        let version = self
+            .upstream
+            .set_if_version(table, key.clone(), value.clone(), version)?;
+        self.cache(table, &key, value)?;
+        Ok(version)
+    }
+
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        let moved = self.upstream.move_key(src, dst, key, force)?;
+        self.evict(src, key)?;
+        self.evict(dst, key)?;
+        Ok(moved)
+    }
+
+    fn undelete(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let restored = self.upstream.undelete(table, key)?;
+        self.evict(table, key)?;
+        Ok(restored)
+    }
+
+    // upstream 上的 key 可能比缓存的 ttl 更早过期，缓存里不再保留它
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        let exists = self.upstream.expire_at(table, key, deadline)?;
+        self.evict(table, key)?;
+        Ok(exists)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        let n = self.upstream.drop_table(table)?;
+        self.evict_table(Some(table))?;
+        Ok(n)
+    }
+
+    fn flush_all(&self) -> Result<usize, KvError> {
+        let n = self.upstream.flush_all()?;
+        self.evict_table(None)?;
+        Ok(n)
+    }
+
+    fn bulk_load(
+        &self,
+        table: &str,
+        pairs: &mut dyn Iterator<Item = Kvpair>,
+    ) -> Result<BulkLoadStats, KvError> {
+        let stats = self.upstream.bulk_load(table, pairs)?;
+        self.evict_table(Some(table))?;
+        Ok(stats)
+    }
+
+    // 其它的操作都由 upstream 处理，import 用缺省实现，经过上面的 set 更新缓存
+    forward_to!(upstream;
+        get_iter_ordered, snapshot, len, get_iter_matching, scan, get_range, admin,
+        purge_trash, history, clone_table, list_tables, stats, persist, deadline,
+        purge_expired, version, meta, flush, query, export, metrics);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, StorageExt};
+    use std::sync::Arc;
+
+    #[test]
+    fn read_through_cache_should_fetch_on_miss_and_expire() {
+        // upstream 用 Arc 共享，模拟别的客户端直接写上游
+        let upstream = Arc::new(MemTable::new());
+        let local = Arc::new(MemTable::new());
+        let cache =
+            ReadThroughCache::new(local.clone(), upstream.clone(), Duration::from_millis(50));
+
+        upstream.hset("t1", "k1", "v1").unwrap();
+        assert_eq!(cache.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(local.get("t1", "k1").unwrap(), Some("v1".into()));
+
+        // ttl 内读到的是缓存的值
+        upstream.hset("t1", "k1", "v2").unwrap();
+        assert_eq!(cache.get("t1", "k1").unwrap(), Some("v1".into()));
+
+        // 过期后重新从 upstream 取
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get("t1", "k1").unwrap(), Some("v2".into()));
+    }
+
+    #[test]
+    fn read_through_cache_should_write_through() {
+        let upstream = Arc::new(MemTable::new());
+        let cache =
+            ReadThroughCache::new(MemTable::new(), upstream.clone(), Duration::from_secs(60));
+
+        assert_eq!(cache.hset("t1", "k1", "v1").unwrap(), None);
+        assert_eq!(upstream.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(cache.del("t1", "k1").unwrap(), Some("v1".into()));
+        assert!(!cache.contains("t1", "k1").unwrap());
+        assert!(!upstream.contains("t1", "k1").unwrap());
+    }
+
+    #[test]
+    fn hot_keys_should_be_warmed_up_after_restart() {
+        let upstream = Arc::new(MemTable::new());
+        for key in ["k1", "k2", "k3"] {
+            upstream.hset("t1", key, "v").unwrap();
+        }
+        upstream.hset("t2", "k1", "v").unwrap();
+
+        let cache =
+            ReadThroughCache::new(MemTable::new(), upstream.clone(), Duration::from_secs(60));
+        for key in ["k1", "k2", "k1", "k3"] {
+            cache.get("t1", key).unwrap();
+        }
+        cache.get("t1", "k2").unwrap();
+        assert_eq!(cache.save_hot_keys(2).unwrap(), 2);
+
+        // 重启之后本地是空的
+        let local = Arc::new(MemTable::new());
+        let cache = ReadThroughCache::new(local.clone(), upstream, Duration::from_secs(60));
+        assert_eq!(cache.warm_up(&["t2"], true).unwrap(), 3);
+        assert!(local.contains("t2", "k1").unwrap());
+        assert!(local.contains("t1", "k2").unwrap());
+        assert!(local.contains("t1", "k3").unwrap());
+        assert!(!local.contains("t1", "k1").unwrap());
+    }
+
+    #[test]
+    fn read_through_cache_should_publish_expired_events() {
+        let upstream = Arc::new(MemTable::new());
+        let feed = Changefeed::new(16);
+        let mut events = feed.subscribe();
+        let cache = ReadThroughCache::new(MemTable::new(), upstream.clone(), Duration::ZERO)
+            .with_changefeed(feed);
+
+        upstream.hset("t1", "k1", "v1").unwrap();
+        cache.get("t1", "k1").unwrap();
+        // 显式的删除不产生事件
+        cache.del("t1", "k1").unwrap();
+        assert!(events.try_recv().is_err());
+
+        cache.hset("t1", "k1", "v2").unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+        cache.get("t1", "k1").unwrap();
+        let event = events.try_recv().unwrap();
+        assert_eq!(
+            event,
+            KeyEvent::new("t1", "k1", KeyEventKind::Expired, &"v2".into())
+        );
+    }
+
+    #[test]
+    fn atomic_commands_should_update_cache() {
+        let upstream = Arc::new(MemTable::new());
+        let cache =
+            ReadThroughCache::new(MemTable::new(), upstream.clone(), Duration::from_secs(60));
+
+        cache.hset("t1", "n", 1).unwrap();
+        assert_eq!(cache.incr("t1", "n", 2).unwrap(), 3);
+        assert_eq!(cache.get("t1", "n").unwrap(), Some(3.into()));
+        assert_eq!(cache.len("t1").unwrap(), 1);
+        assert_eq!(cache.list_tables().unwrap(), vec!["t1".to_string()]);
+
+        // 表删除之后缓存里也没有了
+        assert_eq!(cache.drop_table("t1").unwrap(), 1);
+        assert_eq!(cache.get("t1", "n").unwrap(), None);
+        assert!(cache.expires.is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+use tokio::sync::broadcast;
+
+use super::merkle::value_hash;
+use crate::Value;
+
+/// key 发生了什么变化
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEventKind {
+    /// ttl 到期，自然消失
+    Expired,
+    /// 因为容量或者内存的限制被淘汰
+    Evicted,
+}
+
+/// changefeed 里的一个事件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub table: String,
+    pub key: String,
+    pub kind: KeyEventKind,
+    /// 最后一个 value 的 hash，订阅者可以用它判断自己手里的是不是同一个 value
+    pub value_hash: u64,
+}
+
+impl KeyEvent {
+    pub fn new(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        kind: KeyEventKind,
+        value: &Value,
+    ) -> Self {
+        Self {
+            table: table.into(),
+            key: key.into(),
+            kind,
+            value_hash: value_hash(value),
+        }
+    }
+}
+
+/// 存储层的变化通知
+///
+/// 显式的删除调用者自己知道，changefeed 只报告存储自己让 key 消失的情况，
+/// 这样缓存的使用者可以区分自然过期和有意的删除。订阅者跟不上时会丢掉最老的事件，
+/// 不会阻塞存储。
+#[derive(Debug, Clone)]
+pub struct Changefeed {
+    tx: broadcast::Sender<KeyEvent>,
+}
+
+impl Changefeed {
+    /// capacity 是每个订阅者最多缓存的事件数
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity.max(1));
+        Self { tx }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<KeyEvent> {
+        self.tx.subscribe()
+    }
+
+    /// 没有订阅者的时候事件直接丢掉
+    pub fn publish(&self, event: KeyEvent) {
+        let _ = self.tx.send(event);
+    }
+}
@@ -0,0 +1,242 @@
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Crc};
+
+use crate::network::MAX_MESSAGE;
+use crate::{KvError, Value};
+
+/// 压缩过的 value 以它开头。protobuf 的 field number 不能是 0，
+/// 没有压缩的 value 编码出来不会以 0 开头，老的数据不需要转换
+const COMPRESSED: u8 = 0;
+/// 没有压缩的 Value 只有一个 oneof 字段，第一个字节一定是这几个 tag 之一。
+/// 它们和 COMPRESSED 至少差两位，第一个字节翻转了一位也不会被当成没有压缩的 value
+const VALUE_TAGS: [u8; 5] = [0x0a, 0x12, 0x18, 0x21, 0x28];
+/// 算法字节里的这一位表示最后 4 个字节是前面所有字节的 CRC32
+const CHECKSUMMED: u8 = 0x80;
+
+/// 写入 backend 之前压缩 value 的方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    #[cfg(any(test, feature = "zstd"))]
+    Zstd,
+}
+
+impl Compression {
+    // 跟在 COMPRESSED 后面，代表压缩算法的字节
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Gzip => 1,
+            #[cfg(any(test, feature = "zstd"))]
+            Compression::Zstd => 2,
+        }
+    }
+}
+
+/// 把 value 编码成保存在 backend 里的字节，编码之后不小于 threshold 的才压缩，
+/// checksum 时在最后加上 CRC32。格式是自描述的，解码不需要知道写入时的配置
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ValueCodec {
+    pub compression: Compression,
+    pub threshold: usize,
+    pub checksum: bool,
+}
+
+impl ValueCodec {
+    pub fn encode(&self, value: Value) -> Result<Vec<u8>, KvError> {
+        let data: Vec<u8> = value.try_into()?;
+        let mut buf = match self.compress(&data)? {
+            Some(buf) => buf,
+            None if !self.checksum => return Ok(data),
+            None => [&[COMPRESSED, Compression::None.id()][..], &data].concat(),
+        };
+        if self.checksum {
+            buf[1] |= CHECKSUMMED;
+            buf.extend_from_slice(&crc32(&buf).to_be_bytes());
+        }
+        Ok(buf)
+    }
+
+    // 返回以 COMPRESSED 和算法开头的压缩结果，不需要压缩或者压缩之后没有变小时返回 None
+    fn compress(&self, data: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+        if self.compression == Compression::None || data.len() < self.threshold {
+            return Ok(None);
+        }
+        let mut buf = vec![COMPRESSED, self.compression.id()];
+        match self.compression {
+            Compression::None => unreachable!(),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(buf, flate2::Compression::default());
+                encoder.write_all(data)?;
+                buf = encoder.finish()?;
+            }
+            #[cfg(any(test, feature = "zstd"))]
+            Compression::Zstd => zstd::stream::copy_encode(data, &mut buf, 0)?,
+        }
+        // 压缩之后没有变小就不压缩
+        Ok((buf.len() < data.len()).then_some(buf))
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+/// 去掉 checksum 和没有压缩时的格式字节之后的长度，用来统计数据本身的大小
+pub(crate) fn payload_len(data: &[u8]) -> usize {
+    match data {
+        [COMPRESSED, CHECKSUMMED, ..] => data.len().saturating_sub(6),
+        [COMPRESSED, id, ..] if id & CHECKSUMMED != 0 => data.len().saturating_sub(4),
+        _ => data.len(),
+    }
+}
+
+/// 解码 ValueCodec::encode 的结果。checksum 不对或者解压之后超过 MAX_MESSAGE 时
+/// 返回 DataCorruption，编译时没有打开的压缩算法返回 Unsupported
+pub(crate) fn decode_value(data: &[u8]) -> Result<Value, KvError> {
+    decode_with_limit(data, MAX_MESSAGE)
+}
+
+fn decode_with_limit(data: &[u8], max: usize) -> Result<Value, KvError> {
+    let (mut id, mut compressed) = match data {
+        [COMPRESSED, id, rest @ ..] => (*id, rest),
+        [] => return Ok(Value::default()),
+        [tag, ..] if VALUE_TAGS.contains(tag) => return data.try_into(),
+        [tag, ..] => {
+            return Err(KvError::DataCorruption(format!(
+                "unknown value format {:02x}",
+                tag
+            )))
+        }
+    };
+    if id & CHECKSUMMED != 0 {
+        if compressed.len() < 4 {
+            return Err(KvError::DataCorruption("value is truncated".into()));
+        }
+        let (body, sum) = data.split_at(data.len() - 4);
+        let expected = u32::from_be_bytes(sum.try_into().unwrap());
+        let actual = crc32(body);
+        if actual != expected {
+            return Err(KvError::DataCorruption(format!(
+                "checksum is {:08x}, expect {:08x}",
+                actual, expected
+            )));
+        }
+        id &= !CHECKSUMMED;
+        compressed = &body[2..];
+    }
+    let mut buf = Vec::new();
+    // 和 frame 一样限制解压之后的大小，一个压缩炸弹不能耗尽内存
+    let limit = max as u64 + 1;
+    match id {
+        0 => return compressed.try_into(),
+        1 => {
+            GzDecoder::new(compressed)
+                .take(limit)
+                .read_to_end(&mut buf)?;
+        }
+        #[cfg(any(test, feature = "zstd"))]
+        2 => {
+            zstd::stream::read::Decoder::new(compressed)?
+                .take(limit)
+                .read_to_end(&mut buf)?;
+        }
+        _ => {
+            return Err(KvError::Unsupported(format!(
+                "value compressed with algorithm {}",
+                id
+            )))
+        }
+    }
+    if buf.len() > max {
+        return Err(KvError::DataCorruption(format!(
+            "value is larger than {} bytes after decompression",
+            max
+        )));
+    }
+    buf.as_slice().try_into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codec_should_roundtrip() {
+        let json: Value = "{\"name\": \"kv\"}".repeat(100).into();
+        let small: Value = "small".into();
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let codec = ValueCodec {
+                compression,
+                threshold: 64,
+                checksum: false,
+            };
+            let data = codec.encode(json.clone()).unwrap();
+            assert_eq!(data[0] == COMPRESSED, compression != Compression::None);
+            assert_eq!(decode_value(&data).unwrap(), json);
+
+            // 小的 value 和没有压缩时一样
+            let data = codec.encode(small.clone()).unwrap();
+            assert_eq!(data, Vec::<u8>::try_from(small.clone()).unwrap());
+            assert_eq!(decode_value(&data).unwrap(), small);
+        }
+        assert!(decode_value(&[COMPRESSED, 9, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn codec_should_limit_decompressed_size() {
+        let zeros = vec![0u8; 64 * 1024];
+        let mut gzip = vec![COMPRESSED, Compression::Gzip.id()];
+        let mut encoder = GzEncoder::new(&mut gzip, flate2::Compression::fast());
+        encoder.write_all(&zeros).unwrap();
+        encoder.finish().unwrap();
+        let mut zstd = vec![COMPRESSED, Compression::Zstd.id()];
+        zstd::stream::copy_encode(zeros.as_slice(), &mut zstd, 1).unwrap();
+
+        for data in [gzip, zstd] {
+            let err = decode_with_limit(&data, 1024).unwrap_err();
+            assert!(matches!(err, KvError::DataCorruption(_)), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn codec_should_detect_corruption() {
+        let json: Value = "{\"name\": \"kv\"}".repeat(100).into();
+        for (compression, value) in [
+            (Compression::None, "small".into()),
+            (Compression::Gzip, json),
+        ] {
+            let codec = ValueCodec {
+                compression,
+                threshold: 64,
+                checksum: true,
+            };
+            let mut data = codec.encode(value.clone()).unwrap();
+            assert_eq!(decode_value(&data).unwrap(), value);
+
+            // 不管哪一位翻转都能发现
+            for bit in 0..8 {
+                data[0] ^= 1 << bit;
+                let err = decode_value(&data).unwrap_err();
+                assert!(matches!(err, KvError::DataCorruption(_)), "{:?}", err);
+                data[0] ^= 1 << bit;
+            }
+            for i in [1, data.len() / 2, data.len() - 1] {
+                data[i] ^= 0x01;
+                let err = decode_value(&data).unwrap_err();
+                assert!(matches!(err, KvError::DataCorruption(_)), "{:?}", err);
+                data[i] ^= 0x01;
+            }
+            assert!(matches!(
+                decode_value(&data[..data.len() - 1]),
+                Err(KvError::DataCorruption(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use std::io::{BufRead, Write};
+
+use bytes::Bytes;
+use serde_json::{json, Value as JsonValue};
+
+use crate::{value, KvError, Kvpair, Storage, Value};
+
+// load 的时候每攒够这么多 key 写一次
+const LOAD_BATCH: usize = 1024;
+
+/// dump 和 load 的文本格式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// 每行一个 JSON 对象：`{"table": "t1", "key": "k1", "value": "v1"}`。
+    /// 字符串、整数、浮点数和布尔值是 JSON 的标量，binary 是字节的数组
+    #[default]
+    JsonLines,
+    /// 第一行是 `table,key,type,value`，type 是 string、integer、float、bool 或 binary，
+    /// binary 写成十六进制。按 RFC 4180 加引号
+    Csv,
+}
+
+impl std::str::FromStr for DumpFormat {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" | "jsonl" => Ok(DumpFormat::JsonLines),
+            "csv" => Ok(DumpFormat::Csv),
+            _ => Err(KvError::InvalidCommand(format!("unknown format {}", s))),
+        }
+    }
+}
+
+const CSV_HEADER: &str = "table,key,type,value";
+
+/// 把 tables 写成 format 的文本，tables 为空时写所有 table，返回写出的 key 的数量。
+/// 用来给测试环境准备数据，或者交给别的工具分析；需要完整恢复的话用 Storage::export
+pub fn dump<S: Storage + ?Sized>(
+    store: &S,
+    tables: &[&str],
+    format: DumpFormat,
+    w: &mut dyn Write,
+) -> Result<usize, KvError> {
+    let tables = match tables {
+        [] => store.list_tables()?,
+        tables => tables.iter().map(|t| t.to_string()).collect(),
+    };
+    if format == DumpFormat::Csv {
+        writeln!(w, "{}", CSV_HEADER)?;
+    }
+    let mut n = 0;
+    for table in &tables {
+        for pair in store.get_iter(table)? {
+            let value = pair.value.unwrap_or_default();
+            match format {
+                DumpFormat::JsonLines => {
+                    let line = json!({
+                        "table": table,
+                        "key": pair.key,
+                        "value": value_to_json(&value)?,
+                    });
+                    writeln!(w, "{}", line)?;
+                }
+                DumpFormat::Csv => {
+                    let (kind, text) = value_to_csv(&value);
+                    let fields = [table.as_str(), &pair.key, kind, &text].map(csv_field);
+                    writeln!(w, "{}", fields.join(","))?;
+                }
+            }
+            n += 1;
+        }
+    }
+    w.flush()?;
+    Ok(n)
+}
+
+/// 读入 dump 写出的文本，已经存在的 key 被覆盖，返回读入的 key 的数量。
+/// 出错时返回的错误带着行号，之前的行已经写进去了
+pub fn load<S: Storage + ?Sized>(
+    store: &S,
+    format: DumpFormat,
+    r: &mut dyn BufRead,
+) -> Result<usize, KvError> {
+    let (mut table, mut batch, mut n) = (String::new(), Vec::new(), 0);
+    let mut line = 0;
+    while let Some((next, pair)) = read_row(format, r, &mut line)? {
+        if next != table || batch.len() >= LOAD_BATCH {
+            if !batch.is_empty() {
+                store.mset(&table, std::mem::take(&mut batch))?;
+            }
+            table = next;
+        }
+        batch.push(pair);
+        n += 1;
+    }
+    if !batch.is_empty() {
+        store.mset(&table, batch)?;
+    }
+    Ok(n)
+}
+
+fn read_row(
+    format: DumpFormat,
+    r: &mut dyn BufRead,
+    line: &mut usize,
+) -> Result<Option<(String, Kvpair)>, KvError> {
+    let bad = |line: usize, msg: &str| KvError::InvalidCommand(format!("line {}: {}", line, msg));
+    loop {
+        let start = *line + 1;
+        let row = match format {
+            DumpFormat::JsonLines => {
+                let mut text = String::new();
+                if r.read_line(&mut text)? == 0 {
+                    return Ok(None);
+                }
+                *line += 1;
+                if text.trim().is_empty() {
+                    continue;
+                }
+                let mut row: JsonValue =
+                    serde_json::from_str(&text).map_err(|e| bad(start, &e.to_string()))?;
+                let mut field = |name| match row.get_mut(name).map(JsonValue::take) {
+                    Some(JsonValue::String(s)) => Ok(s),
+                    _ => Err(bad(start, &format!("{} must be a string", name))),
+                };
+                let (table, key) = (field("table")?, field("key")?);
+                let value = match row.get_mut("value").map(JsonValue::take) {
+                    None | Some(JsonValue::Null) => Value::default(),
+                    Some(v) => json_to_value(v).ok_or_else(|| bad(start, "unsupported value"))?,
+                };
+                (table, Kvpair::new(key, value))
+            }
+            DumpFormat::Csv => {
+                let fields = match read_csv_record(r, line)? {
+                    Some(fields) => fields,
+                    None => return Ok(None),
+                };
+                match fields.as_slice() {
+                    // 第一行可能是 CSV_HEADER
+                    [t, k, kind, v]
+                        if start == 1
+                            && t == "table"
+                            && k == "key"
+                            && kind == "type"
+                            && v == "value" =>
+                    {
+                        continue
+                    }
+                    [table, key, kind, text] => {
+                        let value = csv_to_value(kind, text)
+                            .ok_or_else(|| bad(start, &format!("bad {} {:?}", kind, text)))?;
+                        (table.clone(), Kvpair::new(key, value))
+                    }
+                    [f] if f.is_empty() => continue,
+                    _ => return Err(bad(start, "expect table,key,type,value")),
+                }
+            }
+        };
+        return Ok(Some(row));
+    }
+}
+
+/// JSON 里能直接表示的 Value 用 JSON 的标量，binary 是字节的数组
+fn value_to_json(v: &Value) -> Result<JsonValue, KvError> {
+    Ok(match &v.value {
+        None => JsonValue::Null,
+        Some(value::Value::String(s)) => s.as_str().into(),
+        Some(value::Value::Integer(i)) => (*i).into(),
+        Some(value::Value::Float(f)) => serde_json::Number::from_f64(*f)
+            .ok_or_else(|| KvError::ConvertError(v.clone(), "JSON number"))?
+            .into(),
+        Some(value::Value::Bool(b)) => (*b).into(),
+        Some(value::Value::Binary(b)) => b.as_ref().into(),
+    })
+}
+
+/// JSON 的标量直接对应 Value 的各个类型；其他的按 protobuf 的 JSON 形式
+/// 解析，比如 `{"value": {"binary": [1, 2, 3]}}`
+pub(crate) fn json_to_value(v: JsonValue) -> Option<Value> {
+    match v {
+        JsonValue::String(s) => Some(s.into()),
+        JsonValue::Bool(b) => Some(b.into()),
+        JsonValue::Number(n) => match n.as_i64() {
+            Some(i) => Some(i.into()),
+            None => n.as_f64().map(|f| f.into()),
+        },
+        JsonValue::Array(a) => a
+            .into_iter()
+            .map(|v| v.as_u64().and_then(|b| u8::try_from(b).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .map(|b| Value {
+                value: Some(value::Value::Binary(Bytes::from(b))),
+            }),
+        v => serde_json::from_value::<Value>(v)
+            .ok()
+            .filter(|v| v.value.is_some()),
+    }
+}
+
+fn value_to_csv(v: &Value) -> (&'static str, String) {
+    match &v.value {
+        None => ("", String::new()),
+        Some(value::Value::String(s)) => ("string", s.clone()),
+        Some(value::Value::Integer(i)) => ("integer", i.to_string()),
+        Some(value::Value::Float(f)) => ("float", f.to_string()),
+        Some(value::Value::Bool(b)) => ("bool", b.to_string()),
+        Some(value::Value::Binary(b)) => {
+            ("binary", b.iter().map(|b| format!("{:02x}", b)).collect())
+        }
+    }
+}
+
+fn csv_to_value(kind: &str, text: &str) -> Option<Value> {
+    Some(match kind {
+        "" => Value::default(),
+        "string" => text.into(),
+        "integer" => text.parse::<i64>().ok()?.into(),
+        "float" => text.parse::<f64>().ok()?.into(),
+        "bool" => text.parse::<bool>().ok()?.into(),
+        "binary" if text.len().is_multiple_of(2) => {
+            let bytes = (0..text.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+            Bytes::from(bytes).into()
+        }
+        _ => return None,
+    })
+}
+
+// 有逗号、引号或者换行的字段加上引号，里面的引号写两次
+fn csv_field(s: &str) -> String {
+    match s.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", s.replace('"', "\"\"")),
+        false => s.to_string(),
+    }
+}
+
+// 读一条记录，引号里的换行属于字段，一条记录可能跨多行
+fn read_csv_record(r: &mut dyn BufRead, line: &mut usize) -> Result<Option<Vec<String>>, KvError> {
+    let (mut fields, mut field) = (Vec::new(), String::new());
+    let (mut quoted, mut text) = (false, String::new());
+    loop {
+        text.clear();
+        if r.read_line(&mut text)? == 0 {
+            return match quoted {
+                true => Err(KvError::InvalidCommand(format!(
+                    "line {}: unclosed quote",
+                    line
+                ))),
+                false if fields.is_empty() && field.is_empty() => Ok(None),
+                false => {
+                    fields.push(field);
+                    Ok(Some(fields))
+                }
+            };
+        }
+        *line += 1;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (quoted, c) {
+                (true, '"') if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                (true, '"') => quoted = false,
+                (true, c) => field.push(c),
+                (false, '"') => quoted = true,
+                (false, ',') => fields.push(std::mem::take(&mut field)),
+                (false, '\r' | '\n') => {}
+                (false, c) => field.push(c),
+            }
+        }
+        if !quoted {
+            fields.push(field);
+            return Ok(Some(fields));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, StorageExt};
+
+    fn sample() -> MemTable {
+        let store = MemTable::new();
+        store.hset("t1", "s", "a,\"b\"\nc").unwrap();
+        store.hset("t1", "i", 42).unwrap();
+        store.hset("t1", "f", 1.0).unwrap();
+        store.hset("t2", "b", true).unwrap();
+        store.hset("t2", "bin", b"\x00\xff").unwrap();
+        store
+    }
+
+    #[test]
+    fn dump_and_load_should_roundtrip() {
+        for format in [DumpFormat::JsonLines, DumpFormat::Csv] {
+            let src = sample();
+            let mut buf = Vec::new();
+            assert_eq!(dump(&src, &[], format, &mut buf).unwrap(), 5);
+
+            let dst = MemTable::new();
+            assert_eq!(load(&dst, format, &mut buf.as_slice()).unwrap(), 5);
+            for table in ["t1", "t2"] {
+                let mut expected = src.get_all(table).unwrap();
+                let mut pairs = dst.get_all(table).unwrap();
+                expected.sort_by(|a, b| a.key.cmp(&b.key));
+                pairs.sort_by(|a, b| a.key.cmp(&b.key));
+                assert_eq!(pairs, expected, "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn dump_should_write_readable_rows() {
+        let store = sample();
+        let mut buf = Vec::new();
+        dump(&store, &["t2"], DumpFormat::JsonLines, &mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.contains(r#"{"key":"b","table":"t2","value":true}"#));
+        assert!(text.contains(r#""value":[0,255]"#));
+
+        let mut buf = Vec::new();
+        dump(&store, &["t1"], DumpFormat::Csv, &mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.starts_with("table,key,type,value\n"));
+        assert!(text.contains("t1,s,string,\"a,\"\"b\"\"\nc\"\n"));
+        assert!(text.contains("t1,f,float,1\n"));
+
+        let err = load(&store, DumpFormat::Csv, &mut "t1,k,integer,x\n".as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("line 1"), "{}", err);
+    }
+
+    #[test]
+    fn json_to_value_should_work() {
+        assert_eq!(json_to_value("a".into()), Some("a".into()));
+        assert_eq!(json_to_value(1.into()), Some(1.into()));
+        assert_eq!(json_to_value(1.5.into()), Some(1.5.into()));
+        assert_eq!(json_to_value(true.into()), Some(true.into()));
+        assert_eq!(json_to_value(json!([1, 2])), Some(b"\x01\x02".into()));
+        assert_eq!(
+            json_to_value(json!({"value": {"integer": 3}})),
+            Some(3.into())
+        );
+        assert_eq!(json_to_value(json!({"bad": 1})), None);
+    }
+}
@@ -0,0 +1,417 @@
+use std::convert::TryInto;
+
+use bytes::Bytes;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::table_key;
+use crate::{
+    value, BulkLoadStats, Glob, KeyMeta, KeyRange, KvError, Kvpair, Storage, StorageMetrics,
+    TableStats, Value,
+};
+
+/// 加密 value 的算法，key 都是 32 个字节
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Cipher {
+    #[default]
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Cipher::Aes256Gcm => &aead::AES_256_GCM,
+            Cipher::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+}
+
+/// 加密 value 的 Storage
+///
+/// value 编码之后用 AEAD 加密，以 binary 的 value 保存在 inner 里：一个字节的算法，
+/// 随机的 nonce，然后是密文和 tag。table 和 key 不加密，但作为 associated data 参与认证，
+/// 一个 key 的密文换到别的 key 下面会解密失败。
+/// 所以 MOVE 不能直接移动密文，不支持；clone_table 会逐个解密再加密。
+/// 整数在 inner 里是密文，HINCRBY 也不支持
+pub struct EncryptedStore<S> {
+    inner: S,
+    cipher: Cipher,
+    key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl<S: Storage> EncryptedStore<S> {
+    pub fn new(inner: S, cipher: Cipher, key: &[u8]) -> Result<Self, KvError> {
+        let key = UnboundKey::new(cipher.algorithm(), key)
+            .map_err(|_| KvError::CryptoError("encryption key must be 32 bytes".into()))?;
+        Ok(Self {
+            inner,
+            cipher,
+            key: LessSafeKey::new(key),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    fn encrypt(&self, table: &str, key: &str, value: Value) -> Result<Value, KvError> {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| KvError::CryptoError("cannot generate nonce".into()))?;
+        let mut data: Vec<u8> = value.try_into()?;
+        let aad = table_key(table, key);
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(&aad),
+                &mut data,
+            )
+            .map_err(|_| KvError::CryptoError(format!("cannot encrypt {}", key)))?;
+        let sealed = [&[self.cipher.id()][..], &nonce, &data].concat();
+        Ok(Bytes::from(sealed).into())
+    }
+
+    fn decrypt(&self, table: &str, key: &str, value: Value) -> Result<Value, KvError> {
+        let sealed = match value.value {
+            Some(value::Value::Binary(b)) => b,
+            _ => return Err(KvError::CryptoError(format!("{} is not encrypted", key))),
+        };
+        let (id, rest) = match sealed.split_first() {
+            Some((id, rest)) if rest.len() >= NONCE_LEN => (*id, rest),
+            _ => return Err(KvError::CryptoError(format!("{} is not encrypted", key))),
+        };
+        if id != self.cipher.id() {
+            return Err(KvError::CryptoError(format!(
+                "{} is encrypted with cipher {}",
+                key, id
+            )));
+        }
+        let (nonce, data) = rest.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce)
+            .map_err(|_| KvError::CryptoError(format!("bad nonce of {}", key)))?;
+        let mut data = data.to_vec();
+        let aad = table_key(table, key);
+        let plain = self
+            .key
+            .open_in_place(nonce, Aad::from(&aad), &mut data)
+            .map_err(|_| KvError::CryptoError(format!("cannot decrypt {}", key)))?;
+        (&*plain).try_into()
+    }
+
+    fn decrypt_opt(
+        &self,
+        table: &str,
+        key: &str,
+        value: Option<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        value.map(|v| self.decrypt(table, key, v)).transpose()
+    }
+
+    fn decrypt_pairs(
+        &self,
+        table: &str,
+        pairs: impl IntoIterator<Item = Kvpair>,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        pairs
+            .into_iter()
+            .map(|pair| {
+                let value = self.decrypt(table, &pair.key, pair.value.unwrap_or_default())?;
+                Ok(Kvpair::new(pair.key, value))
+            })
+            .collect()
+    }
+
+    fn decrypt_values(
+        &self,
+        table: &str,
+        keys: impl IntoIterator<Item = impl AsRef<str>>,
+        values: Vec<Option<Value>>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        keys.into_iter()
+            .zip(values)
+            .map(|(key, value)| self.decrypt_opt(table, key.as_ref(), value))
+            .collect()
+    }
+}
+
+impl<S: Storage> Storage for EncryptedStore<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let value = self.inner.get(table, key)?;
+        self.decrypt_opt(table, key, value)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let sealed = self.encrypt(table, &key, value)?;
+        let old = self.inner.set(table, key.clone(), sealed)?;
+        self.decrypt_opt(table, &key, old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let old = self.inner.del(table, key)?;
+        self.decrypt_opt(table, key, old)
+    }
+
+    fn mget(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        let values = self.inner.mget(table, keys)?;
+        self.decrypt_values(table, keys, values)
+    }
+
+    fn mset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+        let sealed = pairs
+            .into_iter()
+            .map(|pair| {
+                let value = self.encrypt(table, &pair.key, pair.value.unwrap_or_default())?;
+                Ok(Kvpair::new(pair.key, value))
+            })
+            .collect::<Result<Vec<_>, KvError>>()?;
+        let keys: Vec<_> = sealed.iter().map(|p| p.key.clone()).collect();
+        let olds = self.inner.mset(table, sealed)?;
+        self.decrypt_values(table, keys, olds)
+    }
+
+    fn mdel(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        let olds = self.inner.mdel(table, keys)?;
+        self.decrypt_values(table, keys, olds)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.decrypt_pairs(table, self.inner.get_iter(table)?)
+    }
+
+    // 解密失败时要返回错误，所以先全部解密出来
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        Ok(Box::new(self.get_all(table)?.into_iter()))
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        self.inner.len(table)
+    }
+
+    // key 没有加密，inner 可以先按 key 过滤，只解密留下来的
+    fn get_iter_matching(
+        &self,
+        table: &str,
+        pattern: &Glob,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let pairs = self.inner.get_iter_matching(table, pattern)?;
+        Ok(Box::new(self.decrypt_pairs(table, pairs)?.into_iter()))
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        pattern: Option<&Glob>,
+        count: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let pairs = self.inner.scan(table, after, pattern, count)?;
+        self.decrypt_pairs(table, pairs)
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        range: KeyRange,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let pairs = self.inner.get_range(table, range)?;
+        Ok(Box::new(self.decrypt_pairs(table, pairs)?.into_iter()))
+    }
+
+    fn undelete(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let restored = self.inner.undelete(table, key)?;
+        self.decrypt_opt(table, key, restored)
+    }
+
+    fn purge_trash(&self, table: &str, all: bool) -> Result<usize, KvError> {
+        self.inner.purge_trash(table, all)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<(u64, Value)>, KvError> {
+        self.inner
+            .history(table, key)?
+            .into_iter()
+            .map(|(version, v)| Ok((version, self.decrypt(table, key, v)?)))
+            .collect()
+    }
+
+    fn admin(&self, command: &str, args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.admin(command, args)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.inner.list_tables()
+    }
+
+    // bytes 是密文的大小
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        self.inner.stats()
+    }
+
+    fn flush_all(&self) -> Result<usize, KvError> {
+        self.inner.flush_all()
+    }
+
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        self.inner.drop_table(table)
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        let sealed = self.encrypt(table, &key, value)?;
+        self.inner.set_nx(table, key, sealed)
+    }
+
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        self.inner.expire_at(table, key, deadline)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.persist(table, key)
+    }
+
+    fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, KvError> {
+        self.inner.deadline(table, key)
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.inner.purge_expired()
+    }
+
+    // nonce 每次都不一样，同样的 value 再写一次也算改动
+    fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        self.inner.version(table, key)
+    }
+
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        let sealed = self.encrypt(table, &key, value)?;
+        self.inner.set_if_version(table, key, sealed, version)
+    }
+
+    fn meta(&self, table: &str, key: &str) -> Result<Option<KeyMeta>, KvError> {
+        self.inner.meta(table, key)
+    }
+
+    fn metrics(&self) -> Result<StorageMetrics, KvError> {
+        self.inner.metrics()
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.inner.flush()
+    }
+
+    fn bulk_load(
+        &self,
+        table: &str,
+        pairs: &mut dyn Iterator<Item = Kvpair>,
+    ) -> Result<BulkLoadStats, KvError> {
+        let mut error = None;
+        let mut sealed = pairs.map_while(|pair| {
+            match self.encrypt(table, &pair.key, pair.value.unwrap_or_default()) {
+                Ok(value) => Some(Kvpair::new(pair.key, value)),
+                Err(e) => {
+                    error = Some(e);
+                    None
+                }
+            }
+        });
+        let stats = self.inner.bulk_load(table, &mut sealed)?;
+        match error {
+            Some(e) => Err(e),
+            None => Ok(stats),
+        }
+    }
+
+    // 备份里是密文，只能用同样的密钥恢复
+    fn export(&self, w: &mut dyn std::io::Write) -> Result<usize, KvError> {
+        self.inner.export(w)
+    }
+
+    fn import(&self, r: &mut dyn std::io::Read) -> Result<usize, KvError> {
+        self.inner.import(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, StorageExt};
+
+    const KEY: [u8; 32] = [7; 32];
+
+    #[test]
+    fn encrypted_store_should_hide_values() {
+        for cipher in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305] {
+            let store = EncryptedStore::new(MemTable::new(), cipher, &KEY).unwrap();
+            assert_eq!(store.hset("t1", "k1", "secret").unwrap(), None);
+            assert_eq!(store.get("t1", "k1").unwrap(), Some("secret".into()));
+            assert_eq!(store.hset("t1", "k1", 42).unwrap(), Some("secret".into()));
+            assert_eq!(
+                store.get_all("t1").unwrap(),
+                vec![Kvpair::new("k1", 42.into())]
+            );
+
+            // inner 里只有密文，key 还是明文
+            let sealed = store.inner.get("t1", "k1").unwrap().unwrap();
+            assert!(matches!(sealed.value, Some(value::Value::Binary(_))));
+            assert_ne!(sealed, 42.into());
+            assert_eq!(store.del("t1", "k1").unwrap(), Some(42.into()));
+        }
+    }
+
+    #[test]
+    fn encrypted_store_should_reject_tampered_data() {
+        let store = EncryptedStore::new(MemTable::new(), Cipher::Aes256Gcm, &KEY).unwrap();
+        store.hset("t1", "k1", "secret").unwrap();
+
+        // 密文换到别的 key 下面
+        let sealed = store.inner.get("t1", "k1").unwrap().unwrap();
+        store.inner.hset("t1", "k2", sealed).unwrap();
+        assert!(store.get("t1", "k2").is_err());
+        // 没有加密的数据
+        store.inner.hset("t1", "k3", "plain").unwrap();
+        assert!(store.get("t1", "k3").is_err());
+        assert!(store.get_all("t1").is_err());
+
+        // 换了 key 之后读不出来
+        let other = EncryptedStore::new(store.inner, Cipher::Aes256Gcm, &[8; 32]).unwrap();
+        assert!(other.get("t1", "k1").is_err());
+        assert!(EncryptedStore::new(MemTable::new(), Cipher::Aes256Gcm, &[1; 16]).is_err());
+    }
+
+    #[test]
+    fn batch_and_scan_should_decrypt() {
+        let store = EncryptedStore::new(MemTable::new(), Cipher::default(), &KEY).unwrap();
+        let pairs = vec![
+            Kvpair::new("k1", "v1".into()),
+            Kvpair::new("k2", "v2".into()),
+        ];
+        assert_eq!(store.mset("t1", pairs.clone()).unwrap(), vec![None, None]);
+        assert_eq!(
+            store.mget("t1", &["k2".into(), "k3".into()]).unwrap(),
+            vec![Some("v2".into()), None]
+        );
+        assert_eq!(store.scan("t1", None, None, 10).unwrap(), pairs);
+        assert_eq!(store.scan("t1", Some("k1"), None, 10).unwrap(), pairs[1..]);
+        assert_eq!(
+            store.mdel("t1", &["k1".into()]).unwrap(),
+            vec![Some("v1".into())]
+        );
+        assert!(store.move_key("t1", "t2", "k2", false).is_err());
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::warn;
+
+use crate::KvError;
+
+/// 当前的 UNIX 时间，毫秒。key 的过期时间都用它表示，重启之后依然有效
+pub(crate) fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// 后台的 sweep 线程，drop 的时候停止
+pub struct Sweeper {
+    _stop: Sender<()>,
+}
+
+impl Sweeper {
+    /// 每隔 interval 调用一次 sweep
+    pub(crate) fn spawn<F>(name: &str, interval: Duration, mut sweep: F) -> Result<Self, KvError>
+    where
+        F: FnMut() -> Result<usize, KvError> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel::<()>();
+        thread::Builder::new().name(name.into()).spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                if let Err(e) = sweep() {
+                    warn!("Sweep failed: {}", e);
+                }
+            }
+        })?;
+        Ok(Self { _stop: tx })
+    }
+}
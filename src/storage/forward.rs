@@ -0,0 +1,338 @@
+// 包装其他存储的 Storage 用它把自己不关心的方法原样转发给里面的存储，
+// 在 impl Storage 里使用：
+//
+//     forward_to!(inner; mget, len, list_tables);
+//     forward_to!(inner, check; mget, len, list_tables);
+//
+// 第二种写法在转发有 table 参数的方法之前先调用 self.check(table)?，
+// 用来拒绝保留的 table。没有列出来的方法还是用 trait 的缺省实现，
+// 缺省实现会回到 get / set / get_iter 这些方法上
+macro_rules! forward_to {
+    ($inner:ident; $($method:ident),* $(,)?) => {
+        $(forward_to!(@ $method, $inner, none);)*
+    };
+    ($inner:ident, $check:ident; $($method:ident),* $(,)?) => {
+        $(forward_to!(@ $method, $inner, $check);)*
+    };
+
+    (@check $this:tt, none, $($table:expr),*) => {};
+    (@check $this:tt, $check:ident, $($table:expr),*) => {
+        $($this.$check($table)?;)*
+    };
+
+    (@ get, $inner:ident, $check:ident) => {
+        fn get(&self, table: &str, key: &str) -> Result<Option<$crate::Value>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.get(table, key)
+        }
+    };
+    (@ set, $inner:ident, $check:ident) => {
+        fn set(
+            &self,
+            table: &str,
+            key: String,
+            value: $crate::Value,
+        ) -> Result<Option<$crate::Value>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.set(table, key, value)
+        }
+    };
+    (@ contains, $inner:ident, $check:ident) => {
+        fn contains(&self, table: &str, key: &str) -> Result<bool, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.contains(table, key)
+        }
+    };
+    (@ del, $inner:ident, $check:ident) => {
+        fn del(&self, table: &str, key: &str) -> Result<Option<$crate::Value>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.del(table, key)
+        }
+    };
+    (@ mget, $inner:ident, $check:ident) => {
+        fn mget(
+            &self,
+            table: &str,
+            keys: &[String],
+        ) -> Result<Vec<Option<$crate::Value>>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.mget(table, keys)
+        }
+    };
+    (@ mset, $inner:ident, $check:ident) => {
+        fn mset(
+            &self,
+            table: &str,
+            pairs: Vec<$crate::Kvpair>,
+        ) -> Result<Vec<Option<$crate::Value>>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.mset(table, pairs)
+        }
+    };
+    (@ mdel, $inner:ident, $check:ident) => {
+        fn mdel(
+            &self,
+            table: &str,
+            keys: &[String],
+        ) -> Result<Vec<Option<$crate::Value>>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.mdel(table, keys)
+        }
+    };
+    (@ get_all, $inner:ident, $check:ident) => {
+        fn get_all(&self, table: &str) -> Result<Vec<$crate::Kvpair>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.get_all(table)
+        }
+    };
+    (@ get_iter, $inner:ident, $check:ident) => {
+        fn get_iter(
+            &self,
+            table: &str,
+        ) -> Result<Box<dyn Iterator<Item = $crate::Kvpair> + Send>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.get_iter(table)
+        }
+    };
+    (@ get_iter_ordered, $inner:ident, $check:ident) => {
+        fn get_iter_ordered(
+            &self,
+            table: &str,
+            order: $crate::Order,
+        ) -> Result<Box<dyn Iterator<Item = $crate::Kvpair> + Send>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.get_iter_ordered(table, order)
+        }
+    };
+    (@ snapshot, $inner:ident, $check:ident) => {
+        fn snapshot(
+            &self,
+            table: &str,
+        ) -> Result<Box<dyn $crate::Snapshot>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.snapshot(table)
+        }
+    };
+    (@ len, $inner:ident, $check:ident) => {
+        fn len(&self, table: &str) -> Result<usize, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.len(table)
+        }
+    };
+    (@ get_iter_matching, $inner:ident, $check:ident) => {
+        fn get_iter_matching(
+            &self,
+            table: &str,
+            pattern: &$crate::Glob,
+        ) -> Result<Box<dyn Iterator<Item = $crate::Kvpair> + Send>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.get_iter_matching(table, pattern)
+        }
+    };
+    (@ scan, $inner:ident, $check:ident) => {
+        fn scan(
+            &self,
+            table: &str,
+            after: Option<&str>,
+            pattern: Option<&$crate::Glob>,
+            count: usize,
+        ) -> Result<Vec<$crate::Kvpair>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.scan(table, after, pattern, count)
+        }
+    };
+    (@ get_range, $inner:ident, $check:ident) => {
+        fn get_range(
+            &self,
+            table: &str,
+            range: $crate::KeyRange,
+        ) -> Result<Box<dyn Iterator<Item = $crate::Kvpair> + Send>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.get_range(table, range)
+        }
+    };
+    (@ admin, $inner:ident, $check:ident) => {
+        fn admin(
+            &self,
+            command: &str,
+            args: &[$crate::Value],
+        ) -> Result<Vec<$crate::Kvpair>, $crate::KvError> {
+            self.$inner.admin(command, args)
+        }
+    };
+    (@ undelete, $inner:ident, $check:ident) => {
+        fn undelete(
+            &self,
+            table: &str,
+            key: &str,
+        ) -> Result<Option<$crate::Value>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.undelete(table, key)
+        }
+    };
+    (@ purge_trash, $inner:ident, $check:ident) => {
+        fn purge_trash(&self, table: &str, all: bool) -> Result<usize, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.purge_trash(table, all)
+        }
+    };
+    (@ history, $inner:ident, $check:ident) => {
+        fn history(
+            &self,
+            table: &str,
+            key: &str,
+        ) -> Result<Vec<(u64, $crate::Value)>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.history(table, key)
+        }
+    };
+    (@ clone_table, $inner:ident, $check:ident) => {
+        fn clone_table(&self, src: &str, dst: &str) -> Result<usize, $crate::KvError> {
+            forward_to!(@check self, $check, src, dst);
+            self.$inner.clone_table(src, dst)
+        }
+    };
+    (@ list_tables, $inner:ident, $check:ident) => {
+        fn list_tables(&self) -> Result<Vec<String>, $crate::KvError> {
+            self.$inner.list_tables()
+        }
+    };
+    (@ stats, $inner:ident, $check:ident) => {
+        fn stats(&self) -> Result<Vec<$crate::TableStats>, $crate::KvError> {
+            self.$inner.stats()
+        }
+    };
+    (@ flush_all, $inner:ident, $check:ident) => {
+        fn flush_all(&self) -> Result<usize, $crate::KvError> {
+            self.$inner.flush_all()
+        }
+    };
+    (@ drop_table, $inner:ident, $check:ident) => {
+        fn drop_table(&self, table: &str) -> Result<usize, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.drop_table(table)
+        }
+    };
+    (@ move_key, $inner:ident, $check:ident) => {
+        fn move_key(
+            &self,
+            src: &str,
+            dst: &str,
+            key: &str,
+            force: bool,
+        ) -> Result<Option<$crate::Value>, $crate::KvError> {
+            forward_to!(@check self, $check, src, dst);
+            self.$inner.move_key(src, dst, key, force)
+        }
+    };
+    (@ incr, $inner:ident, $check:ident) => {
+        fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.incr(table, key, delta)
+        }
+    };
+    (@ set_nx, $inner:ident, $check:ident) => {
+        fn set_nx(
+            &self,
+            table: &str,
+            key: String,
+            value: $crate::Value,
+        ) -> Result<bool, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.set_nx(table, key, value)
+        }
+    };
+    (@ expire_at, $inner:ident, $check:ident) => {
+        fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.expire_at(table, key, deadline)
+        }
+    };
+    (@ persist, $inner:ident, $check:ident) => {
+        fn persist(&self, table: &str, key: &str) -> Result<bool, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.persist(table, key)
+        }
+    };
+    (@ deadline, $inner:ident, $check:ident) => {
+        fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.deadline(table, key)
+        }
+    };
+    (@ purge_expired, $inner:ident, $check:ident) => {
+        fn purge_expired(&self) -> Result<usize, $crate::KvError> {
+            self.$inner.purge_expired()
+        }
+    };
+    (@ version, $inner:ident, $check:ident) => {
+        fn version(&self, table: &str, key: &str) -> Result<Option<u64>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.version(table, key)
+        }
+    };
+    (@ set_if_version, $inner:ident, $check:ident) => {
+        fn set_if_version(
+            &self,
+            table: &str,
+            key: String,
+            value: $crate::Value,
+            version: u64,
+        ) -> Result<u64, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.set_if_version(table, key, value, version)
+        }
+    };
+    (@ meta, $inner:ident, $check:ident) => {
+        fn meta(
+            &self,
+            table: &str,
+            key: &str,
+        ) -> Result<Option<$crate::KeyMeta>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.meta(table, key)
+        }
+    };
+    (@ flush, $inner:ident, $check:ident) => {
+        fn flush(&self) -> Result<(), $crate::KvError> {
+            self.$inner.flush()
+        }
+    };
+    (@ bulk_load, $inner:ident, $check:ident) => {
+        fn bulk_load(
+            &self,
+            table: &str,
+            pairs: &mut dyn Iterator<Item = $crate::Kvpair>,
+        ) -> Result<$crate::BulkLoadStats, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.bulk_load(table, pairs)
+        }
+    };
+    (@ query, $inner:ident, $check:ident) => {
+        fn query(
+            &self,
+            table: &str,
+            index: &str,
+            value: &$crate::Value,
+            limit: usize,
+        ) -> Result<Vec<$crate::Kvpair>, $crate::KvError> {
+            forward_to!(@check self, $check, table);
+            self.$inner.query(table, index, value, limit)
+        }
+    };
+    (@ export, $inner:ident, $check:ident) => {
+        fn export(&self, w: &mut dyn std::io::Write) -> Result<usize, $crate::KvError> {
+            self.$inner.export(w)
+        }
+    };
+    (@ import, $inner:ident, $check:ident) => {
+        fn import(&self, r: &mut dyn std::io::Read) -> Result<usize, $crate::KvError> {
+            self.$inner.import(r)
+        }
+    };
+    (@ metrics, $inner:ident, $check:ident) => {
+        fn metrics(&self) -> Result<$crate::StorageMetrics, $crate::KvError> {
+            self.$inner.metrics()
+        }
+    };
+}
@@ -0,0 +1,160 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::KvError;
+
+/// 匹配 key 的 glob 模式
+///
+/// 支持 `*`（任意个字符）、`?`（一个字符）、`[abc]`、`[a-z]`、`[!abc]`，
+/// 用 `\` 转义这些特殊字符，比如 `user:*:profile`。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    Any,
+    Star,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self, KvError> {
+        let mut tokens = Vec::new();
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '*' => Token::Star,
+                '?' => Token::Any,
+                '\\' => Token::Char(chars.next().unwrap_or('\\')),
+                '[' => parse_class(&mut chars).ok_or_else(|| {
+                    KvError::InvalidCommand(format!("Unclosed [ in pattern {}", pattern))
+                })?,
+                c => Token::Char(c),
+            };
+            // 连续的 * 和一个 * 是一样的
+            if token != Token::Star || tokens.last() != Some(&Token::Star) {
+                tokens.push(token);
+            }
+        }
+        Ok(Self { tokens })
+    }
+
+    /// 命令里的 pattern 字段，空字符串表示不过滤
+    pub(crate) fn optional(pattern: &str) -> Result<Option<Self>, KvError> {
+        match pattern {
+            "" => Ok(None),
+            p => Self::new(p).map(Some),
+        }
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        let key: Vec<char> = key.chars().collect();
+        let (mut t, mut k) = (0, 0);
+        // 最近的一个 * 的位置，和它之后开始匹配的 key 的位置。
+        // 后面匹配失败时让这个 * 多吃一个字符再试，不需要递归
+        let mut star = None;
+        while k < key.len() {
+            match self.tokens.get(t) {
+                Some(Token::Star) => {
+                    star = Some((t, k));
+                    t += 1;
+                    continue;
+                }
+                Some(token) if token.matches(key[k]) => {
+                    t += 1;
+                    k += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            match star {
+                Some((st, sk)) => {
+                    star = Some((st, sk + 1));
+                    t = st + 1;
+                    k = sk + 1;
+                }
+                None => return false,
+            }
+        }
+        self.tokens[t..].iter().all(|t| *t == Token::Star)
+    }
+}
+
+impl Token {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Char(x) => *x == c,
+            Token::Any => true,
+            Token::Star => false,
+            Token::Class { negated, ranges } => {
+                ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated
+            }
+        }
+    }
+}
+
+// 解析 [ 之后的部分，没有 ] 结尾时返回 None。紧跟在 [ 后面的 ] 当作普通字符
+fn parse_class(chars: &mut Peekable<Chars>) -> Option<Token> {
+    let negated = chars.next_if(|c| *c == '!' || *c == '^').is_some();
+    let mut ranges = Vec::new();
+    loop {
+        let lo = match chars.next()? {
+            ']' if !ranges.is_empty() => return Some(Token::Class { negated, ranges }),
+            '\\' => chars.next()?,
+            c => c,
+        };
+        let hi = match chars.next_if_eq(&'-') {
+            Some(_) => match chars.next()? {
+                // [a-] 里的 - 是普通字符
+                ']' => {
+                    ranges.push((lo, lo));
+                    ranges.push(('-', '-'));
+                    return Some(Token::Class { negated, ranges });
+                }
+                '\\' => chars.next()?,
+                c => c,
+            },
+            None => lo,
+        };
+        ranges.push((lo.min(hi), lo.max(hi)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, key: &str) -> bool {
+        Glob::new(pattern).unwrap().matches(key)
+    }
+
+    #[test]
+    fn glob_should_match_wildcards() {
+        assert!(matches("user:*:profile", "user:42:profile"));
+        assert!(matches("user:*:profile", "user::profile"));
+        assert!(!matches("user:*:profile", "user:42:settings"));
+        assert!(matches("*", ""));
+        assert!(matches("k?", "k1"));
+        assert!(!matches("k?", "k12"));
+        assert!(matches("*a*b", "xxaxxab"));
+        assert!(matches("用户*", "用户1"));
+    }
+
+    #[test]
+    fn glob_should_match_classes() {
+        assert!(matches("k[0-9]", "k7"));
+        assert!(!matches("k[0-9]", "ka"));
+        assert!(matches("k[!0-9]", "ka"));
+        assert!(matches("k[]a]", "k]"));
+        assert!(matches("k[a-]", "k-"));
+        assert!(matches("k\\*", "k*"));
+        assert!(!matches("k\\*", "k1"));
+        assert!(Glob::new("k[0-9").is_err());
+    }
+}
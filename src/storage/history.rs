@@ -0,0 +1,353 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+
+use super::{check_clone_target, load_in_batches};
+use crate::{BulkLoadStats, KvError, Kvpair, Storage, StorageMetrics, TableStats, Value};
+
+/// 历史版本使用的 table 都以它开头，不能直接访问
+pub const HISTORY_PREFIX: &str = "__history__:";
+
+/// 保留历史版本的 Storage
+///
+/// 指定的 tables 里每次写入都是一个新的版本，版本号对每个 key 从 1 开始递增：
+/// `__history__:n:<table>` 保存 key 最新的版本号，`__history__:v:<table>` 保存
+/// 每个版本的 value，key 是 `<key>\0<版本号>`。每个 key 只保留最近的 keep 个版本，
+/// 写入新版本的时候删掉最旧的那个。删除 key 不会删除它的历史，之后再写入接着编号
+pub struct VersionedStore<S> {
+    inner: S,
+    tables: HashSet<String>,
+    keep: usize,
+}
+
+impl<S: Storage> VersionedStore<S> {
+    /// keep 至少是 1，也就是只有当前的版本
+    pub fn new(inner: S, tables: impl IntoIterator<Item = impl Into<String>>, keep: usize) -> Self {
+        Self {
+            inner,
+            tables: tables.into_iter().map(Into::into).collect(),
+            keep: keep.max(1),
+        }
+    }
+
+    fn check(&self, table: &str) -> Result<(), KvError> {
+        match table.starts_with(HISTORY_PREFIX) {
+            true => Err(KvError::InvalidCommand(format!(
+                "Table {} is reserved",
+                table
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    fn check_versioned(&self, table: &str) -> Result<(), KvError> {
+        match self.tables.contains(table) {
+            true => Ok(()),
+            false => Err(KvError::InvalidCommand(format!(
+                "Table {} keeps no history",
+                table
+            ))),
+        }
+    }
+
+    // 先写入数据再记录版本，中途失败最多是少了一个版本
+    fn record(&self, table: &str, key: &str, value: Value) -> Result<(), KvError> {
+        if !self.tables.contains(table) {
+            return Ok(());
+        }
+        let version = self.inner.incr(&latest_of(table), key, 1)? as u64;
+        self.inner
+            .set(&values_of(table), version_key(key, version), value)?;
+        // 版本号是连续的，超出 keep 的只有一个
+        if version > self.keep as u64 {
+            let oldest = version_key(key, version - self.keep as u64);
+            self.inner.del(&values_of(table), &oldest)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Storage> Storage for VersionedStore<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.check(table)?;
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.check(table)?;
+        if !self.tables.contains(table) {
+            return self.inner.set(table, key, value);
+        }
+        let old = self.inner.set(table, key.clone(), value.clone())?;
+        self.record(table, &key, value)?;
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.check(table)?;
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.check(table)?;
+        self.inner.del(table, key)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.check(table)?;
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.check(table)?;
+        self.inner.get_iter(table)
+    }
+
+    fn admin(&self, command: &str, args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.admin(command, args)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.check(table)?;
+        let n = self.inner.incr(table, key, delta)?;
+        self.record(table, key, n.into())?;
+        Ok(n)
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        self.check(table)?;
+        let written = self.inner.set_nx(table, key.clone(), value.clone())?;
+        if written {
+            self.record(table, &key, value)?;
+        }
+        Ok(written)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<(u64, Value)>, KvError> {
+        self.check_versioned(table)?;
+        let latest = match self.inner.get(&latest_of(table), key)? {
+            Some(v) => TryInto::<i64>::try_into(v)? as u64,
+            None => return Ok(Vec::new()),
+        };
+        let oldest = latest.saturating_sub(self.keep as u64) + 1;
+        let mut versions = Vec::new();
+        for version in (oldest..=latest).rev() {
+            if let Some(v) = self
+                .inner
+                .get(&values_of(table), &version_key(key, version))?
+            {
+                versions.push((version, v));
+            }
+        }
+        Ok(versions)
+    }
+
+    fn mset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+        self.check(table)?;
+        if !self.tables.contains(table) {
+            return self.inner.mset(table, pairs);
+        }
+        pairs
+            .into_iter()
+            .map(|pair| self.set(table, pair.key, pair.value.unwrap_or_default()))
+            .collect()
+    }
+
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        self.check(table)?;
+        let version = self
+            .inner
+            .set_if_version(table, key.clone(), value.clone(), version)?;
+        self.record(table, &key, value)?;
+        Ok(version)
+    }
+
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        self.check(src)?;
+        self.check(dst)?;
+        let moved = self.inner.move_key(src, dst, key, force)?;
+        if let Some(value) = &moved {
+            self.record(dst, key, value.clone())?;
+        }
+        Ok(moved)
+    }
+
+    fn undelete(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.check(table)?;
+        let restored = self.inner.undelete(table, key)?;
+        if let Some(value) = &restored {
+            self.record(table, key, value.clone())?;
+        }
+        Ok(restored)
+    }
+
+    // 保留历史的 table 每个 key 都要记录一个版本，只能逐个写入
+    fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+        self.check(src)?;
+        self.check(dst)?;
+        if !self.tables.contains(dst) {
+            return self.inner.clone_table(src, dst);
+        }
+        check_clone_target(self, src, dst)?;
+        let mut n = 0;
+        for pair in self.inner.get_iter(src)? {
+            self.set(dst, pair.key, pair.value.unwrap_or_default())?;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    fn bulk_load(
+        &self,
+        table: &str,
+        pairs: &mut dyn Iterator<Item = Kvpair>,
+    ) -> Result<BulkLoadStats, KvError> {
+        self.check(table)?;
+        match self.tables.contains(table) {
+            true => load_in_batches(self, table, pairs),
+            false => self.inner.bulk_load(table, pairs),
+        }
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = self.inner.list_tables()?;
+        tables.retain(|t| !t.starts_with(HISTORY_PREFIX));
+        Ok(tables)
+    }
+
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        let mut stats = self.inner.stats()?;
+        stats.retain(|s| !s.table.starts_with(HISTORY_PREFIX));
+        Ok(stats)
+    }
+
+    fn metrics(&self) -> Result<StorageMetrics, KvError> {
+        let mut metrics = self.inner.metrics()?;
+        metrics
+            .tables
+            .retain(|s| !s.table.starts_with(HISTORY_PREFIX));
+        Ok(metrics)
+    }
+
+    // 删除 table 和删除 key 一样，历史还在；FLUSHALL 连历史一起删掉
+    forward_to!(inner, check;
+        mget, mdel, get_iter_ordered, snapshot, len, get_iter_matching, scan, get_range,
+        purge_trash, drop_table, flush_all, expire_at, persist, deadline, purge_expired,
+        version, meta, flush, query, export, import);
+}
+
+fn latest_of(table: &str) -> String {
+    format!("{}n:{}", HISTORY_PREFIX, table)
+}
+
+fn values_of(table: &str) -> String {
+    format!("{}v:{}", HISTORY_PREFIX, table)
+}
+
+// 版本号里没有 \0，从最后一个 \0 分开就能还原 key 和版本号，不会混淆
+fn version_key(key: &str, version: u64) -> String {
+    format!("{}\0{}", key, version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, MemTable, Service, ServiceInner, StorageExt};
+
+    fn store(keep: usize) -> VersionedStore<MemTable> {
+        let store = VersionedStore::new(MemTable::new(), ["config"], keep);
+        store.hset("config", "k1", "v1").unwrap();
+        store.hset("other", "k1", "v1").unwrap();
+        store
+    }
+
+    #[test]
+    fn history_should_keep_recent_versions() {
+        let store = store(3);
+        store.hset("config", "k1", "v2").unwrap();
+        store.incr("config", "k2", 5).unwrap();
+        assert_eq!(
+            store.history("config", "k1").unwrap(),
+            vec![(2, "v2".into()), (1, "v1".into())]
+        );
+        assert_eq!(store.history("config", "k2").unwrap(), vec![(1, 5.into())]);
+
+        // 最旧的版本被回收，删除 key 之后历史还在
+        store.hset("config", "k1", "v3").unwrap();
+        store.hset("config", "k1", "v4").unwrap();
+        store.del("config", "k1").unwrap();
+        let versions: Vec<_> = store
+            .history("config", "k1")
+            .unwrap()
+            .into_iter()
+            .map(|(v, _)| v)
+            .collect();
+        assert_eq!(versions, [4, 3, 2]);
+        assert_eq!(store.inner.len(&values_of("config")).unwrap(), 4);
+
+        store.hset("config", "k1", "v5").unwrap();
+        assert_eq!(store.history("config", "k1").unwrap()[0], (5, "v5".into()));
+    }
+
+    #[test]
+    fn history_should_be_limited_to_versioned_tables() {
+        let store = store(3);
+        assert!(store.history("other", "k1").is_err());
+        assert!(store.get_all(&values_of("config")).is_err());
+        assert_eq!(store.history("config", "k3").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn hgetat_should_work() {
+        let service: Service<_> = ServiceInner::new(store(3)).into();
+        service.store().hset("config", "k1", "v2").unwrap();
+
+        let res = service.execute(CommandRequest::new_hgetat("config", "k1", 1));
+        assert_eq!(res.values, vec!["v1".into()]);
+        let res = service.execute(CommandRequest::new_hgetat("config", "k1", 0));
+        assert_eq!(
+            res.pairs,
+            vec![Kvpair::new("2", "v2".into()), Kvpair::new("1", "v1".into())]
+        );
+        let res = service.execute(CommandRequest::new_hgetat("config", "k1", 3));
+        assert_eq!(res.status, 404);
+    }
+
+    #[test]
+    fn other_commands_should_pass_through() {
+        let service: Service<_> = ServiceInner::new(store(3)).into();
+        let res = service.execute(CommandRequest::new_htables());
+        assert_eq!(res.values, vec!["config".into(), "other".into()]);
+        let res = service.execute(CommandRequest::new_hlen("config"));
+        assert_eq!(res.values, vec![1.into()]);
+        let res = service.execute(CommandRequest::new_hexpire("other", "k1", 60_000));
+        assert_eq!(res.values, vec![true.into()]);
+
+        // 写入了新的值的命令也记录版本
+        let store = service.store();
+        let version = store.version("config", "k1").unwrap().unwrap();
+        store
+            .set_if_version("config", "k1".into(), "v2".into(), version)
+            .unwrap();
+        store.move_key("other", "config", "k1", true).unwrap();
+        let versions: Vec<_> = store
+            .history("config", "k1")
+            .unwrap()
+            .into_iter()
+            .map(|(_, v)| v)
+            .collect();
+        assert_eq!(versions, ["v1".into(), "v2".into(), "v1".into()]);
+        assert!(store.len(&values_of("config")).is_err());
+    }
+}
@@ -0,0 +1,550 @@
+use std::collections::HashMap;
+use std::ops::Bound;
+
+use serde_json::Value as JsonValue;
+
+use crate::{
+    value, KeyMeta, KeyRange, KvError, Kvpair, Storage, StorageMetrics, TableStats, Value,
+};
+
+/// 二级索引使用的 table 都以它开头，不能直接访问
+pub const INDEX_PREFIX: &str = "__index__:";
+
+/// 索引 value 的哪一部分。字符串、整数、浮点数和布尔值都按文本比较，binary 不索引
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexField {
+    /// 整个 value
+    Value,
+    /// value 是 JSON 字符串时，用 . 分隔的路径上的字段，比如 `address.city`
+    Json(String),
+}
+
+impl IndexField {
+    // value 在这个索引里的值，没有的话不进索引
+    fn term(&self, value: &Value) -> Option<String> {
+        let path = match self {
+            IndexField::Value => return scalar_term(value),
+            IndexField::Json(path) => path,
+        };
+        let mut json: JsonValue = match &value.value {
+            Some(value::Value::String(s)) => serde_json::from_str(s).ok()?,
+            _ => return None,
+        };
+        for name in path.split('.') {
+            json = json.get_mut(name)?.take();
+        }
+        match json {
+            JsonValue::String(s) => Some(s),
+            JsonValue::Number(n) => Some(n.to_string()),
+            JsonValue::Bool(b) => Some(b.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for IndexField {
+    type Err = KvError;
+
+    /// `value` 索引整个 value，`$.a.b` 索引 JSON 的字段
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match (s, s.strip_prefix("$.")) {
+            ("value", _) => Ok(IndexField::Value),
+            (_, Some(path)) if !path.is_empty() => Ok(IndexField::Json(path.into())),
+            _ => Err(KvError::InvalidCommand(format!("bad index field {}", s))),
+        }
+    }
+}
+
+fn scalar_term(value: &Value) -> Option<String> {
+    match &value.value {
+        Some(value::Value::String(s)) => Some(s.clone()),
+        Some(value::Value::Integer(i)) => Some(i.to_string()),
+        Some(value::Value::Float(f)) => Some(f.to_string()),
+        Some(value::Value::Bool(b)) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// 在 value 上维护二级索引的 Storage
+///
+/// 每个索引是 inner 里的一个 table：`__index__:<table>:<name>`，key 是 `<term>\0<key>`，
+/// 写入和删除时用返回的旧值更新，不需要多读一次。过期、MOVE 之类没有经过这里的改动
+/// 会留下旧的索引项，所以 query 会用当前的 value 再检查一遍，顺便删掉对不上的。
+/// 索引和数据不在一个事务里，崩溃之后可能少了索引项，声明了新的索引时也一样，
+/// 这时用 rebuild 重建
+pub struct IndexedStore<S> {
+    inner: S,
+    indexes: HashMap<String, Vec<(String, IndexField)>>,
+}
+
+impl<S: Storage> IndexedStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            indexes: HashMap::new(),
+        }
+    }
+
+    /// 在 table 上声明名为 name 的索引
+    pub fn with_index(
+        mut self,
+        table: impl Into<String>,
+        name: impl Into<String>,
+        field: IndexField,
+    ) -> Self {
+        let indexes = self.indexes.entry(table.into()).or_default();
+        indexes.push((name.into(), field));
+        self
+    }
+
+    /// 清空所有索引，用现有的数据重新建立，返回建立的索引项的数量
+    pub fn rebuild(&self) -> Result<usize, KvError> {
+        let mut n = 0;
+        for (table, indexes) in &self.indexes {
+            for (name, _) in indexes {
+                self.inner.drop_table(&index_table(table, name))?;
+            }
+            for pair in self.inner.get_iter(table)? {
+                n += self.update(table, &pair.key, None, pair.value.as_ref())?;
+            }
+        }
+        Ok(n)
+    }
+
+    fn check(&self, table: &str) -> Result<(), KvError> {
+        match table.starts_with(INDEX_PREFIX) {
+            true => Err(KvError::InvalidCommand(format!(
+                "Table {} is reserved",
+                table
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    // 从 old 变成 new 时更新 table 的索引，返回新加的索引项的数量
+    fn update(
+        &self,
+        table: &str,
+        key: &str,
+        old: Option<&Value>,
+        new: Option<&Value>,
+    ) -> Result<usize, KvError> {
+        let indexes = match self.indexes.get(table) {
+            Some(indexes) => indexes,
+            None => return Ok(0),
+        };
+        let mut n = 0;
+        for (name, field) in indexes {
+            let old = old.and_then(|v| field.term(v));
+            let new = new.and_then(|v| field.term(v));
+            if old == new {
+                continue;
+            }
+            let index = index_table(table, name);
+            if let Some(term) = old {
+                self.inner.del(&index, &entry_key(&term, key))?;
+            }
+            if let Some(term) = new {
+                self.inner
+                    .set(&index, entry_key(&term, key), Value::default())?;
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+
+    // 重新读一次 key，按当前的 value 建立索引
+    fn reindex(&self, table: &str, key: &str) -> Result<(), KvError> {
+        let value = self.inner.get(table, key)?;
+        self.update(table, key, None, value.as_ref())?;
+        Ok(())
+    }
+}
+
+impl<S: Storage> Storage for IndexedStore<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.check(table)?;
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.check(table)?;
+        let old = self.inner.set(table, key.clone(), value.clone())?;
+        self.update(table, &key, old.as_ref(), Some(&value))?;
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.check(table)?;
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.check(table)?;
+        let old = self.inner.del(table, key)?;
+        self.update(table, key, old.as_ref(), None)?;
+        Ok(old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.check(table)?;
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.check(table)?;
+        self.inner.get_iter(table)
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        range: KeyRange,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.check(table)?;
+        self.inner.get_range(table, range)
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        self.check(table)?;
+        self.inner.len(table)
+    }
+
+    fn admin(&self, command: &str, args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.admin(command, args)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = self.inner.list_tables()?;
+        tables.retain(|t| !t.starts_with(INDEX_PREFIX));
+        Ok(tables)
+    }
+
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        let mut stats = self.inner.stats()?;
+        stats.retain(|s| !s.table.starts_with(INDEX_PREFIX));
+        Ok(stats)
+    }
+
+    fn metrics(&self) -> Result<StorageMetrics, KvError> {
+        let mut metrics = self.inner.metrics()?;
+        metrics
+            .tables
+            .retain(|s| !s.table.starts_with(INDEX_PREFIX));
+        Ok(metrics)
+    }
+
+    // 恢复的 value 重新进索引
+    fn undelete(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.check(table)?;
+        let value = self.inner.undelete(table, key)?;
+        if let Some(v) = &value {
+            self.update(table, key, None, Some(v))?;
+        }
+        Ok(value)
+    }
+
+    fn purge_trash(&self, table: &str, all: bool) -> Result<usize, KvError> {
+        self.check(table)?;
+        self.inner.purge_trash(table, all)
+    }
+
+    // 索引项不算在删除的数量里
+    fn flush_all(&self) -> Result<usize, KvError> {
+        let mut n = 0;
+        for table in self.inner.list_tables()? {
+            let dropped = self.inner.drop_table(&table)?;
+            if !table.starts_with(INDEX_PREFIX) {
+                n += dropped;
+            }
+        }
+        Ok(n)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        self.check(table)?;
+        for (name, _) in self.indexes.get(table).into_iter().flatten() {
+            self.inner.drop_table(&index_table(table, name))?;
+        }
+        self.inner.drop_table(table)
+    }
+
+    fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+        self.check(src)?;
+        self.check(dst)?;
+        let n = self.inner.clone_table(src, dst)?;
+        if self.indexes.contains_key(dst) {
+            for pair in self.inner.get_iter(dst)? {
+                self.update(dst, &pair.key, None, pair.value.as_ref())?;
+            }
+        }
+        Ok(n)
+    }
+
+    // src 里的索引项留给 query 清理
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        self.check(src)?;
+        self.check(dst)?;
+        let moved = self.inner.move_key(src, dst, key, force)?;
+        if let Some(value) = &moved {
+            self.update(dst, key, None, Some(value))?;
+        }
+        Ok(moved)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.check(table)?;
+        let n = self.inner.incr(table, key, delta)?;
+        let old = n.wrapping_sub(delta).into();
+        self.update(table, key, Some(&old), Some(&n.into()))?;
+        Ok(n)
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        self.check(table)?;
+        let written = self.inner.set_nx(table, key.clone(), value.clone())?;
+        if written {
+            self.update(table, &key, None, Some(&value))?;
+        }
+        Ok(written)
+    }
+
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        self.check(table)?;
+        self.inner.expire_at(table, key, deadline)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.check(table)?;
+        self.inner.persist(table, key)
+    }
+
+    fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, KvError> {
+        self.check(table)?;
+        self.inner.deadline(table, key)
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.inner.purge_expired()
+    }
+
+    fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        self.check(table)?;
+        self.inner.version(table, key)
+    }
+
+    // 旧的索引项留给 query 清理
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        self.check(table)?;
+        let version = self
+            .inner
+            .set_if_version(table, key.clone(), value.clone(), version)?;
+        self.reindex(table, &key)?;
+        Ok(version)
+    }
+
+    fn meta(&self, table: &str, key: &str) -> Result<Option<KeyMeta>, KvError> {
+        self.check(table)?;
+        self.inner.meta(table, key)
+    }
+
+    fn query(
+        &self,
+        table: &str,
+        index: &str,
+        value: &Value,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.check(table)?;
+        let field = self
+            .indexes
+            .get(table)
+            .and_then(|indexes| indexes.iter().find(|(name, _)| name == index))
+            .map(|(_, field)| field)
+            .ok_or_else(|| KvError::NotFound(table.into(), format!("index {}", index)))?;
+        let term = scalar_term(value)
+            .ok_or_else(|| KvError::InvalidCommand(format!("cannot query {:?}", value)))?;
+
+        let name = index_table(table, index);
+        // term 后面是 \0，到 \u{1} 为止就是这个 term 的所有索引项
+        let (start, end) = (format!("{}\0", term), format!("{}\u{1}", term));
+        let entries = self.inner.get_range(
+            &name,
+            (
+                Bound::Included(start.as_str()),
+                Bound::Excluded(end.as_str()),
+            ),
+        )?;
+        let mut pairs = Vec::new();
+        for entry in entries {
+            let key = &entry.key[start.len()..];
+            match self.inner.get(table, key)? {
+                Some(v) if field.term(&v).as_ref() == Some(&term) => {
+                    pairs.push(Kvpair::new(key, v));
+                    if pairs.len() >= limit {
+                        break;
+                    }
+                }
+                _ => {
+                    self.inner.del(&name, &entry.key)?;
+                }
+            }
+        }
+        Ok(pairs)
+    }
+}
+
+fn index_table(table: &str, name: &str) -> String {
+    format!("{}{}:{}", INDEX_PREFIX, table, name)
+}
+
+fn entry_key(term: &str, key: &str) -> String {
+    format!("{}\0{}", term, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CommandRequest, MemTable, Service, ServiceInner, SledDb, SoftDeleteStore, StorageExt,
+    };
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn store<S: Storage>(inner: S) -> IndexedStore<S> {
+        IndexedStore::new(inner)
+            .with_index("users", "by_city", IndexField::Json("address.city".into()))
+            .with_index("tags", "by_tag", IndexField::Value)
+    }
+
+    fn keys(pairs: Vec<Kvpair>) -> Vec<String> {
+        let mut keys: Vec<_> = pairs.into_iter().map(|p| p.key).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn query_should_follow_writes() {
+        let store = store(MemTable::new());
+        let user = |city: &str| format!(r#"{{"address": {{"city": "{}"}}}}"#, city);
+        store.hset("users", "u1", user("paris")).unwrap();
+        store.hset("users", "u2", user("paris")).unwrap();
+        store.hset("users", "u3", user("rome")).unwrap();
+        store.hset("users", "u4", "not json").unwrap();
+        let paris = Value::from("paris");
+        let query = |v: &Value| store.query("users", "by_city", v, usize::MAX).unwrap();
+        assert_eq!(keys(query(&paris)), ["u1", "u2"]);
+
+        store.hset("users", "u1", user("rome")).unwrap();
+        store.del("users", "u2").unwrap();
+        assert!(query(&paris).is_empty());
+        assert_eq!(keys(query(&"rome".into())), ["u1", "u3"]);
+        assert_eq!(
+            store
+                .query("users", "by_city", &"rome".into(), 1)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // 整数和字符串都按文本比较
+        store.hset("tags", "t1", 7).unwrap();
+        store.incr("tags", "t2", 7).unwrap();
+        assert_eq!(
+            keys(store.query("tags", "by_tag", &7.into(), 10).unwrap()),
+            ["t1", "t2"]
+        );
+        assert!(store.query("tags", "nope", &7.into(), 10).is_err());
+        assert!(store.get_all(&index_table("tags", "by_tag")).is_err());
+        assert_eq!(store.list_tables().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn query_should_skip_stale_entries_and_rebuild() {
+        let dir = tempdir().unwrap();
+        let inner = SledDb::new(dir.path()).unwrap();
+        inner.hset("tags", "t1", "red").unwrap();
+        let store = store(inner);
+        // 声明索引之前写入的数据要 rebuild 之后才能查到
+        assert!(store
+            .query("tags", "by_tag", &"red".into(), 10)
+            .unwrap()
+            .is_empty());
+        assert_eq!(store.rebuild().unwrap(), 1);
+        assert_eq!(
+            store
+                .query("tags", "by_tag", &"red".into(), 10)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // 绕过索引改了 value，旧的索引项查询的时候删掉
+        store.inner.hset("tags", "t1", "blue").unwrap();
+        assert!(store
+            .query("tags", "by_tag", &"red".into(), 10)
+            .unwrap()
+            .is_empty());
+        assert_eq!(store.inner.len(&index_table("tags", "by_tag")).unwrap(), 0);
+    }
+
+    #[test]
+    fn undelete_should_restore_index_entries() {
+        let inner = SoftDeleteStore::new(MemTable::new(), ["tags"], Duration::from_secs(60));
+        let service: Service<_> = ServiceInner::new(store(inner)).into();
+        service.execute(CommandRequest::new_hset("tags", "t1", "red".into()));
+        service.execute(CommandRequest::new_hset("tags", "t2", "red".into()));
+        service.execute(CommandRequest::new_hdel("tags", "t1"));
+        service.execute(CommandRequest::new_hdel("tags", "t2"));
+        let query = || service.store().query("tags", "by_tag", &"red".into(), 10);
+        assert!(query().unwrap().is_empty());
+
+        let res = service.execute(CommandRequest::new_undelete("tags", "t1"));
+        assert_eq!(res.values, vec!["red".into()]);
+        assert_eq!(keys(query().unwrap()), ["t1"]);
+        let res = service.execute(CommandRequest::new_purge_trash("tags", true));
+        assert_eq!(res.values, vec![1.into()]);
+
+        // 索引的 table 不算在统计里
+        let stats = service.store().stats().unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].table, "tags");
+    }
+
+    #[test]
+    fn hquery_should_work() {
+        let service: Service<_> = ServiceInner::new(store(MemTable::new())).into();
+        service.execute(CommandRequest::new_hset("tags", "t1", "red".into()));
+        let res = service.execute(CommandRequest::new_hquery(
+            "tags",
+            "by_tag",
+            "red".into(),
+            0,
+        ));
+        assert_eq!(res.status, 200);
+        assert_eq!(res.pairs, vec![Kvpair::new("t1", "red".into())]);
+
+        let res = service.execute(CommandRequest::new_hquery("tags", "nope", "red".into(), 0));
+        assert_eq!(res.status, 404);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let res = service.execute(CommandRequest::new_hquery(
+            "tags",
+            "by_tag",
+            "red".into(),
+            0,
+        ));
+        assert_eq!(res.status, 501);
+    }
+}
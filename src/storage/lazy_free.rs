@@ -0,0 +1,55 @@
+use std::sync::mpsc::{self, Sender};
+use std::sync::OnceLock;
+use std::thread;
+
+use prost::Message;
+
+/// 编码后超过这个大小的数据交给后台线程释放
+pub const LAZY_FREE_LIMIT: usize = 64 * 1024;
+
+type Garbage = Box<dyn Send>;
+
+static QUEUE: OnceLock<Sender<Garbage>> = OnceLock::new();
+
+/// 把 garbage 交给后台线程 drop
+///
+/// 释放几 MB 的 value 要花不少时间，放在处理请求的路径上会拖慢同一个
+/// worker 上的其他请求。后台线程在第一次使用时启动；如果启动失败就直接在当前线程 drop。
+pub fn lazy_free<T: Send + 'static>(garbage: T) {
+    let queue = QUEUE.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<Garbage>();
+        // 线程起不来的话 rx 被 drop，下面的 send 会失败，garbage 就地释放
+        let _ = thread::Builder::new()
+            .name("kv-lazy-free".into())
+            .spawn(move || rx.into_iter().for_each(drop));
+        tx
+    });
+    let _ = queue.send(Box::new(garbage));
+}
+
+/// 大的 protobuf 消息（value、response 等）在后台释放，小的直接 drop
+pub fn free_lazily<M: Message + 'static>(msg: M) {
+    if msg.encoded_len() > LAZY_FREE_LIMIT {
+        lazy_free(msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Guard(Sender<Option<String>>);
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            let _ = self.0.send(thread::current().name().map(Into::into));
+        }
+    }
+
+    #[test]
+    fn lazy_free_should_drop_in_background() {
+        let (tx, rx) = mpsc::channel();
+        lazy_free(Guard(tx));
+        assert_eq!(rx.recv().unwrap().as_deref(), Some("kv-lazy-free"));
+    }
+}
@@ -0,0 +1,710 @@
+use heed::types::Bytes;
+use heed::{Database, Env, EnvOpenOptions, RoTxn, RwTxn};
+use std::collections::VecDeque;
+use std::ops::Bound;
+use std::path::Path;
+use std::str;
+use tracing::warn;
+
+use super::expiry::now_ms;
+use super::{
+    check_move, decode_deadline, decode_version, encode_version, incr_value, move_conflict,
+    split_table_key, table_key, table_prefix, version_conflict, Changefeed, KeyEvent, KeyEventKind,
+    COMPACT_COMMAND, FLUSH_COMMAND, STORAGE_COMMAND,
+};
+use crate::{Glob, KvError, Kvpair, StagedWrites, Storage, StorageIter, TableStats, Value};
+
+/// 缺省的 map size，LMDB 的文件不能超过它。需要更大时用 LmdbStore::with_map_size
+pub const LMDB_MAP_SIZE: usize = 1 << 30;
+
+// get_iter 每次打开一个读 transaction，最多读这么多个 key
+const CURSOR_BATCH: usize = 128;
+// version 的计数器在 versions 里的 key。LMDB 不支持空的 key，table_key 编码出来的 key 至少有 4 个字节
+const NEXT_VERSION: &[u8] = b"\0";
+
+type Db = Database<Bytes, Bytes>;
+type KeyRange<'a> = (Bound<&'a [u8]>, Bound<&'a [u8]>);
+
+/// 用 LMDB 存储的 backend，适合读多写少的场景：读直接访问 mmap 的页面，不会阻塞写
+///
+/// 所有 table 在同一个 database 里，key 是 table_key(table, key)，同一个 table 的 key 排在一起。
+/// 过期时间和 version 各用一个 database，key 的编码一样。
+/// LMDB 同时只有一个写 transaction，读改写的操作放在一个写 transaction 里就是原子的。
+/// get 读到过期的 key 时顺便删掉，遍历的时候只是跳过，留给 purge_expired
+#[derive(Clone)]
+pub struct LmdbStore {
+    env: Env,
+    data: Db,
+    expires: Db,
+    versions: Db,
+    changefeed: Option<Changefeed>,
+}
+
+impl LmdbStore {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self::with_map_size(path, LMDB_MAP_SIZE)
+    }
+
+    pub fn with_map_size(path: impl AsRef<Path>, map_size: usize) -> Self {
+        std::fs::create_dir_all(&path).unwrap();
+        // 文件被 mmap 到内存里，别的进程截断或者改写它是未定义行为，所以 open 是 unsafe 的。
+        // 这个目录只由 kv server 使用
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(map_size)
+                .max_dbs(3)
+                .open(path)
+        };
+        let env = env.unwrap();
+        let mut txn = env.write_txn().unwrap();
+        let data = env.create_database(&mut txn, Some("data")).unwrap();
+        let expires = env.create_database(&mut txn, Some("expires")).unwrap();
+        let versions = env.create_database(&mut txn, Some("versions")).unwrap();
+        txn.commit().unwrap();
+        Self {
+            env,
+            data,
+            expires,
+            versions,
+            changefeed: None,
+        }
+    }
+
+    /// 过期的 key 被删掉的时候往 changefeed 里发 Expired 事件
+    pub fn with_changefeed(mut self, changefeed: Changefeed) -> Self {
+        self.changefeed = Some(changefeed);
+        self
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&RoTxn) -> Result<T, KvError>) -> Result<T, KvError> {
+        let txn = self.env.read_txn()?;
+        f(&txn)
+    }
+
+    // f 出错时 transaction 被 drop，所有的修改一起放弃
+    fn write<T>(&self, f: impl FnOnce(&mut RwTxn) -> Result<T, KvError>) -> Result<T, KvError> {
+        let mut txn = self.env.write_txn()?;
+        let v = f(&mut txn)?;
+        txn.commit()?;
+        Ok(v)
+    }
+
+    fn deadline_in(&self, txn: &RoTxn, name: &[u8]) -> Result<Option<i64>, KvError> {
+        Ok(self.expires.get(txn, name)?.map(decode_deadline))
+    }
+
+    // 没有过期的 value，过期的当作不存在
+    fn live_value<'t>(
+        &self,
+        txn: &'t RoTxn,
+        name: &[u8],
+        now: i64,
+    ) -> Result<Option<&'t [u8]>, KvError> {
+        match self.data.get(txn, name)? {
+            Some(_) if passed(self.deadline_in(txn, name)?, now) => Ok(None),
+            v => Ok(v),
+        }
+    }
+
+    // 读到了过期的 key，顺便删掉。大多数 key 都没有过期时间，只需要一次读
+    fn reap(&self, name: &[u8]) -> Result<(), KvError> {
+        let now = now_ms();
+        if !self.read(|txn| Ok(passed(self.deadline_in(txn, name)?, now)))? {
+            return Ok(());
+        }
+        let old = self.write(|txn| {
+            if !passed(self.deadline_in(txn, name)?, now) {
+                return Ok(None);
+            }
+            let old = self.data.get(txn, name)?.map(|v| v.to_vec());
+            self.expires.delete(txn, name)?;
+            self.data.delete(txn, name)?;
+            self.bump_version(txn, name, false)?;
+            Ok(old)
+        })?;
+        if let Some(old) = old {
+            self.notify_expired(name, &old);
+        }
+        Ok(())
+    }
+
+    // transaction 提交之后再发事件，订阅者读到的一定是已经删掉的状态
+    fn notify_expired(&self, name: &[u8], value: &[u8]) {
+        let feed = match &self.changefeed {
+            Some(feed) => feed,
+            None => return,
+        };
+        if let Some((table, key)) = split_table_key(name) {
+            // 只用来算 hash，坏掉的 value 当作空值
+            let value = Value::try_from(value).unwrap_or_default();
+            feed.publish(KeyEvent::new(table, key, KeyEventKind::Expired, &value));
+        }
+    }
+
+    // version 在整个 env 里单调递增，0 留给不存在的 key
+    fn next_version(&self, txn: &mut RwTxn) -> Result<u64, KvError> {
+        let current = self.versions.get(txn, NEXT_VERSION)?;
+        let next = current.map_or(0, decode_counter) + 1;
+        self.versions.put(txn, NEXT_VERSION, &next.to_be_bytes())?;
+        Ok(next)
+    }
+
+    // 写入或者删除 key 的 transaction 里调用，之前读到的 version 都不再有效。
+    // 记录过 version 的 key 换一个新的，删掉的 key 不再记录，重新创建时再分配
+    fn bump_version(&self, txn: &mut RwTxn, name: &[u8], exists: bool) -> Result<(), KvError> {
+        if self.versions.get(txn, name)?.is_none() {
+            return Ok(());
+        }
+        match exists {
+            true => {
+                let next = self.next_version(txn)?;
+                self.versions.put(txn, name, &encode_version(next))?;
+            }
+            false => {
+                self.versions.delete(txn, name)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn cursor(&self, table: &str, after: Option<&str>, pattern: Option<Glob>) -> Cursor {
+        Cursor {
+            store: self.clone(),
+            prefix: table_prefix(table),
+            after: after.map(|key| table_key(table, key)),
+            pattern,
+            batch: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+impl Storage for LmdbStore {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let name = table_key(table, key);
+        self.reap(&name)?;
+        self.read(|txn| decode(self.live_value(txn, &name, now_ms())?))
+    }
+
+    // 写入会去掉 key 的过期时间，已经过期的旧值当作不存在
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let name = table_key(table, &key);
+        let value: Vec<u8> = value.try_into()?;
+        self.write(|txn| {
+            let old = self.live_value(txn, &name, now_ms())?.map(<[u8]>::to_vec);
+            self.expires.delete(txn, &name)?;
+            self.data.put(txn, &name, &value)?;
+            self.bump_version(txn, &name, true)?;
+            decode(old.as_deref())
+        })
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let name = table_key(table, key);
+        self.reap(&name)?;
+        self.read(|txn| Ok(self.live_value(txn, &name, now_ms())?.is_some()))
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let name = table_key(table, key);
+        self.write(|txn| {
+            let old = self.live_value(txn, &name, now_ms())?.map(<[u8]>::to_vec);
+            self.data.delete(txn, &name)?;
+            self.expires.delete(txn, &name)?;
+            self.bump_version(txn, &name, false)?;
+            decode(old.as_deref())
+        })
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        Ok(self.get_iter(table)?.collect())
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        Ok(Box::new(StorageIter::new(self.cursor(table, None, None))))
+    }
+
+    // 在拷贝 value 之前就跳过不匹配的 key
+    fn get_iter_matching(
+        &self,
+        table: &str,
+        pattern: &Glob,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let cursor = self.cursor(table, None, Some(pattern.clone()));
+        Ok(Box::new(StorageIter::new(cursor)))
+    }
+
+    // LMDB 的 key 是有序的，直接从 after 后面开始读
+    fn scan(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        pattern: Option<&Glob>,
+        count: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let cursor = self.cursor(table, after, pattern.cloned());
+        Ok(StorageIter::new(cursor).take(count).collect())
+    }
+
+    // 只遍历 key，没有任何 key 设置过期时间时不需要读 expires
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        let prefix = table_prefix(table);
+        self.read(|txn| {
+            let check = !self.expires.is_empty(txn)?;
+            let now = now_ms();
+            let mut len = 0;
+            for item in self.data.prefix_iter(txn, &prefix)? {
+                let (name, _) = item?;
+                len += !(check && passed(self.deadline_in(txn, name)?, now)) as usize;
+            }
+            Ok(len)
+        })
+    }
+
+    // 找到一个 table 之后直接跳到它的最后一个 key 后面，每个 table 只读一个 key
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.read(|txn| {
+            let mut names = Vec::new();
+            let mut next = self.data.first(txn)?;
+            while let Some((name, _)) = next {
+                let start = match split_table_key(name) {
+                    Some((table, _)) => {
+                        names.push(table.to_string());
+                        match prefix_end(&table_prefix(table)) {
+                            Some(end) => end,
+                            None => break,
+                        }
+                    }
+                    // 不是 Storage 写入的 key，跳过
+                    None => [name, b"\0"].concat(),
+                };
+                next = self.data.get_greater_than_or_equal_to(txn, &start)?;
+            }
+            // key 先按 table 名字的长度排序，所以还要再按名字排一次
+            names.sort_unstable();
+            Ok(names)
+        })
+    }
+
+    // value 在 LMDB 里就是编码之后的样子，直接用它的长度，不需要解码。
+    // 过期了还没删掉的 key 也算在里面
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        let mut stats: Vec<TableStats> = self.read(|txn| {
+            let mut stats: Vec<TableStats> = Vec::new();
+            for item in self.data.iter(txn)? {
+                let (name, value) = item?;
+                let (table, key) = match split_table_key(name) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let s = match stats.last_mut() {
+                    Some(s) if s.table == table => s,
+                    _ => {
+                        stats.push(TableStats {
+                            table: table.into(),
+                            ..Default::default()
+                        });
+                        stats.last_mut().unwrap()
+                    }
+                };
+                s.keys += 1;
+                s.bytes += key.len() + value.len();
+            }
+            Ok(stats)
+        })?;
+        stats.sort_unstable_by(|a, b| a.table.cmp(&b.table));
+        Ok(stats)
+    }
+
+    // 清空三个 database，只保留 version 的计数器，之后的 version 还是递增的
+    fn flush_all(&self) -> Result<usize, KvError> {
+        self.write(|txn| {
+            let now = now_ms();
+            let mut expired = 0;
+            for item in self.expires.iter(txn)? {
+                let (_, deadline) = item?;
+                expired += passed(Some(decode_deadline(deadline)), now) as usize;
+            }
+            let n = (self.data.len(txn)? as usize).saturating_sub(expired);
+            let counter = self.versions.get(txn, NEXT_VERSION)?.map(<[u8]>::to_vec);
+            self.data.clear(txn)?;
+            self.expires.clear(txn)?;
+            self.versions.clear(txn)?;
+            if let Some(counter) = counter {
+                self.versions.put(txn, NEXT_VERSION, &counter)?;
+            }
+            Ok(n)
+        })
+    }
+
+    // table 的 key 是连续的一段，三个 database 各删除一个 range
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        let prefix = table_prefix(table);
+        let end = prefix_end(&prefix);
+        let range: KeyRange = (
+            Bound::Included(&prefix),
+            end.as_deref().map_or(Bound::Unbounded, Bound::Excluded),
+        );
+        self.write(|txn| {
+            let now = now_ms();
+            let mut n = 0;
+            for item in self.data.range(txn, &range)? {
+                let (name, _) = item?;
+                n += !passed(self.deadline_in(txn, name)?, now) as usize;
+            }
+            for db in [&self.data, &self.expires, &self.versions] {
+                db.delete_range(txn, &range)?;
+            }
+            Ok(n)
+        })
+    }
+
+    fn admin(&self, command: &str, _args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        match command {
+            STORAGE_COMMAND => self.read(|txn| {
+                Ok(vec![
+                    Kvpair::new("size_on_disk", (self.env.real_disk_size()? as i64).into()),
+                    Kvpair::new("keys", (self.data.len(txn)? as i64).into()),
+                    Kvpair::new("expiring", (self.expires.len(txn)? as i64).into()),
+                ])
+            }),
+            FLUSH_COMMAND => {
+                self.env.force_sync()?;
+                Ok(vec![Kvpair::new("flushed", true.into())])
+            }
+            // LMDB 会重用释放的页面，只有复制整个 env 才能让文件变小
+            COMPACT_COMMAND => Err(KvError::Unsupported(
+                "LMDB reuses freed pages and cannot shrink in place".into(),
+            )),
+            _ => Err(KvError::Unsupported(format!("admin command {}", command))),
+        }
+    }
+
+    // 删除和写入在同一个写 transaction 里，dst 冲突时一起放弃。
+    // key 的过期时间跟着一起移过去
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        check_move(src, dst)?;
+        let from = table_key(src, key);
+        let to = table_key(dst, key);
+        self.write(|txn| {
+            let now = now_ms();
+            let deadline = self.deadline_in(txn, &from)?;
+            let v = match self.data.get(txn, &from)? {
+                Some(v) => v.to_vec(),
+                None => return Ok(None),
+            };
+            self.data.delete(txn, &from)?;
+            self.expires.delete(txn, &from)?;
+            self.bump_version(txn, &from, false)?;
+            if passed(deadline, now) {
+                return Ok(None);
+            }
+
+            let occupied = self.live_value(txn, &to, now)?.is_some();
+            if !force && occupied {
+                return Err(move_conflict(dst, key));
+            }
+            self.data.put(txn, &to, &v)?;
+            self.bump_version(txn, &to, true)?;
+            match deadline {
+                Some(d) => self.expires.put(txn, &to, &d.to_be_bytes())?,
+                None => {
+                    self.expires.delete(txn, &to)?;
+                }
+            }
+            decode(Some(&v))
+        })
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        let name = table_key(table, &key);
+        let value: Vec<u8> = value.try_into()?;
+        self.write(|txn| {
+            if self.live_value(txn, &name, now_ms())?.is_some() {
+                return Ok(false);
+            }
+            self.expires.delete(txn, &name)?;
+            self.data.put(txn, &name, &value)?;
+            self.bump_version(txn, &name, true)?;
+            Ok(true)
+        })
+    }
+
+    // 所有的写入在同一个写 transaction 里，出错时一个都不会生效
+    fn commit(&self, writes: StagedWrites) -> Result<(), KvError> {
+        let writes = writes
+            .into_iter()
+            .map(|((table, key), value)| {
+                let value = value.map(Vec::<u8>::try_from).transpose()?;
+                Ok((table_key(&table, &key), value))
+            })
+            .collect::<Result<Vec<_>, KvError>>()?;
+        self.write(|txn| {
+            for (name, value) in &writes {
+                self.expires.delete(txn, name)?;
+                match value {
+                    Some(value) => self.data.put(txn, name, value)?,
+                    None => {
+                        self.data.delete(txn, name)?;
+                    }
+                }
+                self.bump_version(txn, name, value.is_some())?;
+            }
+            Ok(())
+        })
+    }
+
+    // 过期的旧值当作不存在，没有过期的保留原来的过期时间
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let name = table_key(table, key);
+        self.write(|txn| {
+            let now = now_ms();
+            if passed(self.deadline_in(txn, &name)?, now) {
+                self.expires.delete(txn, &name)?;
+            }
+            let old = decode(self.live_value(txn, &name, now)?)?;
+            let n = incr_value(key, old.as_ref(), delta)?;
+            let data: Vec<u8> = Value::from(n).try_into()?;
+            self.data.put(txn, &name, &data)?;
+            self.bump_version(txn, &name, true)?;
+            Ok(n)
+        })
+    }
+
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        let name = table_key(table, key);
+        self.write(|txn| {
+            let now = now_ms();
+            if self.data.get(txn, &name)?.is_none() {
+                return Ok(false);
+            }
+            let expired = passed(self.deadline_in(txn, &name)?, now);
+            if expired || deadline <= now {
+                self.data.delete(txn, &name)?;
+                self.expires.delete(txn, &name)?;
+                self.bump_version(txn, &name, false)?;
+                return Ok(!expired);
+            }
+            self.expires.put(txn, &name, &deadline.to_be_bytes())?;
+            Ok(true)
+        })
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let name = table_key(table, key);
+        self.write(|txn| {
+            if self.data.get(txn, &name)?.is_none() {
+                return Ok(false);
+            }
+            let deadline = self.deadline_in(txn, &name)?;
+            self.expires.delete(txn, &name)?;
+            if passed(deadline, now_ms()) {
+                self.data.delete(txn, &name)?;
+                self.bump_version(txn, &name, false)?;
+                return Ok(false);
+            }
+            Ok(deadline.is_some())
+        })
+    }
+
+    fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, KvError> {
+        let name = table_key(table, key);
+        self.reap(&name)?;
+        self.read(|txn| match self.live_value(txn, &name, now_ms())? {
+            Some(_) => self.deadline_in(txn, &name),
+            None => Err(KvError::NotFound(table.into(), key.into())),
+        })
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let purged = self.write(|txn| {
+            let now = now_ms();
+            let mut expired = Vec::new();
+            for item in self.expires.iter(txn)? {
+                let (name, deadline) = item?;
+                if passed(Some(decode_deadline(deadline)), now) {
+                    expired.push(name.to_vec());
+                }
+            }
+            let mut purged = Vec::new();
+            for name in expired {
+                self.expires.delete(txn, &name)?;
+                if let Some(old) = self.data.get(txn, &name)?.map(|v| v.to_vec()) {
+                    self.data.delete(txn, &name)?;
+                    self.bump_version(txn, &name, false)?;
+                    purged.push((name, old));
+                }
+            }
+            Ok(purged)
+        })?;
+        for (name, old) in &purged {
+            self.notify_expired(name, old);
+        }
+        Ok(purged.len())
+    }
+
+    // 记录新的 version 需要写，所以整个放在写 transaction 里
+    fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        let name = table_key(table, key);
+        self.write(|txn| {
+            if self.live_value(txn, &name, now_ms())?.is_none() {
+                return Ok(None);
+            }
+            // 记录的 version 在上次写入之后一直有效
+            match self.versions.get(txn, &name)?.map(decode_version) {
+                Some(version) if version > 0 => Ok(Some(version)),
+                _ => {
+                    let next = self.next_version(txn)?;
+                    self.versions.put(txn, &name, &encode_version(next))?;
+                    Ok(Some(next))
+                }
+            }
+        })
+    }
+
+    // 过期的旧值当作不存在，写入会去掉 key 的过期时间
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        let name = table_key(table, &key);
+        let value: Vec<u8> = value.try_into()?;
+        self.write(|txn| {
+            let current = match self.live_value(txn, &name, now_ms())? {
+                Some(_) => match self.versions.get(txn, &name)?.map(decode_version) {
+                    Some(v) if v > 0 => v,
+                    // 没有人读过 version，客户端给的一定不对
+                    _ => u64::MAX,
+                },
+                None => 0,
+            };
+            if current != version {
+                return Err(version_conflict(table, &key, version));
+            }
+            let next = self.next_version(txn)?;
+            self.data.put(txn, &name, &value)?;
+            self.expires.delete(txn, &name)?;
+            self.versions.put(txn, &name, &encode_version(next))?;
+            Ok(next)
+        })
+    }
+}
+
+impl From<heed::Error> for KvError {
+    fn from(e: heed::Error) -> Self {
+        KvError::LmdbError(e.to_string())
+    }
+}
+
+/// 分批读一个 table 的游标。每批在一个新的读 transaction 里从上一批的最后一个 key 之后开始，
+/// 调用者慢慢消费的时候不会一直占着读 transaction，LMDB 可以回收旧的页面
+struct Cursor {
+    store: LmdbStore,
+    prefix: Vec<u8>,
+    after: Option<Vec<u8>>,
+    pattern: Option<Glob>,
+    batch: VecDeque<Entry>,
+    done: bool,
+}
+
+struct Entry(String, Vec<u8>);
+
+impl Cursor {
+    fn fill(&mut self) -> Result<(), KvError> {
+        let store = self.store.clone();
+        let txn = store.env.read_txn()?;
+        let after = self.after.take();
+        let start: KeyRange = match &after {
+            Some(name) => (Bound::Excluded(name), Bound::Unbounded),
+            None => (Bound::Included(&self.prefix), Bound::Unbounded),
+        };
+        let check = !store.expires.is_empty(&txn)?;
+        let now = now_ms();
+        let mut iter = store.data.range(&txn, &start)?;
+        for _ in 0..CURSOR_BATCH {
+            let (name, value) = match iter.next().transpose()? {
+                Some((name, value)) if name.starts_with(&self.prefix) => (name, value),
+                _ => {
+                    self.done = true;
+                    return Ok(());
+                }
+            };
+            self.after = Some(name.to_vec());
+            let key = match str::from_utf8(&name[self.prefix.len()..]) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            if self.pattern.as_ref().is_some_and(|p| !p.matches(key)) {
+                continue;
+            }
+            if check && passed(store.deadline_in(&txn, name)?, now) {
+                continue;
+            }
+            self.batch.push_back(Entry(key.into(), value.to_vec()));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Cursor {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.batch.pop_front() {
+                return Some(entry);
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(e) = self.fill() {
+                warn!("Failed to read LMDB: {:?}", e);
+                self.done = true;
+            }
+        }
+    }
+}
+
+impl From<Entry> for Kvpair {
+    fn from(Entry(key, value): Entry) -> Self {
+        match value.as_slice().try_into() {
+            Ok(v) => Kvpair::new(key, v),
+            Err(_) => Kvpair::default(),
+        }
+    }
+}
+
+fn decode(value: Option<&[u8]>) -> Result<Option<Value>, KvError> {
+    value.map(Value::try_from).transpose()
+}
+
+fn decode_counter(data: &[u8]) -> u64 {
+    <[u8; 8]>::try_from(data)
+        .map(u64::from_be_bytes)
+        .unwrap_or_default()
+}
+
+// 比所有以 prefix 开头的 key 都大的最小的 key，prefix 全是 0xff 时没有
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(b) = end.pop() {
+        if b < u8::MAX {
+            end.push(b + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+fn passed(deadline: Option<i64>, now: i64) -> bool {
+    matches!(deadline, Some(d) if d <= now)
+}
@@ -0,0 +1,400 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use prost::Message;
+
+use super::{check_clone_target, incr_value, Changefeed, KeyEvent, KeyEventKind, STORAGE_COMMAND};
+use crate::{KvError, Kvpair, Storage, TableStats, Value};
+
+/// 满了之后先淘汰哪个 key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Eviction {
+    /// 最久没有被读写的
+    #[default]
+    Lru,
+    /// 被读写次数最少的，次数一样时最久没有被读写的
+    Lfu,
+}
+
+/// LruMemTable 的容量
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LruConfig {
+    /// 最多保存的 key 的数量，0 表示不限
+    pub max_entries: usize,
+    /// key 和编码之后的 value 的长度之和的上限，0 表示不限
+    pub max_bytes: usize,
+    pub eviction: Eviction,
+}
+
+impl Default for LruConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 100_000,
+            max_bytes: 64 << 20,
+            eviction: Eviction::Lru,
+        }
+    }
+}
+
+/// 有容量限制的内存存储
+///
+/// MemTable 没有上限，当作 cache 放在 sled 前面（比如 TieredStore）会一直增长。
+/// LruMemTable 写入之后超过 max_entries 或者 max_bytes 时按 eviction 淘汰 key，
+/// 比 max_bytes 还大的 value 直接丢掉，不会把别的 key 挤出去。get 和写操作算作一次访问，
+/// 遍历和 contains 不算。所有的 table 共享一个容量，用一个锁保护
+pub struct LruMemTable {
+    config: LruConfig,
+    inner: Mutex<Inner>,
+    evicted: AtomicU64,
+    changefeed: Option<Changefeed>,
+}
+
+#[derive(Default)]
+struct Inner {
+    tables: HashMap<String, HashMap<String, Entry>>,
+    // 淘汰的顺序，最前面的先淘汰
+    order: BTreeMap<Rank, (String, String)>,
+    entries: usize,
+    bytes: usize,
+    tick: u64,
+}
+
+struct Entry {
+    value: Value,
+    size: usize,
+    rank: Rank,
+}
+
+// LRU 时是 (最近访问的时间, 0)，LFU 时是 (访问的次数, 最近访问的时间)
+type Rank = (u64, u64);
+
+impl LruMemTable {
+    pub fn new(config: LruConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::default(),
+            evicted: AtomicU64::new(0),
+            changefeed: None,
+        }
+    }
+
+    /// 淘汰 key 的时候往 changefeed 里发 Evicted 事件
+    pub fn with_changefeed(mut self, changefeed: Changefeed) -> Self {
+        self.changefeed = Some(changefeed);
+        self
+    }
+
+    /// 因为容量被淘汰的 key 的数量
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// 当前 key 的数量和大小
+    pub fn usage(&self) -> (usize, usize) {
+        let inner = self.lock();
+        (inner.entries, inner.bytes)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // 写入 value，返回旧的值。先淘汰出足够的空间，刚写入的 key 不会马上被 LFU 淘汰
+    fn insert(&self, inner: &mut Inner, table: &str, key: String, value: Value) -> Option<Value> {
+        let size = key.len() + value.encoded_len();
+        let old = inner.remove(table, &key);
+        if self.config.max_bytes > 0 && size > self.config.max_bytes {
+            self.notify_evicted(table, &key, &value);
+            return old.map(|e| e.value);
+        }
+        self.evict(inner, 1, size);
+        let rank = inner.next_rank(self.config.eviction, old.as_ref().map(|e| e.rank));
+        inner.order.insert(rank, (table.into(), key.clone()));
+        inner.entries += 1;
+        inner.bytes += size;
+        let entry = Entry { value, size, rank };
+        inner
+            .tables
+            .entry(table.into())
+            .or_default()
+            .insert(key, entry);
+        old.map(|e| e.value)
+    }
+
+    // 淘汰到还能再放下 entries 个 key、bytes 字节为止
+    fn evict(&self, inner: &mut Inner, entries: usize, bytes: usize) {
+        let LruConfig {
+            max_entries,
+            max_bytes,
+            ..
+        } = self.config;
+        while (max_entries > 0 && inner.entries + entries > max_entries)
+            || (max_bytes > 0 && inner.bytes + bytes > max_bytes)
+        {
+            let (table, key) = match inner.order.first_key_value() {
+                Some((_, id)) => id.clone(),
+                None => break,
+            };
+            if let Some(entry) = inner.remove(&table, &key) {
+                self.notify_evicted(&table, &key, &entry.value);
+            }
+        }
+    }
+
+    fn notify_evicted(&self, table: &str, key: &str, value: &Value) {
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+        if let Some(feed) = &self.changefeed {
+            feed.publish(KeyEvent::new(table, key, KeyEventKind::Evicted, value));
+        }
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, table: &str, key: &str) -> Option<Entry> {
+        let entries = self.tables.get_mut(table)?;
+        let entry = entries.remove(key)?;
+        if entries.is_empty() {
+            self.tables.remove(table);
+        }
+        self.order.remove(&entry.rank);
+        self.entries -= 1;
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+
+    fn next_rank(&mut self, eviction: Eviction, old: Option<Rank>) -> Rank {
+        self.tick += 1;
+        match eviction {
+            Eviction::Lru => (self.tick, 0),
+            Eviction::Lfu => (old.map_or(0, |(hits, _)| hits) + 1, self.tick),
+        }
+    }
+
+    // 访问一次 key，更新它的淘汰顺序
+    fn touch(&mut self, eviction: Eviction, table: &str, key: &str) -> Option<Value> {
+        let old = self.tables.get(table)?.get(key)?.rank;
+        let rank = self.next_rank(eviction, Some(old));
+        let id = self.order.remove(&old)?;
+        self.order.insert(rank, id);
+        let entry = self.tables.get_mut(table)?.get_mut(key)?;
+        entry.rank = rank;
+        Some(entry.value.clone())
+    }
+
+    fn pairs(&self, table: &str) -> Vec<Kvpair> {
+        match self.tables.get(table) {
+            Some(entries) => entries
+                .iter()
+                .map(|(k, e)| Kvpair::new(k, e.value.clone()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Default for LruMemTable {
+    fn default() -> Self {
+        Self::new(LruConfig::default())
+    }
+}
+
+impl Storage for LruMemTable {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        Ok(self.lock().touch(self.config.eviction, table, key))
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let mut inner = self.lock();
+        Ok(self.insert(&mut inner, table, key, value))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let inner = self.lock();
+        Ok(inner.tables.get(table).is_some_and(|t| t.contains_key(key)))
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        Ok(self.lock().remove(table, key).map(|e| e.value))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        Ok(self.lock().pairs(table))
+    }
+
+    // 在锁里复制一份，遍历的时候不会挡住别的操作
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        Ok(Box::new(self.lock().pairs(table).into_iter()))
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self.lock().tables.get(table).map_or(0, HashMap::len))
+    }
+
+    fn admin(&self, command: &str, _args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        match command {
+            STORAGE_COMMAND => {
+                let (entries, bytes) = self.usage();
+                Ok(vec![
+                    Kvpair::new("keys", (entries as i64).into()),
+                    Kvpair::new("bytes", (bytes as i64).into()),
+                    Kvpair::new("evicted", (self.evicted() as i64).into()),
+                ])
+            }
+            _ => Err(KvError::Unsupported(format!("admin command {}", command))),
+        }
+    }
+
+    fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+        check_clone_target(self, src, dst)?;
+        let mut inner = self.lock();
+        let pairs = inner.pairs(src);
+        for pair in &pairs {
+            let value = pair.value.clone().unwrap_or_default();
+            self.insert(&mut inner, dst, pair.key.clone(), value);
+        }
+        Ok(pairs.len())
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut names: Vec<_> = self.lock().tables.keys().cloned().collect();
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        let inner = self.lock();
+        let mut stats: Vec<_> = inner
+            .tables
+            .iter()
+            .map(|(table, entries)| TableStats {
+                table: table.clone(),
+                keys: entries.len(),
+                bytes: entries.values().map(|e| e.size).sum(),
+            })
+            .collect();
+        stats.sort_unstable_by(|a, b| a.table.cmp(&b.table));
+        Ok(stats)
+    }
+
+    fn flush_all(&self) -> Result<usize, KvError> {
+        let mut inner = self.lock();
+        let n = inner.entries;
+        *inner = Inner::default();
+        Ok(n)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        let mut inner = self.lock();
+        let keys: Vec<_> = match inner.tables.get(table) {
+            Some(entries) => entries.keys().cloned().collect(),
+            None => return Ok(0),
+        };
+        for key in &keys {
+            inner.remove(table, key);
+        }
+        Ok(keys.len())
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let mut inner = self.lock();
+        let old = inner.tables.get(table).and_then(|t| t.get(key));
+        let n = incr_value(key, old.map(|e| &e.value), delta)?;
+        self.insert(&mut inner, table, key.into(), n.into());
+        Ok(n)
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        let mut inner = self.lock();
+        if inner
+            .tables
+            .get(table)
+            .is_some_and(|t| t.contains_key(&key))
+        {
+            return Ok(false);
+        }
+        self.insert(&mut inner, table, key, value);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StorageExt;
+
+    fn config(max_entries: usize, max_bytes: usize, eviction: Eviction) -> LruConfig {
+        LruConfig {
+            max_entries,
+            max_bytes,
+            eviction,
+        }
+    }
+
+    #[test]
+    fn lru_should_evict_least_recently_used() {
+        let store = LruMemTable::new(config(2, 0, Eviction::Lru));
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t2", "k2", "v2").unwrap();
+        // 读过的 k1 比 k2 新
+        store.get("t1", "k1").unwrap();
+        store.hset("t1", "k3", "v3").unwrap();
+
+        assert!(store.contains("t1", "k1").unwrap());
+        assert!(!store.contains("t2", "k2").unwrap());
+        assert_eq!(store.list_tables().unwrap(), ["t1"]);
+        assert_eq!(store.evicted(), 1);
+    }
+
+    #[test]
+    fn lfu_should_evict_least_frequently_used() {
+        let store = LruMemTable::new(config(3, 0, Eviction::Lfu));
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+        store.get("t1", "k1").unwrap();
+        store.get("t1", "k1").unwrap();
+        store.get("t1", "k2").unwrap();
+        store.hset("t1", "k3", "v3").unwrap();
+        // k3 只访问过一次，比 k2 少
+        store.hset("t1", "k4", "v4").unwrap();
+
+        assert!(store.contains("t1", "k1").unwrap());
+        assert!(store.contains("t1", "k2").unwrap());
+        assert!(!store.contains("t1", "k3").unwrap());
+        assert!(store.contains("t1", "k4").unwrap());
+    }
+
+    #[test]
+    fn lru_should_respect_max_bytes() {
+        let size = "k1".len() + Value::from("value").encoded_len();
+        let store = LruMemTable::new(config(0, size * 2, Eviction::Lru));
+        store.hset("t1", "k1", "value").unwrap();
+        store.hset("t1", "k2", "value").unwrap();
+        assert_eq!(store.usage(), (2, size * 2));
+        store.hset("t1", "k3", "value").unwrap();
+        assert_eq!(store.usage(), (2, size * 2));
+        assert!(!store.contains("t1", "k1").unwrap());
+
+        // 比容量还大的 value 直接丢掉，旧的值也没有了
+        store.hset("t1", "big", "x".repeat(size * 2)).unwrap();
+        assert!(!store.contains("t1", "big").unwrap());
+        assert_eq!(store.del("t1", "k2").unwrap(), Some("value".into()));
+        assert_eq!(store.usage(), (1, size));
+    }
+
+    #[test]
+    fn eviction_should_publish_changefeed() {
+        let feed = Changefeed::new(16);
+        let mut rx = feed.subscribe();
+        let store = LruMemTable::new(config(1, 0, Eviction::Lru)).with_changefeed(feed);
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+
+        let event = rx.try_recv().unwrap();
+        assert_eq!(
+            event,
+            KeyEvent::new("t1", "k1", KeyEventKind::Evicted, &"v1".into())
+        );
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -1,10 +1,89 @@
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
-use dashmap::{mapref::one::Ref, DashMap};
+use super::expiry::now_ms;
+use super::metrics::OpCounters;
+use super::{
+    check_clone_target, check_move, first_keys, incr_value, move_conflict, version_conflict,
+    Changefeed, KeyEvent, KeyEventKind, COMPACT_COMMAND, FLUSH_COMMAND, STORAGE_COMMAND,
+};
+use crate::{
+    Glob, KeyMeta, KeyRange, KvError, Kvpair, Snapshot, Storage, StorageIter, StorageMetrics,
+    TableStats, Value,
+};
+use dashmap::{
+    mapref::{entry::Entry, one::Ref},
+    DashMap,
+};
+use prost::Message;
+use std::mem::size_of;
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// MemTable 的 admin 命令，按 table 报告占用的内存
+pub const MEMORY_COMMAND: &str = "memory";
+
+/// 一个 table 占用的内存
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TableMemory {
+    pub entries: usize,
+    pub key_bytes: usize,
+    /// value 编码之后的长度
+    pub value_bytes: usize,
+}
+
+impl TableMemory {
+    /// DashMap 自己的开销的估算，每个 entry 至少要存一个 (String, Value)
+    pub fn overhead(&self) -> usize {
+        self.entries * size_of::<(String, Value)>()
+    }
+}
+
+// 统计是在放开 key 所在分片的锁之后更新的，并发地写入和删除同一个 key 时，
+// 删除可能先被统计。所以用有符号数累计，中间短暂地是负数也没关系，最后总是对得上
+#[derive(Clone, Copy, Debug, Default)]
+struct MemoryCounter {
+    entries: i64,
+    key_bytes: i64,
+    value_bytes: i64,
+}
+
+impl MemoryCounter {
+    fn add(&mut self, key: usize, value: usize) {
+        self.entries += 1;
+        self.key_bytes += key as i64;
+        self.value_bytes += value as i64;
+    }
+
+    fn sub(&mut self, key: usize, value: usize) {
+        self.entries -= 1;
+        self.key_bytes -= key as i64;
+        self.value_bytes -= value as i64;
+    }
+
+    fn total(&self) -> TableMemory {
+        let clamp = |n: i64| n.max(0) as usize;
+        TableMemory {
+            entries: clamp(self.entries),
+            key_bytes: clamp(self.key_bytes),
+            value_bytes: clamp(self.value_bytes),
+        }
+    }
+}
 
 /// 使用DashMap构建的MemTable, 实现了Storage trait
 #[derive(Clone, Debug, Default)]
 pub struct MemTable {
     tables: DashMap<String, DashMap<String, Value>>,
+    // 每次写入时更新，报告内存的时候不需要遍历所有的 key
+    memory: DashMap<String, MemoryCounter>,
+    // 设置了过期时间的 key，(table, key) -> UNIX 毫秒
+    expires: DashMap<(String, String), i64>,
+    // 读过 version 的 key，(table, key) -> version。每次写入都换一个新的 version
+    versions: DashMap<(String, String), u64>,
+    next_version: Arc<AtomicU64>,
+    // 打开了元数据记录时才有，(table, key) -> KeyMeta
+    metas: Option<DashMap<(String, String), KeyMeta>>,
+    ops: OpCounters,
+    changefeed: Option<Changefeed>,
 }
 
 impl MemTable {
@@ -13,8 +92,23 @@ impl MemTable {
         Self::default()
     }
 
+    /// 记录每个 key 的元数据的 MemTable。每个 key 要多存一份 table 和 key，
+    /// 所以缺省不记录
+    pub fn with_meta() -> Self {
+        Self {
+            metas: Some(DashMap::new()),
+            ..Default::default()
+        }
+    }
+
+    /// key 过期被清掉的时候往 changefeed 里发 Expired 事件
+    pub fn with_changefeed(mut self, changefeed: Changefeed) -> Self {
+        self.changefeed = Some(changefeed);
+        self
+    }
+
     /// 如果名为name的hash table 不存在,则创建,否则返回
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    fn get_or_create_table(&self, name: &str) -> Ref<'_, String, DashMap<String, Value>> {
         match self.tables.get(name) {
             Some(table) => table,
             None => {
@@ -23,47 +117,765 @@ impl MemTable {
             }
         }
     }
+
+    /// 每个 table 占用的内存
+    pub fn memory(&self) -> Vec<(String, TableMemory)> {
+        self.memory
+            .iter()
+            .map(|m| (m.key().clone(), m.value().total()))
+            .collect()
+    }
+
+    // 记录一次写入：key 的 value 从 old 变成了 new
+    fn account(&self, table: &str, key: &str, old: Option<&Value>, new: Option<&Value>) {
+        self.account_len(
+            table,
+            key.len(),
+            old.map(Message::encoded_len),
+            new.map(Message::encoded_len),
+        );
+        self.record_meta(table, key, old.is_some(), new.is_some());
+    }
+
+    // 元数据只是统计信息，在写入之后更新，并发写同一个 key 时 updates 可能少算
+    fn record_meta(&self, table: &str, key: &str, existed: bool, exists: bool) {
+        let metas = match &self.metas {
+            Some(metas) => metas,
+            None => return,
+        };
+        let now = now_ms();
+        match (existed, exists) {
+            (_, false) => {
+                metas.remove(&id(table, key));
+            }
+            (false, true) => {
+                metas.insert(id(table, key), KeyMeta::created(now));
+            }
+            (true, true) => {
+                metas
+                    .entry(id(table, key))
+                    .and_modify(|m| m.touch(now))
+                    .or_insert_with(|| KeyMeta::created(now));
+            }
+        }
+    }
+
+    fn account_len(&self, table: &str, key: usize, old: Option<usize>, new: Option<usize>) {
+        let mut m = self.memory.entry(table.into()).or_default();
+        if let Some(len) = old {
+            m.sub(key, len);
+        }
+        if let Some(len) = new {
+            m.add(key, len);
+        }
+    }
+
+    // 下面修改过期时间的函数都在持有 key 所在分片的锁时调用，
+    // 这样同一个 key 的写入和过期不会交错
+    fn has_deadlines(&self) -> bool {
+        !self.expires.is_empty()
+    }
+
+    fn deadline_passed(&self, table: &str, key: &str, now: i64) -> bool {
+        self.has_deadlines() && passed(self.expires.get(&id(table, key)).map(|d| *d), now)
+    }
+
+    // key 已经过期的话删掉它的过期时间，返回 true
+    fn take_expired(&self, table: &str, key: &str, now: i64) -> bool {
+        let expired = self.has_deadlines()
+            && self
+                .expires
+                .remove_if(&id(table, key), |_, d| *d <= now)
+                .is_some();
+        if expired {
+            self.bump_version(table, key, false);
+        }
+        expired
+    }
+
+    fn clear_deadline(&self, table: &str, key: &str) -> Option<i64> {
+        match self.has_deadlines() {
+            true => self.expires.remove(&id(table, key)).map(|(_, d)| d),
+            false => None,
+        }
+    }
+
+    fn restore_deadline(&self, table: &str, key: &str, deadline: Option<i64>) {
+        if let Some(d) = deadline {
+            self.expires.insert(id(table, key), d);
+        }
+    }
+
+    // 持有 key 所在分片的锁时调用。记录过的 version 在上次写入之后一直有效，
+    // 没有记录的分配一个新的
+    fn version_of(&self, table: &str, key: &str) -> u64 {
+        let recorded = self.versions.get(&id(table, key)).map(|v| *v);
+        recorded.unwrap_or_else(|| self.record_version(table, key))
+    }
+
+    fn record_version(&self, table: &str, key: &str) -> u64 {
+        let version = self.next_version.fetch_add(1, Ordering::Relaxed) + 1;
+        self.versions.insert(id(table, key), version);
+        version
+    }
+
+    // 持有 key 所在分片的锁时，在每次写入或者删除 key 之后调用，之前读到的 version 都不再有效。
+    // 记录过 version 的 key 换一个新的，删掉的 key 不再记录，重新创建时再分配。
+    // version 来自同一个递增的计数器，不会再出现用过的数字
+    fn bump_version(&self, table: &str, key: &str, exists: bool) {
+        if self.versions.is_empty() {
+            return;
+        }
+        let id = id(table, key);
+        match exists {
+            true => {
+                if let Some(mut v) = self.versions.get_mut(&id) {
+                    *v = self.next_version.fetch_add(1, Ordering::Relaxed) + 1;
+                }
+            }
+            false => {
+                self.versions.remove(&id);
+            }
+        }
+    }
+
+    // 在已经找到的 table 里写入 key，mset 写多个 key 时只需要找一次 table。
+    // 写入会去掉 key 的过期时间，已经过期的旧值当作不存在
+    fn insert(
+        &self,
+        t: &DashMap<String, Value>,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> Option<Value> {
+        self.ops.write(1);
+        let (key_len, value_len) = (key.len(), value.encoded_len());
+        let now = now_ms();
+        let (old, deadline) = match t.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let deadline = self.clear_deadline(table, entry.key());
+                self.record_meta(table, entry.key(), !passed(deadline, now), true);
+                self.bump_version(table, entry.key(), true);
+                (Some(entry.insert(value)), deadline)
+            }
+            Entry::Vacant(entry) => {
+                self.record_meta(table, entry.key(), false, true);
+                self.bump_version(table, entry.key(), true);
+                entry.insert(value);
+                (None, None)
+            }
+        };
+
+        let old_len = old.as_ref().map(Message::encoded_len);
+        self.account_len(table, key_len, old_len, Some(value_len));
+        old.filter(|_| !passed(deadline, now))
+    }
+
+    fn remove(&self, t: &DashMap<String, Value>, table: &str, key: &str) -> Option<Value> {
+        self.ops.delete(1);
+        let (old, deadline) = match t.entry(key.into()) {
+            Entry::Occupied(entry) => {
+                let deadline = self.clear_deadline(table, key);
+                self.bump_version(table, key, false);
+                (Some(entry.remove()), deadline)
+            }
+            Entry::Vacant(_) => (None, None),
+        };
+        self.account(table, key, old.as_ref(), None);
+        old.filter(|_| !passed(deadline, now_ms()))
+    }
+
+    // 读到了过期的 key，顺便删掉
+    fn reap(&self, t: &DashMap<String, Value>, table: &str, key: &str) {
+        let now = now_ms();
+        if !self.deadline_passed(table, key, now) {
+            return;
+        }
+        if let Some((_, v)) = t.remove_if(key, |_, _| self.take_expired(table, key, now)) {
+            self.account(table, key, Some(&v), None);
+            self.notify_expired(table, key, &v);
+        }
+    }
+
+    fn notify_expired(&self, table: &str, key: &str, value: &Value) {
+        if let Some(feed) = &self.changefeed {
+            feed.publish(KeyEvent::new(table, key, KeyEventKind::Expired, value));
+        }
+    }
+}
+
+// MemTable 的快照，复制出来的一个 table
+struct MemSnapshot(DashMap<String, Value>);
+
+impl Snapshot for MemSnapshot {
+    fn get(&self, key: &str) -> Option<Value> {
+        self.0.get(key).map(|v| v.value().clone())
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Kvpair> + '_> {
+        Box::new(
+            self.0
+                .iter()
+                .map(|v| Kvpair::new(v.key(), v.value().clone())),
+        )
+    }
+
+    fn into_pairs(self: Box<Self>) -> Box<dyn Iterator<Item = Kvpair> + Send> {
+        Box::new(StorageIter::new(self.0.into_iter()))
+    }
+}
+
+fn id(table: &str, key: &str) -> (String, String) {
+    (table.into(), key.into())
+}
+
+fn passed(deadline: Option<i64>, now: i64) -> bool {
+    matches!(deadline, Some(d) if d <= now)
 }
 
 impl Storage for MemTable {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.get(key).map(|v| v.value().clone()))
+        let t = self.get_or_create_table(table);
+        self.reap(&t, table, key);
+        let value = t.get(key).map(|v| v.value().clone());
+        self.ops.read(value.is_some());
+        Ok(value)
     }
 
-    fn set(
-        &self,
-        table: &str,
-        key: impl Into<String>,
-        value: impl Into<Value>,
-    ) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.insert(key.into(), value.into()))
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let t = self.get_or_create_table(table);
+        Ok(self.insert(&t, table, key, value))
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.contains_key(key))
+        let t = self.get_or_create_table(table);
+        self.reap(&t, table, key);
+        Ok(t.contains_key(key))
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.remove(key).map(|(_k, v)| v))
+        let t = self.get_or_create_table(table);
+        Ok(self.remove(&t, table, key))
     }
 
-    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table
+    // 下面三个批量操作都只找一次 table
+    fn mget(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        let t = self.get_or_create_table(table);
+        Ok(keys
             .iter()
+            .map(|key| {
+                self.reap(&t, table, key);
+                let value = t.get(key).map(|v| v.value().clone());
+                self.ops.read(value.is_some());
+                value
+            })
+            .collect())
+    }
+
+    fn mset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+        let t = self.get_or_create_table(table);
+        Ok(pairs
+            .into_iter()
+            .map(|pair| self.insert(&t, table, pair.key, pair.value.unwrap_or_default()))
+            .collect())
+    }
+
+    fn mdel(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        let t = self.get_or_create_table(table);
+        Ok(keys.iter().map(|key| self.remove(&t, table, key)).collect())
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let now = now_ms();
+        let t = self.get_or_create_table(table);
+        Ok(t.iter()
+            .filter(|v| !self.deadline_passed(table, v.key(), now))
             .map(|v| Kvpair::new(v.key(), v.value().clone()))
             .collect())
     }
 
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        // 使用clone()来获取table的snapshot
-        let table = self.get_or_create_table(table).clone();
-        let iter = StorageIter::new(table.into_iter()); // 这行改掉了
-        Ok(Box::new(iter))
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        Ok(self.snapshot(table)?.into_pairs())
+    }
+
+    // 现在还是 clone 整个 DashMap，大的 table 会占用两倍的内存。
+    // 以后换成共享数据的实现时，调用者不需要改
+    fn snapshot(&self, table: &str) -> Result<Box<dyn Snapshot>, KvError> {
+        let t = self.get_or_create_table(table).clone();
+        if self.has_deadlines() {
+            let now = now_ms();
+            t.retain(|k, _| !self.deadline_passed(table, k, now));
+        }
+        Ok(Box::new(MemSnapshot(t)))
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        let t = self.get_or_create_table(table);
+        if !self.has_deadlines() {
+            return Ok(t.len());
+        }
+        let now = now_ms();
+        Ok(t.iter()
+            .filter(|v| !self.deadline_passed(table, v.key(), now))
+            .count())
+    }
+
+    // 和 get_iter 不同，只 clone 匹配的 kv pair，不需要整个 table 的快照
+    fn get_iter_matching(
+        &self,
+        table: &str,
+        pattern: &Glob,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let now = now_ms();
+        let t = self.get_or_create_table(table);
+        let pairs: Vec<Kvpair> = t
+            .iter()
+            .filter(|v| pattern.matches(v.key()))
+            .filter(|v| !self.deadline_passed(table, v.key(), now))
+            .map(|v| Kvpair::new(v.key(), v.value().clone()))
+            .collect();
+        Ok(Box::new(pairs.into_iter()))
+    }
+
+    // DashMap 是无序的，不过只需要 clone after 之后的 kv pair
+    fn scan(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        pattern: Option<&Glob>,
+        count: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let now = now_ms();
+        let t = self.get_or_create_table(table);
+        let pairs = t
+            .iter()
+            .filter(|v| after.is_none_or(|after| v.key().as_str() > after))
+            .filter(|v| pattern.is_none_or(|pattern| pattern.matches(v.key())))
+            .filter(|v| !self.deadline_passed(table, v.key(), now))
+            .map(|v| Kvpair::new(v.key(), v.value().clone()))
+            .collect();
+        Ok(first_keys(pairs, count))
+    }
+
+    // DashMap 是无序的，只复制 range 里的 kv pair，再排序
+    fn get_range(
+        &self,
+        table: &str,
+        range: KeyRange,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let now = now_ms();
+        let t = self.get_or_create_table(table);
+        let mut pairs: Vec<Kvpair> = t
+            .iter()
+            .filter(|v| RangeBounds::<str>::contains(&range, v.key().as_str()))
+            .filter(|v| !self.deadline_passed(table, v.key(), now))
+            .map(|v| Kvpair::new(v.key(), v.value().clone()))
+            .collect();
+        pairs.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        Ok(Box::new(pairs.into_iter()))
+    }
+
+    fn admin(&self, command: &str, _args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        match command {
+            STORAGE_COMMAND => {
+                let keys: usize = self.tables.iter().map(|t| t.len()).sum();
+                Ok(vec![
+                    Kvpair::new("tables", (self.tables.len() as i64).into()),
+                    Kvpair::new("keys", (keys as i64).into()),
+                    Kvpair::new("expiring", (self.expires.len() as i64).into()),
+                ])
+            }
+            MEMORY_COMMAND => Ok(self
+                .memory()
+                .into_iter()
+                .flat_map(|(table, m)| {
+                    [
+                        ("entries", m.entries),
+                        ("key_bytes", m.key_bytes),
+                        ("value_bytes", m.value_bytes),
+                        ("overhead", m.overhead()),
+                    ]
+                    .map(|(name, n)| Kvpair::new(format!("{}.{}", table, name), (n as i64).into()))
+                })
+                .collect()),
+            // 数据都在内存里，没有什么需要刷的
+            FLUSH_COMMAND => Ok(vec![Kvpair::new("flushed", 0.into())]),
+            // 去掉空的 table，释放 DashMap 里多余的容量
+            COMPACT_COMMAND => {
+                let before = self.tables.len();
+                self.tables.retain(|_, t| !t.is_empty());
+                self.memory.retain(|_, m| m.entries > 0);
+                self.tables.iter().for_each(|t| t.shrink_to_fit());
+                self.tables.shrink_to_fit();
+                let removed = before - self.tables.len();
+                Ok(vec![Kvpair::new("removed_tables", (removed as i64).into())])
+            }
+            _ => Err(KvError::Unsupported(format!("admin command {}", command))),
+        }
+    }
+
+    // 直接 clone 整个 DashMap，不需要逐个 key 写入，过期时间也一起复制
+    fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+        check_clone_target(self, src, dst)?;
+        let now = now_ms();
+        let copy = self.get_or_create_table(src).clone();
+        let mut deadlines = Vec::new();
+        if self.has_deadlines() {
+            copy.retain(|k, _| !self.deadline_passed(src, k, now));
+            for e in copy.iter() {
+                if let Some(d) = self.expires.get(&id(src, e.key())) {
+                    deadlines.push((e.key().clone(), *d));
+                }
+            }
+            self.expires.retain(|(t, _), _| t != dst);
+        }
+
+        let n = copy.len();
+        let mut m = MemoryCounter::default();
+        copy.iter()
+            .for_each(|e| m.add(e.key().len(), e.value().encoded_len()));
+        if let Some(metas) = &self.metas {
+            for e in copy.iter() {
+                let meta = metas.get(&id(src, e.key())).map(|m| *m);
+                if let Some(meta) = meta {
+                    metas.insert(id(dst, e.key()), meta);
+                }
+            }
+        }
+        self.tables.insert(dst.into(), copy);
+        self.memory.insert(dst.into(), m);
+        // 换掉了整个 table，之前读到的 version 都不再有效
+        if !self.versions.is_empty() {
+            self.versions.retain(|(t, _), _| t != dst);
+        }
+        for (key, d) in deadlines {
+            self.expires.insert(id(dst, &key), d);
+        }
+        Ok(n)
+    }
+
+    // 读的时候会创建空的 table，所以要跳过它们
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut names: Vec<String> = self
+            .tables
+            .iter()
+            .filter(|t| !t.is_empty())
+            .map(|t| t.key().clone())
+            .collect();
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    // 直接用写入时记录的数字，不需要遍历。过期了还没删掉的 key 也算在里面
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        let mut stats: Vec<TableStats> = self
+            .memory()
+            .into_iter()
+            .filter(|(_, m)| m.entries > 0)
+            .map(|(table, m)| TableStats {
+                table,
+                keys: m.entries,
+                bytes: m.key_bytes + m.value_bytes,
+            })
+            .collect();
+        stats.sort_unstable_by(|a, b| a.table.cmp(&b.table));
+        Ok(stats)
+    }
+
+    // 估算的内存占用，包括 DashMap 的开销
+    fn metrics(&self) -> Result<StorageMetrics, KvError> {
+        let memory: usize = self
+            .memory()
+            .iter()
+            .map(|(_, m)| m.key_bytes + m.value_bytes + m.overhead())
+            .sum();
+        let backend = vec![Kvpair::new("memory.bytes", (memory as i64).into())];
+        Ok(self.ops.metrics(self.stats()?, backend))
+    }
+
+    // 整个 DashMap 从 tables 里拿掉，不需要逐个 key 删除
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        let now = now_ms();
+        let dropped = match self.tables.remove(table) {
+            Some((_, t)) => t,
+            None => return Ok(0),
+        };
+        self.memory.remove(table);
+        if !self.versions.is_empty() {
+            self.versions.retain(|(t, _), _| t != table);
+        }
+        if let Some(metas) = &self.metas {
+            metas.retain(|(t, _), _| t != table);
+        }
+        let mut n = dropped.len();
+        if self.has_deadlines() {
+            // 已经过期的 key 不算在删除的数量里
+            n -= dropped
+                .iter()
+                .filter(|e| self.deadline_passed(table, e.key(), now))
+                .count();
+            self.expires.retain(|(t, _), _| t != table);
+        }
+        Ok(n)
+    }
+
+    // entry 持有这个 key 所在分片的写锁，读和写之间不会有别的修改。
+    // 过期的旧值当作不存在，没有过期的保留原来的过期时间
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let now = now_ms();
+        let t = self.get_or_create_table(table);
+        let (old, n) = match t.entry(key.into()) {
+            Entry::Occupied(mut entry) => {
+                let current = match self.take_expired(table, key, now) {
+                    true => None,
+                    false => Some(entry.get()),
+                };
+                let n = incr_value(key, current, delta)?;
+                self.bump_version(table, key, true);
+                (Some(entry.insert(n.into())), n)
+            }
+            Entry::Vacant(entry) => {
+                let n = incr_value(key, None, delta)?;
+                self.bump_version(table, key, true);
+                entry.insert(n.into());
+                (None, n)
+            }
+        };
+        drop(t);
+        self.account(table, key, old.as_ref(), Some(&n.into()));
+        Ok(n)
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        let now = now_ms();
+        let t = self.get_or_create_table(table);
+        let (old, value) = match t.entry(key.clone()) {
+            Entry::Occupied(mut entry) if self.take_expired(table, &key, now) => {
+                (Some(entry.insert(value.clone())), value)
+            }
+            Entry::Occupied(_) => return Ok(false),
+            Entry::Vacant(entry) => {
+                self.bump_version(table, entry.key(), true);
+                (None, entry.insert(value).clone())
+            }
+        };
+        drop(t);
+        self.account(table, &key, old.as_ref(), Some(&value));
+        Ok(true)
+    }
+
+    // 数据都在内存里，不存在崩溃后留下两份的问题。每次只锁一个 table，
+    // 避免同时持有外层 DashMap 的两个锁；并发的读可能短暂地在两边都看不到这个 key。
+    // key 的过期时间跟着一起移过去
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        check_move(src, dst)?;
+        if !force && self.contains(dst, key)? {
+            return Err(move_conflict(dst, key));
+        }
+        let now = now_ms();
+        let t = self.get_or_create_table(src);
+        let removed = match t.entry(key.into()) {
+            Entry::Occupied(entry) => {
+                let deadline = self.clear_deadline(src, key);
+                self.bump_version(src, key, false);
+                Some((entry.remove(), deadline))
+            }
+            Entry::Vacant(_) => None,
+        };
+        drop(t);
+        let (v, deadline) = match removed {
+            Some((v, deadline)) => {
+                self.account(src, key, Some(&v), None);
+                if passed(deadline, now) {
+                    return Ok(None);
+                }
+                (v, deadline)
+            }
+            None => return Ok(None),
+        };
+
+        let t = self.get_or_create_table(dst);
+        let inserted = match t.entry(key.into()) {
+            // 检查之后又有人写入了 dst
+            Entry::Occupied(_) if !force && !self.take_expired(dst, key, now) => false,
+            Entry::Occupied(mut entry) => {
+                self.clear_deadline(dst, key);
+                self.restore_deadline(dst, key, deadline);
+                self.bump_version(dst, key, true);
+                let old = entry.insert(v.clone());
+                self.account(dst, key, Some(&old), Some(&v));
+                true
+            }
+            Entry::Vacant(entry) => {
+                self.restore_deadline(dst, key, deadline);
+                self.bump_version(dst, key, true);
+                entry.insert(v.clone());
+                self.account(dst, key, None, Some(&v));
+                true
+            }
+        };
+        drop(t);
+
+        if inserted {
+            return Ok(Some(v));
+        }
+        // 把 value 放回去
+        let t = self.get_or_create_table(src);
+        let old = match t.entry(key.into()) {
+            Entry::Occupied(mut entry) => {
+                self.clear_deadline(src, key);
+                self.restore_deadline(src, key, deadline);
+                self.bump_version(src, key, true);
+                Some(entry.insert(v.clone()))
+            }
+            Entry::Vacant(entry) => {
+                self.restore_deadline(src, key, deadline);
+                self.bump_version(src, key, true);
+                entry.insert(v.clone());
+                None
+            }
+        };
+        drop(t);
+        self.account(src, key, old.as_ref(), Some(&v));
+        Err(move_conflict(dst, key))
+    }
+
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        let now = now_ms();
+        let t = self.get_or_create_table(table);
+        let (removed, existed) = match t.entry(key.into()) {
+            Entry::Vacant(_) => return Ok(false),
+            Entry::Occupied(entry) => {
+                if self.take_expired(table, key, now) {
+                    (entry.remove(), false)
+                } else if deadline <= now {
+                    self.clear_deadline(table, key);
+                    self.bump_version(table, key, false);
+                    (entry.remove(), true)
+                } else {
+                    self.expires.insert(id(table, key), deadline);
+                    return Ok(true);
+                }
+            }
+        };
+        drop(t);
+        self.account(table, key, Some(&removed), None);
+        Ok(existed)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let now = now_ms();
+        let t = self.get_or_create_table(table);
+        let removed = match t.entry(key.into()) {
+            Entry::Vacant(_) => return Ok(false),
+            Entry::Occupied(_) if !self.take_expired(table, key, now) => {
+                return Ok(self.clear_deadline(table, key).is_some())
+            }
+            Entry::Occupied(entry) => entry.remove(),
+        };
+        drop(t);
+        self.account(table, key, Some(&removed), None);
+        Ok(false)
+    }
+
+    fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, KvError> {
+        let t = self.get_or_create_table(table);
+        self.reap(&t, table, key);
+        if !t.contains_key(key) {
+            return Err(KvError::NotFound(table.into(), key.into()));
+        }
+        Ok(self.expires.get(&id(table, key)).map(|d| *d))
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let now = now_ms();
+        let expired: Vec<(String, String)> = self
+            .expires
+            .iter()
+            .filter(|e| *e.value() <= now)
+            .map(|e| e.key().clone())
+            .collect();
+
+        let mut purged = 0;
+        for (table, key) in expired {
+            let t = match self.tables.get(&table) {
+                Some(t) => t,
+                None => continue,
+            };
+            if let Some((_, v)) = t.remove_if(&key, |_, _| self.take_expired(&table, &key, now)) {
+                self.account(&table, &key, Some(&v), None);
+                self.notify_expired(&table, &key, &v);
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    fn meta(&self, table: &str, key: &str) -> Result<Option<KeyMeta>, KvError> {
+        let metas = match &self.metas {
+            Some(metas) => metas,
+            None => return Err(KvError::Unsupported(format!("metadata in table {}", table))),
+        };
+        let t = self.get_or_create_table(table);
+        self.reap(&t, table, key);
+        if !t.contains_key(key) {
+            return Ok(None);
+        }
+        Ok(Some(
+            metas.get(&id(table, key)).map(|m| *m).unwrap_or_default(),
+        ))
+    }
+
+    // get_mut 持有 key 所在分片的写锁，和 set_if_version 不会交错
+    fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        let t = self.get_or_create_table(table);
+        self.reap(&t, table, key);
+        let entry = t.get_mut(key);
+        Ok(entry.map(|_| self.version_of(table, key)))
+    }
+
+    // 过期的旧值当作不存在，写入会去掉 key 的过期时间
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        let now = now_ms();
+        let t = self.get_or_create_table(table);
+        let (old, new_version) = match t.entry(key.clone()) {
+            Entry::Occupied(mut entry) => {
+                let current = match self.take_expired(table, &key, now) {
+                    true => 0,
+                    false => self.version_of(table, &key),
+                };
+                if current != version {
+                    return Err(version_conflict(table, &key, version));
+                }
+                self.clear_deadline(table, &key);
+                let old = entry.insert(value.clone());
+                (Some(old), self.record_version(table, &key))
+            }
+            Entry::Vacant(entry) if version == 0 => {
+                entry.insert(value.clone());
+                (None, self.record_version(table, &key))
+            }
+            Entry::Vacant(_) => return Err(version_conflict(table, &key, version)),
+        };
+        drop(t);
+        self.account(table, &key, old.as_ref(), Some(&value));
+        Ok(new_version)
     }
 }
 
@@ -78,6 +890,7 @@ impl From<(String, Value)> for Kvpair {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::StorageExt;
 
     #[test]
     fn get_or_create_table_should_work() {
@@ -86,9 +899,79 @@ mod tests {
         store.get_or_create_table("t1");
         assert!(store.tables.contains_key("t1"));
     }
+
+    #[test]
+    fn memory_should_be_tracked_incrementally() {
+        let store = MemTable::new();
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k1", "value1").unwrap();
+        store.hset("t1", "k2", 42).unwrap();
+        store.hset("t2", "k1", "v1").unwrap();
+        store.del("t2", "k1").unwrap();
+        store.move_key("t1", "t3", "k2", false).unwrap();
+        store.clone_table("t1", "t4").unwrap();
+
+        // 和完整遍历一遍算出来的结果一样
+        for (name, m) in store.memory() {
+            let mut expected = MemoryCounter::default();
+            for pair in store.get_iter(&name).unwrap() {
+                expected.add(pair.key.len(), pair.value.unwrap().encoded_len());
+            }
+            assert_eq!(m, expected.total(), "table {}", name);
+        }
+        let res = store.admin(MEMORY_COMMAND, &[]).unwrap();
+        assert!(res.contains(&Kvpair::new("t1.entries", 1.into())));
+        assert!(res.contains(&Kvpair::new("t2.entries", 0.into())));
+    }
+
+    #[test]
+    fn compact_should_remove_empty_tables() {
+        let store = MemTable::new();
+        store.hset("t1", "k1", "v1").unwrap();
+        store.get("t2", "k1").unwrap();
+
+        let res = store.admin(COMPACT_COMMAND, &[]).unwrap();
+        assert_eq!(res, vec![Kvpair::new("removed_tables", 1.into())]);
+        let res = store.admin(STORAGE_COMMAND, &[]).unwrap();
+        assert_eq!(
+            res,
+            vec![
+                Kvpair::new("tables", 1.into()),
+                Kvpair::new("keys", 1.into()),
+                Kvpair::new("expiring", 0.into())
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_should_survive_racing_set_and_del() {
+        let store = Arc::new(MemTable::new());
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..2000 {
+                        match i % 2 {
+                            0 => store.hset("t1", "k1", "v1").map(|_| ()).unwrap(),
+                            _ => store.del("t1", "k1").map(|_| ()).unwrap(),
+                        }
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        store.del("t1", "k1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+        let (_, m) = store.memory().into_iter().find(|(t, _)| t == "t1").unwrap();
+        assert_eq!(m.entries, 1);
+        assert_eq!(m.key_bytes, 2);
+    }
 }
 
-// fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+// fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
 //     // 使用clone()来获取table的snapshot
 //     let table = self.get_or_create_table(table).clone();
 //     // 版本一:
@@ -109,4 +992,4 @@ mod tests {
 //     // 版本三:
 //     let iter = StorageIter::new(table.into_iter()); // 这行改掉了
 //     Ok(Box::new(iter))
-// }
+//
@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use prost::Message;
+use tracing::warn;
+
+use crate::{KvError, Storage, Value};
+
+/// 树的深度，叶子的数量是 2^DEPTH
+const DEPTH: usize = 8;
+const LEAVES: usize = 1 << DEPTH;
+
+/// 一个 table 的 Merkle tree
+///
+/// key 按 hash 分到 LEAVES 个叶子里，叶子的 hash 由其中每个 key 的 hash 算出，
+/// 上层的节点再由两个子节点算出。两个副本比较时从根往下走，只进入 hash 不同的
+/// 子树，最后比较叶子里每个 key 的 hash，就能找到不一致的 key，而不需要比较整个 table。
+/// hash 用 FNV-1a，value 用 protobuf 编码后参与计算，不同的进程之间结果是稳定的。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    // 堆的布局：1 是根，i 的子节点是 2i 和 2i + 1，叶子从 LEAVES 开始
+    nodes: Vec<u64>,
+    leaves: Vec<BTreeMap<String, u64>>,
+}
+
+impl MerkleTree {
+    pub fn build(store: &impl Storage, table: &str) -> Result<Self, KvError> {
+        let mut leaves = vec![BTreeMap::new(); LEAVES];
+        for pair in store.get_iter(table)? {
+            let value = pair.value.unwrap_or_default().encode_to_vec();
+            let hash = fnv(fnv(FNV_OFFSET, pair.key.as_bytes()), &value);
+            leaves[leaf_of(&pair.key)].insert(pair.key, hash);
+        }
+
+        let mut nodes = vec![0; 2 * LEAVES];
+        for (i, leaf) in leaves.iter().enumerate() {
+            nodes[LEAVES + i] = leaf.iter().fold(FNV_OFFSET, |h, (k, v)| {
+                fnv(fnv(h, k.as_bytes()), &v.to_be_bytes())
+            });
+        }
+        for i in (1..LEAVES).rev() {
+            let h = fnv(FNV_OFFSET, &nodes[2 * i].to_be_bytes());
+            nodes[i] = fnv(h, &nodes[2 * i + 1].to_be_bytes());
+        }
+        Ok(Self { nodes, leaves })
+    }
+
+    pub fn root(&self) -> u64 {
+        self.nodes[1]
+    }
+
+    /// 找出两棵树之间不一致的 key，包括只在其中一边的 key
+    pub fn diff(&self, other: &MerkleTree) -> Vec<String> {
+        let mut keys = Vec::new();
+        let mut stack = vec![1];
+        while let Some(i) = stack.pop() {
+            if self.nodes[i] == other.nodes[i] {
+                continue;
+            }
+            if i < LEAVES {
+                stack.extend([2 * i, 2 * i + 1]);
+                continue;
+            }
+            let (a, b) = (&self.leaves[i - LEAVES], &other.leaves[i - LEAVES]);
+            keys.extend(
+                a.iter()
+                    .filter(|(k, v)| b.get(*k) != Some(v))
+                    .map(|(k, _)| k),
+            );
+            keys.extend(b.keys().filter(|k| !a.contains_key(*k)));
+        }
+        keys.into_iter().cloned().collect()
+    }
+}
+
+/// 用 Merkle tree 找出 target 和 source 不一致的 key，只把这些 key 从 source 复制过去。
+/// 返回修复的 key 的数量
+pub fn anti_entropy(
+    source: &impl Storage,
+    target: &impl Storage,
+    table: &str,
+) -> Result<usize, KvError> {
+    let keys = MerkleTree::build(source, table)?.diff(&MerkleTree::build(target, table)?);
+    for key in &keys {
+        match source.get(table, key)? {
+            Some(v) => target.set(table, key.clone(), v)?,
+            None => target.del(table, key)?,
+        };
+    }
+    Ok(keys.len())
+}
+
+/// 在后台定期做 anti-entropy 修复，drop 的时候停止
+pub struct AntiEntropy {
+    _stop: Sender<()>,
+    repaired: Arc<AtomicU64>,
+}
+
+impl AntiEntropy {
+    pub fn spawn<S, T>(
+        source: S,
+        target: T,
+        tables: Vec<String>,
+        interval: Duration,
+    ) -> Result<Self, KvError>
+    where
+        S: Storage + Send + 'static,
+        T: Storage + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel::<()>();
+        let repaired = Arc::new(AtomicU64::new(0));
+
+        let counter = repaired.clone();
+        thread::Builder::new()
+            .name("kv-anti-entropy".into())
+            .spawn(move || {
+                // 只有超时才继续，AntiEntropy 被 drop 时 channel 断开，线程退出
+                while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                    for table in &tables {
+                        match anti_entropy(&source, &target, table) {
+                            Ok(n) => counter.fetch_add(n as u64, Ordering::Relaxed),
+                            Err(e) => {
+                                warn!("Anti-entropy on {} failed: {}", table, e);
+                                continue;
+                            }
+                        };
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            _stop: tx,
+            repaired,
+        })
+    }
+
+    /// 到目前为止修复的 key 的数量
+    pub fn repaired(&self) -> u64 {
+        self.repaired.load(Ordering::Relaxed)
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+fn fnv(mut hash: u64, data: &[u8]) -> u64 {
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// value 的稳定 hash
+pub(crate) fn value_hash(value: &Value) -> u64 {
+    fnv(FNV_OFFSET, &value.encode_to_vec())
+}
+
+fn leaf_of(key: &str) -> usize {
+    fnv(FNV_OFFSET, key.as_bytes()) as usize % LEAVES
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, StorageExt};
+
+    fn store_with(n: usize) -> MemTable {
+        let store = MemTable::new();
+        for i in 0..n {
+            store.hset("t1", format!("k{}", i), i as i64).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn merkle_tree_should_find_divergent_keys() {
+        let a = store_with(1000);
+        let b = store_with(1000);
+        assert_eq!(
+            MerkleTree::build(&a, "t1").unwrap(),
+            MerkleTree::build(&b, "t1").unwrap()
+        );
+
+        b.hset("t1", "k1", "changed").unwrap();
+        b.del("t1", "k2").unwrap();
+        b.hset("t1", "extra", 1).unwrap();
+        let ta = MerkleTree::build(&a, "t1").unwrap();
+        let tb = MerkleTree::build(&b, "t1").unwrap();
+        assert_ne!(ta.root(), tb.root());
+
+        let mut keys = ta.diff(&tb);
+        keys.sort();
+        assert_eq!(keys, vec!["extra", "k1", "k2"]);
+    }
+
+    #[test]
+    fn anti_entropy_should_repair_target() {
+        let a = store_with(100);
+        let b = store_with(90);
+        b.hset("t1", "extra", 1).unwrap();
+
+        assert_eq!(anti_entropy(&a, &b, "t1").unwrap(), 11);
+        assert_eq!(anti_entropy(&a, &b, "t1").unwrap(), 0);
+        assert!(!b.contains("t1", "extra").unwrap());
+    }
+
+    #[test]
+    fn anti_entropy_should_run_in_background() {
+        let a = Arc::new(store_with(10));
+        let b = Arc::new(MemTable::new());
+        let task =
+            AntiEntropy::spawn(a, b.clone(), vec!["t1".into()], Duration::from_millis(10)).unwrap();
+
+        for _ in 0..100 {
+            if task.repaired() == 10 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(task.repaired(), 10);
+        assert_eq!(b.get_all("t1").unwrap().len(), 10);
+    }
+}
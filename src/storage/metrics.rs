@@ -0,0 +1,175 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{value, Kvpair, TableStats};
+
+/// backend 运行时的统计，Storage::metrics 返回它
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StorageMetrics {
+    /// 按 key 读的次数，其中 hits 次读到了 value
+    pub reads: u64,
+    pub hits: u64,
+    pub writes: u64,
+    pub deletes: u64,
+    /// 每个 table 的 key 的数量和大小
+    pub tables: Vec<TableStats>,
+    /// backend 自己的统计，比如 sled 在磁盘上的大小，value 都是整数
+    pub backend: Vec<Kvpair>,
+}
+
+impl StorageMetrics {
+    pub fn misses(&self) -> u64 {
+        self.reads - self.hits
+    }
+
+    /// 读到 value 的比例，还没有读过时是 0
+    pub fn hit_rate(&self) -> f64 {
+        match self.reads {
+            0 => 0.0,
+            n => self.hits as f64 / n as f64,
+        }
+    }
+
+    /// INFO 返回的 kv pair，都以 storage. 开头
+    pub(crate) fn pairs(&self) -> Vec<Kvpair> {
+        let keys: usize = self.tables.iter().map(|s| s.keys).sum();
+        let mut pairs = vec![
+            Kvpair::new("storage.reads", (self.reads as i64).into()),
+            Kvpair::new("storage.hits", (self.hits as i64).into()),
+            Kvpair::new("storage.misses", (self.misses() as i64).into()),
+            Kvpair::new("storage.hit_rate", self.hit_rate().into()),
+            Kvpair::new("storage.writes", (self.writes as i64).into()),
+            Kvpair::new("storage.deletes", (self.deletes as i64).into()),
+            Kvpair::new("storage.tables", (self.tables.len() as i64).into()),
+            Kvpair::new("storage.keys", (keys as i64).into()),
+        ];
+        for pair in &self.backend {
+            let name = format!("storage.{}", pair.key);
+            pairs.push(Kvpair::new(name, pair.value.clone().unwrap_or_default()));
+        }
+        pairs
+    }
+}
+
+/// 把 INFO 的 kv pair 和每个 table 的统计写成 Prometheus 的文本格式。
+/// 名字里的 . 换成 _，加上 kv_ 前缀；不是数字的 pair（比如 version）跳过
+pub fn prometheus_text(info: &[Kvpair], tables: &[TableStats]) -> String {
+    let mut text = String::new();
+    for pair in info {
+        let n = match pair.value.as_ref().and_then(|v| v.value.as_ref()) {
+            Some(value::Value::Integer(n)) => *n as f64,
+            Some(value::Value::Float(f)) => *f,
+            _ => continue,
+        };
+        let name = format!("kv_{}", pair.key.replace(['.', '-'], "_"));
+        writeln!(text, "# TYPE {} gauge\n{} {}", name, name, n).unwrap();
+    }
+    if tables.is_empty() {
+        return text;
+    }
+    text.push_str("# TYPE kv_table_keys gauge\n");
+    for s in tables {
+        writeln!(
+            text,
+            "kv_table_keys{{table=\"{}\"}} {}",
+            label(&s.table),
+            s.keys
+        )
+        .unwrap();
+    }
+    text.push_str("# TYPE kv_table_bytes gauge\n");
+    for s in tables {
+        writeln!(
+            text,
+            "kv_table_bytes{{table=\"{}\"}} {}",
+            label(&s.table),
+            s.bytes
+        )
+        .unwrap();
+    }
+    text
+}
+
+// label 的值里 \ 和 " 要转义
+fn label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// backend 在 get、set、del 里更新的计数
+#[derive(Debug, Default)]
+pub(crate) struct OpCounters {
+    reads: AtomicU64,
+    hits: AtomicU64,
+    writes: AtomicU64,
+    deletes: AtomicU64,
+}
+
+impl OpCounters {
+    pub fn read(&self, hit: bool) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn write(&self, n: usize) {
+        self.writes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn delete(&self, n: usize) {
+        self.deletes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn metrics(&self, tables: Vec<TableStats>, backend: Vec<Kvpair>) -> StorageMetrics {
+        StorageMetrics {
+            reads: self.reads.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            tables,
+            backend,
+        }
+    }
+}
+
+// clone 出来的 backend 从现在的计数接着算
+impl Clone for OpCounters {
+    fn clone(&self) -> Self {
+        let load = |n: &AtomicU64| AtomicU64::new(n.load(Ordering::Relaxed));
+        Self {
+            reads: load(&self.reads),
+            hits: load(&self.hits),
+            writes: load(&self.writes),
+            deletes: load(&self.deletes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus_text_should_work() {
+        let counters = OpCounters::default();
+        counters.read(true);
+        counters.read(false);
+        counters.write(1);
+        let tables = vec![TableStats {
+            table: "t\"1".into(),
+            keys: 1,
+            bytes: 4,
+        }];
+        let metrics = counters.metrics(tables.clone(), vec![]);
+        assert_eq!(metrics.misses(), 1);
+        assert_eq!(metrics.hit_rate(), 0.5);
+
+        let mut info = vec![Kvpair::new("version", "0.1.1".into())];
+        info.extend(metrics.pairs());
+        let text = prometheus_text(&info, &tables);
+        assert!(!text.contains("kv_version "));
+        assert!(text.contains("# TYPE kv_storage_reads gauge\nkv_storage_reads 2\n"));
+        assert!(text.contains("kv_storage_hit_rate 0.5\n"));
+        assert!(text.contains("kv_table_keys{table=\"t\\\"1\"} 1\n"));
+    }
+}
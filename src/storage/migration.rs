@@ -0,0 +1,596 @@
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tracing::warn;
+
+use super::backup::{deadline_of, put_batch};
+use crate::{
+    Glob, KeyMeta, KeyRange, KvError, Kvpair, Order, Snapshot, Storage, StorageMetrics, TableStats,
+    Value,
+};
+
+/// MigrationStore 的 admin 命令，返回各项不一致的计数
+pub const DIVERGENCE_COMMAND: &str = "divergence";
+
+/// migrate 每次从 src 读出、用 mset 写到 dst 的 key 的数量
+pub const MIGRATE_BATCH: usize = 1024;
+
+/// migrate 复制到的位置：table 之前的 table 都复制完了，table 里不大于 key 的也复制完了。
+/// 从它继续时接着往后复制
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrateCheckpoint {
+    pub table: String,
+    pub key: Option<String>,
+}
+
+/// 每复制完一批报告一次的进度
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrateProgress {
+    /// 复制完的 table 和 table 的总数
+    pub tables_done: usize,
+    pub tables: usize,
+    /// 这一次 migrate 复制的 key 的数量，不包括 resume 之前复制的
+    pub keys: u64,
+    pub checkpoint: MigrateCheckpoint,
+}
+
+/// 把 src 的所有 table 复制到 dst，value 和过期时间都复制，返回复制的 key 的数量
+///
+/// table 按名字、key 按顺序通过 get_range 读，每 MIGRATE_BATCH 个 key 一批写入，
+/// 写完之后调用 progress，其中的 checkpoint 可以保存下来，中断之后作为 resume
+/// 从这里继续。dst 里已经有的 key 被覆盖，src 里没有的 key 保留。
+/// 内存里最多有一批 key，但 get_range 没有按顺序遍历的 backend 会读出整个 table 来排序
+pub fn migrate<S: Storage + ?Sized, D: Storage + ?Sized>(
+    src: &S,
+    dst: &D,
+    resume: Option<MigrateCheckpoint>,
+    mut progress: impl FnMut(&MigrateProgress),
+) -> Result<u64, KvError> {
+    let mut tables = src.list_tables()?;
+    tables.sort();
+    let resume = resume.unwrap_or_default();
+    let mut state = MigrateProgress {
+        tables: tables.len(),
+        ..Default::default()
+    };
+    for table in tables {
+        if table < resume.table {
+            state.tables_done += 1;
+            continue;
+        }
+        let lower = match &resume.key {
+            Some(key) if table == resume.table => Bound::Excluded(key.as_str()),
+            _ => Bound::Unbounded,
+        };
+        let mut pairs = src.get_range(&table, (lower, Bound::Unbounded))?;
+        loop {
+            let batch = pairs
+                .by_ref()
+                .take(MIGRATE_BATCH)
+                .map(|p| deadline_of(src, &table, &p.key).map(|d| (p, d)))
+                .collect::<Result<Vec<_>, KvError>>()?;
+            let last = match batch.last() {
+                Some((p, _)) => p.key.clone(),
+                None => break,
+            };
+            state.keys += batch.len() as u64;
+            put_batch(dst, &table, batch)?;
+            state.checkpoint = MigrateCheckpoint {
+                table: table.clone(),
+                key: Some(last),
+            };
+            progress(&state);
+        }
+        state.tables_done += 1;
+    }
+    Ok(state.keys)
+}
+
+/// 读请求优先使用哪一个 backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadPreference {
+    Old,
+    New,
+}
+
+/// 迁移期间两个 backend 之间的不一致
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Divergence {
+    /// 一边有、另一边没有的 key
+    pub missing: u64,
+    /// 两边都有，但 value 不一样的 key
+    pub mismatched: u64,
+    /// 只在其中一个 backend 上出错的操作
+    pub errors: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    missing: AtomicU64,
+    mismatched: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// 在两个 backend 之间做不停机迁移的 Storage
+///
+/// 写操作同时写到 old 和 new，读操作先读 prefer 指定的 backend，没找到或者出错时
+/// 再读另一个。迁移时先用 ReadPreference::Old 双写，把存量数据导到 new 之后
+/// 切换成 ReadPreference::New，确认没有问题再去掉 old。
+///
+/// 双写时两边返回的旧值可以顺便比较出不一致，读的时候走到 fallback 也说明 primary
+/// 缺数据，这些都记在 Divergence 里，可以通过 admin 命令 `divergence` 查看。
+/// get_all / get_iter 只读 primary，出错时才读另一个。
+pub struct MigrationStore<O, N> {
+    old: O,
+    new: N,
+    prefer: ReadPreference,
+    counters: Counters,
+}
+
+impl<O: Storage, N: Storage> MigrationStore<O, N> {
+    pub fn new(old: O, new: N, prefer: ReadPreference) -> Self {
+        Self {
+            old,
+            new,
+            prefer,
+            counters: Counters::default(),
+        }
+    }
+
+    pub fn divergence(&self) -> Divergence {
+        let c = &self.counters;
+        Divergence {
+            missing: c.missing.load(Ordering::Relaxed),
+            mismatched: c.mismatched.load(Ordering::Relaxed),
+            errors: c.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    fn read<T>(
+        &self,
+        old: impl FnOnce() -> Result<Option<T>, KvError>,
+        new: impl FnOnce() -> Result<Option<T>, KvError>,
+    ) -> Result<Option<T>, KvError> {
+        match self.prefer {
+            ReadPreference::Old => self.fallback(old, new),
+            ReadPreference::New => self.fallback(new, old),
+        }
+    }
+
+    fn fallback<T>(
+        &self,
+        primary: impl FnOnce() -> Result<Option<T>, KvError>,
+        secondary: impl FnOnce() -> Result<Option<T>, KvError>,
+    ) -> Result<Option<T>, KvError> {
+        match primary() {
+            Ok(Some(v)) => Ok(Some(v)),
+            Ok(None) => {
+                let v = secondary()?;
+                if v.is_some() {
+                    self.counters.missing.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(v)
+            }
+            Err(e) => {
+                warn!("Primary read failed, fallback: {}", e);
+                self.counters.errors.fetch_add(1, Ordering::Relaxed);
+                secondary()
+            }
+        }
+    }
+
+    /// 按 prefer 排列的 (primary, secondary)
+    fn sides(&self) -> (&dyn Storage, &dyn Storage) {
+        match self.prefer {
+            ReadPreference::Old => (&self.old, &self.new),
+            ReadPreference::New => (&self.new, &self.old),
+        }
+    }
+
+    // 别的读操作只读 primary，出错时才读 secondary
+    fn read_either<T>(&self, f: impl Fn(&dyn Storage) -> Result<T, KvError>) -> Result<T, KvError> {
+        let (primary, secondary) = self.sides();
+        self.fallback(|| f(primary).map(Some), || f(secondary).map(Some))
+            .map(|v| v.expect("both sides are read"))
+    }
+
+    // 别的写操作两边都执行，不比较结果，只记录在一边出错的次数
+    fn write_both<T>(&self, f: impl Fn(&dyn Storage) -> Result<T, KvError>) -> Result<T, KvError> {
+        let (primary, secondary) = self.sides();
+        let (primary, secondary) = (f(primary), f(secondary));
+        match (&primary, &secondary) {
+            (Ok(_), Err(e)) | (Err(e), Ok(_)) => {
+                warn!("Migration write failed on one side: {}", e);
+                self.counters.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+        primary
+    }
+
+    // 比较两边的结果，返回 primary 的结果。只有 primary 出错时才把错误返回给调用者
+    fn write(
+        &self,
+        old: Result<Option<Value>, KvError>,
+        new: Result<Option<Value>, KvError>,
+    ) -> Result<Option<Value>, KvError> {
+        let (primary, secondary) = match self.prefer {
+            ReadPreference::Old => (old, new),
+            ReadPreference::New => (new, old),
+        };
+        let counter = match (&primary, &secondary) {
+            (Ok(a), Ok(b)) if a == b => None,
+            (Ok(Some(_)), Ok(Some(_))) => Some(&self.counters.mismatched),
+            (Ok(_), Ok(_)) => Some(&self.counters.missing),
+            (Err(_), Err(_)) => None,
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("Migration write failed on one side: {}", e);
+                Some(&self.counters.errors)
+            }
+        };
+        if let Some(counter) = counter {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        primary
+    }
+}
+
+impl<O: Storage, N: Storage> Storage for MigrationStore<O, N> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.read(|| self.old.get(table, key), || self.new.get(table, key))
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let old = self.old.set(table, key.clone(), value.clone());
+        let new = self.new.set(table, key, value);
+        self.write(old, new)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let found = |r: Result<bool, KvError>| r.map(|b| b.then_some(()));
+        let v = self.read(
+            || found(self.old.contains(table, key)),
+            || found(self.new.contains(table, key)),
+        )?;
+        Ok(v.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let old = self.old.del(table, key);
+        let new = self.new.del(table, key);
+        self.write(old, new)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let v = self.read(
+            || self.old.get_all(table).map(Some),
+            || self.new.get_all(table).map(Some),
+        )?;
+        Ok(v.unwrap_or_default())
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let v = self.read(
+            || self.old.get_iter(table).map(Some),
+            || self.new.get_iter(table).map(Some),
+        )?;
+        Ok(v.unwrap_or_else(|| Box::new(std::iter::empty())))
+    }
+
+    fn admin(&self, command: &str, args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        // 其它的命令交给 primary
+        if command != DIVERGENCE_COMMAND {
+            return match self.prefer {
+                ReadPreference::Old => self.old.admin(command, args),
+                ReadPreference::New => self.new.admin(command, args),
+            };
+        }
+        let d = self.divergence();
+        Ok(vec![
+            Kvpair::new("missing", (d.missing as i64).into()),
+            Kvpair::new("mismatched", (d.mismatched as i64).into()),
+            Kvpair::new("errors", (d.errors as i64).into()),
+        ])
+    }
+
+    fn get_iter_ordered(
+        &self,
+        table: &str,
+        order: Order,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.read_either(|s| s.get_iter_ordered(table, order))
+    }
+
+    fn snapshot(&self, table: &str) -> Result<Box<dyn Snapshot>, KvError> {
+        self.read_either(|s| s.snapshot(table))
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        self.read_either(|s| s.len(table))
+    }
+
+    fn get_iter_matching(
+        &self,
+        table: &str,
+        pattern: &Glob,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.read_either(|s| s.get_iter_matching(table, pattern))
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        pattern: Option<&Glob>,
+        count: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.read_either(|s| s.scan(table, after, pattern, count))
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        range: KeyRange,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.read_either(|s| s.get_range(table, range))
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<(u64, Value)>, KvError> {
+        self.read_either(|s| s.history(table, key))
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.read_either(|s| s.list_tables())
+    }
+
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        self.read_either(|s| s.stats())
+    }
+
+    fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, KvError> {
+        self.read_either(|s| s.deadline(table, key))
+    }
+
+    fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        self.read_either(|s| s.version(table, key))
+    }
+
+    fn meta(&self, table: &str, key: &str) -> Result<Option<KeyMeta>, KvError> {
+        self.read_either(|s| s.meta(table, key))
+    }
+
+    fn query(
+        &self,
+        table: &str,
+        index: &str,
+        value: &Value,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.read_either(|s| s.query(table, index, value, limit))
+    }
+
+    fn export(&self, w: &mut dyn std::io::Write) -> Result<usize, KvError> {
+        self.sides().0.export(w)
+    }
+
+    fn metrics(&self) -> Result<StorageMetrics, KvError> {
+        self.read_either(|s| s.metrics())
+    }
+
+    fn mset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+        self.write_both(|s| s.mset(table, pairs.clone()))
+    }
+
+    fn mdel(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        self.write_both(|s| s.mdel(table, keys))
+    }
+
+    fn undelete(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let old = self.old.undelete(table, key);
+        let new = self.new.undelete(table, key);
+        self.write(old, new)
+    }
+
+    fn purge_trash(&self, table: &str, all: bool) -> Result<usize, KvError> {
+        self.write_both(|s| s.purge_trash(table, all))
+    }
+
+    fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+        self.write_both(|s| s.clone_table(src, dst))
+    }
+
+    fn flush_all(&self) -> Result<usize, KvError> {
+        self.write_both(|s| s.flush_all())
+    }
+
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        self.write_both(|s| s.drop_table(table))
+    }
+
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        let old = self.old.move_key(src, dst, key, force);
+        let new = self.new.move_key(src, dst, key, force);
+        self.write(old, new)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        // 新的值也当作 value 比较，两边不一样说明之前就不一致
+        let old = self.old.incr(table, key, delta).map(|n| Some(n.into()));
+        let new = self.new.incr(table, key, delta).map(|n| Some(n.into()));
+        self.write(old, new)?.map_or(Ok(0), TryInto::try_into)
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        self.write_both(|s| s.set_nx(table, key.clone(), value.clone()))
+    }
+
+    // 两边的 version 是各自的，secondary 上不检查 version，直接写入
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        let (primary, secondary) = self.sides();
+        let version = primary.set_if_version(table, key.clone(), value.clone(), version)?;
+        if let Err(e) = secondary.set(table, key, value) {
+            warn!("Migration write failed on one side: {}", e);
+            self.counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(version)
+    }
+
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        self.write_both(|s| s.expire_at(table, key, deadline))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.write_both(|s| s.persist(table, key))
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.write_both(|s| s.purge_expired())
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.write_both(|s| s.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CommandRequest, MemTable, Service, ServiceInner, SledDb, StorageExt, COMPACT_COMMAND,
+    };
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn migrate_should_copy_in_batches_and_resume() {
+        let src = MemTable::new();
+        for i in 0..MIGRATE_BATCH + 10 {
+            src.hset("t1", format!("k{:05}", i), i as i64).unwrap();
+        }
+        src.hset("t2", "k1", "v1").unwrap();
+        src.expire_at("t2", "k1", i64::MAX).unwrap();
+
+        // 复制完第一批之后中断
+        let dst = SledDb::new(tempdir().unwrap()).unwrap();
+        let half = MemTable::new();
+        let mut checkpoints = Vec::new();
+        let all = (Bound::Unbounded, Bound::Unbounded);
+        for pair in src.get_range("t1", all).unwrap().take(MIGRATE_BATCH) {
+            half.set("t1", pair.key, pair.value.unwrap()).unwrap();
+        }
+        migrate(&half, &dst, None, |p| checkpoints.push(p.clone())).unwrap();
+        assert_eq!(checkpoints.len(), 1);
+        assert_eq!(checkpoints[0].keys, MIGRATE_BATCH as u64);
+
+        let resume = checkpoints.pop().unwrap().checkpoint;
+        assert_eq!(resume.key, Some(format!("k{:05}", MIGRATE_BATCH - 1)));
+        let mut last = MigrateProgress::default();
+        let n = migrate(&src, &dst, Some(resume), |p| last = p.clone()).unwrap();
+        assert_eq!(n, 11);
+        assert_eq!((last.tables_done, last.tables), (1, 2));
+        assert_eq!(dst.len("t1").unwrap(), MIGRATE_BATCH + 10);
+        assert_eq!(dst.get("t2", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(dst.deadline("t2", "k1").unwrap(), Some(i64::MAX));
+    }
+
+    #[test]
+    fn migration_store_should_write_both() {
+        let old = Arc::new(MemTable::new());
+        let new = Arc::new(MemTable::new());
+        let store = MigrationStore::new(old.clone(), new.clone(), ReadPreference::Old);
+
+        store.hset("t1", "k1", "v1").unwrap();
+        assert_eq!(old.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(new.get("t1", "k1").unwrap(), Some("v1".into()));
+
+        assert_eq!(store.del("t1", "k1").unwrap(), Some("v1".into()));
+        assert!(!old.contains("t1", "k1").unwrap());
+        assert!(!new.contains("t1", "k1").unwrap());
+        assert_eq!(store.divergence(), Divergence::default());
+    }
+
+    #[test]
+    fn migration_store_should_fallback_and_count_divergence() {
+        let old = Arc::new(MemTable::new());
+        let new = Arc::new(MemTable::new());
+        // 迁移之前就在 old 里的数据
+        old.hset("t1", "k1", "v1").unwrap();
+        old.hset("t1", "k2", "v2").unwrap();
+        new.hset("t1", "k2", "stale").unwrap();
+
+        let store = MigrationStore::new(old, new.clone(), ReadPreference::New);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert!(store.contains("t1", "k1").unwrap());
+        assert_eq!(store.divergence().missing, 2);
+
+        // 以 new 为准，返回 new 上的旧值
+        assert_eq!(store.hset("t1", "k2", "v3").unwrap(), Some("stale".into()));
+        assert_eq!(store.divergence().mismatched, 1);
+        assert_eq!(new.get("t1", "k2").unwrap(), Some("v3".into()));
+    }
+
+    #[test]
+    fn divergence_should_be_reported_by_admin_command() {
+        let old = MemTable::new();
+        old.hset("t1", "k1", "v1").unwrap();
+        let store = MigrationStore::new(old, MemTable::new(), ReadPreference::New);
+        let service: Service<_> = ServiceInner::new(store).into();
+
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_eq!(res.values, vec!["v1".into()]);
+
+        let res = service.execute(CommandRequest::new_admin(DIVERGENCE_COMMAND, vec![]));
+        assert_eq!(res.status, 200);
+        assert_eq!(
+            res.pairs,
+            vec![
+                Kvpair::new("missing", 1.into()),
+                Kvpair::new("mismatched", 0.into()),
+                Kvpair::new("errors", 0.into()),
+            ]
+        );
+
+        // 其它的命令交给 primary
+        let res = service.execute(CommandRequest::new_admin(COMPACT_COMMAND, vec![]));
+        assert_eq!(res.status, 200);
+    }
+
+    #[test]
+    fn migration_store_should_write_both_for_other_commands() {
+        let old = Arc::new(MemTable::new());
+        let new = Arc::new(MemTable::new());
+        let store = MigrationStore::new(old.clone(), new.clone(), ReadPreference::Old);
+
+        assert_eq!(store.incr("t1", "n", 2).unwrap(), 2);
+        assert!(store.set_nx("t1", "k1".into(), "v1".into()).unwrap());
+        assert!(store.expire_at("t1", "k1", i64::MAX).unwrap());
+        assert_eq!(store.len("t1").unwrap(), 2);
+        assert_eq!(store.list_tables().unwrap(), vec!["t1".to_string()]);
+        for s in [&old, &new] {
+            assert_eq!(s.get("t1", "n").unwrap(), Some(2.into()));
+            assert_eq!(s.deadline("t1", "k1").unwrap(), Some(i64::MAX));
+        }
+
+        // version 各自独立，secondary 上直接写入
+        let version = store.version("t1", "k1").unwrap().unwrap();
+        store
+            .set_if_version("t1", "k1".into(), "v2".into(), version)
+            .unwrap();
+        assert_eq!(new.get("t1", "k1").unwrap(), Some("v2".into()));
+        assert_eq!(store.drop_table("t1").unwrap(), 2);
+        assert_eq!(new.len("t1").unwrap(), 0);
+        assert_eq!(store.divergence(), Divergence::default());
+    }
+}
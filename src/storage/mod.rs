@@ -1,31 +1,977 @@
+#[macro_use]
+mod forward;
+
+mod async_storage;
+mod backup;
+mod bitcask;
+mod bloom;
+mod cache;
+mod changefeed;
+mod codec;
+mod dump;
+mod encrypted;
+mod expiry;
+mod glob;
+mod history;
+mod index;
+mod lazy_free;
+#[cfg(feature = "lmdb")]
+mod lmdb;
+mod lru;
 mod memory;
+mod merkle;
+mod metrics;
+mod migration;
+mod object;
+mod remote;
+mod shadow;
+mod sharded;
 mod sleddb;
+mod snapshot;
+mod staged;
+mod table_ttl;
+mod throttle;
+mod tiered;
+mod timer;
+mod trash;
+mod verify;
+mod wal;
 
 use crate::{KvError, Kvpair, Value};
-pub use memory::MemTable;
-pub use sleddb::SledDb;
+pub use async_storage::{AsyncStorage, PooledStorage};
+pub use backup::{check_backup, BACKUP_FORMAT_VERSION};
+pub use bitcask::{BitcaskStore, BITCASK_SEGMENT_SIZE};
+pub use cache::{ReadThroughCache, HOT_KEYS_TABLE};
+pub use changefeed::{Changefeed, KeyEvent, KeyEventKind};
+pub use codec::Compression;
+#[cfg(any(test, feature = "axum"))]
+pub(crate) use dump::json_to_value;
+pub use dump::{dump, load, DumpFormat};
+pub use encrypted::{Cipher, EncryptedStore};
+pub(crate) use expiry::now_ms;
+pub use expiry::Sweeper;
+pub use glob::Glob;
+pub use history::{VersionedStore, HISTORY_PREFIX};
+pub use index::{IndexField, IndexedStore, INDEX_PREFIX};
+pub use lazy_free::{free_lazily, lazy_free, LAZY_FREE_LIMIT};
+#[cfg(feature = "lmdb")]
+pub use lmdb::{LmdbStore, LMDB_MAP_SIZE};
+pub use lru::{Eviction, LruConfig, LruMemTable};
+pub use memory::{MemTable, TableMemory, MEMORY_COMMAND};
+pub use merkle::{anti_entropy, AntiEntropy, MerkleTree};
+pub use metrics::{prometheus_text, StorageMetrics};
+pub use migration::{
+    migrate, Divergence, MigrateCheckpoint, MigrateProgress, MigrationStore, ReadPreference,
+    DIVERGENCE_COMMAND, MIGRATE_BATCH,
+};
+#[cfg(feature = "s3")]
+pub use object::S3ObjectStore;
+pub use object::{FsObjectStore, ObjectStorage, ObjectStore};
+use prost::Message;
+pub use remote::{DelegatingStore, RemoteStore};
+pub use shadow::{ShadowStats, ShadowStore};
+pub use sharded::{ShardedMemTable, MEMTABLE_SHARDS};
+pub use sleddb::{SledDb, SledDbBuilder, SledMode};
+pub use snapshot::{PairsSnapshot, Snapshot};
+pub(crate) use staged::Staged;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::io::{Read, Write};
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+pub use table_ttl::{TablePolicy, TableTtlStore};
+pub use throttle::{ThrottleConfig, ThrottleStats, WriteThrottle, THROTTLE_COMMAND};
+pub use tiered::{TieredStore, WritePolicy};
+pub use timer::TimerWheel;
+pub use trash::{SoftDeleteStore, TRASH_PREFIX};
+pub use verify::{verify, DiffEntry, Difference, VerifyReport};
+pub use wal::DurableMemTable;
+
+/// backend 的 admin 命令：报告存储占用的空间和 key 的数量
+pub const STORAGE_COMMAND: &str = "storage";
+/// backend 的 admin 命令：把还在内存里的写操作刷到磁盘
+pub const FLUSH_COMMAND: &str = "flush";
+/// backend 的 admin 命令：整理存储，回收已经删除的数据占用的空间
+pub const COMPACT_COMMAND: &str = "compact";
+
+/// get_range 的 key 的范围，两端都可以是开的、闭的或者没有边界
+pub type KeyRange<'a> = (Bound<&'a str>, Bound<&'a str>);
+
+/// EXEC 暂存下来的写入，按 (table, key) 排好序，value 是 None 的表示删除
+pub type StagedWrites = BTreeMap<(String, String), Option<Value>>;
+
+/// get_iter_ordered 遍历 table 的顺序
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Order {
+    /// 不保证顺序，backend 用最快的方式遍历
+    #[default]
+    Unordered,
+    Ascending,
+    Descending,
+}
+
+impl Order {
+    /// 协议里的 order 字段：0 不保证顺序，大于 0 按 key 升序，小于 0 降序
+    pub fn from_sign(order: i32) -> Self {
+        match order {
+            0 => Order::Unordered,
+            1.. => Order::Ascending,
+            _ => Order::Descending,
+        }
+    }
+
+    pub fn sign(self) -> i32 {
+        match self {
+            Order::Unordered => 0,
+            Order::Ascending => 1,
+            Order::Descending => -1,
+        }
+    }
+}
 
 /// 对存储的抽象,我们不关心数据在哪儿,但需要定义外界如何和存储打交道
 pub trait Storage {
     /// 从一个HashTable里获取一个key的value
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
     /// 从一个HashTable里设置一个key的value, 返回旧的value
-    fn set(
-        &self,
-        table: &str,
-        key: impl Into<String>,
-        value: impl Into<Value>,
-    ) -> Result<Option<Value>, KvError>;
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError>;
     /// 查看HashTable中是否有key
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError>;
     /// 从HashTable中删除一个key
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+    /// 读出 table 里的多个 key，结果和 keys 一一对应。缺省逐个 get
+    fn mget(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        keys.iter().map(|key| self.get(table, key)).collect()
+    }
+    /// 写入多个 kv pair，返回每个 key 的旧值。缺省逐个 set，
+    /// backend 可以只找一次 table，或者把它们原子地一起写入
+    fn mset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+        pairs
+            .into_iter()
+            .map(|pair| self.set(table, pair.key, pair.value.unwrap_or_default()))
+            .collect()
+    }
+    /// 删除多个 key，返回每个 key 的旧值。缺省逐个 del
+    fn mdel(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        keys.iter().map(|key| self.del(table, key)).collect()
+    }
     /// 遍历HashTable, 返回所有的kv pair (这个接口不好)
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
     /// 遍历HashTable, 返回kv pair的Iterator
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError>;
+    /// 按 order 指定的顺序遍历 table。缺省把 get_iter 的结果放进堆里，
+    /// 每次 next 取出一个，只读前面几个的时候不用排好整个 table
+    fn get_iter_ordered(
+        &self,
+        table: &str,
+        order: Order,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let iter = self.get_iter(table)?;
+        Ok(match order {
+            Order::Unordered => iter,
+            order => Box::new(SortedPairs::new(iter, order)),
+        })
+    }
+    /// table 当前内容的快照，之后的写入不会改变它。缺省把 get_iter 的结果复制一份
+    fn snapshot(&self, table: &str) -> Result<Box<dyn Snapshot>, KvError> {
+        Ok(Box::new(self.get_iter(table)?.collect::<PairsSnapshot>()))
+    }
+    /// table 里 key 的数量。缺省遍历 get_iter，backend 可以提供更快的实现
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self.get_iter(table)?.count())
+    }
+    /// 遍历 table 里 key 和 pattern 匹配的 kv pair。
+    /// 缺省过滤 get_iter，backend 可以在读出 value 之前就跳过不匹配的 key
+    fn get_iter_matching(
+        &self,
+        table: &str,
+        pattern: &Glob,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let pattern = pattern.clone();
+        let iter = self.get_iter(table)?;
+        Ok(Box::new(iter.filter(move |p| pattern.matches(&p.key))))
+    }
+    /// 按 key 的顺序返回 after 之后的最多 count 个 kv pair，after 为 None 时从头开始，
+    /// 有 pattern 时只返回 key 和它匹配的。
+    /// 用上一批最后一个 key 作为 after 就可以接着遍历，不需要保存任何状态。
+    /// 缺省遍历整个 table 再排序，有序的 backend 可以直接从 after 开始读
+    fn scan(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        pattern: Option<&Glob>,
+        count: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let pairs = self
+            .get_iter(table)?
+            .filter(|p| after.is_none_or(|after| p.key.as_str() > after))
+            .filter(|p| pattern.is_none_or(|pattern| pattern.matches(&p.key)))
+            .collect();
+        Ok(first_keys(pairs, count))
+    }
+    /// 按 key 的顺序遍历 table 里 key 在 range 里的 kv pair。
+    /// 缺省过滤 get_iter 再排序，有序的 backend 可以直接从 range 的起点开始读
+    fn get_range(
+        &self,
+        table: &str,
+        range: KeyRange,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let mut pairs: Vec<Kvpair> = self
+            .get_iter(table)?
+            .filter(|p| RangeBounds::<str>::contains(&range, p.key.as_str()))
+            .collect();
+        pairs.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        Ok(Box::new(pairs.into_iter()))
+    }
+    /// 执行 backend 自己的管理命令，结果用 kv pair 返回。缺省不支持任何命令
+    fn admin(&self, command: &str, _args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        Err(KvError::Unsupported(format!("admin command {}", command)))
+    }
+    /// 从回收站里恢复一个被删除的 key，返回恢复的 value。缺省没有回收站
+    fn undelete(&self, table: &str, _key: &str) -> Result<Option<Value>, KvError> {
+        Err(KvError::Unsupported(format!(
+            "recycle bin of table {}",
+            table
+        )))
+    }
+    /// 清理回收站，返回清理掉的 key 的数量
+    fn purge_trash(&self, table: &str, _all: bool) -> Result<usize, KvError> {
+        Err(KvError::Unsupported(format!(
+            "recycle bin of table {}",
+            table
+        )))
+    }
+    /// key 保留下来的历史版本，新的在前。缺省不保留历史
+    fn history(&self, table: &str, _key: &str) -> Result<Vec<(u64, Value)>, KvError> {
+        Err(KvError::Unsupported(format!("history of table {}", table)))
+    }
+    /// 把 src 当前的内容复制到空的 table dst，返回复制的 key 的数量。
+    /// 缺省是遍历 src 逐个写入，backend 可以提供更便宜的实现
+    fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+        check_clone_target(self, src, dst)?;
+        let mut n = 0;
+        for pair in self.get_iter(src)? {
+            self.set(dst, pair.key, pair.value.unwrap_or_default())?;
+            n += 1;
+        }
+        Ok(n)
+    }
+    /// 所有 table 的名字，按字母顺序排列，空的 table 不算。缺省不支持
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        Err(KvError::Unsupported("listing tables".into()))
+    }
+    /// 每个 table 的 key 的数量和大小，按 table 的名字排列。
+    /// 缺省遍历所有的 table，backend 可以用自己记录的数字
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        let mut stats = Vec::new();
+        for table in self.list_tables()? {
+            let mut s = TableStats {
+                table,
+                ..Default::default()
+            };
+            for pair in self.get_iter(&s.table)? {
+                s.keys += 1;
+                s.bytes += pair.key.len() + pair.value.map_or(0, |v| v.encoded_len());
+            }
+            stats.push(s);
+        }
+        Ok(stats)
+    }
+    /// 删除所有的 table，返回删除的 key 的数量。缺省是逐个 drop_table
+    fn flush_all(&self) -> Result<usize, KvError> {
+        let mut n = 0;
+        for table in self.list_tables()? {
+            n += self.drop_table(&table)?;
+        }
+        Ok(n)
+    }
+    /// 删除整个 table，返回删除的 key 的数量。缺省是遍历 table 逐个删除
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        let mut n = 0;
+        for pair in self.get_iter(table)? {
+            n += self.del(table, &pair.key)?.is_some() as usize;
+        }
+        Ok(n)
+    }
+    /// 把 key 从 src 原子地移到 dst，返回移动的 value，src 里没有这个 key 时返回 None。
+    /// dst 里已经有这个 key 时报错，除非 force。没有办法保证原子性的 backend 不支持
+    fn move_key(
+        &self,
+        src: &str,
+        _dst: &str,
+        _key: &str,
+        _force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        Err(KvError::Unsupported(format!("MOVE from table {}", src)))
+    }
+    /// 把 key 的整数 value 原子地加上 delta，返回新的值，key 不存在时从 0 开始。
+    /// 没有办法保证原子性的 backend 不支持
+    fn incr(&self, table: &str, _key: &str, _delta: i64) -> Result<i64, KvError> {
+        Err(KvError::Unsupported(format!("HINCRBY in table {}", table)))
+    }
+    /// key 不存在时才写入，返回是否写入了。检查和写入必须是原子的，
+    /// 没有办法保证的 backend 不支持
+    fn set_nx(&self, table: &str, _key: String, _value: Value) -> Result<bool, KvError> {
+        Err(KvError::Unsupported(format!("HSETNX in table {}", table)))
+    }
+    /// 设置 key 的过期时间（UNIX 毫秒），时间已经过去的话马上删掉这个 key。
+    /// 返回 key 是否存在。缺省不支持过期
+    fn expire_at(&self, table: &str, _key: &str, _deadline: i64) -> Result<bool, KvError> {
+        Err(KvError::Unsupported(format!(
+            "expiration in table {}",
+            table
+        )))
+    }
+    /// 去掉 key 的过期时间，返回之前是否设置了
+    fn persist(&self, table: &str, _key: &str) -> Result<bool, KvError> {
+        Err(KvError::Unsupported(format!(
+            "expiration in table {}",
+            table
+        )))
+    }
+    /// key 的过期时间，没有设置时返回 None，key 不存在时返回 NotFound
+    fn deadline(&self, table: &str, _key: &str) -> Result<Option<i64>, KvError> {
+        Err(KvError::Unsupported(format!(
+            "expiration in table {}",
+            table
+        )))
+    }
+    /// 删除所有已经过期的 key，返回删除的数量。缺省没有会过期的 key
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        Ok(0)
+    }
+    /// key 当前的 version，key 不存在时返回 None。key 每次被写入之后
+    /// 读到的 version 都比之前的大，写入相同的 value 也一样。缺省不支持
+    fn version(&self, table: &str, _key: &str) -> Result<Option<u64>, KvError> {
+        Err(KvError::Unsupported(format!("versions in table {}", table)))
+    }
+    /// key 当前的 version 等于 version 时才写入，返回新的 version。
+    /// version 为 0 表示 key 必须不存在，不相等时返回 Conflict。缺省不支持
+    fn set_if_version(
+        &self,
+        table: &str,
+        _key: String,
+        _value: Value,
+        _version: u64,
+    ) -> Result<u64, KvError> {
+        Err(KvError::Unsupported(format!("versions in table {}", table)))
+    }
+    /// key 的创建时间、最后写入的时间和写入次数，key 不存在时返回 None。
+    /// 缺省不记录元数据
+    fn meta(&self, table: &str, _key: &str) -> Result<Option<KeyMeta>, KvError> {
+        Err(KvError::Unsupported(format!("metadata in table {}", table)))
+    }
+    /// 把已经完成的写入持久化到磁盘，返回之后崩溃也不会丢失。缺省执行
+    /// FLUSH_COMMAND 管理命令，不支持这个命令的 backend 没有需要写盘的数据
+    fn flush(&self) -> Result<(), KvError> {
+        match self.admin(FLUSH_COMMAND, &[]) {
+            Ok(_) | Err(KvError::Unsupported(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+    /// 把 pairs 写入 table，已经存在的 key 被覆盖，用来导入大量数据。
+    /// 不返回旧值，backend 可以不读旧值、批量写入。缺省每 BULK_LOAD_BATCH 个 key 调用一次 mset
+    fn bulk_load(
+        &self,
+        table: &str,
+        pairs: &mut dyn Iterator<Item = Kvpair>,
+    ) -> Result<BulkLoadStats, KvError> {
+        load_in_batches(self, table, pairs)
+    }
+    /// 提交 EXEC 暂存的写入，要么都生效要么都不生效，写入会去掉 key 的过期时间。
+    /// 缺省逐个 set / del，中途出错时前面的已经生效了，有事务的 backend 应该覆盖它
+    fn commit(&self, writes: StagedWrites) -> Result<(), KvError> {
+        for ((table, key), value) in writes {
+            match value {
+                Some(value) => self.set(&table, key, value)?,
+                None => self.del(&table, &key)?,
+            };
+        }
+        Ok(())
+    }
+    /// 用 table 上名为 index 的二级索引找出索引的值等于 value 的 key，最多 limit 个。
+    /// 缺省没有索引
+    fn query(
+        &self,
+        table: &str,
+        _index: &str,
+        _value: &Value,
+        _limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        Err(KvError::Unsupported(format!("indexes in table {}", table)))
+    }
+    /// 把所有 table 写成备份，返回 key 的数量。每个 table 是它自己那个时刻的快照，
+    /// 不同的 table 不是同一时刻的
+    fn export(&self, w: &mut dyn Write) -> Result<usize, KvError> {
+        backup::export(self, w)
+    }
+    /// 导入 export 写出的备份，返回 key 的数量。已经存在的 key 被覆盖，
+    /// 中途出错时已经导入的部分不会回滚，需要的话先用 check_backup 检查
+    fn import(&self, r: &mut dyn Read) -> Result<usize, KvError> {
+        backup::import(self, r)
+    }
+    /// 操作的计数、每个 table 的统计和 backend 自己的统计。
+    /// 缺省只有 stats，没有计数
+    fn metrics(&self) -> Result<StorageMetrics, KvError> {
+        Ok(StorageMetrics {
+            tables: self.stats()?,
+            ..Default::default()
+        })
+    }
+}
+
+/// 运行时选择的 backend，可以交给 Service 和网络层
+pub type DynStorage = Box<dyn Storage + Send + Sync>;
+
+/// Storage 的便捷方法，key 和 value 可以是任何能转换成 String 和 Value 的类型。
+/// Storage 自己只接受具体的类型，这样才能放进 Box<dyn Storage>
+pub trait StorageExt: Storage {
+    fn hset(
+        &self,
+        table: &str,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        self.set(table, key.into(), value.into())
+    }
+
+    fn hsetnx(
+        &self,
+        table: &str,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Result<bool, KvError> {
+        self.set_nx(table, key.into(), value.into())
+    }
+
+    fn hsetver(
+        &self,
+        table: &str,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        self.set_if_version(table, key.into(), value.into(), version)
+    }
+}
+
+impl<S: Storage + ?Sized> StorageExt for S {}
+
+/// bulk_load 每次写入的 key 的数量
+pub const BULK_LOAD_BATCH: usize = 4096;
+
+/// bulk_load 写入了多少 key、多少字节，用了多长时间
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BulkLoadStats {
+    pub keys: u64,
+    /// key 和编码之后的 value 的长度之和
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl BulkLoadStats {
+    /// 每秒写入的 key 的数量
+    pub fn keys_per_sec(&self) -> f64 {
+        self.keys as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// 一个 table 的统计
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableStats {
+    pub table: String,
+    pub keys: usize,
+    /// key 和编码之后的 value 的长度之和，是数据本身的大小，不包括 backend 的开销
+    pub bytes: usize,
+}
+
+/// 一个 key 的元数据，时间都是 UNIX 毫秒。
+/// 开始记录之前就写入的 key 没有元数据，所有的字段都是 0
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyMeta {
+    pub created_at: i64,
+    pub updated_at: i64,
+    /// 创建之后又被写入的次数
+    pub updates: u64,
+}
+
+impl KeyMeta {
+    /// key 不存在，或者旧的值已经过期，这次写入创建了它
+    pub(crate) fn created(now: i64) -> Self {
+        Self {
+            created_at: now,
+            updated_at: now,
+            updates: 0,
+        }
+    }
+
+    /// 覆盖已有的 key
+    pub(crate) fn touch(&mut self, now: i64) {
+        self.updated_at = now;
+        self.updates += 1;
+    }
+
+    /// 保存在磁盘上的格式
+    pub(crate) fn encode(&self) -> [u8; 24] {
+        let mut data = [0; 24];
+        data[..8].copy_from_slice(&self.created_at.to_be_bytes());
+        data[8..16].copy_from_slice(&self.updated_at.to_be_bytes());
+        data[16..].copy_from_slice(&self.updates.to_be_bytes());
+        data
+    }
+
+    /// 长度不对的数据当作没有记录
+    pub(crate) fn decode(data: &[u8]) -> Self {
+        match <[u8; 24]>::try_from(data) {
+            Ok(d) => Self {
+                created_at: i64::from_be_bytes(d[..8].try_into().unwrap()),
+                updated_at: i64::from_be_bytes(d[8..16].try_into().unwrap()),
+                updates: u64::from_be_bytes(d[16..].try_into().unwrap()),
+            },
+            Err(_) => Self::default(),
+        }
+    }
+}
+
+impl From<KeyMeta> for Vec<Kvpair> {
+    fn from(meta: KeyMeta) -> Self {
+        vec![
+            Kvpair::new("created_at", meta.created_at.into()),
+            Kvpair::new("updated_at", meta.updated_at.into()),
+            Kvpair::new("updates", (meta.updates as i64).into()),
+        ]
+    }
+}
+
+// 按 key 比较的 kv pair，desc 为 false 时反过来比较，这样最大堆先弹出最小的 key
+struct ByKey {
+    pair: Kvpair,
+    desc: bool,
+}
+
+impl PartialEq for ByKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.pair.key == other.pair.key
+    }
+}
+
+impl Eq for ByKey {}
+
+impl PartialOrd for ByKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ByKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let ord = self.pair.key.cmp(&other.pair.key);
+        match self.desc {
+            true => ord,
+            false => ord.reverse(),
+        }
+    }
+}
+
+/// 惰性排序的 Iterator：建堆是 O(n)，取出前 k 个是 O(k log n)
+pub(crate) struct SortedPairs(BinaryHeap<ByKey>);
+
+impl SortedPairs {
+    pub(crate) fn new(iter: impl Iterator<Item = Kvpair>, order: Order) -> Self {
+        let desc = order == Order::Descending;
+        Self(iter.map(|pair| ByKey { pair, desc }).collect())
+    }
+}
+
+impl Iterator for SortedPairs {
+    type Item = Kvpair;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.pop().map(|v| v.pair)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.len(), Some(self.0.len()))
+    }
+}
+
+/// 按 key 排序之后的前 count 个 kv pair
+pub(crate) fn first_keys(mut pairs: Vec<Kvpair>, count: usize) -> Vec<Kvpair> {
+    if pairs.len() > count {
+        pairs.select_nth_unstable_by(count, |a, b| a.key.cmp(&b.key));
+        pairs.truncate(count);
+    }
+    pairs.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+    pairs
+}
+
+/// range 的起点在终点之后，或者两端重合而且至少有一端是开的，这样的 range 里没有 key。
+/// BTreeMap 和 sled 遇到这样的 range 会 panic，需要先检查
+pub(crate) fn is_empty_range(range: &KeyRange) -> bool {
+    match range {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => {
+            s >= e
+        }
+        _ => false,
+    }
+}
+
+/// 数据的指纹（64 位 FNV-1a），ShardedMemTable 用它给 key 分片
+pub(crate) fn fingerprint(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// 把 table 和 key 编码成一个 key：4 个字节的 table 长度，table，key。
+/// 一个 table 的所有 key 有同样的前缀，table 和 key 里有什么字符都不会混淆
+pub(crate) fn table_key(table: &str, key: &str) -> Vec<u8> {
+    [table_prefix(table).as_slice(), key.as_bytes()].concat()
+}
+
+/// 一个 table 的所有 key 的前缀
+pub(crate) fn table_prefix(table: &str) -> Vec<u8> {
+    [&(table.len() as u32).to_be_bytes()[..], table.as_bytes()].concat()
+}
+
+/// table_key 的反过程，不是它编码出来的数据返回 None
+pub(crate) fn split_table_key(data: &[u8]) -> Option<(&str, &str)> {
+    let len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let name = data.get(4..)?;
+    let table = std::str::from_utf8(name.get(..len)?).ok()?;
+    let key = std::str::from_utf8(&name[len..]).ok()?;
+    Some((table, key))
+}
+
+/// 保存在磁盘上的过期时间是 8 个字节的 UNIX 毫秒，长度不对的数据当作已经过期
+pub(crate) fn decode_deadline(data: &[u8]) -> i64 {
+    <[u8; 8]>::try_from(data)
+        .map(i64::from_be_bytes)
+        .unwrap_or_default()
+}
+
+/// 保存在磁盘上的 version
+pub(crate) fn encode_version(version: u64) -> [u8; 8] {
+    version.to_be_bytes()
+}
+
+/// 长度不对的数据当作没有记录 version，返回 0。以前的格式后面还有 value 的指纹，
+/// 那时的写入不会更新记录，这样的 version 不能再相信
+pub(crate) fn decode_version(data: &[u8]) -> u64 {
+    match <[u8; 8]>::try_from(data) {
+        Ok(d) => u64::from_be_bytes(d),
+        Err(_) => 0,
+    }
+}
+
+/// key 的 version 不是 version
+pub(crate) fn version_conflict(table: &str, key: &str, version: u64) -> KvError {
+    KvError::Conflict(format!("version of {}:{} is not {}", table, key, version))
+}
+
+/// move_key 的两个 table 不能相同
+pub(crate) fn check_move(src: &str, dst: &str) -> Result<(), KvError> {
+    match src == dst {
+        true => Err(KvError::InvalidCommand(format!(
+            "Cannot move key within table {}",
+            src
+        ))),
+        false => Ok(()),
+    }
+}
+
+/// dst 里已经有这个 key 的错误
+pub(crate) fn move_conflict(dst: &str, key: &str) -> KvError {
+    KvError::InvalidCommand(format!("Key {} already exists in table {}", key, dst))
+}
+
+/// incr 算出新的值，旧的 value 不是整数或者溢出时报错
+pub(crate) fn incr_value(key: &str, old: Option<&Value>, delta: i64) -> Result<i64, KvError> {
+    let n = match old.map(|v| i64::try_from(v.clone())) {
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            return Err(KvError::InvalidCommand(format!(
+                "Value of key {} is not an integer",
+                key
+            )))
+        }
+        None => 0,
+    };
+    n.checked_add(delta)
+        .ok_or_else(|| KvError::InvalidCommand(format!("Value of key {} would overflow", key)))
+}
+
+/// bulk_load 的缺省实现，每 BULK_LOAD_BATCH 个 key 调用一次 mset
+pub(crate) fn load_in_batches<S: Storage + ?Sized>(
+    store: &S,
+    table: &str,
+    pairs: &mut dyn Iterator<Item = Kvpair>,
+) -> Result<BulkLoadStats, KvError> {
+    let start = Instant::now();
+    let mut stats = BulkLoadStats::default();
+    loop {
+        let batch: Vec<_> = pairs.take(BULK_LOAD_BATCH).collect();
+        if batch.is_empty() {
+            break;
+        }
+        stats.keys += batch.len() as u64;
+        stats.bytes += batch
+            .iter()
+            .map(|p| (p.key.len() + p.value.as_ref().map_or(0, |v| v.encoded_len())) as u64)
+            .sum::<u64>();
+        store.mset(table, batch)?;
+    }
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+/// clone_table 的目标必须是另一个空的 table
+pub(crate) fn check_clone_target<S: Storage + ?Sized>(
+    store: &S,
+    src: &str,
+    dst: &str,
+) -> Result<(), KvError> {
+    if src == dst {
+        return Err(KvError::InvalidCommand(format!(
+            "Cannot clone table {} to itself",
+            src
+        )));
+    }
+    if store.get_iter(dst)?.next().is_some() {
+        return Err(KvError::InvalidCommand(format!(
+            "Table {} is not empty",
+            dst
+        )));
+    }
+    Ok(())
+}
+
+// 指针里的存储直接转发给它指向的存储
+macro_rules! forward_storage {
+    ($ptr:ident) => {
+        impl<T: Storage + ?Sized> Storage for $ptr<T> {
+            fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+                (**self).get(table, key)
+            }
+
+            fn set(
+                &self,
+                table: &str,
+                key: String,
+                value: Value,
+            ) -> Result<Option<Value>, KvError> {
+                (**self).set(table, key, value)
+            }
+
+            fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+                (**self).contains(table, key)
+            }
+
+            fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+                (**self).del(table, key)
+            }
+
+            fn mget(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+                (**self).mget(table, keys)
+            }
+
+            fn mset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+                (**self).mset(table, pairs)
+            }
+
+            fn mdel(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+                (**self).mdel(table, keys)
+            }
+
+            fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+                (**self).get_all(table)
+            }
+
+            fn get_iter(
+                &self,
+                table: &str,
+            ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+                (**self).get_iter(table)
+            }
+
+            fn get_iter_ordered(
+                &self,
+                table: &str,
+                order: Order,
+            ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+                (**self).get_iter_ordered(table, order)
+            }
+
+            fn snapshot(&self, table: &str) -> Result<Box<dyn Snapshot>, KvError> {
+                (**self).snapshot(table)
+            }
+
+            fn len(&self, table: &str) -> Result<usize, KvError> {
+                (**self).len(table)
+            }
+
+            fn get_iter_matching(
+                &self,
+                table: &str,
+                pattern: &Glob,
+            ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+                (**self).get_iter_matching(table, pattern)
+            }
+
+            fn scan(
+                &self,
+                table: &str,
+                after: Option<&str>,
+                pattern: Option<&Glob>,
+                count: usize,
+            ) -> Result<Vec<Kvpair>, KvError> {
+                (**self).scan(table, after, pattern, count)
+            }
+
+            fn get_range(
+                &self,
+                table: &str,
+                range: KeyRange,
+            ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+                (**self).get_range(table, range)
+            }
+
+            fn admin(&self, command: &str, args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+                (**self).admin(command, args)
+            }
+
+            fn undelete(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+                (**self).undelete(table, key)
+            }
+
+            fn purge_trash(&self, table: &str, all: bool) -> Result<usize, KvError> {
+                (**self).purge_trash(table, all)
+            }
+
+            fn history(&self, table: &str, key: &str) -> Result<Vec<(u64, Value)>, KvError> {
+                (**self).history(table, key)
+            }
+
+            fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+                (**self).clone_table(src, dst)
+            }
+
+            fn list_tables(&self) -> Result<Vec<String>, KvError> {
+                (**self).list_tables()
+            }
+
+            fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+                (**self).stats()
+            }
+
+            fn flush_all(&self) -> Result<usize, KvError> {
+                (**self).flush_all()
+            }
+
+            fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+                (**self).drop_table(table)
+            }
+
+            fn move_key(
+                &self,
+                src: &str,
+                dst: &str,
+                key: &str,
+                force: bool,
+            ) -> Result<Option<Value>, KvError> {
+                (**self).move_key(src, dst, key, force)
+            }
+
+            fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+                (**self).incr(table, key, delta)
+            }
+
+            fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+                (**self).set_nx(table, key, value)
+            }
+
+            fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+                (**self).expire_at(table, key, deadline)
+            }
+
+            fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+                (**self).persist(table, key)
+            }
+
+            fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, KvError> {
+                (**self).deadline(table, key)
+            }
+
+            fn purge_expired(&self) -> Result<usize, KvError> {
+                (**self).purge_expired()
+            }
+
+            fn meta(&self, table: &str, key: &str) -> Result<Option<KeyMeta>, KvError> {
+                (**self).meta(table, key)
+            }
+
+            fn flush(&self) -> Result<(), KvError> {
+                (**self).flush()
+            }
+
+            fn bulk_load(
+                &self,
+                table: &str,
+                pairs: &mut dyn Iterator<Item = Kvpair>,
+            ) -> Result<BulkLoadStats, KvError> {
+                (**self).bulk_load(table, pairs)
+            }
+
+            fn commit(&self, writes: StagedWrites) -> Result<(), KvError> {
+                (**self).commit(writes)
+            }
+
+            fn query(
+                &self,
+                table: &str,
+                index: &str,
+                value: &Value,
+                limit: usize,
+            ) -> Result<Vec<Kvpair>, KvError> {
+                (**self).query(table, index, value, limit)
+            }
+
+            fn export(&self, w: &mut dyn Write) -> Result<usize, KvError> {
+                (**self).export(w)
+            }
+
+            fn import(&self, r: &mut dyn Read) -> Result<usize, KvError> {
+                (**self).import(r)
+            }
+
+            fn metrics(&self) -> Result<StorageMetrics, KvError> {
+                (**self).metrics()
+            }
+
+            fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+                (**self).version(table, key)
+            }
+
+            fn set_if_version(
+                &self,
+                table: &str,
+                key: String,
+                value: Value,
+                version: u64,
+            ) -> Result<u64, KvError> {
+                (**self).set_if_version(table, key, value, version)
+            }
+        }
+    };
 }
 
+// 多个地方共享同一个存储时可以用 Arc 包起来
+forward_storage!(Arc);
+// 运行时才知道用哪个 backend 的时候放在 Box<dyn Storage> 里
+forward_storage!(Box);
+
 /// 提供 Storage iterator, 这样trait的实现者只需要
 /// 把它们的iterator提供给StorageIter, 然后它们保证
 /// next()传出的类型实现了Into<Kvpair>即可
@@ -54,6 +1000,8 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::thread;
+    use std::time::Duration;
     use tempfile::tempdir;
 
     #[test]
@@ -75,32 +1023,1337 @@ mod tests {
     }
 
     #[test]
-    fn sleddb_basic_interface_should_work() {
+    fn memtable_clone_table_should_work() {
+        let store = MemTable::new();
+        test_clone_table(store);
+    }
+
+    #[test]
+    fn sleddb_list_tables_should_work() {
         let dir = tempdir().unwrap();
-        let store = SledDb::new(dir);
-        test_basic_interface(store);
+        let store = SledDb::new(dir).unwrap();
+        test_list_tables(store);
     }
 
     #[test]
-    fn sleddb_get_all_should_work() {
+    fn sleddb_stats_should_work() {
         let dir = tempdir().unwrap();
-        let store = SledDb::new(dir);
-        test_get_all(store);
+        let store = SledDb::new(dir).unwrap();
+        test_stats(store);
     }
 
     #[test]
-    fn sleddb_iter_should_work() {
+    fn sleddb_flush_all_should_work() {
         let dir = tempdir().unwrap();
-        let store = SledDb::new(dir);
-        test_get_iter(store);
+        let store = SledDb::new(dir).unwrap();
+        test_flush_all(store);
+    }
+
+    #[test]
+    fn sleddb_drop_table_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_drop_table(store);
+    }
+
+    #[test]
+    fn memtable_list_tables_should_work() {
+        let store = MemTable::new();
+        test_list_tables(store);
+    }
+
+    #[test]
+    fn memtable_stats_should_work() {
+        let store = MemTable::new();
+        test_stats(store);
+    }
+
+    #[test]
+    fn memtable_flush_all_should_work() {
+        let store = MemTable::new();
+        test_flush_all(store);
+    }
+
+    #[test]
+    fn memtable_drop_table_should_work() {
+        let store = MemTable::new();
+        test_drop_table(store);
+    }
+
+    #[test]
+    fn memtable_incr_should_work() {
+        let store = MemTable::new();
+        test_incr(store);
+    }
+
+    #[test]
+    fn boxed_storage_should_work() {
+        let dir = tempdir().unwrap();
+        let stores: Vec<DynStorage> = vec![
+            Box::new(MemTable::new()),
+            Box::new(SledDb::new(dir).unwrap()),
+        ];
+        for store in stores {
+            test_basic_interface(store);
+        }
+    }
+
+    #[test]
+    fn memtable_snapshot_should_work() {
+        test_snapshot(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_snapshot_should_work() {
+        let dir = tempdir().unwrap();
+        test_snapshot(SledDb::new(dir).unwrap());
+    }
+
+    #[test]
+    fn sharded_snapshot_should_work() {
+        test_snapshot(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn memtable_meta_should_work() {
+        test_meta(MemTable::with_meta());
+        assert!(MemTable::new().meta("t1", "k1").is_err());
+    }
+
+    #[test]
+    fn sleddb_meta_should_work() {
+        let dir = tempdir().unwrap();
+        test_meta(SledDb::builder(dir).meta(true).open().unwrap());
+    }
+
+    #[test]
+    fn memtable_get_iter_ordered_should_work() {
+        test_get_iter_ordered(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_get_iter_ordered_should_work() {
+        let dir = tempdir().unwrap();
+        test_get_iter_ordered(SledDb::new(dir).unwrap());
+    }
+
+    #[test]
+    fn memtable_get_range_should_work() {
+        test_get_range(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_get_range_should_work() {
+        let dir = tempdir().unwrap();
+        test_get_range(SledDb::new(dir).unwrap());
+    }
+
+    #[test]
+    fn sharded_get_range_should_work() {
+        test_get_range(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn lru_get_range_should_work() {
+        test_get_range(LruMemTable::default());
+    }
+
+    #[test]
+    fn memtable_batch_should_work() {
+        test_batch(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_batch_should_work() {
+        let dir = tempdir().unwrap();
+        test_batch(SledDb::new(dir).unwrap());
+    }
+
+    #[test]
+    fn memtable_bulk_load_should_work() {
+        test_bulk_load(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_bulk_load_should_work() {
+        let dir = tempdir().unwrap();
+        test_bulk_load(SledDb::new(dir).unwrap());
+    }
+
+    #[test]
+    fn sleddb_bulk_load_should_clear_deadlines() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::builder(dir).meta(true).open().unwrap();
+        store.hset("t1", "k1", "v1").unwrap();
+        store.expire_at("t1", "k1", now_ms() + 60_000).unwrap();
+        let mut pairs = std::iter::once(Kvpair::new("k1", "v2".into()));
+        store.bulk_load("t1", &mut pairs).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v2".into()));
+        assert_eq!(store.deadline("t1", "k1").unwrap(), None);
+        // 没有读旧的元数据，当作新建的 key
+        assert_eq!(store.meta("t1", "k1").unwrap().unwrap().updates, 0);
+    }
+
+    #[test]
+    fn sleddb_mset_should_clear_deadlines() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        store.hset("t1", "k1", "v1").unwrap();
+        store.expire_at("t1", "k1", now_ms() + 60_000).unwrap();
+        let pairs = vec![Kvpair::new("k1", "v2".into())];
+        assert_eq!(store.mset("t1", pairs).unwrap(), [Some("v1".into())]);
+        assert_eq!(store.deadline("t1", "k1").unwrap(), None);
+    }
+
+    #[test]
+    fn lru_batch_should_work() {
+        test_batch(LruMemTable::default());
+    }
+
+    #[test]
+    fn memtable_set_nx_should_work() {
+        let store = MemTable::new();
+        test_set_nx(store);
+    }
+
+    #[test]
+    fn memtable_len_should_work() {
+        let store = MemTable::new();
+        test_len(store);
+    }
+
+    #[test]
+    fn memtable_versions_should_work() {
+        let store = MemTable::new();
+        test_versions(store);
+    }
+
+    #[test]
+    fn memtable_scan_should_work() {
+        let store = MemTable::new();
+        test_scan(store);
+    }
+
+    #[test]
+    fn memtable_get_iter_matching_should_work() {
+        let store = MemTable::new();
+        test_get_iter_matching(store);
+    }
+
+    #[test]
+    fn memtable_expire_should_work() {
+        let store = MemTable::new();
+        test_expire(store);
+    }
+
+    #[test]
+    fn memtable_move_key_should_work() {
+        let store = MemTable::new();
+        test_move_key(store);
+    }
+
+    #[test]
+    fn object_storage_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = ObjectStorage::new(FsObjectStore::new(dir.path()).unwrap()).unwrap();
+        test_basic_interface(store);
+    }
+
+    #[test]
+    fn object_storage_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = ObjectStorage::new(FsObjectStore::new(dir.path()).unwrap()).unwrap();
+        test_get_all(store);
+    }
+
+    #[test]
+    fn object_storage_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = ObjectStorage::new(FsObjectStore::new(dir.path()).unwrap()).unwrap();
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn sleddb_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_basic_interface(store);
+    }
+
+    #[test]
+    fn sleddb_compression_should_work() {
+        let dir = tempdir().unwrap();
+        let json: Value = "{\"id\": 1, \"tags\": []}".repeat(64).into();
+        let store = SledDb::new(dir.path()).unwrap();
+        store.hset("t1", "old", "plain").unwrap();
+        drop(store);
+
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let store = SledDb::builder(dir.path())
+                .compression(compression, 128)
+                .open()
+                .unwrap();
+            store.hset("t1", "json", json.clone()).unwrap();
+            assert_eq!(store.get("t1", "json").unwrap(), Some(json.clone()));
+            // 压缩之前写入的数据照样可以读
+            assert_eq!(store.get("t1", "old").unwrap(), Some("plain".into()));
+            let pairs = store.get_all("t1").unwrap();
+            assert!(pairs.contains(&Kvpair::new("json", json.clone())));
+            drop(store);
+
+            // 不压缩的时候也能读出压缩过的数据
+            let store = SledDb::new(dir.path()).unwrap();
+            assert_eq!(store.get("t1", "json").unwrap(), Some(json.clone()));
+            drop(store);
+        }
+        test_basic_interface(
+            SledDb::builder(tempdir().unwrap())
+                .compression(Compression::Gzip, 0)
+                .open()
+                .unwrap(),
+        );
+    }
+
+    #[test]
+    fn metrics_should_count_operations() {
+        let dir = tempdir().unwrap();
+        test_metrics(MemTable::new(), "memory.bytes");
+        test_metrics(SledDb::new(dir.path()).unwrap(), "sled.size_on_disk");
+    }
+
+    fn test_metrics(store: impl Storage, backend: &str) {
+        store.hset("t1", "k1", "v1").unwrap();
+        store.mset("t1", vec![Kvpair::new("k2", 2.into())]).unwrap();
+        store.get("t1", "k1").unwrap();
+        store.get("t1", "k3").unwrap();
+        store.del("t1", "k2").unwrap();
+
+        let metrics = store.metrics().unwrap();
+        assert_eq!(
+            (metrics.reads, metrics.hits, metrics.writes, metrics.deletes),
+            (2, 1, 2, 1)
+        );
+        assert_eq!(metrics.hit_rate(), 0.5);
+        assert_eq!(metrics.tables, store.stats().unwrap());
+        assert_eq!(metrics.backend[0].key, backend);
+    }
+
+    #[test]
+    fn sleddb_bloom_should_skip_missing_keys() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::builder(dir.path()).bloom(true).open().unwrap();
+        test_basic_interface(store);
+
+        let store = SledDb::builder(tempdir().unwrap())
+            .bloom(true)
+            .open()
+            .unwrap();
+        store.hset("t1", "k1", "v1").unwrap();
+        store
+            .mset("t1", vec![Kvpair::new("k2", "v2".into())])
+            .unwrap();
+        store.incr("t1", "k3", 1).unwrap();
+        let mut pairs = std::iter::once(Kvpair::new("k4", "v4".into()));
+        store.bulk_load("t1", &mut pairs).unwrap();
+        store.move_key("t1", "t2", "k4", false).unwrap();
+        for key in ["k1", "k2", "k3"] {
+            assert!(store.contains("t1", key).unwrap(), "{}", key);
+        }
+        assert_eq!(store.get("t2", "k4").unwrap(), Some("v4".into()));
+        assert_eq!(store.get("t1", "nope").unwrap(), None);
+        assert!(!store.contains("t3", "k1").unwrap());
+        let metrics = store.metrics().unwrap();
+        assert_eq!(metrics.reads, 2);
+        // t3 没有 filter，一定跳过；t1 的 nope 可能误判
+        let skips = metrics.backend.iter().find(|p| p.key == "sled.bloom_skips");
+        let skips: i64 = skips.unwrap().value.clone().unwrap().try_into().unwrap();
+        assert!(skips >= 1);
+
+        // 重新打开时按已有的 key 重建
+        let dir = tempdir().unwrap();
+        let store = SledDb::builder(dir.path()).bloom(true).open().unwrap();
+        store.hset("t1", "k1", "v1").unwrap();
+        drop(store);
+        let store = SledDb::builder(dir.path()).bloom(true).open().unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+    }
+
+    #[test]
+    fn sleddb_builder_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::builder(dir.path())
+            .cache_capacity(1 << 20)
+            .flush_every(None)
+            .mode(SledMode::Fast)
+            .open()
+            .unwrap();
+        store.hset("t1", "k1", "v1").unwrap();
+        // 同一个目录已经被打开了，返回错误而不是 panic
+        assert!(SledDb::new(dir.path()).is_err());
+        drop(store);
+        let store = SledDb::new(dir.path()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+    }
+
+    #[test]
+    fn sleddb_should_detect_corrupted_values() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        store.hset("t1", "k1", "v1").unwrap();
+        store.incr("t1", "n", 42).unwrap();
+        drop(store);
+
+        // 模拟磁盘上的一位翻转
+        let db = sled::open(dir.path()).unwrap();
+        let tree = db.open_tree("t1").unwrap();
+        for key in ["k1", "n"] {
+            let mut data = tree.get(key).unwrap().unwrap().to_vec();
+            data[3] ^= 0x10;
+            tree.insert(key, data).unwrap();
+        }
+        drop((tree, db));
+
+        let store = SledDb::new(dir.path()).unwrap();
+        let corrupted = |r: Result<_, KvError>| matches!(r, Err(KvError::DataCorruption(_)));
+        assert!(corrupted(store.get("t1", "k1").map(|_| ())));
+        // HINCRBY 写入的 value 也带着 checksum
+        assert!(corrupted(store.get("t1", "n").map(|_| ())));
+        // 遍历的时候也不会悄悄地跳过
+        assert!(corrupted(store.get_all("t1").map(|_| ())));
+        assert!(corrupted(store.get_iter("t1").map(|_| ())));
+        assert!(corrupted(store.scan("t1", None, None, 10).map(|_| ())));
+        let range = (Bound::Unbounded, Bound::Unbounded);
+        assert!(corrupted(store.get_range("t1", range).map(|_| ())));
+    }
+
+    #[test]
+    fn sleddb_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_get_all(store);
+    }
+
+    #[test]
+    fn sleddb_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn sleddb_admin_should_report_storage() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        store.hset("t1", "k1", "v1").unwrap();
+
+        let res = store.admin(FLUSH_COMMAND, &[]).unwrap();
+        assert_eq!(res[0].key, "flushed");
+        let res = store.admin(STORAGE_COMMAND, &[]).unwrap();
+        assert_eq!(res[1], Kvpair::new("keys", 1.into()));
+        assert!(matches!(
+            store.admin(COMPACT_COMMAND, &[]),
+            Err(KvError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn sleddb_tables_should_not_share_keys() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        // 以前 "a:b" 表里的 "c" 和 "a" 表里的 "b:c" 是同一个 key
+        store.hset("a:b", "c", "v1").unwrap();
+        store.hset("a", "b:c", "v2").unwrap();
+        store.expire_at("a", "b:c", now_ms() + 60_000).unwrap();
+        assert_eq!(store.get("a:b", "c").unwrap(), Some("v1".into()));
+        assert_eq!(store.deadline("a:b", "c").unwrap(), None);
+        assert_eq!(store.list_tables().unwrap(), ["a", "a:b"]);
+
+        // 名字和 sled 内部的 tree 一样也没有关系
+        store.hset("__sled__default", "k1", "v1").unwrap();
+        assert_eq!(store.drop_table("a").unwrap(), 1);
+        assert_eq!(store.len("a:b").unwrap(), 1);
+        assert_eq!(store.list_tables().unwrap(), ["__sled__default", "a:b"]);
+        assert_eq!(store.flush_all().unwrap(), 2);
+        assert!(store.list_tables().unwrap().is_empty());
+    }
+
+    #[test]
+    fn sleddb_reads_should_not_create_tables() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        let trees = |store: &SledDb| {
+            let metrics = store.metrics().unwrap();
+            let trees = metrics.backend.iter().find(|p| p.key == "sled.trees");
+            trees.unwrap().value.clone()
+        };
+        let before = trees(&store);
+
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert!(!store.contains("t1", "k1").unwrap());
+        assert_eq!(store.len("t1").unwrap(), 0);
+        assert!(store.get_all("t1").unwrap().is_empty());
+        assert!(store.scan("t1", None, None, 10).unwrap().is_empty());
+        assert_eq!(store.del("t1", "k1").unwrap(), None);
+        assert_eq!(store.version("t1", "k1").unwrap(), None);
+        assert!(!store.expire_at("t1", "k1", now_ms() + 1000).unwrap());
+        assert_eq!(store.move_key("t1", "t2", "k1", false).unwrap(), None);
+        assert_eq!(store.drop_table("t1").unwrap(), 0);
+        assert_eq!(trees(&store), before);
+
+        // 写入才创建 tree
+        store.hset("t1", "k1", "v1").unwrap();
+        assert_ne!(trees(&store), before);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+    }
+
+    #[test]
+    fn sleddb_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_incr(store);
+    }
+
+    #[test]
+    fn sleddb_set_nx_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_set_nx(store);
+    }
+
+    #[test]
+    fn sleddb_len_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_len(store);
+    }
+
+    #[test]
+    fn sleddb_versions_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_versions(store);
+    }
+
+    #[test]
+    fn sleddb_scan_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_scan(store);
+    }
+
+    #[test]
+    fn sleddb_get_iter_matching_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_get_iter_matching(store);
+    }
+
+    #[test]
+    fn sleddb_expire_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_expire(store);
+    }
+
+    #[test]
+    fn sleddb_move_key_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_move_key(store);
+    }
+
+    #[test]
+    fn sleddb_clone_table_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_clone_table(store);
+    }
+
+    #[test]
+    fn bitcask_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStore::new(dir.path());
+        test_basic_interface(store);
+    }
+
+    #[test]
+    fn bitcask_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStore::new(dir.path());
+        test_get_all(store);
+    }
+
+    #[test]
+    fn bitcask_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStore::new(dir.path());
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn bitcask_list_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStore::new(dir.path());
+        test_list_tables(store);
+    }
+
+    #[test]
+    fn bitcask_stats_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStore::new(dir.path());
+        test_stats(store);
+    }
+
+    #[test]
+    fn bitcask_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStore::new(dir.path());
+        test_incr(store);
+    }
+
+    #[test]
+    fn bitcask_set_nx_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStore::new(dir.path());
+        test_set_nx(store);
+    }
+
+    #[test]
+    fn bitcask_get_iter_matching_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStore::new(dir.path());
+        test_get_iter_matching(store);
+    }
+
+    #[test]
+    fn bitcask_clone_table_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStore::new(dir.path());
+        test_clone_table(store);
+    }
+
+    #[test]
+    fn sharded_basic_interface_should_work() {
+        test_basic_interface(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_get_all_should_work() {
+        test_get_all(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_iter_should_work() {
+        test_get_iter(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_clone_table_should_work() {
+        test_clone_table(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_list_tables_should_work() {
+        test_list_tables(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_stats_should_work() {
+        test_stats(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_flush_all_should_work() {
+        test_flush_all(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_drop_table_should_work() {
+        test_drop_table(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_incr_should_work() {
+        test_incr(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_set_nx_should_work() {
+        test_set_nx(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_len_should_work() {
+        test_len(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_versions_should_work() {
+        test_versions(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_scan_should_work() {
+        test_scan(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_get_iter_matching_should_work() {
+        test_get_iter_matching(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_expire_should_work() {
+        test_expire(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn sharded_move_key_should_work() {
+        test_move_key(ShardedMemTable::with_shards(4));
+    }
+
+    #[test]
+    fn lru_basic_interface_should_work() {
+        test_basic_interface(LruMemTable::default());
+    }
+
+    #[test]
+    fn lru_get_all_should_work() {
+        test_get_all(LruMemTable::default());
+    }
+
+    #[test]
+    fn lru_iter_should_work() {
+        test_get_iter(LruMemTable::default());
+    }
+
+    #[test]
+    fn lru_list_tables_should_work() {
+        test_list_tables(LruMemTable::default());
+    }
+
+    #[test]
+    fn lru_stats_should_work() {
+        test_stats(LruMemTable::default());
+    }
+
+    #[test]
+    fn lru_incr_should_work() {
+        test_incr(LruMemTable::default());
+    }
+
+    #[test]
+    fn lru_set_nx_should_work() {
+        test_set_nx(LruMemTable::default());
+    }
+
+    #[test]
+    fn lru_get_iter_matching_should_work() {
+        test_get_iter_matching(LruMemTable::default());
+    }
+
+    #[test]
+    fn lru_clone_table_should_work() {
+        test_clone_table(LruMemTable::default());
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_basic_interface(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_get_all(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_get_iter(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_list_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_list_tables(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_stats_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_stats(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_flush_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_flush_all(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_drop_table_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_drop_table(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_incr(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_set_nx_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_set_nx(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_len_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_len(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_versions_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_versions(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_scan_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_scan(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_get_iter_matching_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_get_iter_matching(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_expire_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_expire(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_move_key_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_move_key(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_clone_table_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        test_clone_table(store);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_iter_should_read_in_batches() {
+        let dir = tempdir().unwrap();
+        let store = LmdbStore::new(dir.path());
+        for i in 0..300 {
+            store.hset("t1", format!("k{:03}", i), i).unwrap();
+        }
+        store.hset("t1:x", "k000", "other").unwrap();
+        let keys: Vec<_> = store.get_iter("t1").unwrap().map(|p| p.key).collect();
+        assert_eq!(keys.len(), 300);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        let pairs = store.scan("t1", Some("k199"), None, 2).unwrap();
+        assert_eq!(pairs[0], Kvpair::new("k200", 200.into()));
+        assert_eq!(store.list_tables().unwrap(), ["t1", "t1:x"]);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn memtable_set_get_should_roundtrip(
+            pairs in proptest::collection::vec(crate::strategies::kvpair(), 1..16)
+        ) {
+            test_set_get_roundtrip(MemTable::new(), pairs);
+        }
+
+        #[test]
+        fn sleddb_set_get_should_roundtrip(
+            pairs in proptest::collection::vec(crate::strategies::kvpair(), 1..16)
+        ) {
+            let dir = tempdir().unwrap();
+            test_set_get_roundtrip(SledDb::new(dir).unwrap(), pairs);
+        }
+    }
+
+    fn test_set_get_roundtrip(store: impl Storage, pairs: Vec<Kvpair>) {
+        let mut expected = std::collections::HashMap::new();
+        for pair in pairs {
+            let value = pair.value.unwrap();
+            store.set("t1", pair.key.clone(), value.clone()).unwrap();
+            expected.insert(pair.key, value);
+        }
+        for (key, value) in expected {
+            assert_eq!(store.get("t1", &key).unwrap(), Some(value));
+        }
+    }
+
+    fn test_clone_table(store: impl Storage) {
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+        assert_eq!(store.clone_table("t1", "t2").unwrap(), 2);
+
+        // 复制之后两个 table 互不影响
+        store.hset("t1", "k1", "changed").unwrap();
+        assert_eq!(store.get("t2", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t2", "k2").unwrap(), Some("v2".into()));
+
+        assert!(store.clone_table("t1", "t2").is_err());
+        assert!(store.clone_table("t1", "t1").is_err());
+    }
+
+    fn test_list_tables(store: impl Storage) {
+        assert!(store.list_tables().unwrap().is_empty());
+        store.hset("t2", "k1", "v1").unwrap();
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+        store.hset("t10", "k1", "v1").unwrap();
+        // 读一个不存在的 table 不会创建它
+        store.get("t3", "k1").unwrap();
+        assert_eq!(store.list_tables().unwrap(), ["t1", "t10", "t2"]);
+
+        store.drop_table("t10").unwrap();
+        assert_eq!(store.list_tables().unwrap(), ["t1", "t2"]);
+    }
+
+    fn test_stats(store: impl Storage) {
+        assert!(store.stats().unwrap().is_empty());
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", 42).unwrap();
+        store.hset("t10", "key", "value").unwrap();
+        store.del("t1", "k2").unwrap();
+        store.hset("t2", "k1", "v1").unwrap();
+        store.del("t2", "k1").unwrap();
+
+        let size = |key: &str, value: Value| key.len() + value.encoded_len();
+        let stats = store.stats().unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                TableStats {
+                    table: "t1".into(),
+                    keys: 1,
+                    bytes: size("k1", "v1".into()),
+                },
+                TableStats {
+                    table: "t10".into(),
+                    keys: 1,
+                    bytes: size("key", "value".into()),
+                },
+            ]
+        );
+    }
+
+    fn test_flush_all(store: impl Storage) {
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+        store.hset("t2", "k1", "v1").unwrap();
+        store.expire_at("t1", "k2", now_ms() - 1).unwrap();
+        store.expire_at("t2", "k1", now_ms() + 60_000).unwrap();
+        assert_eq!(store.flush_all().unwrap(), 2);
+
+        assert!(store.list_tables().unwrap().is_empty());
+        assert_eq!(store.flush_all().unwrap(), 0);
+        store.hset("t2", "k1", "v1").unwrap();
+        assert_eq!(store.deadline("t2", "k1").unwrap(), None);
+    }
+
+    fn test_drop_table(store: impl Storage) {
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+        store.hset("t10", "k1", "v1").unwrap();
+        store.expire_at("t1", "k2", now_ms() + 60_000).unwrap();
+        assert_eq!(store.drop_table("t1").unwrap(), 2);
+
+        assert!(store.get_all("t1").unwrap().is_empty());
+        assert_eq!(store.drop_table("t1").unwrap(), 0);
+        // 名字以 t1 开头的 table 不受影响
+        assert_eq!(store.get("t10", "k1").unwrap(), Some("v1".into()));
+        // 过期时间也一起删掉了，重新写入的 key 不会过期
+        store.hset("t1", "k2", "v2").unwrap();
+        assert_eq!(store.deadline("t1", "k2").unwrap(), None);
+    }
+
+    fn test_move_key(store: impl Storage) {
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+        store.hset("t2", "k2", "old").unwrap();
+
+        assert_eq!(
+            store.move_key("t1", "t2", "k1", false).unwrap(),
+            Some("v1".into())
+        );
+        assert!(!store.contains("t1", "k1").unwrap());
+        assert_eq!(store.get("t2", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.move_key("t1", "t2", "k1", true).unwrap(), None);
+
+        // 目标已经存在时，不 force 就什么也不改
+        assert!(store.move_key("t1", "t2", "k2", false).is_err());
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+        assert_eq!(store.get("t2", "k2").unwrap(), Some("old".into()));
+
+        store.move_key("t1", "t2", "k2", true).unwrap();
+        assert_eq!(store.get("t2", "k2").unwrap(), Some("v2".into()));
+        assert!(store.move_key("t1", "t1", "k2", true).is_err());
+    }
+
+    fn test_expire(store: impl Storage) {
+        let now = now_ms();
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+        assert!(!store.expire_at("t1", "k3", now + 1000).unwrap());
+        assert!(store.deadline("t1", "k3").is_err());
+
+        assert!(store.expire_at("t1", "k1", now + 60_000).unwrap());
+        assert_eq!(store.deadline("t1", "k1").unwrap(), Some(now + 60_000));
+        assert!(store.persist("t1", "k1").unwrap());
+        assert!(!store.persist("t1", "k1").unwrap());
+        assert_eq!(store.deadline("t1", "k1").unwrap(), None);
+
+        // 重新写入会清除过期时间
+        store.expire_at("t1", "k1", now + 60_000).unwrap();
+        store.hset("t1", "k1", "v1").unwrap();
+        assert_eq!(store.deadline("t1", "k1").unwrap(), None);
+
+        // 过期的 key 读不到，也不出现在 get_all 里
+        store.expire_at("t1", "k2", now_ms() + 50).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+        assert_eq!(store.get_all("t1").unwrap().len(), 1);
+
+        store.hset("t1", "k3", "v3").unwrap();
+        store.expire_at("t1", "k3", now_ms() - 1).unwrap();
+        assert!(!store.contains("t1", "k3").unwrap());
+        store.hset("t1", "k4", "v4").unwrap();
+        store.expire_at("t1", "k4", now_ms() + 50).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(store.purge_expired().unwrap(), 1);
+        assert_eq!(store.get_all("t1").unwrap().len(), 1);
+    }
+
+    fn test_len(store: impl Storage) {
+        assert_eq!(store.len("t1").unwrap(), 0);
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+        store.hset("t2", "k1", "v1").unwrap();
+        assert_eq!(store.len("t1").unwrap(), 2);
+
+        // 过期的 key 不算
+        store.expire_at("t1", "k2", now_ms() - 1).unwrap();
+        store.hset("t1", "k3", "v3").unwrap();
+        store.expire_at("t1", "k3", now_ms() + 60_000).unwrap();
+        assert_eq!(store.len("t1").unwrap(), 2);
+        store.del("t1", "k1").unwrap();
+        assert_eq!(store.len("t1").unwrap(), 1);
+    }
+
+    fn test_versions(store: impl Storage) {
+        assert_eq!(store.version("t1", "k1").unwrap(), None);
+        // 0 表示必须不存在
+        let v1 = store.hsetver("t1", "k1", "v1", 0).unwrap();
+        assert!(store.hsetver("t1", "k1", "v1", 0).is_err());
+        assert_eq!(store.version("t1", "k1").unwrap(), Some(v1));
+
+        let v2 = store.hsetver("t1", "k1", "v2", v1).unwrap();
+        assert!(v2 > v1);
+        assert!(matches!(
+            store.hsetver("t1", "k1", "v3", v1),
+            Err(KvError::Conflict(_))
+        ));
+
+        // 普通的写入也会让旧的 version 失效
+        store.hset("t1", "k1", "v3").unwrap();
+        let v3 = store.version("t1", "k1").unwrap().unwrap();
+        assert!(v3 > v2);
+        assert!(store.hsetver("t1", "k1", "v4", v2).is_err());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v3".into()));
+
+        // 写回一样的 value 也算一次写入，读到的 version 不会因为 value 相同又变得有效
+        store.hset("t1", "k1", "v4").unwrap();
+        store.hset("t1", "k1", "v3").unwrap();
+        assert!(matches!(
+            store.hsetver("t1", "k1", "v4", v3),
+            Err(KvError::Conflict(_))
+        ));
+        let v3 = store.version("t1", "k1").unwrap().unwrap();
+
+        // 删除之后重新写入，version 也不会回到以前
+        store.del("t1", "k1").unwrap();
+        assert_eq!(store.version("t1", "k1").unwrap(), None);
+        store.hset("t1", "k1", "v5").unwrap();
+        assert!(store.version("t1", "k1").unwrap().unwrap() > v3);
+
+        // 过期的 key 当作不存在
+        store.hset("t1", "k2", "v1").unwrap();
+        store.expire_at("t1", "k2", now_ms() + 20).unwrap();
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(store.version("t1", "k2").unwrap(), None);
+        store.hset("t1", "k3", "v1").unwrap();
+        store.expire_at("t1", "k3", now_ms() + 20).unwrap();
+        thread::sleep(Duration::from_millis(40));
+        store.hsetver("t1", "k3", "v2", 0).unwrap();
+        assert_eq!(store.deadline("t1", "k3").unwrap(), None);
+    }
+
+    fn test_scan(store: impl Storage) {
+        for i in 0..5 {
+            store.hset("t1", format!("k{}", i), i).unwrap();
+        }
+        store.hset("t2", "k0", 0).unwrap();
+        store.hset("t10", "k0", 0).unwrap();
+
+        let keys = |pairs: Vec<Kvpair>| pairs.into_iter().map(|p| p.key).collect::<Vec<_>>();
+        assert_eq!(keys(store.scan("t1", None, None, 2).unwrap()), ["k0", "k1"]);
+        let page = store.scan("t1", Some("k1"), None, 2).unwrap();
+        assert_eq!(keys(page), ["k2", "k3"]);
+        assert_eq!(keys(store.scan("t1", Some("k3"), None, 2).unwrap()), ["k4"]);
+        assert!(store.scan("t1", Some("k4"), None, 2).unwrap().is_empty());
+
+        // 过期的 key 跳过
+        store.expire_at("t1", "k2", now_ms() - 1).unwrap();
+        let page = store.scan("t1", Some("k1"), None, 2).unwrap();
+        assert_eq!(keys(page), ["k3", "k4"]);
+
+        // count 是匹配之后的数量
+        let pattern = Glob::new("k[14]").unwrap();
+        let page = store.scan("t1", None, Some(&pattern), 2).unwrap();
+        assert_eq!(keys(page), ["k1", "k4"]);
+    }
+
+    fn test_get_iter_matching(store: impl Storage) {
+        store.hset("t1", "user:1:profile", "p1").unwrap();
+        store.hset("t1", "user:1:settings", "s1").unwrap();
+        store.hset("t1", "user:2:profile", "p2").unwrap();
+        store.hset("t2", "user:3:profile", "p3").unwrap();
+
+        let pattern = Glob::new("user:*:profile").unwrap();
+        let mut pairs: Vec<_> = store.get_iter_matching("t1", &pattern).unwrap().collect();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(
+            pairs,
+            vec![
+                Kvpair::new("user:1:profile", "p1".into()),
+                Kvpair::new("user:2:profile", "p2".into()),
+            ]
+        );
+    }
+
+    fn test_incr(store: impl Storage) {
+        assert_eq!(store.incr("t1", "k1", 3).unwrap(), 3);
+        assert_eq!(store.incr("t1", "k1", -1).unwrap(), 2);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(2.into()));
+
+        // 不是整数或者溢出时什么也不改
+        store.hset("t1", "k2", "v2").unwrap();
+        assert!(store.incr("t1", "k2", 1).is_err());
+        assert!(store.incr("t1", "k1", i64::MAX).is_err());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(2.into()));
+    }
+
+    fn test_snapshot(store: impl Storage) {
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+        let snapshot = store.snapshot("t1").unwrap();
+        store.hset("t1", "k1", "v3").unwrap();
+        store.del("t1", "k2").unwrap();
+        store.hset("t1", "k3", "v3").unwrap();
+
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot.get("k1"), Some("v1".into()));
+        assert_eq!(snapshot.get("k3"), None);
+        let mut pairs: Vec<_> = snapshot.iter().collect();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(
+            pairs,
+            vec![
+                Kvpair::new("k1", "v1".into()),
+                Kvpair::new("k2", "v2".into())
+            ]
+        );
+        assert_eq!(snapshot.into_pairs().count(), 2);
+    }
+
+    fn test_meta(store: impl Storage) {
+        assert_eq!(store.meta("t1", "k1").unwrap(), None);
+        store.hset("t1", "k1", "v1").unwrap();
+        let created = store.meta("t1", "k1").unwrap().unwrap();
+        assert_eq!(created.updates, 0);
+        assert_eq!(created.created_at, created.updated_at);
+
+        store.hset("t1", "k1", "v2").unwrap();
+        store.incr("t1", "k2", 1).unwrap();
+        store.incr("t1", "k2", 1).unwrap();
+        let meta = store.meta("t1", "k1").unwrap().unwrap();
+        assert_eq!(meta.created_at, created.created_at);
+        assert!(meta.updated_at >= created.updated_at);
+        assert_eq!(meta.updates, 1);
+        assert_eq!(store.meta("t1", "k2").unwrap().unwrap().updates, 1);
+
+        // 删除之后重新创建，从头开始记录
+        store.del("t1", "k1").unwrap();
+        assert_eq!(store.meta("t1", "k1").unwrap(), None);
+        assert!(store.hsetnx("t1", "k1", "v3").unwrap());
+        assert_eq!(store.meta("t1", "k1").unwrap().unwrap().updates, 0);
+
+        // 过期的 key 再写入也是创建
+        store.expire_at("t1", "k2", 1).unwrap();
+        assert_eq!(store.meta("t1", "k2").unwrap(), None);
+        store.hset("t1", "k2", 1).unwrap();
+        assert_eq!(store.meta("t1", "k2").unwrap().unwrap().updates, 0);
+
+        store.move_key("t1", "t2", "k2", false).unwrap();
+        assert_eq!(store.meta("t1", "k2").unwrap(), None);
+        assert!(store.meta("t2", "k2").unwrap().is_some());
+        store.drop_table("t2").unwrap();
+        assert_eq!(store.meta("t2", "k2").unwrap(), None);
+    }
+
+    fn test_get_iter_ordered(store: impl Storage) {
+        for key in ["k3", "k1", "k4", "k2"] {
+            store.hset("t1", key, key).unwrap();
+        }
+        let keys = |order| -> Vec<String> {
+            let iter = store.get_iter_ordered("t1", order).unwrap();
+            iter.map(|p| p.key).collect()
+        };
+        assert_eq!(keys(Order::Ascending), ["k1", "k2", "k3", "k4"]);
+        assert_eq!(keys(Order::Descending), ["k4", "k3", "k2", "k1"]);
+        assert_eq!(keys(Order::Unordered).len(), 4);
+
+        // 过期的 key 不会出现在降序遍历里
+        store.expire_at("t1", "k4", 1).unwrap();
+        assert_eq!(keys(Order::Descending), ["k3", "k2", "k1"]);
+
+        assert_eq!(Order::from_sign(-5), Order::Descending);
+        assert_eq!(Order::from_sign(Order::Ascending.sign()), Order::Ascending);
+    }
+
+    fn test_get_range(store: impl Storage) {
+        for key in ["k4", "k1", "k3", "k2", "k5"] {
+            store.hset("t1", key, key).unwrap();
+        }
+        store.hset("t2", "k2", "other").unwrap();
+        let keys = |range: KeyRange| -> Vec<String> {
+            store
+                .get_range("t1", range)
+                .unwrap()
+                .map(|p| p.key)
+                .collect()
+        };
+
+        let range = (Bound::Included("k2"), Bound::Excluded("k4"));
+        assert_eq!(keys(range), ["k2", "k3"]);
+        let range = (Bound::Excluded("k2"), Bound::Included("k4"));
+        assert_eq!(keys(range), ["k3", "k4"]);
+        assert_eq!(
+            keys((Bound::Unbounded, Bound::Excluded("k3"))),
+            ["k1", "k2"]
+        );
+        assert_eq!(
+            keys((Bound::Included("k4"), Bound::Unbounded)),
+            ["k4", "k5"]
+        );
+        assert_eq!(keys((Bound::Unbounded, Bound::Unbounded)).len(), 5);
+
+        // 空的和反过来的 range 什么都不返回
+        assert!(keys((Bound::Included("k3"), Bound::Excluded("k3"))).is_empty());
+        assert!(keys((Bound::Included("k4"), Bound::Included("k2"))).is_empty());
+        let pairs: Vec<_> = store
+            .get_range("t1", (Bound::Included("k3"), Bound::Included("k3")))
+            .unwrap()
+            .collect();
+        assert_eq!(pairs, vec![Kvpair::new("k3", "k3".into())]);
+    }
+
+    fn test_batch(store: impl Storage) {
+        store.hset("t1", "k1", "v1").unwrap();
+        let pairs = vec![Kvpair::new("k1", "v2".into()), Kvpair::new("k2", 2.into())];
+        assert_eq!(store.mset("t1", pairs).unwrap(), [Some("v1".into()), None]);
+
+        let keys = ["k1".to_string(), "k2".into(), "k3".into()];
+        assert_eq!(
+            store.mget("t1", &keys).unwrap(),
+            [Some("v2".into()), Some(2.into()), None]
+        );
+        assert_eq!(
+            store.mdel("t1", &keys).unwrap(),
+            [Some("v2".into()), Some(2.into()), None]
+        );
+        assert_eq!(store.len("t1").unwrap(), 0);
+    }
+
+    fn test_bulk_load(store: impl Storage) {
+        store.hset("t1", "k0", "old").unwrap();
+        let mut pairs =
+            (0..BULK_LOAD_BATCH + 1).map(|i| Kvpair::new(format!("k{}", i), (i as i64).into()));
+        let stats = store.bulk_load("t1", &mut pairs).unwrap();
+        assert_eq!(stats.keys, BULK_LOAD_BATCH as u64 + 1);
+        assert!(stats.bytes > stats.keys && stats.keys_per_sec() > 0.0);
+        assert_eq!(store.len("t1").unwrap(), BULK_LOAD_BATCH + 1);
+        assert_eq!(store.get("t1", "k0").unwrap(), Some(0.into()));
+        let stats = store.bulk_load("t1", &mut std::iter::empty()).unwrap();
+        assert_eq!(stats.keys, 0);
+    }
+
+    fn test_set_nx(store: impl Storage) {
+        assert!(store.hsetnx("t1", "k1", "v1").unwrap());
+        assert!(!store.hsetnx("t1", "k1", "v2").unwrap());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+
+        store.del("t1", "k1").unwrap();
+        assert!(store.hsetnx("t1", "k1", "v3").unwrap());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v3".into()));
     }
 
     fn test_basic_interface(store: impl Storage) {
         // 第一次set 会创建table, 插入key 并返回None(之前没值)
-        let v = store.set("t1", "hello", "world");
+        let v = store.hset("t1", "hello", "world");
         assert!(v.unwrap().is_none());
         // 再次set同样的key会更新,并返回之前的值
-        let v1 = store.set("t1", "hello", "world1");
+        let v1 = store.hset("t1", "hello", "world1");
         assert_eq!(v1.unwrap(), Some("world".into()));
 
         // get 存在的key会得到最新的值
@@ -126,8 +2379,8 @@ mod tests {
     }
 
     fn test_get_all(store: impl Storage) {
-        store.set("t2", "k1", "v1").unwrap();
-        store.set("t2", "k2", "v2").unwrap();
+        store.hset("t2", "k1", "v1").unwrap();
+        store.hset("t2", "k2", "v2").unwrap();
         let mut data = store.get_all("t2").unwrap();
         data.sort_by(|a, b| a.partial_cmp(b).unwrap());
         assert_eq!(
@@ -140,8 +2393,8 @@ mod tests {
     }
 
     fn test_get_iter(store: impl Storage) {
-        store.set("t2", "k1", "v1").unwrap();
-        store.set("t2", "k2", "v2").unwrap();
+        store.hset("t2", "k1", "v1").unwrap();
+        store.hset("t2", "k2", "v2").unwrap();
         let mut data: Vec<_> = store.get_iter("t2").unwrap().collect();
         data.sort_by(|a, b| a.partial_cmp(b).unwrap());
         assert_eq!(
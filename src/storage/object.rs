@@ -0,0 +1,411 @@
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+use crate::{Glob, KvError, Kvpair, Storage, StorageIter, Value};
+
+/// 对象存储的抽象：按名字整块地存取数据
+///
+/// S3 这样的对象存储适合放很大但很少读的 value，ObjectStorage 在它之上
+/// 实现了 Storage。
+pub trait ObjectStore: Send + Sync {
+    /// 写入一个对象，已存在就覆盖
+    fn put(&self, name: &str, data: Vec<u8>) -> Result<(), KvError>;
+    /// 读取一个对象，不存在返回 None
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, KvError>;
+    /// 删除一个对象，不存在也不报错
+    fn delete(&self, name: &str) -> Result<(), KvError>;
+    /// 列出所有的对象名
+    fn list(&self) -> Result<Vec<String>, KvError>;
+}
+
+/// 把对象存在本地目录里，一个对象一个文件。可以用来测试，
+/// 也可以把对象放在挂载的网络文件系统上
+#[derive(Debug)]
+pub struct FsObjectStore {
+    root: PathBuf,
+}
+
+impl FsObjectStore {
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self, KvError> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+}
+
+impl ObjectStore for FsObjectStore {
+    fn put(&self, name: &str, data: Vec<u8>) -> Result<(), KvError> {
+        // 先写临时文件再 rename，这样读的时候不会读到写了一半的对象
+        let tmp = self.root.join(format!(".{}.tmp", name));
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, self.root.join(name))?;
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, KvError> {
+        match fs::read(self.root.join(name)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn delete(&self, name: &str) -> Result<(), KvError> {
+        match fs::remove_file(self.root.join(name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, KvError> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if !name.starts_with('.') {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// S3 兼容的对象存储
+#[cfg(feature = "s3")]
+pub struct S3ObjectStore {
+    bucket: Box<s3::Bucket>,
+}
+
+#[cfg(feature = "s3")]
+impl S3ObjectStore {
+    /// endpoint 是 S3 兼容服务的地址，比如 `http://127.0.0.1:9000`（MinIO）
+    pub fn new(
+        bucket: &str,
+        region: &str,
+        endpoint: &str,
+        access_key: &str,
+        secret_key: &str,
+    ) -> Result<Self, KvError> {
+        let region = s3::Region::Custom {
+            region: region.into(),
+            endpoint: endpoint.into(),
+        };
+        let credentials =
+            s3::creds::Credentials::new(Some(access_key), Some(secret_key), None, None, None)
+                .map_err(|e| KvError::ObjectStoreError(e.to_string()))?;
+        let bucket = s3::Bucket::new(bucket, region, credentials)
+            .map_err(|e| KvError::ObjectStoreError(e.to_string()))?
+            .with_path_style();
+        Ok(Self { bucket })
+    }
+}
+
+#[cfg(feature = "s3")]
+impl ObjectStore for S3ObjectStore {
+    fn put(&self, name: &str, data: Vec<u8>) -> Result<(), KvError> {
+        let res = self.bucket.put_object(name, &data).map_err(s3_error)?;
+        s3_status(res.status_code())
+    }
+
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, KvError> {
+        match self.bucket.get_object(name) {
+            Ok(res) if res.status_code() == 404 => Ok(None),
+            Ok(res) => s3_status(res.status_code()).map(|_| Some(res.to_vec())),
+            Err(s3::error::S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Err(e) => Err(s3_error(e)),
+        }
+    }
+
+    fn delete(&self, name: &str) -> Result<(), KvError> {
+        let res = self.bucket.delete_object(name).map_err(s3_error)?;
+        match res.status_code() {
+            404 => Ok(()),
+            code => s3_status(code),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, KvError> {
+        let results = self.bucket.list(String::new(), None).map_err(s3_error)?;
+        Ok(results
+            .into_iter()
+            .flat_map(|r| r.contents.into_iter().map(|o| o.key))
+            .collect())
+    }
+}
+
+#[cfg(feature = "s3")]
+fn s3_error(e: s3::error::S3Error) -> KvError {
+    KvError::ObjectStoreError(e.to_string())
+}
+
+#[cfg(feature = "s3")]
+fn s3_status(code: u16) -> Result<(), KvError> {
+    match code {
+        200..=299 => Ok(()),
+        code => Err(KvError::ObjectStoreError(format!("S3 returned {}", code))),
+    }
+}
+
+/// 把 value 存在对象存储里的 Storage
+///
+/// 每个 kv pair 是一个对象，名字由 table 和 key 转义后拼成。本地在内存里维护一个
+/// 索引记录有哪些 key，打开时从对象存储 list 一次重建，这样 contains 和
+/// 遍历 key 都不需要访问远端，只有读写 value 时才会访问对象存储。
+pub struct ObjectStorage<O> {
+    store: Arc<O>,
+    index: DashMap<String, DashMap<String, ()>>,
+}
+
+impl<O: ObjectStore + 'static> ObjectStorage<O> {
+    pub fn new(store: O) -> Result<Self, KvError> {
+        let index: DashMap<String, DashMap<String, ()>> = DashMap::new();
+        for name in store.list()? {
+            if let Some((table, key)) = decode_name(&name) {
+                index.entry(table).or_default().insert(key, ());
+            }
+        }
+
+        Ok(Self {
+            store: Arc::new(store),
+            index,
+        })
+    }
+
+    fn indexed(&self, table: &str, key: &str) -> bool {
+        self.index
+            .get(table)
+            .map(|t| t.contains_key(key))
+            .unwrap_or(false)
+    }
+
+    fn load(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        match self.store.get(&encode_name(table, key))? {
+            Some(data) => Ok(Some(data.as_slice().try_into()?)),
+            None => Ok(None),
+        }
+    }
+
+    // 索引里 table 的 key，只看索引，不访问对象存储
+    fn keys(&self, table: &str, filter: impl Fn(&str) -> bool) -> Vec<String> {
+        match self.index.get(table) {
+            Some(t) => t
+                .iter()
+                .filter(|e| filter(e.key()))
+                .map(|e| e.key().clone())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    // 按需从对象存储里读取 value，读失败的 key 直接跳过
+    fn load_lazily(
+        &self,
+        table: &str,
+        keys: Vec<String>,
+    ) -> Box<dyn Iterator<Item = Kvpair> + Send> {
+        let store = self.store.clone();
+        let table = table.to_string();
+        let iter = keys.into_iter().filter_map(move |key| {
+            let data = store.get(&encode_name(&table, &key)).ok()??;
+            let value = Value::try_from(data.as_slice()).ok()?;
+            Some((key, value))
+        });
+        Box::new(StorageIter::new(iter))
+    }
+}
+
+impl<O: ObjectStore + 'static> Storage for ObjectStorage<O> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        if !self.indexed(table, key) {
+            return Ok(None);
+        }
+        self.load(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        // 只有索引里有这个 key 的时候才需要去远端取旧值
+        let old = self.get(table, &key)?;
+        let data: Vec<u8> = value.try_into()?;
+        self.store.put(&encode_name(table, &key), data)?;
+        self.index.entry(table.into()).or_default().insert(key, ());
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.indexed(table, key))
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let old = self.get(table, key)?;
+        if old.is_some() {
+            self.store.delete(&encode_name(table, key))?;
+            if let Some(t) = self.index.get(table) {
+                t.remove(key);
+            }
+        }
+        Ok(old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        Ok(self.get_iter(table)?.collect())
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        Ok(self.load_lazily(table, self.keys(table, |_| true)))
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self.index.get(table).map_or(0, |t| t.len()))
+    }
+
+    fn get_iter_matching(
+        &self,
+        table: &str,
+        pattern: &Glob,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        Ok(self.load_lazily(table, self.keys(table, |k| pattern.matches(k))))
+    }
+
+    // 在索引里选出这一批的 key，只读取它们的 value
+    fn scan(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        pattern: Option<&Glob>,
+        count: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let mut keys = self.keys(table, |k| {
+            after.is_none_or(|after| k > after) && pattern.is_none_or(|p| p.matches(k))
+        });
+        keys.sort_unstable();
+        keys.truncate(count);
+        Ok(self.load_lazily(table, keys).collect())
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables: Vec<_> = self
+            .index
+            .iter()
+            .filter(|e| !e.value().is_empty())
+            .map(|e| e.key().clone())
+            .collect();
+        tables.sort_unstable();
+        Ok(tables)
+    }
+
+    // 删除不需要读出 value
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        let mut n = 0;
+        for key in self.keys(table, |_| true) {
+            self.store.delete(&encode_name(table, &key))?;
+            if let Some(t) = self.index.get(table) {
+                t.remove(&key);
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+/// 对象名是 `table~key`，table 和 key 中除了字母数字和 `-_` 之外的字节
+/// 都转义成 `%XX`，这样分隔符不会出现在 table 里，名字也可以安全地当文件名
+fn encode_name(table: &str, key: &str) -> String {
+    format!("{}~{}", escape(table), escape(key))
+}
+
+fn decode_name(name: &str) -> Option<(String, String)> {
+    let (table, key) = name.split_once('~')?;
+    Some((unescape(table)?, unescape(key)?))
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StorageExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn object_name_should_roundtrip() {
+        for (table, key) in [("t1", "k1"), ("a~b", "c/d"), (".hidden", "%41"), ("表", "")] {
+            let name = encode_name(table, key);
+            assert!(!name.contains('/'));
+            assert!(!name.starts_with('.'));
+            assert_eq!(decode_name(&name), Some((table.into(), key.into())));
+        }
+    }
+
+    #[test]
+    fn object_storage_should_rebuild_index_on_open() {
+        let dir = tempdir().unwrap();
+        let store = ObjectStorage::new(FsObjectStore::new(dir.path()).unwrap()).unwrap();
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k/2", 2).unwrap();
+        drop(store);
+
+        let store = ObjectStorage::new(FsObjectStore::new(dir.path()).unwrap()).unwrap();
+        assert!(store.contains("t1", "k/2").unwrap());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get_all("t1").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn object_storage_should_use_index_for_tables() {
+        let dir = tempdir().unwrap();
+        let store = ObjectStorage::new(FsObjectStore::new(dir.path()).unwrap()).unwrap();
+        for key in ["k3", "k1", "k2"] {
+            store.hset("t1", key, key).unwrap();
+        }
+        store.hset("t2", "k1", "v1").unwrap();
+
+        assert_eq!(store.len("t1").unwrap(), 3);
+        let keys: Vec<_> = store
+            .scan("t1", Some("k1"), None, 1)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.key)
+            .collect();
+        assert_eq!(keys, ["k2"]);
+        assert_eq!(store.drop_table("t1").unwrap(), 3);
+        assert_eq!(store.list_tables().unwrap(), vec!["t2".to_string()]);
+        assert_eq!(
+            FsObjectStore::new(dir.path())
+                .unwrap()
+                .list()
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
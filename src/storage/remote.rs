@@ -0,0 +1,683 @@
+use std::collections::HashSet;
+use std::ops::Bound;
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+
+use http::StatusCode;
+use tokio::sync::mpsc;
+
+use super::check_clone_target;
+use crate::{
+    now_ms, BulkLoadStats, CircuitConfig, CommandRequest, CommandResponse, Connect, FailoverClient,
+    Glob, KeyMeta, KeyRange, KvError, Kvpair, Order, Snapshot, Storage, StorageMetrics, TableStats,
+    Value,
+};
+
+type Job = (
+    CommandRequest,
+    std_mpsc::Sender<Result<CommandResponse, KvError>>,
+);
+
+/// 把操作转发给另一个 kv server 的 Storage
+///
+/// Storage 的接口是同步的，而客户端是异步的，所以 RemoteStore 自己起一个线程，
+/// 在线程里的 runtime 上用 FailoverClient 访问上游，调用者阻塞等待结果，
+/// 和 sled 这样会阻塞在磁盘 IO 上的存储一样。所有请求共用一个连接，按顺序执行。
+pub struct RemoteStore {
+    tx: mpsc::UnboundedSender<Job>,
+}
+
+impl RemoteStore {
+    /// addrs 是上游集群的 endpoint，一个不可用时会自动切换到下一个
+    pub fn new<C>(connector: C, addrs: &[&str], config: CircuitConfig) -> Result<Self, KvError>
+    where
+        C: Connect + Send + 'static,
+    {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Job>();
+        let mut client = FailoverClient::new(connector, addrs, config);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        // RemoteStore 被 drop 后 channel 关闭，线程随之退出
+        thread::Builder::new()
+            .name("kv-remote-store".into())
+            .spawn(move || {
+                rt.block_on(async move {
+                    while let Some((cmd, reply)) = rx.recv().await {
+                        let _ = reply.send(client.execute(cmd).await);
+                    }
+                })
+            })?;
+
+        Ok(Self { tx })
+    }
+
+    fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let closed = || KvError::Internal("Remote store is closed".into());
+        let (reply, rx) = std_mpsc::channel();
+        self.tx.send((cmd, reply)).map_err(|_| closed())?;
+        rx.recv().map_err(|_| closed())?
+    }
+
+    /// 上游返回 404 时当作没有值
+    fn execute_optional(&self, cmd: CommandRequest) -> Result<Option<Value>, KvError> {
+        let res = self.execute(cmd)?;
+        if res.status == StatusCode::NOT_FOUND.as_u16() as u32 {
+            return Ok(None);
+        }
+        Ok(res.ok()?.first_value())
+    }
+    /// 上游返回的第一个 value 转换成 T
+    fn execute_as<T>(&self, cmd: CommandRequest) -> Result<T, KvError>
+    where
+        T: TryFrom<Value, Error = KvError>,
+    {
+        self.execute(cmd)?
+            .ok()?
+            .first_value()
+            .unwrap_or_default()
+            .try_into()
+    }
+
+    /// HMGET 这样的批量命令，空的 Value 表示没有值
+    fn execute_values(&self, cmd: CommandRequest) -> Result<Vec<Option<Value>>, KvError> {
+        let res = self.execute(cmd)?.ok()?;
+        Ok(res
+            .values
+            .into_iter()
+            .map(|v| Some(v).filter(|v| v.value.is_some()))
+            .collect())
+    }
+
+    /// 上游返回 404 时当作没有 kv pair
+    fn execute_pairs(&self, cmd: CommandRequest) -> Result<Option<Vec<Kvpair>>, KvError> {
+        let res = self.execute(cmd)?;
+        if res.status == StatusCode::NOT_FOUND.as_u16() as u32 {
+            return Ok(None);
+        }
+        Ok(Some(res.ok()?.pairs))
+    }
+}
+
+impl Storage for RemoteStore {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.execute_optional(CommandRequest::new_hget(table, key))
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.execute_optional(CommandRequest::new_hset(table, key, value))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.get(table, key)?.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.execute_optional(CommandRequest::new_hdel(table, key))
+    }
+
+    fn mget(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        self.execute_values(CommandRequest::new_hmget(table, keys.to_vec()))
+    }
+
+    fn mset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+        self.execute_values(CommandRequest::new_hmset(table, pairs))
+    }
+
+    fn mdel(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        self.execute_values(CommandRequest::new_hmdel(table, keys.to_vec()))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let res = self.execute(CommandRequest::new_hgetall(table))?;
+        Ok(res.ok()?.pairs)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        Ok(Box::new(self.get_all(table)?.into_iter()))
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self.execute_as::<i64>(CommandRequest::new_hlen(table))? as usize)
+    }
+
+    // HRANGE 的起点是闭的、终点是开的，紧挨着 s 的下一个 key 是 s 后面加上 \0
+    fn get_range(
+        &self,
+        table: &str,
+        range: KeyRange,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let start = match range.0 {
+            Bound::Included(s) => s.to_string(),
+            Bound::Excluded(s) => format!("{}\0", s),
+            Bound::Unbounded => String::new(),
+        };
+        let end = match range.1 {
+            Bound::Included(s) => format!("{}\0", s),
+            Bound::Excluded(s) => s.to_string(),
+            Bound::Unbounded => String::new(),
+        };
+        let res = self.execute(CommandRequest::new_hrange(table, start, end, 0))?;
+        Ok(Box::new(res.ok()?.pairs.into_iter()))
+    }
+
+    fn admin(&self, command: &str, args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        let res = self.execute(CommandRequest::new_admin(command, args.to_vec()))?;
+        Ok(res.ok()?.pairs)
+    }
+
+    fn undelete(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.execute_optional(CommandRequest::new_undelete(table, key))
+    }
+
+    fn purge_trash(&self, table: &str, all: bool) -> Result<usize, KvError> {
+        Ok(self.execute_as::<i64>(CommandRequest::new_purge_trash(table, all))? as usize)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<(u64, Value)>, KvError> {
+        let pairs = self
+            .execute_pairs(CommandRequest::new_hgetat(table, key, 0))?
+            .unwrap_or_default();
+        pairs
+            .into_iter()
+            .map(|p| match p.key.parse() {
+                Ok(version) => Ok((version, p.value.unwrap_or_default())),
+                Err(_) => Err(KvError::Internal(format!("Bad version {}", p.key))),
+            })
+            .collect()
+    }
+
+    fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+        Ok(self.execute_as::<i64>(CommandRequest::new_clone_table(src, dst))? as usize)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let res = self.execute(CommandRequest::new_htables())?;
+        res.ok()?.values.into_iter().map(String::try_from).collect()
+    }
+
+    fn flush_all(&self) -> Result<usize, KvError> {
+        Ok(self.execute_as::<i64>(CommandRequest::new_hflushall())? as usize)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self.execute_as::<i64>(CommandRequest::new_hdroptable(table))? as usize)
+    }
+
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        self.execute_optional(CommandRequest::new_move(src, dst, key, force))
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.execute_as(CommandRequest::new_hincrby(table, key, delta))
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        self.execute_as(CommandRequest::new_hsetnx(table, key, value))
+    }
+
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        self.execute_as(CommandRequest::new_hexpireat(table, key, deadline))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.execute_as(CommandRequest::new_hpersist(table, key))
+    }
+
+    // 上游只返回剩下的毫秒数，换算回过期时间会有网络延迟那么多的误差
+    fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, KvError> {
+        match self.execute_as::<i64>(CommandRequest::new_httl(table, key))? {
+            -1 => Ok(None),
+            ttl => Ok(Some(now_ms() + ttl)),
+        }
+    }
+
+    fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        let res = self.execute(CommandRequest::new_hgetver(table, key))?;
+        if res.status == StatusCode::NOT_FOUND.as_u16() as u32 {
+            return Ok(None);
+        }
+        match res.ok()?.values.pop() {
+            Some(v) => Ok(Some(i64::try_from(v)? as u64)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        let res = self.execute(CommandRequest::new_hsetver(table, key, value, version))?;
+        if res.status == StatusCode::CONFLICT.as_u16() as u32 {
+            return Err(KvError::Conflict(res.message));
+        }
+        Ok(i64::try_from(res.ok()?.first_value().unwrap_or_default())? as u64)
+    }
+
+    fn meta(&self, table: &str, key: &str) -> Result<Option<KeyMeta>, KvError> {
+        let pairs = match self.execute_pairs(CommandRequest::new_hmeta(table, key))? {
+            Some(pairs) => pairs,
+            None => return Ok(None),
+        };
+        let mut meta = KeyMeta::default();
+        for p in pairs {
+            let n = i64::try_from(p.value.unwrap_or_default())?;
+            match p.key.as_str() {
+                "created_at" => meta.created_at = n,
+                "updated_at" => meta.updated_at = n,
+                "updates" => meta.updates = n as u64,
+                _ => {}
+            }
+        }
+        Ok(Some(meta))
+    }
+
+    fn query(
+        &self,
+        table: &str,
+        index: &str,
+        value: &Value,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let limit = u32::try_from(limit).unwrap_or(0);
+        let cmd = CommandRequest::new_hquery(table, index, value.clone(), limit);
+        Ok(self.execute(cmd)?.ok()?.pairs)
+    }
+}
+
+/// 按 table 把操作分给本地和远端两个 Storage
+///
+/// 指定的 table 交给 remote（通常是一个 RemoteStore），其他的留在本地，
+/// 这样一个本地实例可以透明地把部分 table 委托给上游的集群。
+pub struct DelegatingStore<L, R> {
+    local: L,
+    remote: R,
+    tables: HashSet<String>,
+}
+
+impl<L: Storage, R: Storage> DelegatingStore<L, R> {
+    pub fn new(local: L, remote: R, tables: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            local,
+            remote,
+            tables: tables.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    fn is_remote(&self, table: &str) -> bool {
+        self.tables.contains(table)
+    }
+
+    fn route(&self, table: &str) -> &dyn Storage {
+        match self.is_remote(table) {
+            true => &self.remote,
+            false => &self.local,
+        }
+    }
+}
+
+impl<L: Storage, R: Storage> Storage for DelegatingStore<L, R> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.route(table).get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.route(table).set(table, key, value)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.route(table).contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.route(table).del(table, key)
+    }
+
+    fn mget(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        self.route(table).mget(table, keys)
+    }
+
+    fn mset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+        self.route(table).mset(table, pairs)
+    }
+
+    fn mdel(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        self.route(table).mdel(table, keys)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.route(table).get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.route(table).get_iter(table)
+    }
+
+    fn get_iter_ordered(
+        &self,
+        table: &str,
+        order: Order,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.route(table).get_iter_ordered(table, order)
+    }
+
+    fn snapshot(&self, table: &str) -> Result<Box<dyn Snapshot>, KvError> {
+        self.route(table).snapshot(table)
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        self.route(table).len(table)
+    }
+
+    fn get_iter_matching(
+        &self,
+        table: &str,
+        pattern: &Glob,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.route(table).get_iter_matching(table, pattern)
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        pattern: Option<&Glob>,
+        count: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.route(table).scan(table, after, pattern, count)
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        range: KeyRange,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.route(table).get_range(table, range)
+    }
+
+    // 管理命令是本地 backend 的
+    fn admin(&self, command: &str, args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        self.local.admin(command, args)
+    }
+
+    fn undelete(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.route(table).undelete(table, key)
+    }
+
+    fn purge_trash(&self, table: &str, all: bool) -> Result<usize, KvError> {
+        self.route(table).purge_trash(table, all)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<(u64, Value)>, KvError> {
+        self.route(table).history(table, key)
+    }
+
+    // 两个 table 在不同的一边时一个个 key 复制过去
+    fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+        if self.is_remote(src) == self.is_remote(dst) {
+            return self.route(src).clone_table(src, dst);
+        }
+        check_clone_target(self, src, dst)?;
+        let mut n = 0;
+        for pair in self.get_iter(src)? {
+            self.set(dst, pair.key, pair.value.unwrap_or_default())?;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = self.local.list_tables()?;
+        tables.retain(|t| !self.is_remote(t));
+        let remote = self.remote.list_tables()?;
+        tables.extend(remote.into_iter().filter(|t| self.is_remote(t)));
+        tables.sort_unstable();
+        Ok(tables)
+    }
+
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        let mut stats = self.local.stats()?;
+        stats.retain(|s| !self.is_remote(&s.table));
+        let remote = self.remote.stats()?;
+        stats.extend(remote.into_iter().filter(|s| self.is_remote(&s.table)));
+        stats.sort_unstable_by(|a, b| a.table.cmp(&b.table));
+        Ok(stats)
+    }
+
+    // 上游的其它 table 不属于这个实例，只删掉委托给它的 table
+    fn flush_all(&self) -> Result<usize, KvError> {
+        let mut n = self.local.flush_all()?;
+        for table in &self.tables {
+            n += self.remote.drop_table(table)?;
+        }
+        Ok(n)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        self.route(table).drop_table(table)
+    }
+
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        if self.is_remote(src) != self.is_remote(dst) {
+            return Err(KvError::Unsupported(format!(
+                "MOVE between local table {} and remote table {}",
+                src, dst
+            )));
+        }
+        self.route(src).move_key(src, dst, key, force)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.route(table).incr(table, key, delta)
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        self.route(table).set_nx(table, key, value)
+    }
+
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        self.route(table).expire_at(table, key, deadline)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.route(table).persist(table, key)
+    }
+
+    fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, KvError> {
+        self.route(table).deadline(table, key)
+    }
+
+    // 上游的过期由上游自己清理
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.local.purge_expired()
+    }
+
+    fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        self.route(table).version(table, key)
+    }
+
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        self.route(table).set_if_version(table, key, value, version)
+    }
+
+    fn meta(&self, table: &str, key: &str) -> Result<Option<KeyMeta>, KvError> {
+        self.route(table).meta(table, key)
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.local.flush()
+    }
+
+    fn bulk_load(
+        &self,
+        table: &str,
+        pairs: &mut dyn Iterator<Item = Kvpair>,
+    ) -> Result<BulkLoadStats, KvError> {
+        self.route(table).bulk_load(table, pairs)
+    }
+
+    fn query(
+        &self,
+        table: &str,
+        index: &str,
+        value: &Value,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.route(table).query(table, index, value, limit)
+    }
+
+    fn metrics(&self) -> Result<StorageMetrics, KvError> {
+        Ok(StorageMetrics {
+            tables: self.stats()?,
+            ..self.local.metrics()?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, ProstServerStream, Service, ServiceInner, StorageExt, TcpConnector};
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    async fn start_upstream() -> (SocketAddr, Service) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let svc = service.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let svc = svc.clone();
+                tokio::spawn(ProstServerStream::new(stream, svc).process());
+            }
+        });
+        (addr, service)
+    }
+
+    // 调用者会阻塞当前线程，所以上游 server 要跑在别的 worker 上
+    #[tokio::test(flavor = "multi_thread")]
+    async fn remote_store_should_forward_to_upstream() {
+        let (addr, upstream) = start_upstream().await;
+        let addr = addr.to_string();
+        let store = RemoteStore::new(TcpConnector, &[&addr], CircuitConfig::default()).unwrap();
+
+        assert_eq!(store.hset("t1", "k1", "v1").unwrap(), None);
+        assert_eq!(store.hset("t1", "k1", "v2").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v2".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+        assert!(store.contains("t1", "k1").unwrap());
+        assert_eq!(store.get_iter("t1").unwrap().count(), 1);
+
+        // 数据确实写到了上游
+        assert_eq!(upstream.store().get("t1", "k1").unwrap(), Some("v2".into()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn delegating_store_should_route_by_table() {
+        let (addr, upstream) = start_upstream().await;
+        let addr = addr.to_string();
+        let remote = RemoteStore::new(TcpConnector, &[&addr], CircuitConfig::default()).unwrap();
+        let store = DelegatingStore::new(MemTable::new(), remote, ["shared"]);
+
+        store.hset("shared", "k1", "remote").unwrap();
+        store.hset("local", "k1", "local").unwrap();
+
+        assert_eq!(store.get("shared", "k1").unwrap(), Some("remote".into()));
+        assert_eq!(store.get("local", "k1").unwrap(), Some("local".into()));
+        assert!(upstream.store().contains("shared", "k1").unwrap());
+        assert!(!upstream.store().contains("local", "k1").unwrap());
+    }
+
+    #[test]
+    fn remote_store_should_fail_without_upstream() {
+        let store =
+            RemoteStore::new(TcpConnector, &["127.0.0.1:1"], CircuitConfig::default()).unwrap();
+        assert!(store.get("t1", "k1").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn remote_store_should_forward_other_commands() {
+        let (addr, upstream) = start_upstream().await;
+        let addr = addr.to_string();
+        let store = RemoteStore::new(TcpConnector, &[&addr], CircuitConfig::default()).unwrap();
+
+        assert_eq!(store.incr("t1", "n", 3).unwrap(), 3);
+        assert!(store.set_nx("t1", "k1".into(), "v1".into()).unwrap());
+        assert!(!store.set_nx("t1", "k1".into(), "v2".into()).unwrap());
+        assert_eq!(
+            store.mget("t1", &["k1".into(), "k2".into()]).unwrap(),
+            vec![Some("v1".into()), None]
+        );
+        assert_eq!(store.len("t1").unwrap(), 2);
+        assert_eq!(store.list_tables().unwrap(), vec!["t1".to_string()]);
+
+        let range = (Bound::Excluded("k1"), Bound::Included("n"));
+        let keys: Vec<_> = store
+            .get_range("t1", range)
+            .unwrap()
+            .map(|p| p.key)
+            .collect();
+        assert_eq!(keys, ["n"]);
+
+        assert!(store.expire_at("t1", "k1", now_ms() + 60_000).unwrap());
+        assert!(store.deadline("t1", "k1").unwrap().is_some());
+        let version = store.version("t1", "k1").unwrap().unwrap();
+        store
+            .set_if_version("t1", "k1".into(), "v3".into(), version)
+            .unwrap();
+        assert!(matches!(
+            store.set_if_version("t1", "k1".into(), "v4".into(), version),
+            Err(KvError::Conflict(_))
+        ));
+        assert_eq!(store.version("t1", "k2").unwrap(), None);
+        assert_eq!(upstream.store().get("t1", "k1").unwrap(), Some("v3".into()));
+        assert_eq!(store.drop_table("t1").unwrap(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn delegating_store_should_route_other_commands() {
+        let (addr, upstream) = start_upstream().await;
+        let addr = addr.to_string();
+        let remote = RemoteStore::new(TcpConnector, &[&addr], CircuitConfig::default()).unwrap();
+        let store = DelegatingStore::new(MemTable::new(), remote, ["shared"]);
+
+        assert_eq!(store.incr("shared", "n", 1).unwrap(), 1);
+        assert_eq!(store.incr("local", "n", 2).unwrap(), 2);
+        upstream.store().hset("other", "k1", "v1").unwrap();
+        assert_eq!(
+            store.list_tables().unwrap(),
+            vec!["local".to_string(), "shared".to_string()]
+        );
+        assert!(store.move_key("local", "shared", "n", false).is_err());
+        assert_eq!(store.clone_table("local", "shared2").unwrap(), 1);
+
+        // 上游别的 table 不受影响
+        assert_eq!(store.flush_all().unwrap(), 3);
+        assert!(upstream.store().contains("other", "k1").unwrap());
+        assert!(!upstream.store().contains("shared", "n").unwrap());
+    }
+}
@@ -0,0 +1,386 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::{BulkLoadStats, KvError, Kvpair, Storage, Value};
+
+// 写到 shadow 的都是操作的结果，比如 HINCRBY 之后的新值，这样两边的内容才一样
+enum ShadowOp {
+    Set(String, String, Value),
+    Del(String, String),
+    ExpireAt(String, String, i64),
+    Persist(String, String),
+    CloneTable(String, String),
+    DropTable(String),
+    FlushAll,
+}
+
+#[derive(Default)]
+struct Metrics {
+    applied: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+    pending: AtomicU64,
+    lag_us: AtomicU64,
+}
+
+/// 影子流量的统计
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShadowStats {
+    /// 已经写到 shadow 的操作数
+    pub applied: u64,
+    /// 在 shadow 上执行出错的操作数
+    pub failed: u64,
+    /// 队列满了被丢弃的操作数
+    pub dropped: u64,
+    /// 还在队列里等待的操作数
+    pub pending: u64,
+    /// 最近一个操作从进入队列到写完 shadow 花的时间
+    pub lag: Duration,
+}
+
+/// 把写操作复制一份到 shadow 存储的 Storage
+///
+/// 读写都由 primary 处理，写成功后再把同样的操作放进队列，由后台线程写到 shadow
+/// （另一个 backend，或者通过 RemoteStore 写到新的集群）。这是尽力而为的：
+/// shadow 出错或者跟不上时不会影响 primary，只会反映在 ShadowStats 里，
+/// 这样可以先用真实的流量验证新的 backend，再做切换。
+pub struct ShadowStore<S> {
+    primary: S,
+    tx: SyncSender<(Instant, ShadowOp)>,
+    metrics: Arc<Metrics>,
+}
+
+impl<S: Storage> ShadowStore<S> {
+    /// capacity 是队列的长度，超出后新的操作会被丢弃
+    pub fn new<W>(primary: S, shadow: W, capacity: usize) -> Result<Self, KvError>
+    where
+        W: Storage + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel::<(Instant, ShadowOp)>(capacity);
+        let metrics = Arc::new(Metrics::default());
+
+        let m = metrics.clone();
+        thread::Builder::new()
+            .name("kv-shadow".into())
+            .spawn(move || {
+                for (queued, op) in rx {
+                    let result = match op {
+                        ShadowOp::Set(table, key, value) => shadow.set(&table, key, value),
+                        ShadowOp::Del(table, key) => shadow.del(&table, &key),
+                        ShadowOp::ExpireAt(table, key, deadline) => {
+                            shadow.expire_at(&table, &key, deadline).map(|_| None)
+                        }
+                        ShadowOp::Persist(table, key) => shadow.persist(&table, &key).map(|_| None),
+                        ShadowOp::CloneTable(src, dst) => {
+                            shadow.clone_table(&src, &dst).map(|_| None)
+                        }
+                        ShadowOp::DropTable(table) => shadow.drop_table(&table).map(|_| None),
+                        ShadowOp::FlushAll => shadow.flush_all().map(|_| None),
+                    };
+                    m.pending.fetch_sub(1, Ordering::Relaxed);
+                    match result {
+                        Ok(_) => m.applied.fetch_add(1, Ordering::Relaxed),
+                        Err(e) => {
+                            warn!("Shadow write failed: {}", e);
+                            m.failed.fetch_add(1, Ordering::Relaxed)
+                        }
+                    };
+                    let lag = queued.elapsed().as_micros() as u64;
+                    m.lag_us.store(lag, Ordering::Relaxed);
+                }
+            })?;
+
+        Ok(Self {
+            primary,
+            tx,
+            metrics,
+        })
+    }
+
+    pub fn stats(&self) -> ShadowStats {
+        let m = &self.metrics;
+        ShadowStats {
+            applied: m.applied.load(Ordering::Relaxed),
+            failed: m.failed.load(Ordering::Relaxed),
+            dropped: m.dropped.load(Ordering::Relaxed),
+            pending: m.pending.load(Ordering::Relaxed),
+            lag: Duration::from_micros(m.lag_us.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn shadow(&self, op: ShadowOp) {
+        // 先加 pending，避免后台线程先处理完导致计数下溢
+        self.metrics.pending.fetch_add(1, Ordering::Relaxed);
+        match self.tx.try_send((Instant::now(), op)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.metrics.pending.fetch_sub(1, Ordering::Relaxed);
+                self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<S: Storage> Storage for ShadowStore<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.primary.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let old = self.primary.set(table, key.clone(), value.clone())?;
+        self.shadow(ShadowOp::Set(table.into(), key, value));
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.primary.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let old = self.primary.del(table, key)?;
+        self.shadow(ShadowOp::Del(table.into(), key.into()));
+        Ok(old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.primary.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.primary.get_iter(table)
+    }
+
+    fn mset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+        let olds = self.primary.mset(table, pairs.clone())?;
+        for pair in pairs {
+            let value = pair.value.unwrap_or_default();
+            self.shadow(ShadowOp::Set(table.into(), pair.key, value));
+        }
+        Ok(olds)
+    }
+
+    fn mdel(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        let olds = self.primary.mdel(table, keys)?;
+        for key in keys {
+            self.shadow(ShadowOp::Del(table.into(), key.clone()));
+        }
+        Ok(olds)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let n = self.primary.incr(table, key, delta)?;
+        self.shadow(ShadowOp::Set(table.into(), key.into(), n.into()));
+        Ok(n)
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        let written = self.primary.set_nx(table, key.clone(), value.clone())?;
+        if written {
+            self.shadow(ShadowOp::Set(table.into(), key, value));
+        }
+        Ok(written)
+    }
+
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        let version = self
+            .primary
+            .set_if_version(table, key.clone(), value.clone(), version)?;
+        self.shadow(ShadowOp::Set(table.into(), key, value));
+        Ok(version)
+    }
+
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        let moved = self.primary.move_key(src, dst, key, force)?;
+        if let Some(value) = &moved {
+            self.shadow(ShadowOp::Del(src.into(), key.into()));
+            self.shadow(ShadowOp::Set(dst.into(), key.into(), value.clone()));
+        }
+        Ok(moved)
+    }
+
+    fn undelete(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let restored = self.primary.undelete(table, key)?;
+        if let Some(value) = &restored {
+            self.shadow(ShadowOp::Set(table.into(), key.into(), value.clone()));
+        }
+        Ok(restored)
+    }
+
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        let exists = self.primary.expire_at(table, key, deadline)?;
+        if exists {
+            self.shadow(ShadowOp::ExpireAt(table.into(), key.into(), deadline));
+        }
+        Ok(exists)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let persisted = self.primary.persist(table, key)?;
+        if persisted {
+            self.shadow(ShadowOp::Persist(table.into(), key.into()));
+        }
+        Ok(persisted)
+    }
+
+    fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+        let n = self.primary.clone_table(src, dst)?;
+        self.shadow(ShadowOp::CloneTable(src.into(), dst.into()));
+        Ok(n)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        let n = self.primary.drop_table(table)?;
+        self.shadow(ShadowOp::DropTable(table.into()));
+        Ok(n)
+    }
+
+    fn flush_all(&self) -> Result<usize, KvError> {
+        let n = self.primary.flush_all()?;
+        self.shadow(ShadowOp::FlushAll);
+        Ok(n)
+    }
+
+    fn bulk_load(
+        &self,
+        table: &str,
+        pairs: &mut dyn Iterator<Item = Kvpair>,
+    ) -> Result<BulkLoadStats, KvError> {
+        let mut pairs = pairs.inspect(|pair| {
+            let value = pair.value.clone().unwrap_or_default();
+            self.shadow(ShadowOp::Set(table.into(), pair.key.clone(), value));
+        });
+        self.primary.bulk_load(table, &mut pairs)
+    }
+
+    // 过期由两边各自处理；import 用缺省实现，经过上面的 set 复制到 shadow
+    forward_to!(primary;
+        mget, get_iter_ordered, snapshot, len, get_iter_matching, scan, get_range, admin,
+        purge_trash, history, list_tables, stats, deadline, purge_expired, version, meta,
+        flush, query, export, metrics);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, StorageExt};
+
+    fn wait_applied<S: Storage>(store: &ShadowStore<S>, n: u64) -> ShadowStats {
+        for _ in 0..100 {
+            let stats = store.stats();
+            if stats.applied + stats.failed >= n {
+                return stats;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        store.stats()
+    }
+
+    #[test]
+    fn shadow_store_should_copy_mutations() {
+        let shadow = Arc::new(MemTable::new());
+        let store = ShadowStore::new(MemTable::new(), shadow.clone(), 16).unwrap();
+
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+        store.del("t1", "k1").unwrap();
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+
+        let stats = wait_applied(&store, 3);
+        assert_eq!(stats.applied, 3);
+        assert_eq!(stats.pending, 0);
+        assert_eq!(shadow.get("t1", "k1").unwrap(), None);
+        assert_eq!(shadow.get("t1", "k2").unwrap(), Some("v2".into()));
+    }
+
+    /// set 会一直阻塞到 gate 被放开的 shadow
+    struct Gated(Arc<std::sync::Mutex<()>>, MemTable);
+
+    impl Storage for Gated {
+        fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            self.1.get(table, key)
+        }
+        fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+            let _gate = self.0.lock().unwrap();
+            self.1.set(table, key, value)
+        }
+        fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+            self.1.contains(table, key)
+        }
+        fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            self.1.del(table, key)
+        }
+        fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+            self.1.get_all(table)
+        }
+        fn get_iter(
+            &self,
+            table: &str,
+        ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+            self.1.get_iter(table)
+        }
+    }
+
+    #[test]
+    fn shadow_store_should_drop_when_queue_is_full() {
+        let gate = Arc::new(std::sync::Mutex::new(()));
+        let guard = gate.lock().unwrap();
+        let store =
+            ShadowStore::new(MemTable::new(), Gated(gate.clone(), MemTable::new()), 4).unwrap();
+
+        // shadow 卡住时，最多一个在执行，4 个在队列里，剩下的都被丢弃
+        for i in 0..10 {
+            store.hset("t1", format!("k{}", i), i as i64).unwrap();
+        }
+        assert!(store.stats().dropped >= 5);
+        // primary 不受影响
+        assert_eq!(store.get_all("t1").unwrap().len(), 10);
+
+        drop(guard);
+        let stats = wait_applied(&store, 10 - store.stats().dropped);
+        assert_eq!(stats.applied + stats.dropped, 10);
+        assert_eq!(stats.pending, 0);
+    }
+
+    #[test]
+    fn atomic_commands_should_copy_their_results() {
+        let shadow = Arc::new(MemTable::new());
+        let primary = MemTable::new();
+        primary.hset("t1", "n", 10).unwrap();
+        let store = ShadowStore::new(primary, shadow.clone(), 16).unwrap();
+
+        assert_eq!(store.incr("t1", "n", 5).unwrap(), 15);
+        assert!(store.set_nx("t1", "k1".into(), "v1".into()).unwrap());
+        assert!(store.expire_at("t1", "k1", i64::MAX).unwrap());
+        assert_eq!(
+            store.move_key("t1", "t2", "n", false).unwrap(),
+            Some(15.into())
+        );
+        assert_eq!(store.len("t1").unwrap(), 1);
+
+        wait_applied(&store, 5);
+        assert_eq!(shadow.get("t2", "n").unwrap(), Some(15.into()));
+        assert_eq!(shadow.get("t1", "n").unwrap(), None);
+        assert_eq!(shadow.deadline("t1", "k1").unwrap(), Some(i64::MAX));
+
+        store.flush_all().unwrap();
+        wait_applied(&store, 6);
+        assert_eq!(shadow.list_tables().unwrap(), Vec::<String>::new());
+    }
+}
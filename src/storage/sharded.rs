@@ -0,0 +1,298 @@
+use std::collections::BTreeMap;
+
+use super::{check_clone_target, fingerprint, first_keys, Changefeed, STORAGE_COMMAND};
+use crate::{Glob, KeyRange, KvError, Kvpair, MemTable, Storage, TableStats, Value};
+
+/// ShardedMemTable 缺省的分片数
+pub const MEMTABLE_SHARDS: usize = 16;
+
+/// 分片的内存存储
+///
+/// MemTable 所有的读写都要先在外层的 DashMap 里找到 table，多个核同时写一个
+/// table 时都挤在外层的同一个分片上。ShardedMemTable 按 key 的 hash 把数据分到
+/// 固定数量的 MemTable 里，每个分片各自有外层的 map，写同一个 table 的不同 key
+/// 会落在不同的分片上。
+///
+/// 只用 key 而不是 table + key 计算分片：同一个 key 在所有 table 里都在同一个分片，
+/// MOVE 和 clone_table 不需要跨分片。遍历 table 的操作要访问所有的分片
+pub struct ShardedMemTable {
+    shards: Box<[MemTable]>,
+}
+
+impl ShardedMemTable {
+    pub fn new() -> Self {
+        Self::with_shards(MEMTABLE_SHARDS)
+    }
+
+    /// 指定分片数，至少一个
+    pub fn with_shards(n: usize) -> Self {
+        Self {
+            shards: (0..n.max(1)).map(|_| MemTable::new()).collect(),
+        }
+    }
+
+    /// 每个分片的过期事件都发到同一个 changefeed
+    pub fn with_changefeed(self, changefeed: Changefeed) -> Self {
+        let shards = self.shards.into_vec().into_iter();
+        Self {
+            shards: shards
+                .map(|s| s.with_changefeed(changefeed.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard(&self, key: &str) -> &MemTable {
+        let n = fingerprint(key.as_bytes()) % self.shards.len() as u64;
+        &self.shards[n as usize]
+    }
+
+    fn sum(&self, f: impl Fn(&MemTable) -> Result<usize, KvError>) -> Result<usize, KvError> {
+        self.shards.iter().map(f).sum()
+    }
+
+    fn concat(
+        &self,
+        f: impl Fn(&MemTable) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError>,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let iters = self.shards.iter().map(f).collect::<Result<Vec<_>, _>>()?;
+        Ok(Box::new(iters.into_iter().flatten()))
+    }
+}
+
+impl Default for ShardedMemTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 把每个分片返回的数字按名字加起来，保持第一个分片里的顺序
+fn sum_pairs(results: Vec<Vec<Kvpair>>) -> Vec<Kvpair> {
+    let mut names = Vec::new();
+    let mut sums = BTreeMap::new();
+    for pair in results.into_iter().flatten() {
+        let n = pair.value.and_then(|v| i64::try_from(v).ok()).unwrap_or(0);
+        match sums.get_mut(&pair.key) {
+            Some(sum) => *sum += n,
+            None => {
+                sums.insert(pair.key.clone(), n);
+                names.push(pair.key);
+            }
+        }
+    }
+    names
+        .into_iter()
+        .map(|name| {
+            let n = sums[&name];
+            Kvpair::new(name, n.into())
+        })
+        .collect()
+}
+
+impl Storage for ShardedMemTable {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.shard(key).get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let shard = self.shard(&key);
+        shard.set(table, key, value)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.shard(key).contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.shard(key).del(table, key)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let mut pairs = Vec::new();
+        for shard in self.shards.iter() {
+            pairs.extend(shard.get_all(table)?);
+        }
+        Ok(pairs)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.concat(|shard| shard.get_iter(table))
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        self.sum(|shard| shard.len(table))
+    }
+
+    fn get_iter_matching(
+        &self,
+        table: &str,
+        pattern: &Glob,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.concat(|shard| shard.get_iter_matching(table, pattern))
+    }
+
+    // 每个分片取前 count 个，合起来之后再取一次
+    fn scan(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        pattern: Option<&Glob>,
+        count: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let mut pairs = Vec::new();
+        for shard in self.shards.iter() {
+            pairs.extend(shard.scan(table, after, pattern, count)?);
+        }
+        Ok(first_keys(pairs, count))
+    }
+
+    // 每个分片各自有序，合起来之后再排一次
+    fn get_range(
+        &self,
+        table: &str,
+        range: KeyRange,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let mut pairs: Vec<Kvpair> = self
+            .concat(|shard| shard.get_range(table, range))?
+            .collect();
+        pairs.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        Ok(Box::new(pairs.into_iter()))
+    }
+
+    // 每个分片都执行一次，数字按名字加起来
+    fn admin(&self, command: &str, args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        let results = self
+            .shards
+            .iter()
+            .map(|shard| shard.admin(command, args))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut pairs = sum_pairs(results);
+        if command == STORAGE_COMMAND {
+            // 每个分片都有自己的 table，加起来的数字没有意义
+            let tables = self.list_tables()?.len() as i64;
+            pairs.retain(|p| p.key != "tables");
+            pairs.insert(0, Kvpair::new("tables", tables.into()));
+            pairs.push(Kvpair::new("shards", (self.shards() as i64).into()));
+        }
+        Ok(pairs)
+    }
+
+    fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+        check_clone_target(self, src, dst)?;
+        self.sum(|shard| shard.clone_table(src, dst))
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut names = Vec::new();
+        for shard in self.shards.iter() {
+            names.extend(shard.list_tables()?);
+        }
+        names.sort_unstable();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        let mut stats = BTreeMap::<String, TableStats>::new();
+        for shard in self.shards.iter() {
+            for s in shard.stats()? {
+                let total = stats.entry(s.table.clone()).or_insert_with(|| TableStats {
+                    table: s.table,
+                    ..Default::default()
+                });
+                total.keys += s.keys;
+                total.bytes += s.bytes;
+            }
+        }
+        Ok(stats.into_values().collect())
+    }
+
+    fn flush_all(&self) -> Result<usize, KvError> {
+        self.sum(MemTable::flush_all)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        self.sum(|shard| shard.drop_table(table))
+    }
+
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        self.shard(key).move_key(src, dst, key, force)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.shard(key).incr(table, key, delta)
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        let shard = self.shard(&key);
+        shard.set_nx(table, key, value)
+    }
+
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        self.shard(key).expire_at(table, key, deadline)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.shard(key).persist(table, key)
+    }
+
+    fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, KvError> {
+        self.shard(key).deadline(table, key)
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.sum(MemTable::purge_expired)
+    }
+
+    // version 只需要对同一个 key 递增，每个分片各自计数就够了
+    fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        self.shard(key).version(table, key)
+    }
+
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        let shard = self.shard(&key);
+        shard.set_if_version(table, key, value, version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StorageExt;
+
+    #[test]
+    fn sharded_memtable_should_spread_keys() {
+        let store = ShardedMemTable::with_shards(4);
+        for i in 0..100 {
+            store.hset("t1", format!("key{}", i), i as i64).unwrap();
+        }
+        assert!(store.shards.iter().all(|s| s.len("t1").unwrap() > 0));
+        assert_eq!(store.len("t1").unwrap(), 100);
+
+        let res = store.admin(STORAGE_COMMAND, &[]).unwrap();
+        assert_eq!(
+            res,
+            vec![
+                Kvpair::new("tables", 1.into()),
+                Kvpair::new("keys", 100.into()),
+                Kvpair::new("expiring", 0.into()),
+                Kvpair::new("shards", 4.into()),
+            ]
+        );
+    }
+}
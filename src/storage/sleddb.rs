@@ -1,25 +1,400 @@
-use sled::{Db, Error, IVec};
-use std::{convert::TryInto, path::Path, str};
+use dashmap::DashMap;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree, UnabortableTransactionError,
+};
+use sled::{Batch, Db, Error, IVec, Transactional, Tree};
+use std::time::{Duration, Instant};
+use std::{ops::Bound, path::Path, str};
 
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
+use super::bloom::Blooms;
+use super::codec::{decode_value, payload_len, ValueCodec};
+use super::expiry::now_ms;
+use super::metrics::OpCounters;
+use super::{
+    check_move, decode_deadline, decode_version, encode_version, incr_value, is_empty_range,
+    move_conflict, split_table_key, table_key, table_prefix, version_conflict, Changefeed,
+    KeyEvent, KeyEventKind, BULK_LOAD_BATCH, COMPACT_COMMAND, FLUSH_COMMAND, STORAGE_COMMAND,
+};
+use crate::{
+    BulkLoadStats, Compression, Glob, KeyMeta, KeyRange, KvError, Kvpair, Order, StagedWrites,
+    Storage, StorageMetrics, TableStats, Value,
+};
 
+/// 过期时间保存在这个 tree 里，key 是 table_key(table, key)，value 是 UNIX 毫秒。
+/// 名字不是合法的 UTF-8，不会和 table 的 tree 重名
+const EXPIRES_TREE: &[u8] = b"\xff__expires__";
+/// 读过 version 的 key 保存在这个 tree 里，value 是 version。
+/// 每个写操作都在自己的 transaction 里换掉它，见 bump_version
+const VERSIONS_TREE: &[u8] = b"\xff__versions__";
+/// 打开了元数据记录时，key 的 KeyMeta 保存在这个 tree 里。过期和 EXPIRE 删掉的 key
+/// 可能留下旧的记录，不过 key 再次被创建时会覆盖它，读的时候也会先检查 key 是否存在
+const METAS_TREE: &[u8] = b"\xff__metas__";
+/// sled 自己的缺省 tree，不能 drop_tree
+const DEFAULT_TREE: &[u8] = b"__sled__default";
+
+/// 每个 table 是一个名字就是 table 的 sled::Tree，key 直接用原来的 key，
+/// 所以 table 和 key 里都可以有任何字符。没有 key 的 tree 当作不存在的 table。
+/// 写入的 value 带着 CRC32，读到坏掉的数据时返回 DataCorruption
 #[derive(Debug)]
-pub struct SledDb(Db);
+pub struct SledDb {
+    db: Db,
+    /// EXPIRES_TREE
+    expires: Tree,
+    /// VERSIONS_TREE
+    versions: Tree,
+    /// METAS_TREE，没有打开元数据记录时是 None
+    metas: Option<Tree>,
+    codec: ValueCodec,
+    ops: OpCounters,
+    /// 每个 table 的 bloom filter，没有打开时是 None
+    blooms: Option<Blooms>,
+    changefeed: Option<Changefeed>,
+    // 打开过的 table 的 tree。读的时候只在这里和 tree_names 里找，不会因为读而创建 tree
+    trees: DashMap<String, Tree>,
+}
 
-impl SledDb {
+/// sled 的运行模式，对应 sled::Mode
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SledMode {
+    /// 少占磁盘空间，更频繁地整理数据
+    #[default]
+    Small,
+    /// 写入的吞吐量更高，可能占用更多的磁盘空间
+    Fast,
+}
+
+/// 打开 SledDb 的选项，没有设置的和 sled 的缺省值一样
+#[derive(Clone, Debug)]
+pub struct SledDbBuilder {
+    config: sled::Config,
+    meta: bool,
+    bloom: bool,
+    codec: ValueCodec,
+    changefeed: Option<Changefeed>,
+}
+
+impl SledDbBuilder {
     pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+        Self {
+            config: sled::Config::new().path(path),
+            meta: false,
+            bloom: false,
+            codec: ValueCodec {
+                checksum: true,
+                ..Default::default()
+            },
+            changefeed: None,
+        }
     }
 
-    // 在sleddb里, 因为它可以scan_prefix, 我们用prefix
-    // 来模拟一个table, 当然还可以用其他方案
-    fn get_full_key(table: &str, key: &str) -> String {
-        format!("{}:{}", table, key)
+    /// sled 的页缓存最多使用的内存，单位是字节
+    pub fn cache_capacity(mut self, bytes: u64) -> Self {
+        self.config = self.config.cache_capacity(bytes);
+        self
     }
 
-    // 遍历table的key时, 我们直接把prefix: 当成table
-    fn get_table_prefix(table: &str) -> String {
-        format!("{}:", table)
+    /// 后台 flush 到磁盘的间隔，None 表示只在调用 flush 或者 drop 的时候写盘
+    pub fn flush_every(mut self, interval: Option<Duration>) -> Self {
+        let ms = interval.map(|d| d.as_millis() as u64);
+        self.config = self.config.flush_every_ms(ms);
+        self
+    }
+
+    pub fn mode(mut self, mode: SledMode) -> Self {
+        let mode = match mode {
+            SledMode::Small => sled::Mode::LowSpace,
+            SledMode::Fast => sled::Mode::HighThroughput,
+        };
+        self.config = self.config.mode(mode);
+        self
+    }
+
+    /// 编码之后不小于 threshold 字节的 value 压缩之后再写入。
+    /// 读的时候根据 value 开头的格式字节解压，没有压缩的老数据照样可以读
+    pub fn compression(mut self, compression: Compression, threshold: usize) -> Self {
+        self.codec.compression = compression;
+        self.codec.threshold = threshold;
+        self
+    }
+
+    /// 记录每个 key 的元数据，每次写入要多写一次 metas tree。
+    /// 没有记录的时候做的写入不会反映在元数据里
+    pub fn meta(mut self, meta: bool) -> Self {
+        self.meta = meta;
+        self
+    }
+
+    /// 给每个 table 维护一个 bloom filter，get 和 contains 不存在的 key 时不用读磁盘。
+    /// 打开的时候要读一遍所有的 key 来重建，filter 每个 key 大约占 10 个 bit 的内存
+    pub fn bloom(mut self, bloom: bool) -> Self {
+        self.bloom = bloom;
+        self
+    }
+
+    /// 过期的 key 被删掉的时候往 changefeed 里发 Expired 事件
+    pub fn changefeed(mut self, changefeed: Changefeed) -> Self {
+        self.changefeed = Some(changefeed);
+        self
+    }
+
+    /// 打不开的时候（比如目录被别的进程锁住了）返回 SledError
+    pub fn open(self) -> Result<SledDb, KvError> {
+        let db = self.config.open()?;
+        let expires = db.open_tree(EXPIRES_TREE)?;
+        let versions = db.open_tree(VERSIONS_TREE)?;
+        let metas = match self.meta {
+            true => Some(db.open_tree(METAS_TREE)?),
+            false => None,
+        };
+        let blooms = self.bloom.then(Blooms::default);
+        let db = SledDb {
+            db,
+            expires,
+            versions,
+            metas,
+            codec: self.codec,
+            ops: OpCounters::default(),
+            blooms,
+            changefeed: self.changefeed,
+            trees: DashMap::new(),
+        };
+        if let Some(blooms) = &db.blooms {
+            for (table, tree) in db.tables()? {
+                for key in tree.iter().keys() {
+                    blooms.insert(&table, &key?);
+                }
+            }
+        }
+        Ok(db)
+    }
+}
+
+impl SledDb {
+    /// 用缺省的选项打开，需要调整的时候用 SledDb::builder
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        SledDbBuilder::new(path).open()
+    }
+
+    pub fn builder(path: impl AsRef<Path>) -> SledDbBuilder {
+        SledDbBuilder::new(path)
+    }
+
+    // 元数据只是统计信息，不和数据在同一个 transaction 里写入，
+    // 并发写同一个 key 时 updates 可能少算
+    fn record_meta(&self, table: &str, key: &str, existed: bool) -> Result<(), KvError> {
+        let metas = match &self.metas {
+            Some(metas) => metas,
+            None => return Ok(()),
+        };
+        let name = table_key(table, key);
+        let now = now_ms();
+        let recorded = match existed {
+            true => metas.get(&name)?.map(|m| KeyMeta::decode(&m)),
+            false => None,
+        };
+        let meta = match recorded {
+            Some(mut meta) => {
+                meta.touch(now);
+                meta
+            }
+            None => KeyMeta::created(now),
+        };
+        metas.insert(name, &meta.encode()[..])?;
+        Ok(())
+    }
+
+    fn forget_meta(&self, table: &str, key: &str) -> Result<(), KvError> {
+        if let Some(metas) = &self.metas {
+            metas.remove(table_key(table, key))?;
+        }
+        Ok(())
+    }
+
+    // 写入 key 之前先加到 bloom filter 里，并发的 get 不会因为 filter 还没更新而漏掉它
+    fn remember(&self, table: &str, key: &str) {
+        if let Some(blooms) = &self.blooms {
+            blooms.insert(table, key.as_bytes());
+        }
+    }
+
+    // bloom filter 确定 key 不在 table 里
+    fn absent(&self, table: &str, key: &str) -> bool {
+        self.blooms
+            .as_ref()
+            .is_some_and(|b| !b.may_contain(table, key.as_bytes()))
+    }
+
+    // version 在整个 db 里单调递增，0 留给不存在的 key
+    fn next_version(&self) -> Result<u64, KvError> {
+        Ok(self.db.generate_id()? + 1)
+    }
+
+    // 写操作用的 tree，table 不存在时创建
+    fn table(&self, table: &str) -> Result<Tree, KvError> {
+        if let Some(tree) = self.trees.get(table) {
+            return Ok(tree.clone());
+        }
+        let tree = self.db.open_tree(table)?;
+        self.trees.insert(table.into(), tree.clone());
+        Ok(tree)
+    }
+
+    // 读操作用的 tree，table 不存在时返回 None。open_tree 会创建 tree，
+    // 所以先在 tree_names 里确认它存在
+    fn existing_table(&self, table: &str) -> Result<Option<Tree>, KvError> {
+        if let Some(tree) = self.trees.get(table) {
+            return Ok(Some(tree.clone()));
+        }
+        match self.db.tree_names().iter().any(|n| n == table.as_bytes()) {
+            true => self.table(table).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    // 所有 table 的 tree，包括空的。过期时间和 version 的 tree 名字不是 UTF-8，会被跳过
+    fn tables(&self) -> Result<Vec<(String, Tree)>, KvError> {
+        let mut tables = Vec::new();
+        for name in self.db.tree_names() {
+            if let Ok(table) = str::from_utf8(&name) {
+                tables.push((table.to_string(), self.db.open_tree(&name)?));
+            }
+        }
+        tables.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(tables)
+    }
+
+    // 删除一个 table 的 tree，缺省的 tree 只能清空
+    fn remove_table(&self, table: &str, tree: &Tree) -> Result<(), KvError> {
+        self.trees.remove(table);
+        match table.as_bytes() == DEFAULT_TREE {
+            true => tree.clear()?,
+            false => {
+                self.db.drop_tree(table)?;
+            }
+        }
+        Ok(())
+    }
+
+    // 没有任何 key 设置过期时间的时候，不需要额外读一次 expires
+    fn has_deadlines(&self) -> bool {
+        !self.expires.is_empty()
+    }
+
+    fn deadline_of(&self, name: &[u8]) -> Result<Option<i64>, KvError> {
+        if !self.has_deadlines() {
+            return Ok(None);
+        }
+        Ok(self.expires.get(name)?.map(|d| decode_deadline(&d)))
+    }
+
+    // 在一个 transaction 里同时修改数据、过期时间和 version，冲突时 sled 会重试
+    fn transaction<T>(
+        &self,
+        data: &Tree,
+        f: impl Fn(
+            &TransactionalTree,
+            &TransactionalTree,
+            &TransactionalTree,
+        ) -> ConflictableTransactionResult<T, KvError>,
+    ) -> Result<T, KvError> {
+        (data, &self.expires, &self.versions)
+            .transaction(|(data, expires, versions)| f(data, expires, versions))
+            .map_err(transaction_error)
+    }
+
+    // 在一个事务里读出 keys 的旧值，去掉它们的过期时间，然后写入 batch。
+    // exists 表示 batch 是写入还是删除这些 key。已经过期的旧值当作不存在
+    fn apply(
+        &self,
+        data: &Tree,
+        table: &str,
+        keys: &[String],
+        batch: &Batch,
+        exists: bool,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let now = now_ms();
+        let next = exists.then(|| self.next_version()).transpose()?;
+        let olds = self.transaction(data, |tx, expires, versions| {
+            let mut olds = Vec::with_capacity(keys.len());
+            for key in keys {
+                let name = table_key(table, key);
+                let deadline = expires.remove(name.as_slice())?;
+                bump_version(versions, &name, next)?;
+                let old = tx.get(key.as_bytes())?;
+                olds.push(old.filter(|_| !passed(deadline.map(|d| decode_deadline(&d)), now)));
+            }
+            tx.apply_batch(batch)?;
+            Ok(olds)
+        })?;
+        olds.into_iter()
+            .map(|old| flip(old.map(|v| decode_value(&v))))
+            .collect()
+    }
+
+    // 读到了过期的 key，顺便删掉。返回是否删掉了
+    fn reap(&self, data: &Tree, table: &str, key: &str) -> Result<bool, KvError> {
+        let name = table_key(table, key);
+        let now = now_ms();
+        if !passed(self.deadline_of(&name)?, now) {
+            return Ok(false);
+        }
+        let reaped = self.transaction(data, |data, expires, versions| {
+            if !passed(deadline_in(expires, &name)?, now) {
+                return Ok(None);
+            }
+            expires.remove(name.as_slice())?;
+            bump_version(versions, &name, None)?;
+            Ok(data.remove(key.as_bytes())?)
+        })?;
+        let old = match reaped {
+            Some(old) => old,
+            None => return Ok(false),
+        };
+        self.forget_meta(table, key)?;
+        if let Some(feed) = &self.changefeed {
+            // 只用来算 hash，坏掉的 value 当作空值
+            let value = decode_value(&old).unwrap_or_default();
+            feed.publish(KeyEvent::new(table, key, KeyEventKind::Expired, &value));
+        }
+        Ok(true)
+    }
+
+    // 解码 tree 里读出来的最多 count 个 kv pair，跳过已经过期的 key，留给 purge_expired 删除。
+    // 读出错误或者 value 损坏时返回错误，而不是悄悄地少了一些 key
+    fn live_pairs(
+        &self,
+        table: &str,
+        iter: impl Iterator<Item = Result<(IVec, IVec), Error>>,
+        count: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let (now, deadlines) = (now_ms(), self.has_deadlines());
+        let mut pairs = Vec::new();
+        for item in iter {
+            if pairs.len() >= count {
+                break;
+            }
+            let (k, v) = item?;
+            let key = ivec_to_key(&k);
+            if deadlines {
+                let deadline = self.expires.get(table_key(table, key))?;
+                if passed(deadline.map(|d| decode_deadline(&d)), now) {
+                    continue;
+                }
+            }
+            pairs.push(Kvpair::new(key, decode_value(&v)?));
+        }
+        Ok(pairs)
+    }
+
+    // table 里过期了还没删掉的 key 的个数
+    fn expired_in(&self, table: &str, now: i64) -> Result<usize, KvError> {
+        let mut expired = 0;
+        for item in self.expires.scan_prefix(table_prefix(table)) {
+            let (_, deadline) = item?;
+            expired += passed(Some(decode_deadline(&deadline)), now) as usize;
+        }
+        Ok(expired)
     }
 }
 
@@ -31,67 +406,760 @@ fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
 
 impl Storage for SledDb {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, key);
-        let result = self.0.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
+        if self.absent(table, key) {
+            self.ops.read(false);
+            return Ok(None);
+        }
+        let data = match self.existing_table(table)? {
+            Some(data) => data,
+            None => {
+                self.ops.read(false);
+                return Ok(None);
+            }
+        };
+        self.reap(&data, table, key)?;
+        let result = data.get(key)?.map(|v| decode_value(&v));
+        self.ops.read(result.is_some());
         flip(result)
     }
 
-    fn set(
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let data = self.table(table)?;
+        let value = self.codec.encode(value)?;
+        self.ops.write(1);
+        self.remember(table, &key);
+        // 写入会去掉 key 的过期时间，已经过期的旧值当作不存在
+        let name = table_key(table, &key);
+        let (now, next) = (now_ms(), self.next_version()?);
+        let old = self.transaction(&data, |tx, expires, versions| {
+            let deadline = expires.remove(name.as_slice())?;
+            bump_version(versions, &name, Some(next))?;
+            let old = tx.insert(key.as_bytes(), value.clone())?;
+            Ok(old.filter(|_| !passed(deadline.map(|d| decode_deadline(&d)), now)))
+        })?;
+        self.record_meta(table, &key, old.is_some())?;
+        flip(old.map(|v| decode_value(&v)))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        if self.absent(table, key) {
+            return Ok(false);
+        }
+        let data = match self.existing_table(table)? {
+            Some(data) => data,
+            None => return Ok(false),
+        };
+        self.reap(&data, table, key)?;
+
+        Ok(data.contains_key(key)?)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let data = match self.existing_table(table)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        self.forget_meta(table, key)?;
+        self.ops.delete(1);
+        let name = table_key(table, key);
+        let now = now_ms();
+        let old = self.transaction(&data, |tx, expires, versions| {
+            let deadline = expires.remove(name.as_slice())?;
+            bump_version(versions, &name, None)?;
+            let old = tx.remove(key.as_bytes())?;
+            Ok(old.filter(|_| !passed(deadline.map(|d| decode_deadline(&d)), now)))
+        })?;
+        flip(old.map(|v| decode_value(&v)))
+    }
+
+    // 不读旧值，每 BULK_LOAD_BATCH 个 key 写一个 sled::Batch，和去掉它们的过期时间、
+    // version 的记录在同一个事务里写入，之后读 version 时分配新的。元数据也不读，都记成新建的 key
+    fn bulk_load(
         &self,
         table: &str,
-        key: impl Into<String>,
-        value: impl Into<Value>,
+        pairs: &mut dyn Iterator<Item = Kvpair>,
+    ) -> Result<BulkLoadStats, KvError> {
+        let start = Instant::now();
+        let data = self.table(table)?;
+        let mut stats = BulkLoadStats::default();
+        loop {
+            let (mut batch, mut expires, mut versions, mut metas) = (
+                Batch::default(),
+                Batch::default(),
+                Batch::default(),
+                Batch::default(),
+            );
+            let (now, mut n) = (now_ms(), 0);
+            let meta = KeyMeta::created(now).encode();
+            for pair in pairs.take(BULK_LOAD_BATCH) {
+                let value = self.codec.encode(pair.value.unwrap_or_default())?;
+                stats.bytes += (pair.key.len() + value.len()) as u64;
+                let name = table_key(table, &pair.key);
+                if self.metas.is_some() {
+                    metas.insert(name.as_slice(), &meta[..]);
+                }
+                expires.remove(name.as_slice());
+                versions.remove(name);
+                self.remember(table, &pair.key);
+                batch.insert(pair.key.as_bytes(), value);
+                n += 1;
+            }
+            if n == 0 {
+                break;
+            }
+            self.transaction(&data, |tx, ex, vs| {
+                tx.apply_batch(&batch)?;
+                ex.apply_batch(&expires)?;
+                vs.apply_batch(&versions)?;
+                Ok(())
+            })?;
+            if let Some(tree) = &self.metas {
+                tree.apply_batch(metas)?;
+            }
+            self.ops.write(n);
+            stats.keys += n as u64;
+        }
+        stats.elapsed = start.elapsed();
+        Ok(stats)
+    }
+
+    // 涉及的 table 和过期时间、version 的 tree 放在同一个 transaction 里，
+    // 崩溃或者出错时不会只写进去一部分
+    fn commit(&self, writes: StagedWrites) -> Result<(), KvError> {
+        let mut tables: Vec<String> = Vec::new();
+        let mut trees = vec![self.expires.clone(), self.versions.clone()];
+        let mut ops = Vec::with_capacity(writes.len());
+        for ((table, key), value) in writes {
+            let i = match tables.iter().position(|t| *t == table) {
+                Some(i) => i,
+                None => {
+                    tables.push(table.clone());
+                    trees.push(self.table(&table)?);
+                    tables.len() - 1
+                }
+            };
+            if value.is_some() {
+                self.remember(&table, &key);
+            }
+            let value = value.map(|v| self.codec.encode(v)).transpose()?;
+            ops.push((i, table_key(&table, &key), key, value));
+        }
+        let (now, next) = (now_ms(), self.next_version()?);
+        let existed = trees[..]
+            .transaction(|trees| {
+                let (expires, versions) = (&trees[0], &trees[1]);
+                let mut existed = Vec::with_capacity(ops.len());
+                for (i, name, key, value) in &ops {
+                    let data = &trees[i + 2];
+                    let deadline = expires.remove(name.as_slice())?;
+                    bump_version(versions, name, value.as_ref().map(|_| next))?;
+                    let old = match value {
+                        Some(value) => data.insert(key.as_bytes(), value.clone())?,
+                        None => data.remove(key.as_bytes())?,
+                    };
+                    existed
+                        .push(old.is_some() && !passed(deadline.map(|d| decode_deadline(&d)), now));
+                }
+                Ok(existed)
+            })
+            .map_err(transaction_error)?;
+        for ((i, _, key, value), existed) in ops.iter().zip(existed) {
+            match value {
+                Some(_) => {
+                    self.ops.write(1);
+                    self.record_meta(&tables[*i], key, existed)?;
+                }
+                None => {
+                    self.ops.delete(1);
+                    self.forget_meta(&tables[*i], key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 所有的 key 放在一个 sled::Batch 里，和去掉它们的过期时间在同一个事务里写入，
+    // 崩溃之后不会只剩下一部分
+    fn mset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+        let data = self.table(table)?;
+        let mut batch = Batch::default();
+        let mut keys = Vec::with_capacity(pairs.len());
+        for pair in pairs {
+            let value = self.codec.encode(pair.value.unwrap_or_default())?;
+            self.remember(table, &pair.key);
+            batch.insert(pair.key.as_bytes(), value);
+            keys.push(pair.key);
+        }
+        let olds = self.apply(&data, table, &keys, &batch, true)?;
+        self.ops.write(keys.len());
+        for (key, old) in keys.iter().zip(&olds) {
+            self.record_meta(table, key, old.is_some())?;
+        }
+        Ok(olds)
+    }
+
+    fn mdel(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        let data = match self.existing_table(table)? {
+            Some(data) => data,
+            None => return Ok(vec![None; keys.len()]),
+        };
+        let mut batch = Batch::default();
+        keys.iter().for_each(|key| batch.remove(key.as_bytes()));
+        for key in keys {
+            self.forget_meta(table, key)?;
+        }
+        self.ops.delete(keys.len());
+        self.apply(&data, table, keys, &batch, false)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        match self.existing_table(table)? {
+            Some(data) => self.live_pairs(table, data.iter(), usize::MAX),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    // 先全部解码，损坏的 value 在这里就返回错误
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        Ok(Box::new(self.get_all(table)?.into_iter()))
+    }
+
+    // tree 本来就是按 key 升序的，降序只要反过来读
+    fn get_iter_ordered(
+        &self,
+        table: &str,
+        order: Order,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        if order != Order::Descending {
+            return self.get_iter(table);
+        }
+        let pairs = match self.existing_table(table)? {
+            Some(data) => self.live_pairs(table, data.iter().rev(), usize::MAX)?,
+            None => Vec::new(),
+        };
+        Ok(Box::new(pairs.into_iter()))
+    }
+
+    // 在解码 value 之前就跳过不匹配的 key
+    fn get_iter_matching(
+        &self,
+        table: &str,
+        pattern: &Glob,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let data = match self.existing_table(table)? {
+            Some(data) => data,
+            None => return Ok(Box::new(std::iter::empty())),
+        };
+        let matches = key_matches(Some(pattern.clone()));
+        let iter = data.iter().filter(matches);
+        Ok(Box::new(
+            self.live_pairs(table, iter, usize::MAX)?.into_iter(),
+        ))
+    }
+
+    // sled 的 key 是有序的，直接从 after 后面开始读
+    fn scan(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        pattern: Option<&Glob>,
+        count: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let start = match after {
+            Some(key) => Bound::Excluded(key.as_bytes()),
+            None => Bound::Unbounded,
+        };
+        let data = match self.existing_table(table)? {
+            Some(data) => data,
+            None => return Ok(Vec::new()),
+        };
+        let matches = key_matches(pattern.cloned());
+        let iter = data
+            .range::<&[u8], _>((start, Bound::Unbounded))
+            .filter(matches);
+        self.live_pairs(table, iter, count)
+    }
+
+    // tree 里的 key 是有序的，直接从 range 的起点开始读
+    fn get_range(
+        &self,
+        table: &str,
+        range: KeyRange,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let data = match self.existing_table(table)? {
+            Some(data) if !is_empty_range(&range) => data,
+            _ => return Ok(Box::new(std::iter::empty())),
+        };
+        let range = (range.0.map(str::as_bytes), range.1.map(str::as_bytes));
+        let iter = data.range::<&[u8], _>(range);
+        Ok(Box::new(
+            self.live_pairs(table, iter, usize::MAX)?.into_iter(),
+        ))
+    }
+
+    // 没有过期时间的时候就是 tree 的长度，不需要遍历
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        let data = match self.existing_table(table)? {
+            Some(data) => data,
+            None => return Ok(0),
+        };
+        if !self.has_deadlines() {
+            return Ok(data.len());
+        }
+        Ok(data.len().saturating_sub(self.expired_in(table, now_ms())?))
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let tables = self.tables()?.into_iter();
+        Ok(tables
+            .filter(|(_, tree)| !tree.is_empty())
+            .map(|(table, _)| table)
+            .collect())
+    }
+
+    // value 在 sled 里就是编码之后的样子，直接用它的长度，不需要解码。
+    // 过期了还没删掉的 key 也算在里面
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        let mut stats = Vec::new();
+        for (table, tree) in self.tables()? {
+            let mut s = TableStats {
+                table,
+                ..Default::default()
+            };
+            for item in tree.iter() {
+                let (k, v) = item?;
+                s.keys += 1;
+                s.bytes += k.len() + payload_len(&v);
+            }
+            if s.keys > 0 {
+                stats.push(s);
+            }
+        }
+        Ok(stats)
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn metrics(&self) -> Result<StorageMetrics, KvError> {
+        let mut backend = vec![
+            Kvpair::new("sled.size_on_disk", (self.db.size_on_disk()? as i64).into()),
+            Kvpair::new("sled.trees", (self.db.tree_names().len() as i64).into()),
+        ];
+        if let Some(blooms) = &self.blooms {
+            backend.push(Kvpair::new(
+                "sled.bloom_skips",
+                (blooms.skips() as i64).into(),
+            ));
+            backend.push(Kvpair::new(
+                "sled.bloom_bytes",
+                (blooms.bytes() as i64).into(),
+            ));
+        }
+        Ok(self.ops.metrics(self.stats()?, backend))
+    }
+
+    // 删掉所有 table 的 tree，再清空过期时间和 version
+    fn flush_all(&self) -> Result<usize, KvError> {
+        let now = now_ms();
+        let mut expired = 0;
+        for item in self.expires.iter() {
+            let (_, deadline) = item?;
+            expired += passed(Some(decode_deadline(&deadline)), now) as usize;
+        }
+        let mut n = 0;
+        for (table, tree) in self.tables()? {
+            n += tree.len();
+            self.remove_table(&table, &tree)?;
+        }
+        self.expires.clear()?;
+        self.versions.clear()?;
+        if let Some(metas) = &self.metas {
+            metas.clear()?;
+        }
+        Ok(n.saturating_sub(expired))
+    }
+
+    // 整个 tree 一起删掉，过期时间和 version 用 batch 删除，每个 batch 都是原子的
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        let data = match self.existing_table(table)? {
+            Some(data) => data,
+            None => return Ok(0),
+        };
+        let n = data.len().saturating_sub(self.expired_in(table, now_ms())?);
+        for meta in [
+            Some(&self.expires),
+            Some(&self.versions),
+            self.metas.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            let mut batch = Batch::default();
+            for key in meta.scan_prefix(table_prefix(table)).keys() {
+                batch.remove(key?);
+            }
+            meta.apply_batch(batch)?;
+        }
+        self.remove_table(table, &data)?;
+        Ok(n)
+    }
+
+    fn admin(&self, command: &str, _args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        match command {
+            STORAGE_COMMAND => {
+                let mut keys = 0;
+                for (_, tree) in self.tables()? {
+                    keys += tree.len();
+                }
+                Ok(vec![
+                    Kvpair::new("size_on_disk", (self.db.size_on_disk()? as i64).into()),
+                    Kvpair::new("keys", (keys as i64).into()),
+                    Kvpair::new("recovered", self.db.was_recovered().into()),
+                    Kvpair::new("expiring", (self.expires.len() as i64).into()),
+                ])
+            }
+            FLUSH_COMMAND => {
+                let flushed = self.db.flush()?;
+                Ok(vec![Kvpair::new("flushed", (flushed as i64).into())])
+            }
+            // sled 在后台自己回收 segment，没有提供手动触发的接口
+            COMPACT_COMMAND => Err(KvError::Unsupported(
+                "sled compacts its segments in the background".into(),
+            )),
+            _ => Err(KvError::Unsupported(format!("admin command {}", command))),
+        }
+    }
+
+    // 在一个 sled transaction 里删除再写入，崩溃时不会两边都有。
+    // key 的过期时间跟着一起移过去
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
     ) -> Result<Option<Value>, KvError> {
-        let key = key.into();
-        let name = SledDb::get_full_key(table, &key);
-        let data: Vec<u8> = value.into().try_into()?;
+        check_move(src, dst)?;
+        // src 不存在的话没有可以移动的 key，也不用创建 dst
+        let from_tree = match self.existing_table(src)? {
+            Some(tree) => tree,
+            None => return Ok(None),
+        };
+        let to_tree = self.table(dst)?;
+        self.remember(dst, key);
+        let from = table_key(src, key);
+        let to = table_key(dst, key);
+        let now = now_ms();
+        let next = self.next_version()?;
+        let trees = (&from_tree, &to_tree, &self.expires, &self.versions);
+        let result = trees
+            .transaction(|(src_tx, dst_tx, expires, versions)| {
+                let deadline = deadline_in(expires, &from)?;
+                let v = match src_tx.remove(key.as_bytes())? {
+                    Some(v) => v,
+                    None => return Ok(None),
+                };
+                expires.remove(from.as_slice())?;
+                bump_version(versions, &from, None)?;
+                if passed(deadline, now) {
+                    return Ok(None);
+                }
 
-        let result = self.0.insert(name, data)?.map(|v| v.as_ref().try_into());
-        flip(result)
+                let occupied = dst_tx.get(key.as_bytes())?.is_some()
+                    && !passed(deadline_in(expires, &to)?, now);
+                if !force && occupied {
+                    return Err(ConflictableTransactionError::Abort(move_conflict(dst, key)));
+                }
+                bump_version(versions, &to, Some(next))?;
+                dst_tx.insert(key.as_bytes(), v.clone())?;
+                match deadline {
+                    Some(d) => expires.insert(to.as_slice(), &d.to_be_bytes()[..])?,
+                    None => expires.remove(to.as_slice())?,
+                };
+                Ok(Some((v, occupied)))
+            })
+            .map_err(transaction_error)?;
+        // 和 MemTable 一样，移过去的 key 在 dst 里重新开始记录
+        if let Some((_, occupied)) = &result {
+            self.forget_meta(src, key)?;
+            self.record_meta(dst, key, *occupied)?;
+        }
+        flip(result.map(|(v, _)| decode_value(&v)))
     }
 
-    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let name = SledDb::get_full_key(table, &key);
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        let data = self.table(table)?;
+        let value = self.codec.encode(value)?;
+        // 已经过期的 value 先删掉，只有没有旧的值时才写入
+        self.reap(&data, table, &key)?;
+        self.remember(table, &key);
+        let name = table_key(table, &key);
+        let (now, next) = (now_ms(), self.next_version()?);
+        let written = self.transaction(&data, |tx, expires, versions| {
+            if tx.get(key.as_bytes())?.is_some() && !passed(deadline_in(expires, &name)?, now) {
+                return Ok(false);
+            }
+            expires.remove(name.as_slice())?;
+            bump_version(versions, &name, Some(next))?;
+            tx.insert(key.as_bytes(), value.clone())?;
+            Ok(true)
+        })?;
+        if written {
+            self.record_meta(table, &key, false)?;
+        }
+        Ok(written)
+    }
 
-        Ok(self.0.contains_key(name)?)
+    // transaction 在冲突时会重试，读和写之间不会有别的修改。
+    // 过期的旧值当作不存在，没有过期的保留原来的过期时间
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let name = table_key(table, key);
+        let (now, next) = (now_ms(), self.next_version()?);
+        self.remember(table, key);
+        let (n, existed) = self.transaction(&self.table(table)?, |tx, expires, versions| {
+            let expired = passed(deadline_in(expires, &name)?, now);
+            if expired {
+                expires.remove(name.as_slice())?;
+            }
+            let old = match tx.get(key.as_bytes())? {
+                Some(v) if !expired => {
+                    Some(decode_value(&v).map_err(ConflictableTransactionError::Abort)?)
+                }
+                _ => None,
+            };
+            let n = incr_value(key, old.as_ref(), delta)
+                .map_err(ConflictableTransactionError::Abort)?;
+            let data = self
+                .codec
+                .encode(Value::from(n))
+                .map_err(ConflictableTransactionError::Abort)?;
+            bump_version(versions, &name, Some(next))?;
+            tx.insert(key.as_bytes(), data)?;
+            Ok((n, old.is_some()))
+        })?;
+        self.record_meta(table, key, existed)?;
+        Ok(n)
     }
 
-    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, &key);
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        let data = match self.existing_table(table)? {
+            Some(data) => data,
+            None => return Ok(false),
+        };
+        let name = table_key(table, key);
+        let now = now_ms();
+        self.transaction(&data, |tx, expires, versions| {
+            if tx.get(key.as_bytes())?.is_none() {
+                return Ok(false);
+            }
+            let expired = passed(deadline_in(expires, &name)?, now);
+            if expired || deadline <= now {
+                tx.remove(key.as_bytes())?;
+                expires.remove(name.as_slice())?;
+                bump_version(versions, &name, None)?;
+                return Ok(!expired);
+            }
+            expires.insert(name.as_slice(), &deadline.to_be_bytes()[..])?;
+            Ok(true)
+        })
+    }
 
-        let result = self.0.remove(name)?.map(|v| v.as_ref().try_into());
-        flip(result)
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let data = match self.existing_table(table)? {
+            Some(data) => data,
+            None => return Ok(false),
+        };
+        let name = table_key(table, key);
+        let now = now_ms();
+        self.transaction(&data, |tx, expires, versions| {
+            if tx.get(key.as_bytes())?.is_none() {
+                return Ok(false);
+            }
+            let deadline = deadline_in(expires, &name)?;
+            expires.remove(name.as_slice())?;
+            if passed(deadline, now) {
+                tx.remove(key.as_bytes())?;
+                bump_version(versions, &name, None)?;
+                return Ok(false);
+            }
+            Ok(deadline.is_some())
+        })
     }
 
-    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let prefix = SledDb::get_table_prefix(table);
-        let result = self.0.scan_prefix(prefix).map(|v| v.into()).collect();
+    fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, KvError> {
+        let data = match self.existing_table(table)? {
+            Some(data) => data,
+            None => return Err(KvError::NotFound(table.into(), key.into())),
+        };
+        self.reap(&data, table, key)?;
+        if !data.contains_key(key)? {
+            return Err(KvError::NotFound(table.into(), key.into()));
+        }
+        self.deadline_of(&table_key(table, key))
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let now = now_ms();
+        let mut purged = 0;
+        for item in self.expires.iter() {
+            let (name, deadline) = item?;
+            if decode_deadline(&deadline) > now {
+                continue;
+            }
+            // 不是 table_key 编码的 key，直接删掉
+            let (table, key) = match split_table_key(&name) {
+                Some(v) => v,
+                None => {
+                    self.expires.remove(&name)?;
+                    continue;
+                }
+            };
+            // table 已经不在了，过期时间是留下来的
+            let data = match self.existing_table(table)? {
+                Some(data) => data,
+                None => {
+                    self.expires.remove(&name)?;
+                    continue;
+                }
+            };
+            if self.reap(&data, table, key)? {
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
 
-        Ok(result)
+    fn meta(&self, table: &str, key: &str) -> Result<Option<KeyMeta>, KvError> {
+        let metas = match &self.metas {
+            Some(metas) => metas,
+            None => return Err(KvError::Unsupported(format!("metadata in table {}", table))),
+        };
+        let data = match self.existing_table(table)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        self.reap(&data, table, key)?;
+        if !data.contains_key(key)? {
+            return Ok(None);
+        }
+        let meta = metas.get(table_key(table, key))?;
+        Ok(Some(meta.map(|m| KeyMeta::decode(&m)).unwrap_or_default()))
     }
 
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        let prefix = SledDb::get_table_prefix(table);
-        let iter = StorageIter::new(self.0.scan_prefix(prefix));
-        Ok(Box::new(iter))
+    // 读 value 和记录新的 version 在同一个 transaction 里。
+    // 冲突重试时用的是同一个新 version，不会浪费更多
+    fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        let data = match self.existing_table(table)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        self.reap(&data, table, key)?;
+        let name = table_key(table, key);
+        let next = self.next_version()?;
+        let result = (&data, &self.versions).transaction(|(data, versions)| {
+            if data.get(key.as_bytes())?.is_none() {
+                return Ok(None);
+            }
+            // 记录的 version 在上次写入之后一直有效
+            match versions.get(name.as_slice())?.map(|v| decode_version(&v)) {
+                Some(version) if version > 0 => Ok(Some(version)),
+                _ => {
+                    versions.insert(name.as_slice(), &encode_version(next)[..])?;
+                    Ok(Some(next))
+                }
+            }
+        });
+        result.map_err(transaction_error)
+    }
+
+    // 过期的旧值当作不存在，写入会去掉 key 的过期时间
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        let data = self.table(table)?;
+        let name = table_key(table, &key);
+        let value = self.codec.encode(value)?;
+        let next = self.next_version()?;
+        let now = now_ms();
+        self.remember(table, &key);
+        let trees = (&data, &self.expires, &self.versions);
+        let result = trees.transaction(|(data, expires, versions)| {
+            let current = match data.get(key.as_bytes())? {
+                Some(_) if passed(deadline_in(expires, &name)?, now) => 0,
+                Some(_) => match versions.get(name.as_slice())?.map(|v| decode_version(&v)) {
+                    Some(v) if v > 0 => v,
+                    // 没有人读过 version，客户端给的一定不对
+                    _ => u64::MAX,
+                },
+                None => 0,
+            };
+            if current != version {
+                let e = version_conflict(table, &key, version);
+                return Err(ConflictableTransactionError::Abort(e));
+            }
+            data.insert(key.as_bytes(), value.clone())?;
+            expires.remove(name.as_slice())?;
+            versions.insert(name.as_slice(), &encode_version(next)[..])?;
+            Ok(current != 0)
+        });
+        let existed = result.map_err(transaction_error)?;
+        self.record_meta(table, &key, existed)?;
+        Ok(next)
     }
 }
 
-impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
-    fn from(v: Result<(IVec, IVec), Error>) -> Self {
-        match v {
-            Ok((k, v)) => match v.as_ref().try_into() {
-                Ok(v) => Kvpair::new(ivec_to_key(k.as_ref()), v),
-                Err(_) => Kvpair::default(),
-            },
-            _ => Kvpair::default(),
-        }
+fn transaction_error(e: TransactionError<KvError>) -> KvError {
+    match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => e.into(),
+    }
+}
+
+// key 是否和 pattern 匹配。读出错误的项留给 live_pairs 处理
+fn key_matches(pattern: Option<Glob>) -> impl Fn(&Result<(IVec, IVec), Error>) -> bool {
+    move |item| match (item, &pattern) {
+        (Ok((k, _)), Some(pattern)) => str::from_utf8(k).is_ok_and(|key| pattern.matches(key)),
+        _ => true,
     }
 }
 
+// 在写入或者删除 key 的 transaction 里调用，之前读到的 version 都不再有效。
+// 记录过 version 的 key 换成 next，删掉的 key（next 是 None）不再记录，重新创建时再分配。
+// next 要在 transaction 外面用 next_version 取好：generate_id 可能要写 db，
+// 在 transaction 里调用会卡住
+fn bump_version(
+    versions: &TransactionalTree,
+    name: &[u8],
+    next: Option<u64>,
+) -> ConflictableTransactionResult<(), KvError> {
+    if versions.get(name)?.is_none() {
+        return Ok(());
+    }
+    match next {
+        Some(next) => versions.insert(name, &encode_version(next)[..])?,
+        None => versions.remove(name)?,
+    };
+    Ok(())
+}
+
+fn deadline_in(
+    expires: &TransactionalTree,
+    name: &[u8],
+) -> Result<Option<i64>, UnabortableTransactionError> {
+    Ok(expires.get(name)?.map(|d| decode_deadline(&d)))
+}
+
+fn passed(deadline: Option<i64>, now: i64) -> bool {
+    matches!(deadline, Some(d) if d <= now)
+}
+
 fn ivec_to_key(ivec: &[u8]) -> &str {
-    let s = str::from_utf8(ivec).unwrap();
-    let mut iter = s.split(":");
-    iter.next();
-    iter.next().unwrap()
+    str::from_utf8(ivec).unwrap()
 }
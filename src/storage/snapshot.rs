@@ -0,0 +1,50 @@
+use std::collections::BTreeMap;
+
+use crate::{Kvpair, Value};
+
+/// 一个 table 在某个时刻的只读视图，之后对 table 的写入都看不到
+///
+/// get_iter 和需要一致地读整个 table 的地方（比如备份）通过 Storage::snapshot 拿到它。
+/// 怎么实现由 backend 决定：缺省复制一份，backend 也可以返回共享数据的视图
+pub trait Snapshot {
+    fn get(&self, key: &str) -> Option<Value>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = Kvpair> + '_>;
+    /// 消耗掉快照，返回不借用它的 Iterator
+    fn into_pairs(self: Box<Self>) -> Box<dyn Iterator<Item = Kvpair> + Send>;
+}
+
+/// 复制出来的快照，按 key 的顺序遍历。Storage::snapshot 缺省返回它
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PairsSnapshot(BTreeMap<String, Value>);
+
+impl FromIterator<Kvpair> for PairsSnapshot {
+    fn from_iter<I: IntoIterator<Item = Kvpair>>(iter: I) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|p| (p.key, p.value.unwrap_or_default()))
+                .collect(),
+        )
+    }
+}
+
+impl Snapshot for PairsSnapshot {
+    fn get(&self, key: &str) -> Option<Value> {
+        self.0.get(key).cloned()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Kvpair> + '_> {
+        Box::new(self.0.iter().map(|(k, v)| Kvpair::new(k, v.clone())))
+    }
+
+    fn into_pairs(self: Box<Self>) -> Box<dyn Iterator<Item = Kvpair> + Send> {
+        Box::new(self.0.into_iter().map(|(k, v)| Kvpair::new(k, v)))
+    }
+}
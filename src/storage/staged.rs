@@ -0,0 +1,151 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use super::incr_value;
+use crate::{KvError, Kvpair, StagedWrites, Storage, Value};
+
+/// EXEC 用的暂存层：读先看暂存的写入，再读里面的存储，写入只记在自己这里
+///
+/// 排队的命令都执行成功之后，EXEC 用 into_writes 取出写入交给 Storage::commit，
+/// 有一个失败的话 drop 掉它，里面的存储没有任何改动。第一次从里面读到的 value 也记下来，
+/// 提交之前用 is_stale 确认这期间没有别人改过它们，读出来再写回去的命令不会覆盖别人的写入。
+/// 只支持读写单个 key 和遍历 table，别的命令（过期时间、admin 之类）返回 Unsupported
+pub(crate) struct Staged<'a, S> {
+    inner: &'a S,
+    writes: RefCell<StagedWrites>,
+    reads: RefCell<BTreeMap<(String, String), Option<Value>>>,
+}
+
+impl<'a, S: Storage> Staged<'a, S> {
+    pub(crate) fn new(inner: &'a S) -> Self {
+        Self {
+            inner,
+            writes: RefCell::default(),
+            reads: RefCell::default(),
+        }
+    }
+
+    /// 读过的 key 现在的 value 和当时读到的不一样了
+    pub(crate) fn is_stale(&self) -> Result<bool, KvError> {
+        for ((table, key), value) in self.reads.borrow().iter() {
+            if self.inner.get(table, key)? != *value {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    pub(crate) fn into_writes(self) -> StagedWrites {
+        self.writes.into_inner()
+    }
+
+    fn stage(
+        &self,
+        table: &str,
+        key: String,
+        value: Option<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        let old = self.get(table, &key)?;
+        self.writes.borrow_mut().insert((table.into(), key), value);
+        Ok(old)
+    }
+}
+
+impl<S: Storage> Storage for Staged<'_, S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let k = (table.to_string(), key.to_string());
+        if let Some(value) = self.writes.borrow().get(&k) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.reads.borrow().get(&k) {
+            return Ok(value.clone());
+        }
+        let value = self.inner.get(table, key)?;
+        self.reads.borrow_mut().insert(k, value.clone());
+        Ok(value)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.stage(table, key, Some(value))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.get(table, key)?.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.stage(table, key.into(), None)
+    }
+
+    // 里面的 kv pair 换成暂存的写入，遍历读到的 value 不参与 is_stale 的检查
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let mut pairs: BTreeMap<String, Value> = self
+            .inner
+            .get_iter(table)?
+            .map(|p| (p.key, p.value.unwrap_or_default()))
+            .collect();
+        for ((t, key), value) in self.writes.borrow().iter() {
+            if t != table {
+                continue;
+            }
+            match value {
+                Some(value) => pairs.insert(key.clone(), value.clone()),
+                None => pairs.remove(key),
+            };
+        }
+        Ok(pairs.into_iter().map(|(k, v)| Kvpair::new(k, v)).collect())
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        Ok(Box::new(self.get_all(table)?.into_iter()))
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let n = incr_value(key, self.get(table, key)?.as_ref(), delta)?;
+        self.stage(table, key.into(), Some(n.into()))?;
+        Ok(n)
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        if self.contains(table, &key)? {
+            return Ok(false);
+        }
+        self.stage(table, key, Some(value))?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+
+    #[test]
+    fn staged_writes_should_not_reach_inner_store() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), 1.into()).unwrap();
+
+        let staged = Staged::new(&store);
+        assert_eq!(staged.del("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(staged.incr("t1", "k2", 2).unwrap(), 3);
+        assert!(staged.set_nx("t1", "k3".into(), "v3".into()).unwrap());
+        assert!(!staged.set_nx("t1", "k3".into(), "v4".into()).unwrap());
+        assert_eq!(staged.get("t1", "k1").unwrap(), None);
+        let pairs = staged.get_all("t1").unwrap();
+        assert_eq!(
+            pairs,
+            [Kvpair::new("k2", 3.into()), Kvpair::new("k3", "v3".into())]
+        );
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert!(staged.expire_at("t1", "k2", 1).is_err());
+
+        assert!(!staged.is_stale().unwrap());
+        store.set("t1", "k2".into(), 10.into()).unwrap();
+        assert!(staged.is_stale().unwrap());
+
+        let writes = staged.into_writes();
+        assert_eq!(writes.len(), 3);
+        assert_eq!(writes[&("t1".into(), "k1".into())], None);
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{check_clone_target, load_in_batches, now_ms, Sweeper};
+use crate::{BulkLoadStats, KvError, Kvpair, Storage, Value};
+
+/// 一个 table 的过期策略
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TablePolicy {
+    /// 写入的 key 在这么久之后过期
+    pub ttl: Option<Duration>,
+    /// table 这么久没有被访问就整个删掉
+    pub idle: Option<Duration>,
+}
+
+/// 按 table 设置过期策略的 Storage
+///
+/// 配置了 ttl 的 table，每次写入之后都用 inner 的 expire_at 重新设置 key 的过期时间，
+/// 所以 inner 必须支持过期。过期时间和数据保存在一起，进程重启之后照样有效，
+/// 读到过期的 key、清理过期的 key 也都由 inner 处理。配置了 idle 的 table
+/// 超过这么久没有任何读写的话，sweep 把整个 table 清空，
+/// 这样每个会话一个的临时 table 不会一直堆积下去。table 最近访问的时间只记在内存里，
+/// 重启之后重新计时。
+pub struct TableTtlStore<S> {
+    inner: S,
+    policies: HashMap<String, TablePolicy>,
+    last_used: Mutex<HashMap<String, Instant>>,
+}
+
+impl<S: Storage> TableTtlStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            policies: HashMap::new(),
+            last_used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 设置 table 的过期策略
+    pub fn with_table(mut self, table: impl Into<String>, policy: TablePolicy) -> Self {
+        self.policies.insert(table.into(), policy);
+        self
+    }
+
+    /// 删除到期的 key 和闲置的 table，返回删除的 key 的数量
+    pub fn sweep(&self) -> Result<usize, KvError> {
+        let now = Instant::now();
+        let mut removed = self.inner.purge_expired()?;
+        let idle: Vec<String> = {
+            let mut last_used = self.last_used.lock().unwrap();
+            let idle: Vec<String> = self
+                .policies
+                .iter()
+                .filter_map(|(table, p)| Some((table, p.idle?)))
+                .filter(|(table, idle)| match last_used.get(*table) {
+                    Some(t) => now.duration_since(*t) > *idle,
+                    None => false,
+                })
+                .map(|(table, _)| table.clone())
+                .collect();
+            for table in &idle {
+                last_used.remove(table);
+            }
+            idle
+        };
+
+        for table in idle {
+            removed += self.inner.drop_table(&table)?;
+        }
+        Ok(removed)
+    }
+
+    // 所有访问 table 的操作都先调用它，签名和 forward_to! 的 check 一样
+    fn touch(&self, table: &str) -> Result<(), KvError> {
+        if matches!(
+            self.policies.get(table),
+            Some(TablePolicy { idle: Some(_), .. })
+        ) {
+            let mut last_used = self.last_used.lock().unwrap();
+            last_used.insert(table.into(), Instant::now());
+        }
+        Ok(())
+    }
+
+    // 写入之后按 table 的 ttl 重新设置过期时间
+    fn renew(&self, table: &str, key: &str) -> Result<(), KvError> {
+        if let Some(ttl) = self.policies.get(table).and_then(|p| p.ttl) {
+            let ttl = i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX);
+            self.inner
+                .expire_at(table, key, now_ms().saturating_add(ttl))?;
+        }
+        Ok(())
+    }
+
+    fn has_ttl(&self, table: &str) -> bool {
+        matches!(
+            self.policies.get(table),
+            Some(TablePolicy { ttl: Some(_), .. })
+        )
+    }
+}
+
+impl<S: Storage> Storage for TableTtlStore<S> {
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.touch(table)?;
+        let old = self.inner.set(table, key.clone(), value)?;
+        self.renew(table, &key)?;
+        Ok(old)
+    }
+
+    // 有 ttl 的 table 逐个写入，每个 key 都要设置过期时间
+    fn mset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+        self.touch(table)?;
+        if !self.has_ttl(table) {
+            return self.inner.mset(table, pairs);
+        }
+        pairs
+            .into_iter()
+            .map(|pair| self.set(table, pair.key, pair.value.unwrap_or_default()))
+            .collect()
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        self.touch(table)?;
+        let written = self.inner.set_nx(table, key.clone(), value)?;
+        if written {
+            self.renew(table, &key)?;
+        }
+        Ok(written)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.touch(table)?;
+        let n = self.inner.incr(table, key, delta)?;
+        self.renew(table, key)?;
+        Ok(n)
+    }
+
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        self.touch(table)?;
+        let version = self
+            .inner
+            .set_if_version(table, key.clone(), value, version)?;
+        self.renew(table, &key)?;
+        Ok(version)
+    }
+
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        self.touch(src)?;
+        self.touch(dst)?;
+        let moved = self.inner.move_key(src, dst, key, force)?;
+        if moved.is_some() {
+            self.renew(dst, key)?;
+        }
+        Ok(moved)
+    }
+
+    fn undelete(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.touch(table)?;
+        let restored = self.inner.undelete(table, key)?;
+        if restored.is_some() {
+            self.renew(table, key)?;
+        }
+        Ok(restored)
+    }
+
+    fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+        self.touch(src)?;
+        self.touch(dst)?;
+        if !self.has_ttl(dst) {
+            return self.inner.clone_table(src, dst);
+        }
+        check_clone_target(self, src, dst)?;
+        let mut n = 0;
+        for pair in self.inner.get_iter(src)? {
+            self.set(dst, pair.key, pair.value.unwrap_or_default())?;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    fn bulk_load(
+        &self,
+        table: &str,
+        pairs: &mut dyn Iterator<Item = Kvpair>,
+    ) -> Result<BulkLoadStats, KvError> {
+        self.touch(table)?;
+        match self.has_ttl(table) {
+            true => load_in_batches(self, table, pairs),
+            false => self.inner.bulk_load(table, pairs),
+        }
+    }
+
+    // 读到过期的 key 由 inner 当作不存在
+    forward_to!(inner, touch;
+        get, contains, del, mget, mdel, get_all, get_iter, get_iter_ordered, snapshot, len,
+        get_iter_matching, scan, get_range, admin, purge_trash, history, list_tables, stats,
+        flush_all, drop_table, expire_at, persist, deadline, purge_expired, version, meta,
+        flush, query, export, import, metrics);
+}
+
+impl<S: Storage + Send + Sync + 'static> TableTtlStore<S> {
+    /// 在后台每隔 interval 调用一次 sweep，返回的 Sweeper drop 的时候停止
+    pub fn spawn_sweeper(store: Arc<Self>, interval: Duration) -> Result<Sweeper, KvError> {
+        Sweeper::spawn("kv-ttl-sweeper", interval, move || store.sweep())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, SledDb, StorageExt};
+    use std::thread;
+    use tempfile::tempdir;
+
+    fn policy(ttl: Option<u64>, idle: Option<u64>) -> TablePolicy {
+        TablePolicy {
+            ttl: ttl.map(Duration::from_millis),
+            idle: idle.map(Duration::from_millis),
+        }
+    }
+
+    #[test]
+    fn table_ttl_should_expire_keys() {
+        let inner = Arc::new(MemTable::new());
+        let store =
+            TableTtlStore::new(inner.clone()).with_table("session", policy(Some(150), None));
+
+        store.hset("session", "k1", "v1").unwrap();
+        store.hset("config", "k1", "v1").unwrap();
+        assert_eq!(store.get("session", "k1").unwrap(), Some("v1".into()));
+
+        thread::sleep(Duration::from_millis(300));
+        assert!(store.get_all("session").unwrap().is_empty());
+        assert_eq!(store.sweep().unwrap(), 1);
+        assert!(!inner.contains("session", "k1").unwrap());
+        // 没有策略的 table 不受影响
+        assert_eq!(store.get("config", "k1").unwrap(), Some("v1".into()));
+    }
+
+    #[test]
+    fn idle_table_should_be_dropped() {
+        let inner = Arc::new(MemTable::new());
+        let store =
+            Arc::new(TableTtlStore::new(inner.clone()).with_table("tmp", policy(None, Some(20))));
+        store.hset("tmp", "k1", "v1").unwrap();
+        store.hset("tmp", "k2", "v2").unwrap();
+
+        let _sweeper =
+            TableTtlStore::spawn_sweeper(store.clone(), Duration::from_millis(10)).unwrap();
+        for _ in 0..100 {
+            if inner.get_all("tmp").unwrap().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(inner.get_all("tmp").unwrap().is_empty());
+    }
+
+    #[test]
+    fn table_ttl_should_be_stored_in_inner() {
+        let dir = tempdir().unwrap();
+        let db = Arc::new(SledDb::new(dir.path()).unwrap());
+        let store =
+            TableTtlStore::new(db.clone()).with_table("session", policy(Some(60_000), None));
+        store.hset("session", "k1", "v1").unwrap();
+        assert_eq!(store.incr("session", "n", 1).unwrap(), 1);
+        assert!(store.set_nx("session", "k2".into(), "v2".into()).unwrap());
+        assert_eq!(store.len("session").unwrap(), 3);
+        drop(store);
+
+        // 过期时间和数据一起保存在 inner 里，不经过 TableTtlStore 也能看到
+        for key in ["k1", "k2", "n"] {
+            let deadline = db.deadline("session", key).unwrap().unwrap();
+            assert!(deadline > now_ms() && deadline <= now_ms() + 60_000);
+        }
+    }
+}
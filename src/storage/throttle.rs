@@ -0,0 +1,381 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Glob, KvError, Kvpair, Storage, TableStats, Value};
+
+/// WriteThrottle 的 admin 命令，返回写延迟和限流的计数
+pub const THROTTLE_COMMAND: &str = "throttle";
+
+/// 拒绝写入期间，隔这么久放一个写操作过去，重新测量 backend 的延迟
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 写限流的阈值
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThrottleConfig {
+    /// 写延迟超过它之后，每个写操作先等待超出的部分
+    pub slow: Duration,
+    /// 写延迟超过它之后，直接返回 Busy
+    pub reject: Duration,
+    /// 同时在 backend 里执行的写操作超过它之后，直接返回 Busy
+    pub max_pending: usize,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            slow: Duration::from_millis(10),
+            reject: Duration::from_millis(100),
+            max_pending: 1024,
+        }
+    }
+}
+
+/// 写限流的统计
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThrottleStats {
+    /// 平滑之后的写延迟
+    pub latency: Duration,
+    /// 正在 backend 里执行的写操作数
+    pub pending: usize,
+    /// 被延迟执行的写操作数
+    pub delayed: u64,
+    /// 被拒绝的写操作数
+    pub rejected: u64,
+}
+
+/// backend 跟不上时对写操作限流的 Storage
+///
+/// 磁盘变慢的时候 sled 的 flush 会积压，写操作越来越慢，连接上排队的请求随之堆积。
+/// WriteThrottle 测量每个写操作在 backend 里花的时间，用 EWMA 平滑：
+/// 超过 slow 时先等待超出的部分再写，让客户端慢下来；超过 reject
+/// 或者积压的写操作太多时直接返回 KvError::Busy，读操作不受影响。
+/// 这样过载时服务器表现为可以预期的变慢和拒绝，而不是内存无限上涨。
+pub struct WriteThrottle<S> {
+    inner: S,
+    config: ThrottleConfig,
+    start: Instant,
+    latency_us: AtomicU64,
+    // 最近一次测量的时间，相对 start 的微秒数
+    sampled_us: AtomicU64,
+    pending: AtomicUsize,
+    delayed: AtomicU64,
+    rejected: AtomicU64,
+}
+
+// 写操作结束（包括出错）时减少 pending
+struct PendingGuard<'a>(&'a AtomicUsize);
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S: Storage> WriteThrottle<S> {
+    pub fn new(inner: S, config: ThrottleConfig) -> Self {
+        Self {
+            inner,
+            config,
+            start: Instant::now(),
+            latency_us: AtomicU64::new(0),
+            sampled_us: AtomicU64::new(0),
+            pending: AtomicUsize::new(0),
+            delayed: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> ThrottleStats {
+        ThrottleStats {
+            latency: Duration::from_micros(self.latency_us.load(Ordering::Relaxed)),
+            pending: self.pending.load(Ordering::Relaxed),
+            delayed: self.delayed.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    fn write<T>(&self, f: impl FnOnce() -> Result<T, KvError>) -> Result<T, KvError> {
+        let latency = Duration::from_micros(self.latency_us.load(Ordering::Relaxed));
+        if latency > self.config.reject && !self.should_probe() {
+            return Err(self.reject(format!("storage is slow: {:?} per write", latency)));
+        }
+
+        let pending = self.pending.fetch_add(1, Ordering::Relaxed);
+        let _guard = PendingGuard(&self.pending);
+        if pending >= self.config.max_pending {
+            return Err(self.reject(format!("{} writes pending", pending)));
+        }
+
+        if latency > self.config.slow {
+            self.delayed.fetch_add(1, Ordering::Relaxed);
+            thread::sleep((latency - self.config.slow).min(self.config.reject));
+        }
+
+        let start = Instant::now();
+        let result = f();
+        self.sample(start.elapsed());
+        result
+    }
+
+    fn reject(&self, reason: String) -> KvError {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        KvError::Busy(reason)
+    }
+
+    // 一直拒绝的话延迟就不会再更新，所以隔一段时间放一个写操作过去
+    fn should_probe(&self) -> bool {
+        let now = self.start.elapsed().as_micros() as u64;
+        let last = self.sampled_us.load(Ordering::Relaxed);
+        now.saturating_sub(last) >= PROBE_INTERVAL.as_micros() as u64
+            && self
+                .sampled_us
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    fn sample(&self, elapsed: Duration) {
+        let sample = elapsed.as_micros() as u64;
+        // 新的测量占 1/4 的权重，偶尔一次慢写不会触发限流
+        let _ = self
+            .latency_us
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| {
+                Some((old * 3 + sample) / 4)
+            });
+        let now = self.start.elapsed().as_micros() as u64;
+        self.sampled_us.store(now, Ordering::Relaxed);
+    }
+}
+
+impl<S: Storage> Storage for WriteThrottle<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.write(|| self.inner.set(table, key, value))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.write(|| self.inner.del(table, key))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.inner.get_iter(table)
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        self.inner.len(table)
+    }
+
+    fn get_iter_matching(
+        &self,
+        table: &str,
+        pattern: &Glob,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.inner.get_iter_matching(table, pattern)
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        pattern: Option<&Glob>,
+        count: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.scan(table, after, pattern, count)
+    }
+
+    fn admin(&self, command: &str, args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        if command != THROTTLE_COMMAND {
+            return self.inner.admin(command, args);
+        }
+        let s = self.stats();
+        Ok(vec![
+            Kvpair::new("latency_us", (s.latency.as_micros() as i64).into()),
+            Kvpair::new("pending", (s.pending as i64).into()),
+            Kvpair::new("delayed", (s.delayed as i64).into()),
+            Kvpair::new("rejected", (s.rejected as i64).into()),
+        ])
+    }
+
+    fn undelete(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.write(|| self.inner.undelete(table, key))
+    }
+
+    fn purge_trash(&self, table: &str, all: bool) -> Result<usize, KvError> {
+        self.write(|| self.inner.purge_trash(table, all))
+    }
+
+    fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+        self.write(|| self.inner.clone_table(src, dst))
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.inner.list_tables()
+    }
+
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        self.inner.stats()
+    }
+
+    fn flush_all(&self) -> Result<usize, KvError> {
+        self.write(|| self.inner.flush_all())
+    }
+
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        self.write(|| self.inner.drop_table(table))
+    }
+
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        self.write(|| self.inner.move_key(src, dst, key, force))
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.write(|| self.inner.incr(table, key, delta))
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        self.write(|| self.inner.set_nx(table, key, value))
+    }
+
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        self.write(|| self.inner.expire_at(table, key, deadline))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.write(|| self.inner.persist(table, key))
+    }
+
+    fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, KvError> {
+        self.inner.deadline(table, key)
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.write(|| self.inner.purge_expired())
+    }
+
+    fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        self.inner.version(table, key)
+    }
+
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        self.write(|| self.inner.set_if_version(table, key, value, version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, MemTable, Service, ServiceInner, StorageExt};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    // 可以随时让写操作变慢的 MemTable
+    #[derive(Default)]
+    struct SlowDisk {
+        inner: MemTable,
+        slow: AtomicBool,
+    }
+
+    impl Storage for SlowDisk {
+        fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            self.inner.get(table, key)
+        }
+
+        fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+            if self.slow.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(50));
+            }
+            self.inner.set(table, key, value)
+        }
+
+        fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+            self.inner.contains(table, key)
+        }
+
+        fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            self.inner.del(table, key)
+        }
+
+        fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+            self.inner.get_all(table)
+        }
+
+        fn get_iter(
+            &self,
+            table: &str,
+        ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+            self.inner.get_iter(table)
+        }
+    }
+
+    fn config() -> ThrottleConfig {
+        ThrottleConfig {
+            slow: Duration::from_millis(5),
+            reject: Duration::from_millis(20),
+            max_pending: 16,
+        }
+    }
+
+    #[test]
+    fn slow_storage_should_reject_writes() {
+        let disk = Arc::new(SlowDisk::default());
+        let store = WriteThrottle::new(disk.clone(), config());
+        store.hset("t1", "k1", "v1").unwrap();
+        assert_eq!(store.stats().rejected, 0);
+
+        disk.slow.store(true, Ordering::Relaxed);
+        let rejected = (0..10)
+            .filter(|_| matches!(store.hset("t1", "k2", "v2"), Err(KvError::Busy(_))))
+            .count();
+        assert!(rejected > 0);
+        assert!(store.stats().delayed > 0);
+        // 读不受影响
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+
+        // 磁盘恢复之后，探测的写操作会把延迟降下来
+        disk.slow.store(false, Ordering::Relaxed);
+        let mut recovered = false;
+        for _ in 0..200 {
+            if store.hset("t1", "k3", "v3").is_ok() && store.stats().latency < config().slow {
+                recovered = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(recovered);
+    }
+
+    #[test]
+    fn throttle_should_be_reported_by_admin_command() {
+        let store = WriteThrottle::new(MemTable::new(), config());
+        let service: Service<_> = ServiceInner::new(store).into();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+
+        let res = service.execute(CommandRequest::new_admin(THROTTLE_COMMAND, vec![]));
+        assert_eq!(res.status, 200);
+        assert_eq!(res.pairs.len(), 4);
+        assert_eq!(res.pairs[3], Kvpair::new("rejected", 0.into()));
+    }
+}
@@ -0,0 +1,471 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tracing::warn;
+
+use super::{Sweeper, TableStats, FLUSH_COMMAND};
+use crate::{
+    BulkLoadStats, Glob, KeyMeta, KeyRange, KvError, Kvpair, Order, Snapshot, Storage,
+    StorageMetrics, Value,
+};
+
+/// TieredStore 的写策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WritePolicy {
+    /// 写操作同时写到 cache 和 persistent，返回时已经持久化了
+    #[default]
+    WriteThrough,
+    /// 写操作只写 cache，记下来之后由 flush 批量写到 persistent。
+    /// 写的延迟只取决于 cache，代价是 flush 之前崩溃会丢掉这些修改
+    WriteBack,
+}
+
+/// 快的 cache 放在慢的 persistent 前面，比如 MemTable 在 SledDb 前面
+///
+/// 读先查 cache，没有的话读 persistent 并且放进 cache。persistent 是数据的权威来源，
+/// 只有通过这里写入才能保证 cache 不会过时；设置了过期时间的 key 不放进 cache。
+/// del 总是把 key 从 cache 里去掉，cache 是有容量限制的 backend 时被淘汰的 key 下次读时重新加载。
+///
+/// HINCRBY、HSETNX 这些需要原子性的操作直接在 persistent 上执行，之后让 cache 里的 key 失效。
+/// 遍历、统计 table 的操作也只看 persistent，write-back 时先 flush
+pub struct TieredStore<C: Storage, P: Storage> {
+    cache: C,
+    persistent: P,
+    policy: WritePolicy,
+    // write-back 时还没写到 persistent 的修改，None 表示删除
+    dirty: DashMap<(String, String), Option<Value>>,
+    // 同时只有一个 flush，保证同一个 key 的修改按顺序写到 persistent
+    flushing: Mutex<()>,
+}
+
+impl<C: Storage, P: Storage> TieredStore<C, P> {
+    pub fn new(cache: C, persistent: P) -> Self {
+        Self {
+            cache,
+            persistent,
+            policy: WritePolicy::default(),
+            dirty: DashMap::new(),
+            flushing: Mutex::new(()),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: WritePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn policy(&self) -> WritePolicy {
+        self.policy
+    }
+
+    /// write-back 时还没有写到 persistent 的 key 的数量
+    pub fn pending(&self) -> usize {
+        self.dirty.len()
+    }
+
+    /// 把 write-back 积累的修改写到 persistent，返回写入的 key 的数量。
+    /// 写失败的 key 留到下一次 flush
+    pub fn flush(&self) -> Result<usize, KvError> {
+        let _flushing = self.flushing.lock().unwrap_or_else(|e| e.into_inner());
+        let ids: Vec<_> = self.dirty.iter().map(|e| e.key().clone()).collect();
+        let mut n = 0;
+        for id in ids {
+            // flush 的途中又被修改的话，新的值下一次再写
+            let (id, value) = match self.dirty.remove(&id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let result = match &value {
+                Some(v) => self
+                    .persistent
+                    .set(&id.0, id.1.clone(), v.clone())
+                    .map(drop),
+                None => self.persistent.del(&id.0, &id.1).map(drop),
+            };
+            if let Err(e) = result {
+                self.dirty.entry(id).or_insert(value);
+                return Err(e);
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    // write-back 时先把这个 key 的修改写到 persistent
+    fn flush_key(&self, table: &str, key: &str) -> Result<(), KvError> {
+        let _flushing = self.flushing.lock().unwrap_or_else(|e| e.into_inner());
+        match self.dirty.remove(&(table.into(), key.into())) {
+            Some((_, Some(v))) => self.persistent.set(table, key.into(), v).map(drop),
+            Some((_, None)) => self.persistent.del(table, key).map(drop),
+            None => Ok(()),
+        }
+    }
+
+    // 在 persistent 上执行一个修改 key 的操作，之后 cache 里的 key 失效
+    fn forward<T>(
+        &self,
+        table: &str,
+        key: &str,
+        f: impl FnOnce(&P) -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        self.flush_key(table, key)?;
+        let result = f(&self.persistent);
+        self.cache.del(table, key)?;
+        result
+    }
+
+    fn flushed(&self) -> Result<&P, KvError> {
+        if self.policy == WritePolicy::WriteBack {
+            self.flush()?;
+        }
+        Ok(&self.persistent)
+    }
+
+    // 过期时间只有 persistent 知道，有过期时间的 key 不放进 cache
+    fn fill(&self, table: &str, key: &str, value: &Value) -> Result<(), KvError> {
+        if let Ok(Some(_)) = self.persistent.deadline(table, key) {
+            return Ok(());
+        }
+        self.cache.set(table, key.into(), value.clone()).map(drop)
+    }
+}
+
+impl<C, P> TieredStore<C, P>
+where
+    C: Storage + Send + Sync + 'static,
+    P: Storage + Send + Sync + 'static,
+{
+    /// write-back 时在后台每隔 interval flush 一次
+    pub fn spawn_flusher(self: &Arc<Self>, interval: Duration) -> Result<Sweeper, KvError> {
+        let store = self.clone();
+        Sweeper::spawn("kv-tiered-flush", interval, move || {
+            TieredStore::flush(&store)
+        })
+    }
+}
+
+impl<C: Storage, P: Storage> Drop for TieredStore<C, P> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to flush {} pending writes: {}", self.pending(), e);
+        }
+    }
+}
+
+impl<C: Storage, P: Storage> Storage for TieredStore<C, P> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        if let Some(value) = self.dirty.get(&(table.into(), key.into())) {
+            return Ok(value.clone());
+        }
+        if let Some(v) = self.cache.get(table, key)? {
+            return Ok(Some(v));
+        }
+        let value = self.persistent.get(table, key)?;
+        if let Some(v) = &value {
+            self.fill(table, key, v)?;
+        }
+        Ok(value)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let old = match self.policy {
+            WritePolicy::WriteThrough => self.persistent.set(table, key.clone(), value.clone())?,
+            WritePolicy::WriteBack => {
+                let old = self.get(table, &key)?;
+                self.dirty
+                    .insert((table.into(), key.clone()), Some(value.clone()));
+                old
+            }
+        };
+        self.cache.set(table, key, value)?;
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.get(table, key)?.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let old = match self.policy {
+            WritePolicy::WriteThrough => self.persistent.del(table, key)?,
+            WritePolicy::WriteBack => {
+                let old = self.get(table, key)?;
+                self.dirty.insert((table.into(), key.into()), None);
+                old
+            }
+        };
+        self.cache.del(table, key)?;
+        Ok(old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.flushed()?.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.flushed()?.get_iter(table)
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        self.flushed()?.len(table)
+    }
+
+    fn get_iter_matching(
+        &self,
+        table: &str,
+        pattern: &Glob,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.flushed()?.get_iter_matching(table, pattern)
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        pattern: Option<&Glob>,
+        count: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.flushed()?.scan(table, after, pattern, count)
+    }
+
+    fn admin(&self, command: &str, args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        match command {
+            FLUSH_COMMAND => self.flushed()?.admin(command, args),
+            _ => self.persistent.admin(command, args),
+        }
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.flushed()?.list_tables()
+    }
+
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        self.flushed()?.stats()
+    }
+
+    fn flush_all(&self) -> Result<usize, KvError> {
+        let n = self.flushed()?.flush_all()?;
+        self.cache.flush_all()?;
+        Ok(n)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        let n = self.flushed()?.drop_table(table)?;
+        self.cache.drop_table(table)?;
+        Ok(n)
+    }
+
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        self.flush_key(dst, key)?;
+        let result = self.forward(src, key, |p| p.move_key(src, dst, key, force));
+        self.cache.del(dst, key)?;
+        result
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.forward(table, key, |p| p.incr(table, key, delta))
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        self.forward(table, &key.clone(), |p| p.set_nx(table, key, value))
+    }
+
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        self.forward(table, key, |p| p.expire_at(table, key, deadline))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.forward(table, key, |p| p.persist(table, key))
+    }
+
+    fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, KvError> {
+        self.flush_key(table, key)?;
+        self.persistent.deadline(table, key)
+    }
+
+    // 有过期时间的 key 不在 cache 里，只需要清理 persistent
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.persistent.purge_expired()
+    }
+
+    fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        self.flush_key(table, key)?;
+        self.persistent.version(table, key)
+    }
+
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        self.forward(table, &key.clone(), |p| {
+            p.set_if_version(table, key, value, version)
+        })
+    }
+
+    fn get_iter_ordered(
+        &self,
+        table: &str,
+        order: Order,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.flushed()?.get_iter_ordered(table, order)
+    }
+
+    fn snapshot(&self, table: &str) -> Result<Box<dyn Snapshot>, KvError> {
+        self.flushed()?.snapshot(table)
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        range: KeyRange,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.flushed()?.get_range(table, range)
+    }
+
+    fn undelete(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.forward(table, key, |p| p.undelete(table, key))
+    }
+
+    fn purge_trash(&self, table: &str, all: bool) -> Result<usize, KvError> {
+        self.persistent.purge_trash(table, all)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<(u64, Value)>, KvError> {
+        self.flush_key(table, key)?;
+        self.persistent.history(table, key)
+    }
+
+    // dst 是空的，cache 里不会有它的 key
+    fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+        self.flushed()?.clone_table(src, dst)
+    }
+
+    fn meta(&self, table: &str, key: &str) -> Result<Option<KeyMeta>, KvError> {
+        self.flush_key(table, key)?;
+        self.persistent.meta(table, key)
+    }
+
+    // 覆盖了哪些 key 不知道，整个 table 都从 cache 里去掉
+    fn bulk_load(
+        &self,
+        table: &str,
+        pairs: &mut dyn Iterator<Item = Kvpair>,
+    ) -> Result<BulkLoadStats, KvError> {
+        let stats = self.flushed()?.bulk_load(table, pairs)?;
+        self.cache.drop_table(table)?;
+        Ok(stats)
+    }
+
+    fn query(
+        &self,
+        table: &str,
+        index: &str,
+        value: &Value,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.flushed()?.query(table, index, value, limit)
+    }
+
+    fn export(&self, w: &mut dyn std::io::Write) -> Result<usize, KvError> {
+        self.flushed()?.export(w)
+    }
+
+    fn import(&self, r: &mut dyn std::io::Read) -> Result<usize, KvError> {
+        let n = self.flushed()?.import(r)?;
+        self.cache.flush_all()?;
+        Ok(n)
+    }
+
+    fn metrics(&self) -> Result<StorageMetrics, KvError> {
+        self.flushed()?.metrics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, SledDb, StorageExt};
+    use std::ops::Bound;
+    use tempfile::tempdir;
+
+    #[test]
+    fn tiered_store_should_fill_cache_on_miss() {
+        let dir = tempdir().unwrap();
+        let persistent = Arc::new(SledDb::new(dir.path()).unwrap());
+        let cache = Arc::new(MemTable::new());
+        let store = TieredStore::new(cache.clone(), persistent.clone());
+
+        persistent.hset("t1", "k1", "v1").unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(cache.get("t1", "k1").unwrap(), Some("v1".into()));
+
+        // 写同时到两边，del 让 cache 失效
+        assert_eq!(store.hset("t1", "k1", "v2").unwrap(), Some("v1".into()));
+        assert_eq!(persistent.get("t1", "k1").unwrap(), Some("v2".into()));
+        assert_eq!(store.del("t1", "k1").unwrap(), Some("v2".into()));
+        assert_eq!(cache.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+
+        // 原子操作在 persistent 上执行，cache 里的旧值失效
+        store.hset("t1", "n", 1).unwrap();
+        assert_eq!(store.incr("t1", "n", 2).unwrap(), 3);
+        assert_eq!(store.get("t1", "n").unwrap(), Some(3.into()));
+    }
+
+    #[test]
+    fn tiered_store_should_write_back_on_flush() {
+        let dir = tempdir().unwrap();
+        let persistent = Arc::new(SledDb::new(dir.path()).unwrap());
+        let store = TieredStore::new(MemTable::new(), persistent.clone())
+            .with_policy(WritePolicy::WriteBack);
+
+        persistent.hset("t1", "k2", "old").unwrap();
+        store.hset("t1", "k1", "v1").unwrap();
+        assert_eq!(store.del("t1", "k2").unwrap(), Some("old".into()));
+        assert_eq!(store.pending(), 2);
+        assert_eq!(persistent.get("t1", "k1").unwrap(), None);
+        // 还没有 flush 的删除不会读到 persistent 里的旧值
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+
+        assert_eq!(store.flush().unwrap(), 2);
+        assert_eq!(persistent.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(persistent.get("t1", "k2").unwrap(), None);
+
+        // 遍历之前先 flush，drop 的时候也会 flush
+        store.hset("t1", "k3", "v3").unwrap();
+        assert_eq!(store.len("t1").unwrap(), 2);
+        store.hset("t1", "k4", "v4").unwrap();
+        drop(store);
+        assert_eq!(persistent.get("t1", "k4").unwrap(), Some("v4".into()));
+    }
+
+    #[test]
+    fn other_commands_should_see_pending_writes() {
+        let store =
+            TieredStore::new(MemTable::new(), MemTable::new()).with_policy(WritePolicy::WriteBack);
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+
+        let range = (Bound::Excluded("k1"), Bound::Unbounded);
+        let keys: Vec<_> = store
+            .get_range("t1", range)
+            .unwrap()
+            .map(|p| p.key)
+            .collect();
+        assert_eq!(keys, ["k2"]);
+        assert_eq!(store.pending(), 0);
+        assert!(store.version("t1", "k1").unwrap().is_some());
+        assert_eq!(store.clone_table("t1", "t2").unwrap(), 2);
+        assert_eq!(store.get("t2", "k2").unwrap(), Some("v2".into()));
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+const LEVELS: usize = 4;
+
+/// 分层的时间轮，用来处理 key 的过期
+///
+/// 每层 64 个槽，第 0 层一个槽是一个 tick，往上每层的槽覆盖下一层一整圈。
+/// 定时器按照到期时间和当前时间最高的不同位放进对应的层，时间走到的时候
+/// 再逐层往下挪，所以 schedule 和 cancel 都是 O(1)，每个 tick 只需要处理
+/// 到期的那个槽，不用扫描所有的 key。超出最高层范围的定时器先放在 overflow 里。
+///
+/// cancel 和重新 schedule 不去槽里找旧的定时器，而是以 deadlines 里记录的为准，
+/// 槽里过时的条目在被处理到的时候直接丢掉。
+pub struct TimerWheel<K> {
+    tick: Duration,
+    start: Instant,
+    current: u64,
+    levels: Vec<Vec<Vec<(K, u64)>>>,
+    overflow: Vec<(K, u64)>,
+    deadlines: HashMap<K, u64>,
+}
+
+impl<K: Hash + Eq + Clone> TimerWheel<K> {
+    /// tick 是时间轮的精度，到期的 key 最多晚一个 tick 被发现
+    pub fn new(tick: Duration) -> Self {
+        Self::with_start(tick, Instant::now())
+    }
+
+    fn with_start(tick: Duration, start: Instant) -> Self {
+        Self {
+            tick,
+            start,
+            current: 0,
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+            overflow: Vec::new(),
+            deadlines: HashMap::new(),
+        }
+    }
+
+    /// 等待到期的 key 的个数
+    pub fn len(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+
+    /// 设置 key 在 at 这个时刻到期，已有的定时器会被替换
+    pub fn schedule(&mut self, key: K, at: Instant) {
+        let ticks = at.saturating_duration_since(self.start).as_nanos() / self.tick.as_nanos();
+        // 已经过了的时间在下一个 tick 到期
+        let deadline = (ticks as u64).max(self.current + 1);
+        self.deadlines.insert(key.clone(), deadline);
+        self.place(key, deadline);
+    }
+
+    /// key 的到期时间，精度是一个 tick
+    pub fn deadline(&self, key: &K) -> Option<Instant> {
+        let ticks = *self.deadlines.get(key)?;
+        let nanos = (self.tick.as_nanos() as u64).saturating_mul(ticks);
+        Some(self.start + Duration::from_nanos(nanos))
+    }
+
+    /// 取消 key 的定时器，返回之前是否有定时器
+    pub fn cancel(&mut self, key: &K) -> bool {
+        self.deadlines.remove(key).is_some()
+    }
+
+    /// 时间走到 now，返回这期间到期的 key
+    pub fn advance(&mut self, now: Instant) -> Vec<K> {
+        let target =
+            (now.saturating_duration_since(self.start).as_nanos() / self.tick.as_nanos()) as u64;
+        let mut expired = Vec::new();
+
+        while self.current < target && !self.deadlines.is_empty() {
+            self.current += 1;
+            self.cascade();
+
+            let slot = (self.current & SLOT_MASK) as usize;
+            for (key, deadline) in std::mem::take(&mut self.levels[0][slot]) {
+                if self.deadlines.get(&key) == Some(&deadline) {
+                    self.deadlines.remove(&key);
+                    expired.push(key);
+                }
+            }
+        }
+
+        // 没有定时器的时候直接跳到目标时间
+        self.current = self.current.max(target);
+        expired
+    }
+
+    fn place(&mut self, key: K, deadline: u64) {
+        let diff = deadline ^ self.current;
+        let level = match diff {
+            0 => 0,
+            _ => ((63 - diff.leading_zeros()) / SLOT_BITS) as usize,
+        };
+
+        if level >= LEVELS {
+            self.overflow.push((key, deadline));
+        } else {
+            let slot = ((deadline >> (level as u32 * SLOT_BITS)) & SLOT_MASK) as usize;
+            self.levels[level][slot].push((key, deadline));
+        }
+    }
+
+    /// 低层转完一圈的时候，把高层对应槽里的定时器重新放到低层
+    fn cascade(&mut self) {
+        if self.current.trailing_zeros() >= LEVELS as u32 * SLOT_BITS {
+            for (key, deadline) in std::mem::take(&mut self.overflow) {
+                self.replace(key, deadline);
+            }
+        }
+
+        for level in (1..LEVELS).rev() {
+            let shift = level as u32 * SLOT_BITS;
+            if self.current & ((1 << shift) - 1) != 0 {
+                continue;
+            }
+            let slot = ((self.current >> shift) & SLOT_MASK) as usize;
+            for (key, deadline) in std::mem::take(&mut self.levels[level][slot]) {
+                self.replace(key, deadline);
+            }
+        }
+    }
+
+    fn replace(&mut self, key: K, deadline: u64) {
+        if self.deadlines.get(&key) == Some(&deadline) {
+            self.place(key, deadline);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    fn at(start: Instant, ticks: u64) -> Instant {
+        start + TICK * ticks as u32
+    }
+
+    #[test]
+    fn timer_wheel_should_expire_in_order() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::with_start(TICK, start);
+        // 覆盖每一层和 overflow
+        let deadlines = [3, 64, 65, 4095, 4097, 300_000, 20_000_000];
+        for (i, d) in deadlines.iter().enumerate() {
+            wheel.schedule(i, at(start, *d));
+        }
+        assert_eq!(wheel.len(), deadlines.len());
+
+        for (i, d) in deadlines.iter().enumerate() {
+            assert!(wheel.advance(at(start, d - 1)).is_empty());
+            assert_eq!(wheel.advance(at(start, *d)), vec![i]);
+        }
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn timer_wheel_should_cancel_and_reschedule() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::with_start(TICK, start);
+        wheel.schedule("a", at(start, 10));
+        wheel.schedule("b", at(start, 10));
+        wheel.schedule("b", at(start, 100));
+        assert!(wheel.cancel(&"a"));
+        assert!(!wheel.cancel(&"c"));
+
+        assert!(wheel.advance(at(start, 50)).is_empty());
+        assert_eq!(wheel.advance(at(start, 100)), vec!["b"]);
+    }
+
+    #[test]
+    fn timer_wheel_should_expire_past_deadline_on_next_tick() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::with_start(TICK, start);
+        wheel.advance(at(start, 1000));
+        wheel.schedule(1, start);
+        assert_eq!(wheel.advance(at(start, 1001)), vec![1]);
+    }
+}
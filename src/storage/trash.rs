@@ -0,0 +1,364 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::time::Duration;
+
+use super::expiry::now_ms;
+use crate::{KeyMeta, KvError, Kvpair, Storage, StorageMetrics, TableStats, Value};
+
+/// 回收站使用的 table 都以它开头，不能直接访问
+pub const TRASH_PREFIX: &str = "__trash__:";
+
+/// 开启了软删除的 Storage
+///
+/// 指定的 tables 里删除的 key 不会马上消失，而是移到回收站里：
+/// `__trash__:v:<table>` 保存 value，`__trash__:t:<table>` 保存删除的时间。
+/// 回收站和数据存在同一个 backend 里，用 sled 时重启之后也可以恢复。
+/// 超过保留期的 key 不能再恢复，可以用 purge_trash 清理掉；purge_expired 也会清理它们，
+/// 所以 Service::spawn_expiry_sweeper 会在后台定期回收。
+pub struct SoftDeleteStore<S> {
+    inner: S,
+    tables: HashSet<String>,
+    retention: Duration,
+}
+
+impl<S: Storage> SoftDeleteStore<S> {
+    pub fn new(
+        inner: S,
+        tables: impl IntoIterator<Item = impl Into<String>>,
+        retention: Duration,
+    ) -> Self {
+        Self {
+            inner,
+            tables: tables.into_iter().map(Into::into).collect(),
+            retention,
+        }
+    }
+
+    fn check(&self, table: &str) -> Result<(), KvError> {
+        match table.starts_with(TRASH_PREFIX) {
+            true => Err(KvError::InvalidCommand(format!(
+                "Table {} is reserved",
+                table
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    fn check_soft(&self, table: &str) -> Result<(), KvError> {
+        match self.tables.contains(table) {
+            true => Ok(()),
+            false => Err(KvError::InvalidCommand(format!(
+                "Table {} has no recycle bin",
+                table
+            ))),
+        }
+    }
+
+    // 删除时间已经超过保留期
+    fn expired(&self, deleted_at: Value) -> Result<bool, KvError> {
+        let deleted_at: i64 = deleted_at.try_into()?;
+        Ok(now_ms() - deleted_at > self.retention.as_millis() as i64)
+    }
+
+    fn forget(&self, table: &str, key: &str) -> Result<(), KvError> {
+        self.inner.del(&values_of(table), key)?;
+        self.inner.del(&times_of(table), key)?;
+        Ok(())
+    }
+}
+
+impl<S: Storage> Storage for SoftDeleteStore<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.check(table)?;
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.check(table)?;
+        self.inner.set(table, key, value)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.check(table)?;
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.check(table)?;
+        if !self.tables.contains(table) {
+            return self.inner.del(table, key);
+        }
+
+        // 先放进回收站再删除，中途失败最多是多了一份
+        if let Some(v) = self.inner.get(table, key)? {
+            self.inner.set(&values_of(table), key.into(), v)?;
+            self.inner
+                .set(&times_of(table), key.into(), now_ms().into())?;
+        }
+        self.inner.del(table, key)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.check(table)?;
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.check(table)?;
+        self.inner.get_iter(table)
+    }
+
+    fn admin(&self, command: &str, args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.admin(command, args)
+    }
+
+    // 回收站的 table 不能直接访问，也不列出来
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = self.inner.list_tables()?;
+        tables.retain(|t| !t.starts_with(TRASH_PREFIX));
+        Ok(tables)
+    }
+
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        let mut stats = self.inner.stats()?;
+        stats.retain(|s| !s.table.starts_with(TRASH_PREFIX));
+        Ok(stats)
+    }
+
+    fn metrics(&self) -> Result<StorageMetrics, KvError> {
+        let mut metrics = self.inner.metrics()?;
+        metrics
+            .tables
+            .retain(|s| !s.table.starts_with(TRASH_PREFIX));
+        Ok(metrics)
+    }
+
+    // 整个 table 一起删掉，不进回收站；回收站里的 key 不算在删除的数量里
+    fn flush_all(&self) -> Result<usize, KvError> {
+        let mut n = 0;
+        for table in self.inner.list_tables()? {
+            let dropped = self.inner.drop_table(&table)?;
+            if !table.starts_with(TRASH_PREFIX) {
+                n += dropped;
+            }
+        }
+        Ok(n)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        self.check(table)?;
+        self.inner.drop_table(table)
+    }
+
+    // 移走的 key 还在 dst 里，不算删除
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        self.check(src)?;
+        self.check(dst)?;
+        self.inner.move_key(src, dst, key, force)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.check(table)?;
+        self.inner.incr(table, key, delta)
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        self.check(table)?;
+        self.inner.set_nx(table, key, value)
+    }
+
+    fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        self.check(table)?;
+        self.inner.version(table, key)
+    }
+
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        self.check(table)?;
+        self.inner.set_if_version(table, key, value, version)
+    }
+
+    fn meta(&self, table: &str, key: &str) -> Result<Option<KeyMeta>, KvError> {
+        self.check(table)?;
+        self.inner.meta(table, key)
+    }
+
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        self.check(table)?;
+        self.inner.expire_at(table, key, deadline)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.check(table)?;
+        self.inner.persist(table, key)
+    }
+
+    fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, KvError> {
+        self.check(table)?;
+        self.inner.deadline(table, key)
+    }
+
+    // 过期的 key 直接删掉，不进回收站；顺便清理超过保留期的回收站
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let mut purged = self.inner.purge_expired()?;
+        for table in &self.tables {
+            purged += self.purge_trash(table, false)?;
+        }
+        Ok(purged)
+    }
+
+    fn undelete(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.check_soft(table)?;
+        // 不覆盖删除之后又写入的 value
+        if self.inner.contains(table, key)? {
+            return Err(KvError::InvalidCommand(format!(
+                "Key {} already exists in table {}",
+                key, table
+            )));
+        }
+
+        let deleted_at = match self.inner.get(&times_of(table), key)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        if self.expired(deleted_at)? {
+            self.forget(table, key)?;
+            return Ok(None);
+        }
+
+        let value = self.inner.get(&values_of(table), key)?;
+        if let Some(v) = &value {
+            self.inner.set(table, key.into(), v.clone())?;
+        }
+        self.forget(table, key)?;
+        Ok(value)
+    }
+
+    fn purge_trash(&self, table: &str, all: bool) -> Result<usize, KvError> {
+        self.check_soft(table)?;
+        let mut purged = 0;
+        for pair in self.inner.get_iter(&times_of(table))? {
+            let deleted_at = pair.value.unwrap_or_default();
+            if all || self.expired(deleted_at)? {
+                self.forget(table, &pair.key)?;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+}
+
+fn values_of(table: &str) -> String {
+    format!("{}v:{}", TRASH_PREFIX, table)
+}
+
+fn times_of(table: &str) -> String {
+    format!("{}t:{}", TRASH_PREFIX, table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, MemTable, Service, ServiceInner, StorageExt};
+
+    fn store(retention: Duration) -> SoftDeleteStore<MemTable> {
+        let store = SoftDeleteStore::new(MemTable::new(), ["config"], retention);
+        store.hset("config", "k1", "v1").unwrap();
+        store.hset("other", "k1", "v1").unwrap();
+        store
+    }
+
+    #[test]
+    fn deleted_key_should_be_restored() {
+        let store = store(Duration::from_secs(60));
+        assert_eq!(store.del("config", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("config", "k1").unwrap(), None);
+
+        assert_eq!(store.undelete("config", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("config", "k1").unwrap(), Some("v1".into()));
+        // 恢复之后回收站里就没有了
+        assert_eq!(store.undelete("config", "k2").unwrap(), None);
+        assert!(store.undelete("config", "k1").is_err());
+
+        // 没有开启软删除的 table 直接删除
+        store.del("other", "k1").unwrap();
+        assert!(store.undelete("other", "k1").is_err());
+        assert!(store.get_all(&values_of("config")).is_err());
+    }
+
+    #[test]
+    fn trash_should_respect_retention() {
+        let store = store(Duration::ZERO);
+        store.hset("config", "k2", "v2").unwrap();
+        store.del("config", "k1").unwrap();
+        store.del("config", "k2").unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(store.undelete("config", "k1").unwrap(), None);
+        assert_eq!(store.purge_trash("config", false).unwrap(), 1);
+        assert_eq!(store.purge_trash("config", true).unwrap(), 0);
+    }
+
+    #[test]
+    fn purge_expired_should_collect_old_trash() {
+        let store = store(Duration::ZERO);
+        store.del("config", "k1").unwrap();
+        assert_eq!(store.list_tables().unwrap(), ["other"]);
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(store.purge_expired().unwrap(), 1);
+        assert!(store
+            .inner
+            .get_all(&values_of("config"))
+            .unwrap()
+            .is_empty());
+        assert!(store.inner.get_all(&times_of("config")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn atomic_commands_should_pass_through() {
+        let service: Service<_> = ServiceInner::new(store(Duration::from_secs(60))).into();
+        let res = service.execute(CommandRequest::new_hincrby("config", "n", 5));
+        assert_eq!(res.values, vec![5.into()]);
+        let res = service.execute(CommandRequest::new_hsetnx("config", "k1", "v2".into()));
+        assert_eq!(res.values, vec![false.into()]);
+        let res = service.execute(CommandRequest::new_move("config", "other", "n", false));
+        assert_eq!(res.status, 200);
+        assert_eq!(service.store().get("other", "n").unwrap(), Some(5.into()));
+
+        // drop_table 不进回收站，stats 也不列出回收站
+        let store = service.store();
+        store.del("config", "k1").unwrap();
+        store.hset("config", "k2", "v2").unwrap();
+        assert_eq!(store.drop_table("config").unwrap(), 1);
+        assert_eq!(store.inner.get_all(&values_of("config")).unwrap().len(), 1);
+        let stats = store.stats().unwrap();
+        assert!(stats.iter().all(|s| !s.table.starts_with(TRASH_PREFIX)));
+    }
+
+    #[test]
+    fn trash_commands_should_work() {
+        let service: Service<_> = ServiceInner::new(store(Duration::from_secs(60))).into();
+        service.store().del("config", "k1").unwrap();
+
+        let res = service.execute(CommandRequest::new_undelete("config", "k1"));
+        assert_eq!(res.values, vec!["v1".into()]);
+        let res = service.execute(CommandRequest::new_undelete("config", "k2"));
+        assert_eq!(res.status, 404);
+
+        service.store().del("config", "k1").unwrap();
+        let res = service.execute(CommandRequest::new_purge_trash("config", true));
+        assert_eq!(res.values, vec![1.into()]);
+    }
+}
@@ -0,0 +1,647 @@
+use flate2::Crc;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tracing::warn;
+
+use super::expiry::Sweeper;
+use super::{Changefeed, COMPACT_COMMAND, FLUSH_COMMAND};
+use crate::{
+    KeyMeta, KeyRange, KvError, Kvpair, MemTable, Snapshot, Storage, StorageMetrics, TableStats,
+    Value,
+};
+
+// 每个 record 的头：crc32 和 body 的长度，crc 只覆盖 body
+const HEADER_LEN: usize = 8;
+
+/// 日志里的一个写操作，重放时调用 MemTable 同样的方法
+#[derive(Clone, Debug, PartialEq)]
+enum Op {
+    Set(String, String, Value),
+    Del(String, String),
+    Incr(String, String, i64),
+    ExpireAt(String, String, i64),
+    Persist(String, String),
+    Move(String, String, String),
+    Clone(String, String),
+    DropTable(String),
+    FlushAll,
+}
+
+impl Op {
+    // body 是一个字节的类型，然后是每个字段的长度（u32）和内容
+    fn encode(&self) -> Result<Vec<u8>, KvError> {
+        let b = |s: &String| s.as_bytes().to_vec();
+        let (tag, fields): (u8, Vec<Vec<u8>>) = match self {
+            Op::Set(t, k, v) => (1, vec![b(t), b(k), v.clone().try_into()?]),
+            Op::Del(t, k) => (2, vec![b(t), b(k)]),
+            Op::Incr(t, k, n) => (3, vec![b(t), b(k), n.to_be_bytes().into()]),
+            Op::ExpireAt(t, k, d) => (4, vec![b(t), b(k), d.to_be_bytes().into()]),
+            Op::Persist(t, k) => (5, vec![b(t), b(k)]),
+            Op::Move(s, d, k) => (6, vec![b(s), b(d), b(k)]),
+            Op::Clone(s, d) => (7, vec![b(s), b(d)]),
+            Op::DropTable(t) => (8, vec![b(t)]),
+            Op::FlushAll => (9, vec![]),
+        };
+        let mut body = vec![tag];
+        for field in fields {
+            body.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            body.extend_from_slice(&field);
+        }
+        Ok(body)
+    }
+
+    fn decode(body: &[u8]) -> Result<Self, KvError> {
+        let bad = || KvError::Internal("malformed WAL record".into());
+        let (tag, mut rest) = body.split_first().ok_or_else(bad)?;
+        let mut fields = Vec::new();
+        while !rest.is_empty() {
+            let len = rest.get(..4).ok_or_else(bad)?;
+            let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+            let field = rest.get(4..4 + len).ok_or_else(bad)?;
+            fields.push(field);
+            rest = &rest[4 + len..];
+        }
+        let s = |i: usize| -> Result<String, KvError> {
+            let field = fields.get(i).ok_or_else(bad)?;
+            String::from_utf8(field.to_vec()).map_err(|_| bad())
+        };
+        let n = |i: usize| -> Result<i64, KvError> {
+            let field = fields.get(i).ok_or_else(bad)?;
+            Ok(i64::from_be_bytes((*field).try_into().map_err(|_| bad())?))
+        };
+        Ok(match tag {
+            1 => Op::Set(s(0)?, s(1)?, (*fields.get(2).ok_or_else(bad)?).try_into()?),
+            2 => Op::Del(s(0)?, s(1)?),
+            3 => Op::Incr(s(0)?, s(1)?, n(2)?),
+            4 => Op::ExpireAt(s(0)?, s(1)?, n(2)?),
+            5 => Op::Persist(s(0)?, s(1)?),
+            6 => Op::Move(s(0)?, s(1)?, s(2)?),
+            7 => Op::Clone(s(0)?, s(1)?),
+            8 => Op::DropTable(s(0)?),
+            9 => Op::FlushAll,
+            _ => return Err(bad()),
+        })
+    }
+
+    // 日志里只有执行成功的操作，按原来的顺序重放结果也一样
+    fn apply(self, mem: &MemTable) -> Result<(), KvError> {
+        match self {
+            Op::Set(t, k, v) => mem.set(&t, k, v).map(drop),
+            Op::Del(t, k) => mem.del(&t, &k).map(drop),
+            Op::Incr(t, k, n) => mem.incr(&t, &k, n).map(drop),
+            Op::ExpireAt(t, k, d) => mem.expire_at(&t, &k, d).map(drop),
+            Op::Persist(t, k) => mem.persist(&t, &k).map(drop),
+            Op::Move(s, d, k) => mem.move_key(&s, &d, &k, true).map(drop),
+            Op::Clone(s, d) => mem.clone_table(&s, &d).map(drop),
+            Op::DropTable(t) => mem.drop_table(&t).map(drop),
+            Op::FlushAll => mem.flush_all().map(drop),
+        }
+    }
+}
+
+fn encode_record(op: &Op, buf: &mut Vec<u8>) -> Result<(), KvError> {
+    let body = op.encode()?;
+    let mut crc = Crc::new();
+    crc.update(&body);
+    buf.extend_from_slice(&crc.sum().to_be_bytes());
+    buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&body);
+    Ok(())
+}
+
+// 读下一个 record，文件正好在 record 之间结束时返回 None
+fn read_record(reader: &mut impl Read) -> Result<Option<(Op, usize)>, KvError> {
+    let mut header = [0; HEADER_LEN];
+    let mut n = 0;
+    while n < HEADER_LEN {
+        match reader.read(&mut header[n..]) {
+            Ok(0) if n == 0 => return Ok(None),
+            Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(len) => n += len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let checksum = u32::from_be_bytes(header[..4].try_into().unwrap());
+    let len = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    let mut crc = Crc::new();
+    crc.update(&body);
+    if crc.sum() != checksum {
+        return Err(KvError::Internal("WAL record checksum mismatch".into()));
+    }
+    Ok(Some((Op::decode(&body)?, HEADER_LEN + len)))
+}
+
+/// 带着 AOF 日志和快照的 MemTable，重启之后数据还在
+///
+/// 每个写操作执行成功之后追加到日志，写操作之间拿着同一个锁，日志的顺序就是执行的顺序；
+/// 读不加锁，直接访问 MemTable。日志只写到操作系统，调用 flush（或者带着 flush 扩展的命令）
+/// 才 fsync。snapshot 换一个新的日志文件，把当前的数据写成快照，再删掉旧的日志：
+/// `<n>.snapshot` 是 `<n>.wal` 之前的所有数据。启动时读最新的快照，再按顺序重放之后的日志，
+/// 遇到写了一半的 record 就截断，所以崩溃最多丢掉还没有 fsync 的写入。
+/// version 不会保存，重启之后从头开始
+#[derive(Clone)]
+pub struct DurableMemTable(Arc<Inner>);
+
+struct Inner {
+    dir: PathBuf,
+    mem: MemTable,
+    wal: Mutex<Wal>,
+    // 同时只有一个 snapshot
+    snapshotting: Mutex<()>,
+}
+
+struct Wal {
+    id: u64,
+    file: File,
+}
+
+impl Wal {
+    fn create(dir: &Path, id: u64) -> Result<Self, KvError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_path(dir, id, "wal"))?;
+        Ok(Self { id, file })
+    }
+
+    fn append(&mut self, ops: &[Op]) -> Result<(), KvError> {
+        let mut buf = Vec::new();
+        for op in ops {
+            encode_record(op, &mut buf)?;
+        }
+        // 一次写入，多个 key 的操作不会只留下一部分在文件里
+        self.file.write_all(&buf)?;
+        Ok(())
+    }
+}
+
+impl DurableMemTable {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        Self::open_with(path.as_ref(), MemTable::new())
+    }
+
+    /// 过期的 key 被清掉的时候往 changefeed 里发 Expired 事件
+    pub fn open_with_changefeed(
+        path: impl AsRef<Path>,
+        changefeed: Changefeed,
+    ) -> Result<Self, KvError> {
+        Self::open_with(path.as_ref(), MemTable::new().with_changefeed(changefeed))
+    }
+
+    fn open_with(dir: &Path, mem: MemTable) -> Result<Self, KvError> {
+        fs::create_dir_all(dir)?;
+        let (mut snapshots, mut logs) = (Vec::new(), Vec::new());
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let name = name.to_str().unwrap_or_default();
+            if let Some(id) = parse_id(name, ".snapshot") {
+                snapshots.push(id);
+            } else if let Some(id) = parse_id(name, ".wal") {
+                logs.push(id);
+            }
+        }
+        snapshots.sort_unstable();
+        logs.sort_unstable();
+
+        // 快照是写完之后才改名的，最新的一定是完整的
+        let base = snapshots.last().copied().unwrap_or(0);
+        if let Some(&id) = snapshots.last() {
+            replay(&file_path(dir, id, "snapshot"), &mem, false)?;
+        }
+        logs.retain(|&id| id >= base);
+        for &id in &logs {
+            replay(&file_path(dir, id, "wal"), &mem, true)?;
+        }
+        // 旧的日志不再写，从一个新的文件开始
+        let id = logs.last().map_or(base, |id| id + 1);
+        let wal = Wal::create(dir, id)?;
+        let store = Self(Arc::new(Inner {
+            dir: dir.into(),
+            mem,
+            wal: Mutex::new(wal),
+            snapshotting: Mutex::new(()),
+        }));
+        store.remove_before(base)?;
+        Ok(store)
+    }
+
+    /// 把当前的数据写成快照，删掉它之前的日志和快照，返回快照里 key 的数量。
+    /// 复制数据的时候拿着写锁，写操作要等它复制完；写文件的时候不阻塞读写
+    pub fn snapshot(&self) -> Result<usize, KvError> {
+        let db = &self.0;
+        let _snapshotting = lock(&db.snapshotting);
+        let (id, ops) = {
+            let mut wal = lock(&db.wal);
+            let id = wal.id + 1;
+            wal.file.sync_data()?;
+            *wal = Wal::create(&db.dir, id)?;
+            (id, self.dump()?)
+        };
+
+        let tmp = db.dir.join(format!("{:010}.snapshot.tmp", id));
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let mut buf = Vec::new();
+        let mut keys = 0;
+        for op in &ops {
+            keys += matches!(op, Op::Set(..)) as usize;
+            buf.clear();
+            encode_record(op, &mut buf)?;
+            writer.write_all(&buf)?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&tmp, file_path(&db.dir, id, "snapshot"))?;
+        self.remove_before(id)?;
+        Ok(keys)
+    }
+
+    /// 在后台每隔 interval 写一次快照
+    pub fn spawn_snapshots(&self, interval: Duration) -> Result<Sweeper, KvError> {
+        let store = self.clone();
+        Sweeper::spawn("kv-wal-snapshot", interval, move || store.snapshot())
+    }
+
+    // 所有的 key 和它们的过期时间
+    fn dump(&self) -> Result<Vec<Op>, KvError> {
+        let mem = &self.0.mem;
+        let mut ops = Vec::new();
+        for table in mem.list_tables()? {
+            for pair in mem.get_all(&table)? {
+                let deadline = mem.deadline(&table, &pair.key).unwrap_or_default();
+                let value = pair.value.unwrap_or_default();
+                ops.push(Op::Set(table.clone(), pair.key.clone(), value));
+                if let Some(deadline) = deadline {
+                    ops.push(Op::ExpireAt(table.clone(), pair.key, deadline));
+                }
+            }
+        }
+        Ok(ops)
+    }
+
+    // 删掉 id 之前的日志和快照，它们的数据都已经在 id 的快照里了
+    fn remove_before(&self, id: u64) -> Result<(), KvError> {
+        for entry in fs::read_dir(&self.0.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_str().unwrap_or_default();
+            let old = parse_id(name, ".wal")
+                .or_else(|| parse_id(name, ".snapshot"))
+                .or_else(|| parse_id(name, ".snapshot.tmp"));
+            if matches!(old, Some(old) if old < id) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    // 执行写操作，成功之后把 ops 返回的操作写到日志
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&MemTable) -> Result<T, KvError>,
+        ops: impl FnOnce(&T) -> Vec<Op>,
+    ) -> Result<T, KvError> {
+        let mut wal = lock(&self.0.wal);
+        let result = f(&self.0.mem)?;
+        let ops = ops(&result);
+        if !ops.is_empty() {
+            wal.append(&ops)?;
+        }
+        Ok(result)
+    }
+}
+
+impl Storage for DurableMemTable {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.0.mem.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let op = Op::Set(table.into(), key.clone(), value.clone());
+        self.write(|m| m.set(table, key, value), |_| vec![op])
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.0.mem.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let op = Op::Del(table.into(), key.into());
+        self.write(|m| m.del(table, key), |_| vec![op])
+    }
+
+    fn mget(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        self.0.mem.mget(table, keys)
+    }
+
+    fn mset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+        let ops = pairs
+            .iter()
+            .map(|p| {
+                Op::Set(
+                    table.into(),
+                    p.key.clone(),
+                    p.value.clone().unwrap_or_default(),
+                )
+            })
+            .collect();
+        self.write(|m| m.mset(table, pairs), |_| ops)
+    }
+
+    fn mdel(&self, table: &str, keys: &[String]) -> Result<Vec<Option<Value>>, KvError> {
+        let ops = keys
+            .iter()
+            .map(|k| Op::Del(table.into(), k.clone()))
+            .collect();
+        self.write(|m| m.mdel(table, keys), |_| ops)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.0.mem.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.0.mem.get_iter(table)
+    }
+
+    fn snapshot(&self, table: &str) -> Result<Box<dyn Snapshot>, KvError> {
+        self.0.mem.snapshot(table)
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        self.0.mem.len(table)
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        range: KeyRange<'_>,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.0.mem.get_range(table, range)
+    }
+
+    fn admin(&self, command: &str, args: &[Value]) -> Result<Vec<Kvpair>, KvError> {
+        match command {
+            FLUSH_COMMAND => {
+                self.flush()?;
+                Ok(vec![Kvpair::new("flushed", true.into())])
+            }
+            COMPACT_COMMAND => {
+                let keys = self.snapshot()?;
+                Ok(vec![Kvpair::new("keys", (keys as i64).into())])
+            }
+            _ => self.0.mem.admin(command, args),
+        }
+    }
+
+    fn clone_table(&self, src: &str, dst: &str) -> Result<usize, KvError> {
+        let op = Op::Clone(src.into(), dst.into());
+        self.write(|m| m.clone_table(src, dst), |_| vec![op])
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.0.mem.list_tables()
+    }
+
+    fn stats(&self) -> Result<Vec<TableStats>, KvError> {
+        self.0.mem.stats()
+    }
+
+    fn flush_all(&self) -> Result<usize, KvError> {
+        self.write(|m| m.flush_all(), |_| vec![Op::FlushAll])
+    }
+
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        let op = Op::DropTable(table.into());
+        self.write(|m| m.drop_table(table), |_| vec![op])
+    }
+
+    fn move_key(
+        &self,
+        src: &str,
+        dst: &str,
+        key: &str,
+        force: bool,
+    ) -> Result<Option<Value>, KvError> {
+        let op = Op::Move(src.into(), dst.into(), key.into());
+        self.write(
+            |m| m.move_key(src, dst, key, force),
+            |moved| moved.as_ref().map(|_| op).into_iter().collect(),
+        )
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let op = Op::Incr(table.into(), key.into(), delta);
+        self.write(|m| m.incr(table, key, delta), |_| vec![op])
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        let op = Op::Set(table.into(), key.clone(), value.clone());
+        self.write(
+            |m| m.set_nx(table, key, value),
+            |&written| written.then_some(op).into_iter().collect(),
+        )
+    }
+
+    fn expire_at(&self, table: &str, key: &str, deadline: i64) -> Result<bool, KvError> {
+        let op = Op::ExpireAt(table.into(), key.into(), deadline);
+        self.write(
+            |m| m.expire_at(table, key, deadline),
+            |&existed| existed.then_some(op).into_iter().collect(),
+        )
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let op = Op::Persist(table.into(), key.into());
+        self.write(
+            |m| m.persist(table, key),
+            |&persisted| persisted.then_some(op).into_iter().collect(),
+        )
+    }
+
+    fn deadline(&self, table: &str, key: &str) -> Result<Option<i64>, KvError> {
+        self.0.mem.deadline(table, key)
+    }
+
+    // 过期的 key 重放之后照样是过期的，删除不需要写日志
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.0.mem.purge_expired()
+    }
+
+    fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        self.0.mem.version(table, key)
+    }
+
+    fn set_if_version(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        version: u64,
+    ) -> Result<u64, KvError> {
+        let op = Op::Set(table.into(), key.clone(), value.clone());
+        self.write(
+            |m| m.set_if_version(table, key, value, version),
+            |_| vec![op],
+        )
+    }
+
+    fn meta(&self, table: &str, key: &str) -> Result<Option<KeyMeta>, KvError> {
+        self.0.mem.meta(table, key)
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        lock(&self.0.wal).file.sync_data()?;
+        Ok(())
+    }
+
+    fn metrics(&self) -> Result<StorageMetrics, KvError> {
+        self.0.mem.metrics()
+    }
+}
+
+// 重放一个日志或者快照。日志遇到不完整或者校验失败的 record 时截断到它之前；
+// 快照是完整写完才改名的，读不出来就是出错了
+fn replay(path: &Path, mem: &MemTable, truncate: bool) -> Result<(), KvError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut offset = 0u64;
+    loop {
+        match read_record(&mut reader) {
+            Ok(Some((op, size))) => {
+                op.apply(mem)?;
+                offset += size as u64;
+            }
+            Ok(None) => return Ok(()),
+            Err(e) if truncate => {
+                warn!("Truncating {:?} at {}: {}", path, offset, e);
+                OpenOptions::new().write(true).open(path)?.set_len(offset)?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn parse_id(name: &str, suffix: &str) -> Option<u64> {
+    name.strip_suffix(suffix)?.parse().ok()
+}
+
+fn file_path(dir: &Path, id: u64, ext: &str) -> PathBuf {
+    dir.join(format!("{:010}.{}", id, ext))
+}
+
+// 持有锁的线程 panic 了也继续用，日志总是在操作成功之后才写
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::expiry::now_ms;
+    use crate::StorageExt;
+    use tempfile::tempdir;
+
+    fn write_some(store: &DurableMemTable) {
+        store.hset("t1", "k1", "v1").unwrap();
+        store
+            .mset(
+                "t1",
+                vec![Kvpair::new("k2", 2.into()), Kvpair::new("k3", 3.into())],
+            )
+            .unwrap();
+        store.incr("t1", "k2", 40).unwrap();
+        store.del("t1", "k3").unwrap();
+        store.expire_at("t1", "k1", now_ms() + 60_000).unwrap();
+        store.hset("t2", "k1", "gone").unwrap();
+        store.drop_table("t2").unwrap();
+        store.move_key("t1", "t3", "k2", false).unwrap();
+    }
+
+    fn check(store: &DurableMemTable) {
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert!(store.deadline("t1", "k1").unwrap().is_some());
+        assert_eq!(store.get("t1", "k3").unwrap(), None);
+        assert_eq!(store.get("t3", "k2").unwrap(), Some(42.into()));
+        assert_eq!(store.len("t2").unwrap(), 0);
+    }
+
+    #[test]
+    fn wal_should_recover_after_restart() {
+        let dir = tempdir().unwrap();
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        write_some(&store);
+        store.flush().unwrap();
+        check(&store);
+        drop(store);
+
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        check(&store);
+        // 重放之后接着写，再重启一次
+        store.incr("t3", "k2", 1).unwrap();
+        drop(store);
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        assert_eq!(store.get("t3", "k2").unwrap(), Some(43.into()));
+    }
+
+    #[test]
+    fn snapshot_should_replace_old_logs() {
+        let dir = tempdir().unwrap();
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        write_some(&store);
+        assert_eq!(store.snapshot().unwrap(), 2);
+        store.hset("t1", "k4", "after").unwrap();
+        drop(store);
+
+        let mut files: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["0000000001.snapshot", "0000000001.wal"]);
+
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        check(&store);
+        assert_eq!(store.get("t1", "k4").unwrap(), Some("after".into()));
+        // 快照之后的 INCR 只重放一次
+        store.incr("t3", "k2", 1).unwrap();
+        store.snapshot().unwrap();
+        drop(store);
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        assert_eq!(store.get("t3", "k2").unwrap(), Some(43.into()));
+    }
+
+    #[test]
+    fn wal_should_truncate_torn_records() {
+        let dir = tempdir().unwrap();
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        store.hset("t1", "k1", "v1").unwrap();
+        store.hset("t1", "k2", "v2").unwrap();
+        drop(store);
+
+        // 最后一个 record 只写了一半
+        let path = file_path(dir.path(), 0, "wal");
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+        store.hset("t1", "k3", "v3").unwrap();
+        drop(store);
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        assert_eq!(store.len("t1").unwrap(), 2);
+    }
+}
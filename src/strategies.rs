@@ -0,0 +1,202 @@
+//! 协议类型的 proptest strategy
+//!
+//! 打开 `proptest` feature 之后可用，backend 的实现者可以用它们来写自己的
+//! property test，比如任意 Kvpair 写进去之后都能原样读出来。
+
+use bytes::Bytes;
+use proptest::collection::vec;
+use proptest::num::f64::{NEGATIVE, NORMAL, POSITIVE, ZERO};
+use proptest::option;
+use proptest::prelude::*;
+
+use crate::command_request::RequestData;
+use crate::*;
+
+/// table 和 key 的名字
+pub fn name() -> impl Strategy<Value = String> {
+    "[a-zA-Z0-9_:-]{1,16}"
+}
+
+/// 任意的 Value。float 不包括 NaN，这样 roundtrip 之后还能比较
+pub fn value() -> impl Strategy<Value = Value> {
+    prop_oneof![
+        any::<String>().prop_map(Value::from),
+        vec(any::<u8>(), 0..64).prop_map(|v| Bytes::from(v).into()),
+        any::<i64>().prop_map(Value::from),
+        (NORMAL | ZERO | POSITIVE | NEGATIVE).prop_map(Value::from),
+        any::<bool>().prop_map(Value::from),
+    ]
+}
+
+/// 任意的 Kvpair
+pub fn kvpair() -> impl Strategy<Value = Kvpair> {
+    (name(), value()).prop_map(|(k, v)| Kvpair::new(k, v))
+}
+
+/// 任意的 ValueFilter，kind 包括不认识的类型
+pub fn value_filter() -> impl Strategy<Value = ValueFilter> {
+    let kind = prop_oneof![
+        Just(String::new()),
+        Just("string".to_string()),
+        Just("integer".to_string()),
+        name(),
+    ];
+    (any::<String>(), any::<String>(), kind).prop_map(|(prefix, contains, kind)| ValueFilter {
+        prefix,
+        contains,
+        kind,
+    })
+}
+
+/// 任意的 CommandRequest，覆盖所有的 RequestData
+pub fn command_request() -> impl Strategy<Value = CommandRequest> {
+    let keys = || vec(name(), 0..8);
+    let data = prop_oneof![
+        (name(), name()).prop_map(|(table, key)| RequestData::Hget(Hget { table, key })),
+        (
+            name(),
+            name(),
+            option::of(value_filter()),
+            any::<u32>(),
+            any::<i32>()
+        )
+            .prop_map(|(table, pattern, filter, chunk_size, order)| {
+                RequestData::Hgetall(Hgetall {
+                    table,
+                    pattern,
+                    filter,
+                    chunk_size,
+                    order,
+                })
+            }),
+        (name(), keys()).prop_map(|(table, keys)| RequestData::Hmget(Hmget { table, keys })),
+        (name(), option::of(kvpair()))
+            .prop_map(|(table, pair)| RequestData::Hset(Hset { table, pair })),
+        (name(), vec(kvpair(), 0..8))
+            .prop_map(|(table, pairs)| RequestData::Hmset(Hmset { table, pairs })),
+        (name(), name()).prop_map(|(table, key)| RequestData::Hdel(Hdel { table, key })),
+        (name(), keys()).prop_map(|(table, keys)| RequestData::Hmdel(Hmdel { table, keys })),
+        (name(), name()).prop_map(|(table, key)| RequestData::Hexist(Hexist { table, key })),
+        (name(), keys()).prop_map(|(table, keys)| RequestData::Hmexist(Hmexist { table, keys })),
+        (name(), any::<u64>(), any::<u32>(), name(), any::<i32>()).prop_map(
+            |(table, cursor, count, pattern, order)| {
+                RequestData::Hscan(Hscan {
+                    table,
+                    cursor,
+                    count,
+                    pattern,
+                    order,
+                })
+            }
+        ),
+        (name(), vec(value(), 0..4))
+            .prop_map(|(command, args)| RequestData::Admin(Admin { command, args })),
+        (name(), name()).prop_map(|(table, key)| RequestData::Undelete(Undelete { table, key })),
+        (name(), any::<bool>())
+            .prop_map(|(table, all)| RequestData::PurgeTrash(PurgeTrash { table, all })),
+        (name(), name()).prop_map(|(src, dst)| RequestData::CloneTable(CloneTable { src, dst })),
+        (name(), name(), name(), any::<bool>()).prop_map(|(src_table, dst_table, key, force)| {
+            RequestData::Move(Move {
+                src_table,
+                dst_table,
+                key,
+                force,
+            })
+        }),
+        (name(), name(), any::<i64>())
+            .prop_map(|(table, key, delta)| RequestData::Hincrby(Hincrby { table, key, delta })),
+        (name(), option::of(kvpair()))
+            .prop_map(|(table, pair)| RequestData::Hsetnx(Hsetnx { table, pair })),
+        (name(), name()).prop_map(|(table, key)| RequestData::Hgetdel(Hgetdel { table, key })),
+        (name(), option::of(kvpair()))
+            .prop_map(|(table, pair)| RequestData::Hgetset(Hgetset { table, pair })),
+        (name(), name(), any::<u64>())
+            .prop_map(|(table, key, ttl_ms)| RequestData::Hexpire(Hexpire { table, key, ttl_ms })),
+        (name(), name()).prop_map(|(table, key)| RequestData::Hpersist(Hpersist { table, key })),
+        (name(), name()).prop_map(|(table, key)| RequestData::Httl(Httl { table, key })),
+        (name(), name(), any::<i64>()).prop_map(|(table, key, at_ms)| RequestData::Hexpireat(
+            Hexpireat { table, key, at_ms }
+        )),
+        name().prop_map(|table| RequestData::Hkeys(Hkeys { table })),
+        name().prop_map(|table| RequestData::Hvals(Hvals { table })),
+        name().prop_map(|table| RequestData::Hlen(Hlen { table })),
+        name().prop_map(|table| RequestData::Hdroptable(Hdroptable { table })),
+        Just(RequestData::Htables(Htables {})),
+        Just(RequestData::Hflushall(Hflushall {})),
+        Just(RequestData::Hstats(Hstats {})),
+        any::<String>().prop_map(|payload| RequestData::Ping(Ping { payload })),
+        Just(RequestData::Info(Info {})),
+        name().prop_map(|topic| RequestData::Subscribe(Subscribe { topic })),
+        (name(), any::<u64>())
+            .prop_map(|(topic, id)| RequestData::Unsubscribe(Unsubscribe { topic, id })),
+        (name(), option::of(value()))
+            .prop_map(|(topic, data)| RequestData::Publish(Publish { topic, data })),
+        Just(RequestData::Multi(Multi {})),
+        Just(RequestData::Exec(Exec {})),
+        Just(RequestData::Discard(Discard {})),
+        (name(), keys()).prop_map(|(table, keys)| RequestData::Watch(Watch { table, keys })),
+        Just(RequestData::Unwatch(Unwatch {})),
+        (name(), name()).prop_map(|(table, key)| RequestData::Hgetver(Hgetver { table, key })),
+        (name(), name()).prop_map(|(table, key)| RequestData::Hmeta(Hmeta { table, key })),
+        (name(), name(), any::<u64>()).prop_map(|(table, key, version)| {
+            RequestData::Hgetat(Hgetat {
+                table,
+                key,
+                version,
+            })
+        }),
+        (name(), name(), option::of(value()), any::<u32>()).prop_map(
+            |(table, index, value, limit)| {
+                RequestData::Hquery(Hquery {
+                    table,
+                    index,
+                    value,
+                    limit,
+                })
+            }
+        ),
+        name().prop_map(|name| RequestData::Backup(Backup { name })),
+        name().prop_map(|name| RequestData::Restore(Restore { name })),
+        (name(), option::of(kvpair()), any::<u64>()).prop_map(|(table, pair, version)| {
+            RequestData::Hsetver(Hsetver {
+                table,
+                pair,
+                version,
+            })
+        }),
+        vec((name(), name()), 0..4).prop_map(|keys| {
+            let requests = keys
+                .into_iter()
+                .map(|(table, key)| CommandRequest::new_hget(table, key))
+                .collect();
+            RequestData::Batch(Batch { requests })
+        }),
+        (name(), name(), name(), any::<u32>()).prop_map(|(table, start, end, limit)| {
+            RequestData::Hrange(Hrange {
+                table,
+                start,
+                end,
+                limit,
+            })
+        }),
+    ];
+    option::of(data).prop_map(|request_data| CommandRequest {
+        request_data,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    proptest! {
+        #[test]
+        fn value_encode_decode_should_roundtrip(v in value()) {
+            let data: Vec<u8> = v.clone().try_into().unwrap();
+            let v1: Value = data.as_slice().try_into().unwrap();
+            prop_assert_eq!(v, v1);
+        }
+    }
+}